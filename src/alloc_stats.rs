@@ -0,0 +1,207 @@
+//! Allocation accounting for each encode and decode, enabled by the `alloc-stats` feature.
+//!
+//! This is meant for benchmarks catching memory regressions in the codec. Install
+//! [`CountingAllocator`] as the global allocator, then register a hook with [`set_hook()`]: each
+//! `encode_slice()`/`decode_slice()` call on the current thread reports the number of
+//! allocations it made and the peak memory it used on top of what was already allocated.
+//!
+//! ```
+//! # use mqttrs::{*, alloc_stats::*};
+//! use std::{alloc::System, cell::RefCell};
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: CountingAllocator = CountingAllocator::new(System);
+//!
+//! thread_local!(static EVENTS: RefCell<Vec<AllocEvent>> = RefCell::new(Vec::new()));
+//! set_hook(Some(|event| EVENTS.with(|e| e.borrow_mut().push(*event))));
+//!
+//! let subscribe = [0b1000_0010, 8, 0, 1, 0, 3, b'a', b'/', b'b', 0];
+//! decode_slice(&subscribe)?;
+//! let event = EVENTS.with(|e| e.borrow()[0]);
+//! assert_eq!(metrics::Direction::Decode, event.direction);
+//! assert!(event.allocations > 0);
+//! # Ok::<(), Error>(())
+//! ```
+//!
+//! [`CountingAllocator`]: struct.CountingAllocator.html
+//! [`set_hook()`]: fn.set_hook.html
+
+use crate::metrics::Direction;
+use core::cell::Cell;
+use std::alloc::{GlobalAlloc, Layout, System};
+
+std::thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    /// Bytes allocated minus bytes freed by this thread. Can be negative if the thread frees
+    /// memory allocated by others.
+    static CURRENT: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+    static HOOK: Cell<Option<fn(&AllocEvent)>> = const { Cell::new(None) };
+}
+
+/// Global allocator counting the allocations of each thread, wrapping another allocator.
+///
+/// Without it, all [`AllocEvent`]s report zero.
+///
+/// [`AllocEvent`]: struct.AllocEvent.html
+#[derive(Debug, Default)]
+pub struct CountingAllocator<A = System> {
+    inner: A,
+}
+
+impl<A> CountingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        CountingAllocator { inner }
+    }
+}
+
+fn allocated(size: usize) {
+    let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+    let _ = CURRENT.try_with(|current| {
+        let now = current.get() + size as isize;
+        current.set(now);
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(now)));
+    });
+}
+
+fn freed(size: usize) {
+    let _ = CURRENT.try_with(|current| current.set(current.get() - size as isize));
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = self.inner.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            freed(layout.size());
+            allocated(new_size);
+        }
+        new
+    }
+}
+
+/// Allocations made by one encode or decode call, passed to the hook set by [`set_hook()`].
+///
+/// [`set_hook()`]: fn.set_hook.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocEvent {
+    pub direction: Direction,
+    /// Number of allocations, reallocations included.
+    pub allocations: u64,
+    /// Largest amount of memory allocated during the call, in bytes, on top of what was allocated
+    /// before it. Memory that the call returns, like the topics of a decoded `Subscribe`, counts.
+    pub peak_bytes: usize,
+}
+
+/// Call `hook` after each encode and decode made by the current thread, or stop calling it if
+/// `hook` is `None`.
+pub fn set_hook(hook: Option<fn(&AllocEvent)>) {
+    HOOK.with(|h| h.set(hook));
+}
+
+/// Measures one encode or decode call, reporting it to the hook when dropped.
+pub(crate) struct Measure {
+    direction: Direction,
+    allocations: u64,
+    current: isize,
+    /// Peak of an enclosing measure, restored when this one ends.
+    outer_peak: isize,
+}
+
+impl Measure {
+    pub(crate) fn start(direction: Direction) -> Self {
+        let current = CURRENT.with(Cell::get);
+        Measure {
+            direction,
+            allocations: ALLOCATIONS.with(Cell::get),
+            current,
+            outer_peak: PEAK.with(|peak| peak.replace(current)),
+        }
+    }
+}
+
+impl Drop for Measure {
+    fn drop(&mut self) {
+        let peak = PEAK.with(|peak| peak.replace(peak.get().max(self.outer_peak)));
+        let event = AllocEvent {
+            direction: self.direction,
+            allocations: ALLOCATIONS.with(Cell::get) - self.allocations,
+            peak_bytes: (peak - self.current) as usize,
+        };
+        if let Some(hook) = HOOK.with(Cell::get) {
+            hook(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use std::vec::Vec;
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator::new(System);
+
+    thread_local!(static EVENTS: Cell<Vec<AllocEvent>> = const { Cell::new(Vec::new()) });
+
+    fn events() -> Vec<AllocEvent> {
+        EVENTS.with(Cell::take)
+    }
+
+    #[test]
+    fn counts() {
+        set_hook(Some(|event| {
+            EVENTS.with(|events| {
+                let mut all = events.take();
+                all.push(*event);
+                events.set(all);
+            })
+        }));
+        let mut buf = [0u8; 16];
+        encode_slice(&Packet::Pingreq, &mut buf).unwrap();
+        decode_slice(&buf).unwrap();
+        let none = |direction| AllocEvent {
+            direction,
+            allocations: 0,
+            peak_bytes: 0,
+        };
+        assert_eq!(
+            vec![none(Direction::Encode), none(Direction::Decode)],
+            events()
+        );
+
+        let topics = vec![SubscribeTopic::new("a/b", QoS::AtMostOnce).unwrap()];
+        let len = encode_slice(&Subscribe::new(Pid::new(), topics).into(), &mut buf).unwrap();
+        let decoded = decode_slice(&buf[..len]).unwrap();
+        let recorded = events();
+        assert_eq!(none(Direction::Encode), recorded[0]);
+        assert!(recorded[1].allocations > 0);
+        assert!(recorded[1].peak_bytes > 0);
+        drop(decoded);
+
+        set_hook(None);
+        decode_slice(&buf[..len]).unwrap();
+        assert!(events().is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+//! [Quickcheck] `Arbitrary` implementations, enabled by the `quickcheck` feature.
+//!
+//! Like the `strategy` module, generated values are valid according to the spec, and the
+//! borrowed fields of `Packet<'static>` point to leaked allocations.
+//!
+//! [Quickcheck]: https://docs.rs/quickcheck
+
+use crate::*;
+use core::convert::TryFrom;
+use quickcheck::{Arbitrary, Gen};
+use std::{boxed::Box, string::String, vec::Vec};
+
+const TOPIC_CHARS: &[char] = &[
+    'a', 'b', 'c', 'x', 'y', 'z', 'A', 'Z', '0', '1', '9', '_', '-', '.', ' ', 'é', '温',
+];
+
+fn leak_str(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+fn leak_bytes(b: Vec<u8>) -> &'static [u8] {
+    Box::leak(b.into_boxed_slice())
+}
+
+fn gen_range(g: &mut Gen, min: usize, max: usize) -> usize {
+    min + usize::arbitrary(g) % (max - min + 1)
+}
+
+fn gen_level(g: &mut Gen, min_len: usize) -> String {
+    (0..gen_range(g, min_len, 12))
+        .map(|_| *g.choose(TOPIC_CHARS).unwrap())
+        .collect()
+}
+
+/// One to six `/`-separated levels, without wildcards.
+fn gen_topic_name(g: &mut Gen) -> String {
+    let mut topic = gen_level(g, 1);
+    for _ in 1..gen_range(g, 1, 6) {
+        topic.push('/');
+        topic.push_str(&gen_level(g, 0));
+    }
+    topic
+}
+
+/// A topic name where some levels may be replaced by the `+` and `#` wildcards.
+fn gen_topic_filter(g: &mut Gen) -> String {
+    let mut levels = Vec::new();
+    for _ in 0..gen_range(g, 1, 6) {
+        if *g.choose(&[false, false, true]).unwrap() {
+            levels.push(String::from("+"));
+        } else {
+            levels.push(gen_level(g, 1));
+        }
+    }
+    if bool::arbitrary(g) {
+        levels.push(String::from("#"));
+    }
+    levels.join("/")
+}
+
+fn gen_vec<T>(g: &mut Gen, min: usize, max: usize, f: impl Fn(&mut Gen) -> T) -> Vec<T> {
+    (0..gen_range(g, min, max)).map(|_| f(g)).collect()
+}
+
+impl Arbitrary for Pid {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Pid::try_from(u16::arbitrary(g)).unwrap_or_default()
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new(self.get().shrink().filter_map(|p| Pid::try_from(p).ok()))
+    }
+}
+
+impl Arbitrary for QoS {
+    fn arbitrary(g: &mut Gen) -> Self {
+        *g.choose(&[QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce])
+            .unwrap()
+    }
+}
+
+impl Arbitrary for QosPid {
+    fn arbitrary(g: &mut Gen) -> Self {
+        match QoS::arbitrary(g) {
+            QoS::AtMostOnce => QosPid::AtMostOnce,
+            QoS::AtLeastOnce => QosPid::AtLeastOnce(Pid::arbitrary(g)),
+            QoS::ExactlyOnce => QosPid::ExactlyOnce(Pid::arbitrary(g)),
+        }
+    }
+}
+
+impl Arbitrary for SubscribeTopic {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SubscribeTopic::new(&gen_topic_filter(g), QoS::arbitrary(g)).unwrap()
+    }
+}
+
+impl Arbitrary for SubscribeReturnCodes {
+    fn arbitrary(g: &mut Gen) -> Self {
+        if bool::arbitrary(g) {
+            SubscribeReturnCodes::Success(QoS::arbitrary(g))
+        } else {
+            SubscribeReturnCodes::Failure
+        }
+    }
+}
+
+impl Arbitrary for ConnectReturnCode {
+    fn arbitrary(g: &mut Gen) -> Self {
+        ConnectReturnCode::from_u8(u8::arbitrary(g) % 6).unwrap()
+    }
+}
+
+impl Arbitrary for Connack {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let code = ConnectReturnCode::arbitrary(g);
+        Connack {
+            // The session can only be present if the connection was accepted.
+            session_present: bool::arbitrary(g) && code == ConnectReturnCode::Accepted,
+            code,
+        }
+    }
+}
+
+impl Arbitrary for Subscribe {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Subscribe {
+            pid: Pid::arbitrary(g),
+            topics: gen_vec(g, 1, 10, SubscribeTopic::arbitrary),
+        }
+    }
+}
+
+impl Arbitrary for Suback {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Suback {
+            pid: Pid::arbitrary(g),
+            return_codes: gen_vec(g, 1, 10, SubscribeReturnCodes::arbitrary),
+        }
+    }
+}
+
+impl Arbitrary for Unsubscribe {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Unsubscribe {
+            pid: Pid::arbitrary(g),
+            topics: gen_vec(g, 1, 10, gen_topic_filter),
+        }
+    }
+}
+
+impl Arbitrary for LastWill<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        LastWill {
+            topic: leak_str(gen_topic_name(g)),
+            message: leak_bytes(Vec::arbitrary(g)),
+            qos: QoS::arbitrary(g),
+            retain: bool::arbitrary(g),
+        }
+    }
+}
+
+impl Arbitrary for Connect<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        // A password can only be sent along with a username.
+        let username = if bool::arbitrary(g) {
+            Some(leak_str(gen_level(g, 1)))
+        } else {
+            None
+        };
+        let password = username.and(Option::<Vec<u8>>::arbitrary(g).map(leak_bytes));
+        Connect {
+            protocol: Protocol::MQTT311,
+            keep_alive: u16::arbitrary(g),
+            client_id: leak_str(gen_level(g, 0)),
+            clean_session: bool::arbitrary(g),
+            last_will: Option::arbitrary(g),
+            username,
+            password,
+        }
+    }
+}
+
+impl Arbitrary for Publish<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let qospid = QosPid::arbitrary(g);
+        Publish {
+            // The dup flag must not be set for `QoS 0` messages.
+            dup: bool::arbitrary(g) && qospid != QosPid::AtMostOnce,
+            qospid,
+            retain: bool::arbitrary(g),
+            topic_name: leak_str(gen_topic_name(g)),
+            payload: leak_bytes(Vec::arbitrary(g)),
+        }
+    }
+}
+
+impl Arbitrary for Packet<'static> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        match u8::arbitrary(g) % 14 {
+            0 => Connect::arbitrary(g).into(),
+            1 => Connack::arbitrary(g).into(),
+            2 => Publish::arbitrary(g).into(),
+            3 => Packet::Puback(Pid::arbitrary(g)),
+            4 => Packet::Pubrec(Pid::arbitrary(g)),
+            5 => Packet::Pubrel(Pid::arbitrary(g)),
+            6 => Packet::Pubcomp(Pid::arbitrary(g)),
+            7 => Subscribe::arbitrary(g).into(),
+            8 => Suback::arbitrary(g).into(),
+            9 => Unsubscribe::arbitrary(g).into(),
+            10 => Packet::Unsuback(Pid::arbitrary(g)),
+            11 => Packet::Pingreq,
+            12 => Packet::Pingresp,
+            _ => Packet::Disconnect,
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Asynchronous packet I/O over [async-std], enabled by the `async-std` feature.
+//!
+//! [`read_packet()`] reads exactly one packet from an [`async_std::io::Read`], however the bytes
+//! are split across reads, and [`write_packet()`] writes one packet to an
+//! [`async_std::io::Write`].
+//!
+//! ```
+//! # use mqttrs::{async_std::{read_packet, write_packet}, Error, Packet};
+//! # async_std::task::block_on(async {
+//! let mut stream = async_std::io::Cursor::new(Vec::new());
+//! write_packet(&mut stream, &Packet::Pingreq).await?;
+//! stream.set_position(0);
+//!
+//! let mut buf = Vec::new();
+//! assert_eq!(Some(Packet::Pingreq), read_packet(&mut stream, &mut buf, 1024).await?);
+//! assert_eq!(None, read_packet(&mut stream, &mut buf, 1024).await?);
+//! # Ok::<(), Error>(())
+//! # }).unwrap();
+//! ```
+//!
+//! [async-std]: https://docs.rs/async-std
+//! [`read_packet()`]: fn.read_packet.html
+//! [`write_packet()`]: fn.write_packet.html
+//! [`async_std::io::Read`]: https://docs.rs/async-std/1/async_std/io/trait.Read.html
+//! [`async_std::io::Write`]: https://docs.rs/async-std/1/async_std/io/trait.Write.html
+
+use crate::{futures_io, Error, Packet};
+use ::async_std::io::{Read, Write};
+use std::vec::Vec;
+
+/// Read one packet from `reader`, using `buf` to hold its bytes.
+///
+/// The fixed header is read first, then exactly the announced number of bytes, so no byte of
+/// the next packet is consumed and `reader` doesn't need to be buffered. Returns `Ok(None)` if
+/// `reader` is at end of file before the first byte of a packet; reaching the end of file in the
+/// middle of a packet is an `UnexpectedEof` [`Error::IoError`].
+///
+/// Packets larger than `max_packet_size` bytes (fixed header included) fail with
+/// [`Error::BudgetExceeded`] before `buf` grows, with the rest of the packet left unread.
+///
+/// This is [`futures_io::read_packet()`], as async-std streams implement the futures-io traits.
+///
+/// [`Error::IoError`]: ../enum.Error.html#variant.IoError
+/// [`Error::BudgetExceeded`]: ../enum.Error.html#variant.BudgetExceeded
+/// [`futures_io::read_packet()`]: ../futures_io/fn.read_packet.html
+pub async fn read_packet<'b, R>(
+    reader: &mut R,
+    buf: &'b mut Vec<u8>,
+    max_packet_size: usize,
+) -> Result<Option<Packet<'b>>, Error>
+where
+    R: Read + Unpin,
+{
+    futures_io::read_packet(reader, buf, max_packet_size).await
+}
+
+/// Encode `packet` and write it to `writer`.
+///
+/// The writer isn't flushed.
+pub async fn write_packet<W>(writer: &mut W, packet: &Packet<'_>) -> Result<(), Error>
+where
+    W: Write + Unpin,
+{
+    futures_io::write_packet(writer, packet).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use ::async_std::{io::Cursor, task::block_on};
+    use std::{io::ErrorKind, vec};
+
+    /// Yields one byte per read, like a slow network.
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn poll_read(
+            mut self: core::pin::Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> core::task::Poll<std::io::Result<usize>> {
+            let len = buf.len().min(1);
+            core::pin::Pin::new(&mut self.0).poll_read(cx, &mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn partial_reads() {
+        let publish = Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtLeastOnce(Pid::new()),
+            retain: false,
+            topic_name: "a/b",
+            payload: &[7; 200],
+        });
+        block_on(async {
+            let mut bytes = Cursor::new(Vec::new());
+            write_packet(&mut bytes, &publish).await.unwrap();
+            write_packet(&mut bytes, &Packet::Pingresp).await.unwrap();
+            let mut reader = Trickle(Cursor::new(bytes.into_inner()));
+            let mut buf = Vec::new();
+            assert_eq!(
+                Ok(Some(publish)),
+                read_packet(&mut reader, &mut buf, 1024).await
+            );
+            assert_eq!(
+                Ok(Some(Packet::Pingresp)),
+                read_packet(&mut reader, &mut buf, 1024).await
+            );
+            assert_eq!(Ok(None), read_packet(&mut reader, &mut buf, 1024).await);
+
+            let mut truncated = Cursor::new(vec![0b00110000, 10, 0]);
+            assert!(matches!(
+                read_packet(&mut truncated, &mut buf, 1024).await,
+                Err(Error::IoError(ErrorKind::UnexpectedEof, _))
+            ));
+            let mut malformed = Cursor::new(vec![0b00110000, 0xff, 0xff, 0xff, 0xff]);
+            assert_eq!(
+                Err(Error::MalformedRemainingLength),
+                read_packet(&mut malformed, &mut buf, 1024).await
+            );
+        });
+    }
+}
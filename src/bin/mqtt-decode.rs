@@ -0,0 +1,152 @@
+//! Decode MQTT packets from a file or stdin, for triaging malformed traffic.
+//!
+//! ```text
+//! mqtt-decode [--hex] [--explain] [FILE]
+//! ```
+//!
+//! Input is read as raw bytes, or as hex digits with `--hex` (whitespace and `:` separators are
+//! ignored). Each packet is printed with its offset in the input. Decoding stops at the first
+//! error, which is reported on stderr with the offset of the field that failed to decode, and an
+//! exit status of 1.
+
+use mqttrs::Packet;
+use std::{
+    env,
+    io::{self, Read, Write},
+    process::exit,
+};
+
+const USAGE: &str = "usage: mqtt-decode [--hex] [--explain] [FILE]";
+
+fn parse_hex(text: &[u8]) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = text
+        .iter()
+        .copied()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b':')
+        .collect();
+    if digits.len() & 1 != 0 {
+        return Err(format!("odd number of hex digits ({})", digits.len()));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let s = std::str::from_utf8(pair).map_err(|_| "invalid hex digit".to_string())?;
+            u8::from_str_radix(s, 16).map_err(|_| format!("invalid hex byte {:?}", s))
+        })
+        .collect()
+}
+
+fn main() {
+    let mut hex = false;
+    let mut explain = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--hex" => hex = true,
+            "--explain" => explain = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                exit(2);
+            }
+        }
+    }
+
+    let mut input = Vec::new();
+    let read = match &path {
+        Some(path) => std::fs::File::open(path).and_then(|mut f| f.read_to_end(&mut input)),
+        None => io::stdin().read_to_end(&mut input),
+    };
+    if let Err(e) = read {
+        eprintln!("mqtt-decode: {}", e);
+        exit(2);
+    }
+    let buf = if hex {
+        parse_hex(&input).unwrap_or_else(|e| {
+            eprintln!("mqtt-decode: {}", e);
+            exit(2);
+        })
+    } else {
+        input
+    };
+
+    let stdout = io::stdout();
+    if let Err(e) = decode(&buf, explain, &mut stdout.lock()) {
+        eprintln!("mqtt-decode: {}", e);
+        exit(1);
+    }
+}
+
+/// Print the packets of `buf` to `out`, until the first error.
+fn decode(buf: &[u8], explain: bool, out: &mut impl Write) -> Result<(), String> {
+    let mut offset = 0;
+    while offset < buf.len() {
+        let rest = &buf[offset..];
+        let explanation = match Packet::explain(rest) {
+            Some(explanation) => explanation,
+            None => {
+                return Err(format!(
+                    "@{}: incomplete packet ({} bytes)",
+                    offset,
+                    rest.len()
+                ))
+            }
+        };
+        let res = match &explanation.packet {
+            Ok(packet) if explain => writeln!(out, "@{}: {:?}\n{}", offset, packet, explanation),
+            Ok(packet) => writeln!(out, "@{}: {:?}", offset, packet),
+            // Show the fields decoded before the error.
+            Err(_) if explain => writeln!(out, "@{}: malformed packet\n{}", offset, explanation),
+            Err(_) => Ok(()),
+        };
+        res.map_err(|e| e.to_string())?;
+        if let Err(failure) = explanation.packet {
+            let field = &rest[failure.range.clone()];
+            return Err(format!(
+                "@{}: invalid {} in the packet at @{}: {} (bytes {:02x?})",
+                offset + failure.range.start,
+                failure.name,
+                offset,
+                failure.error,
+                &field[..field.len().min(8)]
+            ));
+        }
+        offset += explanation.bytes.len();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, parse_hex};
+
+    #[test]
+    fn hex() {
+        assert_eq!(Ok(vec![0xc0, 0x00]), parse_hex(b"c0 00\n"));
+        assert_eq!(Ok(vec![0x30, 0xab]), parse_hex(b"30:AB"));
+        assert!(parse_hex(b"c0 0").is_err());
+        assert!(parse_hex(b"zz").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let mut out = Vec::new();
+        // A Pingreq, then a Puback with a pid of 0.
+        let res = decode(&[0b11000000, 0, 0b01000000, 2, 0, 0], false, &mut out);
+        assert_eq!("@0: Pingreq\n", String::from_utf8(out).unwrap());
+        assert_eq!(
+            Err(
+                "@4: invalid pid in the packet at @2: packet identifier must not be 0 \
+                 (bytes [00, 00])"
+                    .to_string()
+            ),
+            res
+        );
+        let res = decode(&[0b11000000], false, &mut Vec::new());
+        assert_eq!(Err("@0: incomplete packet (1 bytes)".to_string()), res);
+    }
+}
@@ -0,0 +1,303 @@
+//! Encode MQTT packets described on the command line or in JSON, for crafting test inputs.
+//!
+//! ```text
+//! mqtt-gen [--raw] PACKET [OPTIONS]
+//! mqtt-gen [--raw] --json FILE
+//! ```
+//!
+//! Encoded bytes are printed as hex, or written as-is with `--raw`. Packets are described using
+//! these options:
+//!
+//! ```text
+//! connect     [--client-id ID] [--keep-alive SECS] [--clean] [--username USER]
+//!             [--password PASS] [--will-topic TOPIC --will-message MSG [--will-qos Q] [--will-retain]]
+//! connack     [--session-present] [--code CODE]
+//! publish     --topic TOPIC [--payload DATA] [--qos Q --pid PID] [--dup] [--retain]
+//! puback, pubrec, pubrel, pubcomp, unsuback   --pid PID
+//! subscribe   --pid PID --topic FILTER[:Q]...
+//! suback      --pid PID --code CODE...
+//! unsubscribe --pid PID --topic FILTER...
+//! pingreq, pingresp, disconnect
+//! ```
+//!
+//! The JSON description (`-` reads stdin) uses the `derive` feature representation, either one
+//! packet or an array of packets. Binary fields can be given as strings:
+//!
+//! ```text
+//! {"Publish": {"dup": false, "qospid": {"AtLeastOnce": 10}, "retain": false,
+//!              "topic_name": "a/b", "payload": "hello"}}
+//! ```
+
+use bytes::BytesMut;
+use mqttrs::*;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    env,
+    io::{self, Read, Write},
+    process::exit,
+};
+
+const USAGE: &str = "usage: mqtt-gen [--raw] PACKET [OPTIONS] | mqtt-gen [--raw] --json FILE";
+
+/// Command-line options, mapping each `--name` to its values (empty for flags).
+struct Opts(HashMap<String, Vec<String>>);
+
+impl Opts {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut map: HashMap<String, Vec<String>> = HashMap::new();
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next() {
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| format!("unexpected argument {:?}", arg))?;
+            let values = map.entry(name.to_string()).or_default();
+            if let Some(value) = args.next_if(|a| !a.starts_with("--")) {
+                values.push(value.clone());
+            }
+        }
+        Ok(Opts(map))
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    fn all(&self, name: &str) -> &[String] {
+        self.0.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.all(name).last().map(String::as_str)
+    }
+
+    fn required(&self, name: &str) -> Result<&str, String> {
+        self.get(name).ok_or_else(|| format!("missing --{}", name))
+    }
+
+    fn num<T: std::str::FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        match self.get(name) {
+            Some(s) => s.parse().map_err(|_| format!("invalid --{} {:?}", name, s)),
+            None => Ok(default),
+        }
+    }
+
+    fn pid(&self) -> Result<Pid, String> {
+        pid(self.num("pid", 1)?)
+    }
+}
+
+fn pid(n: u16) -> Result<Pid, String> {
+    Pid::try_from(n).map_err(|_| format!("invalid pid {}", n))
+}
+
+fn qos(n: u8) -> Result<QoS, String> {
+    match n {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        n => Err(format!("invalid qos {}", n)),
+    }
+}
+
+fn connect_code(n: u8) -> Result<ConnectReturnCode, String> {
+    Ok(match n {
+        0 => ConnectReturnCode::Accepted,
+        1 => ConnectReturnCode::RefusedProtocolVersion,
+        2 => ConnectReturnCode::RefusedIdentifierRejected,
+        3 => ConnectReturnCode::ServerUnavailable,
+        4 => ConnectReturnCode::BadUsernamePassword,
+        5 => ConnectReturnCode::NotAuthorized,
+        n => return Err(format!("invalid connack code {}", n)),
+    })
+}
+
+fn subscribe_code(s: &str) -> Result<SubscribeReturnCodes, String> {
+    match s.parse::<u8>() {
+        Ok(0x80) => Ok(SubscribeReturnCodes::Failure),
+        Ok(n) => Ok(SubscribeReturnCodes::Success(qos(n)?)),
+        Err(_) => Err(format!("invalid suback code {:?}", s)),
+    }
+}
+
+/// Build a packet from a packet name and its options.
+fn packet<'a>(name: &str, opts: &'a Opts) -> Result<Packet<'a>, String> {
+    Ok(match name {
+        "connect" => {
+            let last_will = match opts.get("will-topic") {
+                Some(topic) => Some(LastWill {
+                    topic,
+                    message: opts.required("will-message")?.as_bytes(),
+                    qos: qos(opts.num("will-qos", 0)?)?,
+                    retain: opts.flag("will-retain"),
+                }),
+                None => None,
+            };
+            Packet::Connect(Connect {
+                protocol: Protocol::MQTT311,
+                keep_alive: opts.num("keep-alive", 60)?,
+                client_id: opts.get("client-id").unwrap_or("mqtt-gen"),
+                clean_session: opts.flag("clean"),
+                last_will,
+                username: opts.get("username"),
+                password: opts.get("password").map(str::as_bytes),
+            })
+        }
+        "connack" => Packet::Connack(Connack {
+            session_present: opts.flag("session-present"),
+            code: connect_code(opts.num("code", 0)?)?,
+        }),
+        "publish" => Packet::Publish(Publish {
+            dup: opts.flag("dup"),
+            qospid: match qos(opts.num("qos", 0)?)? {
+                QoS::AtMostOnce => QosPid::AtMostOnce,
+                QoS::AtLeastOnce => QosPid::AtLeastOnce(opts.pid()?),
+                QoS::ExactlyOnce => QosPid::ExactlyOnce(opts.pid()?),
+            },
+            retain: opts.flag("retain"),
+            topic_name: opts.required("topic")?,
+            payload: opts.get("payload").unwrap_or_default().as_bytes(),
+        }),
+        "puback" => Packet::Puback(opts.pid()?),
+        "pubrec" => Packet::Pubrec(opts.pid()?),
+        "pubrel" => Packet::Pubrel(opts.pid()?),
+        "pubcomp" => Packet::Pubcomp(opts.pid()?),
+        "unsuback" => Packet::Unsuback(opts.pid()?),
+        "subscribe" => {
+            let mut topics = Vec::new();
+            for t in opts.all("topic") {
+                let (path, q) = match t.rsplit_once(':') {
+                    Some((path, q)) => {
+                        (path, q.parse().map_err(|_| format!("invalid qos {:?}", q))?)
+                    }
+                    None => (t.as_str(), 0),
+                };
+                topics.push(SubscribeTopic::new(path, qos(q)?).map_err(|e| e.to_string())?);
+            }
+            Subscribe::new(opts.pid()?, topics).into()
+        }
+        "suback" => {
+            let codes = opts.all("code").iter().map(|c| subscribe_code(c));
+            Suback::new(opts.pid()?, codes.collect::<Result<_, _>>()?).into()
+        }
+        "unsubscribe" => Unsubscribe::new(opts.pid()?, opts.all("topic").to_vec()).into(),
+        "pingreq" => Packet::Pingreq,
+        "pingresp" => Packet::Pingresp,
+        "disconnect" => Packet::Disconnect,
+        _ => return Err(format!("unknown packet type {:?}", name)),
+    })
+}
+
+/// Parse one packet or an array of packets.
+fn json_packets(json: &str) -> Result<Vec<Packet<'_>>, String> {
+    let value: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    if value.is_array() {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    } else {
+        Ok(vec![serde_json::from_str(json).map_err(|e| e.to_string())?])
+    }
+}
+
+fn encode(packets: &[Packet]) -> Result<Vec<u8>, String> {
+    let mut out = BytesMut::new();
+    for packet in packets {
+        encode_grow(packet, &mut out)
+            .map_err(|e| format!("cannot encode {:?}: {:?}", packet, e))?;
+    }
+    Ok(out.into())
+}
+
+fn run(args: &[String]) -> Result<Vec<u8>, String> {
+    match args.first().map(String::as_str) {
+        Some("--json") => {
+            let mut json = String::new();
+            let read = match args.get(1).map(String::as_str) {
+                Some("-") | None => io::stdin().read_to_string(&mut json),
+                Some(path) => {
+                    std::fs::File::open(path).and_then(|mut f| f.read_to_string(&mut json))
+                }
+            };
+            read.map_err(|e| e.to_string())?;
+            encode(&json_packets(&json)?)
+        }
+        Some(name) if !name.starts_with("--") => {
+            let opts = Opts::parse(&args[1..])?;
+            encode(&[packet(name, &opts)?])
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let raw = args.first().map(String::as_str) == Some("--raw");
+    if raw {
+        args.remove(0);
+    }
+    match run(&args) {
+        Ok(bytes) if raw => io::stdout().write_all(&bytes).expect("write to stdout"),
+        Ok(bytes) => {
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("{}", hex.join(" "));
+        }
+        Err(e) => {
+            eprintln!("mqtt-gen: {}", e);
+            exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gen(args: &[&str]) -> Result<Vec<u8>, String> {
+        run(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn args() {
+        assert_eq!(Ok(vec![0xc0, 0]), gen(&["pingreq"]));
+        assert_eq!(Ok(vec![0x40, 2, 0, 10]), gen(&["puback", "--pid", "10"]));
+        assert_eq!(
+            Ok(vec![0x33, 7, 0, 1, b'a', 0, 5, b'h', b'i']),
+            gen(&[
+                "publish",
+                "--topic",
+                "a",
+                "--qos",
+                "1",
+                "--pid",
+                "5",
+                "--retain",
+                "--payload",
+                "hi"
+            ])
+        );
+        assert_eq!(
+            Ok(vec![0x82, 6, 0, 1, 0, 1, b'a', 2]),
+            gen(&["subscribe", "--topic", "a:2"])
+        );
+        assert_eq!(
+            Ok(vec![0x90, 4, 0, 1, 0, 0x80]),
+            gen(&["suback", "--code", "0", "--code", "128"])
+        );
+        assert!(gen(&["publish"]).is_err());
+        assert!(gen(&["puback", "--pid", "0"]).is_err());
+        assert!(gen(&["bogus"]).is_err());
+    }
+
+    #[test]
+    fn json() {
+        let json = r#"[{"Publish": {"dup": false, "qospid": {"AtLeastOnce": 5}, "retain": true,
+                                    "topic_name": "a", "payload": "hi"}},
+                       "Pingreq"]"#;
+        let bytes = encode(&json_packets(json).unwrap()).unwrap();
+        assert_eq!(vec![0x33, 7, 0, 1, b'a', 0, 5, b'h', b'i', 0xc0, 0], bytes);
+        assert_eq!(1, json_packets(r#""Disconnect""#).unwrap().len());
+    }
+}
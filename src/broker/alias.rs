@@ -0,0 +1,194 @@
+use std::{collections::HashMap, string::String};
+
+/// How a [`TopicAliasAllocator`] chooses which topics get an alias once all are in use.
+///
+/// [`TopicAliasAllocator`]: struct.TopicAliasAllocator.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AliasPolicy {
+    /// Reassign the alias of the least recently published topic.
+    LeastRecentlyUsed,
+    /// Reassign the alias of the least published topic, once another topic has been published
+    /// more often.
+    Hottest,
+}
+
+/// What to send instead of the topic name of an outgoing publish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicAlias {
+    /// The topic already has this alias: send an empty topic name and the alias.
+    Use(u16),
+    /// The topic just got this alias: send the topic name and the alias.
+    Assign(u16),
+    /// No alias: send the topic name only.
+    None,
+}
+
+/// Allocates the topic aliases a server uses in the publishes it sends to a client.
+///
+/// Topic aliases are an [MQTT 5] feature: the client announces its Topic Alias Maximum in its
+/// `Connect`, and the server can then replace repeated topic names with a 2-byte alias. MQTT
+/// 3.1.1 packets have no properties, so the aliases can't be encoded by this crate; this only
+/// implements the bookkeeping for servers that encode MQTT 5 publishes themselves. Create one
+/// allocator per connection, as aliases don't outlive it.
+///
+/// ```
+/// # use mqttrs::broker::*;
+/// let mut aliases = TopicAliasAllocator::new(1, AliasPolicy::LeastRecentlyUsed);
+/// assert_eq!(TopicAlias::Assign(1), aliases.alias("sensors/1"));
+/// assert_eq!(TopicAlias::Use(1), aliases.alias("sensors/1"));
+/// assert_eq!(TopicAlias::Assign(1), aliases.alias("sensors/2"));
+///
+/// let mut aliases = TopicAliasAllocator::new(0, AliasPolicy::LeastRecentlyUsed);
+/// assert_eq!(TopicAlias::None, aliases.alias("sensors/1"));
+/// ```
+///
+/// [MQTT 5]: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901113
+#[derive(Debug, Clone)]
+pub struct TopicAliasAllocator {
+    maximum: u16,
+    policy: AliasPolicy,
+    aliased: HashMap<String, Usage>,
+    /// Publish counts of topics without an alias, for `AliasPolicy::Hottest`.
+    candidates: HashMap<String, u64>,
+    clock: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    alias: u16,
+    last_used: u64,
+    count: u64,
+}
+
+impl TopicAliasAllocator {
+    /// Create an allocator using at most `maximum` aliases, the client's Topic Alias Maximum.
+    pub fn new(maximum: u16, policy: AliasPolicy) -> Self {
+        TopicAliasAllocator {
+            maximum,
+            policy,
+            aliased: HashMap::new(),
+            candidates: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Return how to send the topic of a publish to `topic_name`.
+    pub fn alias(&mut self, topic_name: &str) -> TopicAlias {
+        self.clock += 1;
+        if let Some(usage) = self.aliased.get_mut(topic_name) {
+            usage.last_used = self.clock;
+            usage.count += 1;
+            return TopicAlias::Use(usage.alias);
+        }
+        if self.maximum == 0 {
+            return TopicAlias::None;
+        }
+        if self.aliased.len() < usize::from(self.maximum) {
+            let alias = self.aliased.len() as u16 + 1;
+            let count = self.candidates.remove(topic_name).unwrap_or(0) + 1;
+            return self.assign(topic_name, alias, count);
+        }
+        match self.policy {
+            AliasPolicy::LeastRecentlyUsed => {
+                let (victim, usage) = self.min_by_key(|u| u.last_used);
+                self.aliased.remove(&victim);
+                self.assign(topic_name, usage.alias, 1)
+            }
+            AliasPolicy::Hottest => {
+                let count = self.candidates.get(topic_name).copied().unwrap_or(0) + 1;
+                let (victim, usage) = self.min_by_key(|u| u.count);
+                if count > usage.count {
+                    self.candidates.remove(topic_name);
+                    self.aliased.remove(&victim);
+                    self.candidates.insert(victim, usage.count);
+                    self.assign(topic_name, usage.alias, count)
+                } else {
+                    self.count_candidate(topic_name, count);
+                    TopicAlias::None
+                }
+            }
+        }
+    }
+
+    /// Number of aliases in use.
+    pub fn len(&self) -> usize {
+        self.aliased.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliased.is_empty()
+    }
+
+    fn assign(&mut self, topic_name: &str, alias: u16, count: u64) -> TopicAlias {
+        let usage = Usage {
+            alias,
+            last_used: self.clock,
+            count,
+        };
+        self.aliased.insert(topic_name.into(), usage);
+        TopicAlias::Assign(alias)
+    }
+
+    fn min_by_key(&self, key: impl Fn(&Usage) -> u64) -> (String, Usage) {
+        let (topic, usage) = self
+            .aliased
+            .iter()
+            .min_by_key(|(_, u)| (key(u), u.alias))
+            .expect("all aliases are in use");
+        (topic.clone(), *usage)
+    }
+
+    /// Count a publish to a topic without alias, halving all the counts when too many topics
+    /// are tracked so that memory stays bounded and old traffic fades out.
+    fn count_candidate(&mut self, topic_name: &str, count: u64) {
+        self.candidates.insert(topic_name.into(), count);
+        if self.candidates.len() > 4 * usize::from(self.maximum) {
+            self.candidates.retain(|_, c| {
+                *c /= 2;
+                *c > 0
+            });
+            for usage in self.aliased.values_mut() {
+                usage.count /= 2;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lru() {
+        let mut aliases = TopicAliasAllocator::new(2, AliasPolicy::LeastRecentlyUsed);
+        assert_eq!(TopicAlias::Assign(1), aliases.alias("a"));
+        assert_eq!(TopicAlias::Assign(2), aliases.alias("b"));
+        assert_eq!(TopicAlias::Use(1), aliases.alias("a"));
+        // "b" is the least recently used.
+        assert_eq!(TopicAlias::Assign(2), aliases.alias("c"));
+        assert_eq!(TopicAlias::Use(1), aliases.alias("a"));
+        assert_eq!(TopicAlias::Assign(2), aliases.alias("b"));
+        assert_eq!(2, aliases.len());
+    }
+
+    #[test]
+    fn hottest() {
+        let mut aliases = TopicAliasAllocator::new(1, AliasPolicy::Hottest);
+        assert_eq!(TopicAlias::Assign(1), aliases.alias("cold"));
+        // "hot" needs more publishes than "cold" to take its alias.
+        assert_eq!(TopicAlias::None, aliases.alias("hot"));
+        assert_eq!(TopicAlias::Assign(1), aliases.alias("hot"));
+        assert_eq!(TopicAlias::Use(1), aliases.alias("hot"));
+        // "cold" keeps its count, and takes the alias back after its 4th publish.
+        assert_eq!(TopicAlias::None, aliases.alias("cold"));
+        assert_eq!(TopicAlias::None, aliases.alias("cold"));
+        assert_eq!(TopicAlias::Assign(1), aliases.alias("cold"));
+
+        // Lots of one-off topics don't grow memory unboundedly, and old counts fade out.
+        for i in 0..1000 {
+            aliases.alias(&i.to_string());
+        }
+        assert!(aliases.candidates.len() <= 4);
+        assert_eq!(1, aliases.len());
+    }
+}
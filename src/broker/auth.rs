@@ -0,0 +1,138 @@
+use crate::{
+    Connect, ConnectReturnCode, Publish, Suback, Subscribe, SubscribeReturnCodes, SubscribeTopic,
+};
+
+/// What to do with a publish, as decided by [`Authorizer::can_publish()`].
+///
+/// MQTT 3.1.1 has no way to tell a client that its publish was refused: the server must either
+/// acknowledge it as usual and drop it, or close the connection ([MQTT-3.3.5-2]).
+///
+/// [`Authorizer::can_publish()`]: trait.Authorizer.html#tymethod.can_publish
+/// [MQTT-3.3.5-2]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718042
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishAccess {
+    /// Acknowledge and route the publish.
+    Allow,
+    /// Acknowledge the publish, but don't route it.
+    Discard,
+    /// Close the connection without acknowledging the publish.
+    Disconnect,
+}
+
+/// Access control, consulted by a broker before accepting a connection, a publish or a
+/// subscription.
+///
+/// `C` identifies the authenticated client, like its username or client id, as chosen by the
+/// broker at connection time. Call [`can_connect()`] for each `Connect`, [`can_publish()`] for
+/// each `Publish` and [`suback()`] to answer each `Subscribe`:
+///
+/// ```
+/// # use mqttrs::{*, broker::*};
+/// /// Clients may only use the topics under their own client id.
+/// struct OwnTopics;
+/// impl Authorizer<str> for OwnTopics {
+///     fn can_connect(&mut self, connect: &Connect) -> ConnectReturnCode {
+///         match connect.client_id {
+///             "" => ConnectReturnCode::RefusedIdentifierRejected,
+///             _ => ConnectReturnCode::Accepted,
+///         }
+///     }
+///     fn can_publish(&mut self, client: &str, publish: &Publish) -> PublishAccess {
+///         match publish.topic_name.strip_prefix(client) {
+///             Some(rest) if rest.starts_with('/') => PublishAccess::Allow,
+///             _ => PublishAccess::Discard,
+///         }
+///     }
+///     fn can_subscribe(&mut self, client: &str, topic: &SubscribeTopic) -> SubscribeReturnCodes {
+///         match topic.topic_filter.strip_prefix(client) {
+///             Some(rest) if rest.starts_with('/') => SubscribeReturnCodes::Success(QoS::ExactlyOnce),
+///             _ => SubscribeReturnCodes::Failure,
+///         }
+///     }
+/// }
+///
+/// let subscribe = Subscribe::new(Pid::new(), vec![
+///     SubscribeTopic::new("c1/#", QoS::AtLeastOnce)?,
+///     SubscribeTopic::new("c2/#", QoS::AtLeastOnce)?,
+/// ]);
+/// let suback = OwnTopics.suback("c1", &subscribe);
+/// assert_eq!(&[SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+///              SubscribeReturnCodes::Failure][..], &suback.return_codes[..]);
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`can_connect()`]: #tymethod.can_connect
+/// [`can_publish()`]: #tymethod.can_publish
+/// [`suback()`]: #method.suback
+pub trait Authorizer<C: ?Sized> {
+    /// Return `ConnectReturnCode::Accepted`, or the code to refuse `connect` with, typically
+    /// `BadUsernamePassword` or `NotAuthorized`.
+    fn can_connect(&mut self, connect: &Connect) -> ConnectReturnCode;
+
+    /// Decide whether `client` may publish `publish`.
+    fn can_publish(&mut self, client: &C, publish: &Publish) -> PublishAccess;
+
+    /// Return the maximum `QoS` granted to `client` for `topic`, or `Failure` to refuse it.
+    fn can_subscribe(&mut self, client: &C, topic: &SubscribeTopic) -> SubscribeReturnCodes;
+
+    /// Build the `Suback` answering `subscribe`, with [`Suback::respond_to()`].
+    ///
+    /// [`Suback::respond_to()`]: ../struct.Suback.html#method.respond_to
+    fn suback(&mut self, client: &C, subscribe: &Subscribe) -> Suback {
+        Suback::respond_to(subscribe, |topic| self.can_subscribe(client, topic))
+    }
+}
+
+/// [`Authorizer`] accepting everything.
+///
+/// [`Authorizer`]: trait.Authorizer.html
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl<C: ?Sized> Authorizer<C> for AllowAll {
+    fn can_connect(&mut self, _: &Connect) -> ConnectReturnCode {
+        ConnectReturnCode::Accepted
+    }
+
+    fn can_publish(&mut self, _: &C, _: &Publish) -> PublishAccess {
+        PublishAccess::Allow
+    }
+
+    fn can_subscribe(&mut self, _: &C, topic: &SubscribeTopic) -> SubscribeReturnCodes {
+        SubscribeReturnCodes::Success(topic.options.qos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn allow_all() {
+        let subscribe = Subscribe::new(
+            Pid::new() + 3,
+            vec![
+                SubscribeTopic::new("a/#", QoS::ExactlyOnce).unwrap(),
+                SubscribeTopic::new("b", QoS::AtMostOnce).unwrap(),
+            ],
+        );
+        let suback = Authorizer::<str>::suback(&mut AllowAll, "c1", &subscribe);
+        assert_eq!(Pid::new() + 3, suback.pid);
+        assert_eq!(
+            &[
+                SubscribeReturnCodes::Success(QoS::ExactlyOnce),
+                SubscribeReturnCodes::Success(QoS::AtMostOnce)
+            ][..],
+            &suback.return_codes[..]
+        );
+        let publish = Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "$SYS/x",
+            payload: b"",
+        };
+        assert_eq!(PublishAccess::Allow, AllowAll.can_publish(&1u32, &publish));
+    }
+}
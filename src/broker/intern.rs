@@ -0,0 +1,275 @@
+use crate::{encode_grow, rewrite_publish_pid, Error, Packet, Publish, SharedPublish};
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+/// Cache sharing the topic and payload of identical publishes, for brokers.
+///
+/// Publishes with the same topic, payload, dup, QoS and retain flags are [`intern()`]ed into
+/// `SharedPublish`es pointing to the same topic and payload allocations, whatever their pid.
+/// Brokers retaining or queueing many copies of the same sensor value then only store it once.
+///
+/// Brokers fanning a message out to many subscribers can also [`encode()`] it once: the other
+/// copies reuse the encoded bytes, only patching their pid.
+///
+/// At most `per_topic` distinct messages are kept for each topic, the least recently interned
+/// ones being forgotten first. Forgotten messages stay valid, they just aren't shared anymore.
+///
+/// ```
+/// # use mqttrs::{*, broker::*};
+/// # use std::sync::Arc;
+/// let mut interner = PublishInterner::new(4);
+/// let reading = |payload| Publish { dup: false,
+///                                   qospid: QosPid::AtMostOnce,
+///                                   retain: true,
+///                                   topic_name: "sensor/temp",
+///                                   payload };
+/// let first = interner.intern(&reading(b"21.5"));
+/// let second = interner.intern(&reading(b"21.5"));
+/// assert!(Arc::ptr_eq(&first.topic_name, &second.topic_name));
+/// assert_eq!(first.payload.as_ptr(), second.payload.as_ptr());
+/// interner.intern(&reading(b"21.6"));
+/// assert_eq!(2, interner.len());
+/// ```
+///
+/// [`intern()`]: #method.intern
+/// [`encode()`]: #method.encode
+#[derive(Debug, Clone)]
+pub struct PublishInterner {
+    per_topic: usize,
+    /// Interned messages of each topic, least recently interned first.
+    topics: HashMap<Arc<str>, VecDeque<Interned>>,
+    len: usize,
+}
+
+/// An interned message, and its encoding once requested.
+#[derive(Debug, Clone)]
+struct Interned {
+    message: SharedPublish,
+    encoded: Option<Bytes>,
+}
+
+impl PublishInterner {
+    /// Keep at most `per_topic` distinct messages for each topic. Zero disables sharing.
+    pub fn new(per_topic: usize) -> Self {
+        PublishInterner {
+            per_topic,
+            topics: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Return a `SharedPublish` equal to `publish`, sharing its topic and payload with a
+    /// previously interned message when possible.
+    pub fn intern(&mut self, publish: &Publish) -> SharedPublish {
+        if self.per_topic == 0 {
+            return SharedPublish::new(publish);
+        }
+        SharedPublish {
+            qospid: publish.qospid,
+            ..self.interned(publish).message.clone()
+        }
+    }
+
+    /// Encode `publish`, reusing the bytes of a previously encoded identical message when
+    /// possible.
+    ///
+    /// Identical messages are only encoded once. `QoS 0` copies share the same bytes, `QoS 1`
+    /// and `QoS 2` copies are copied to patch their pid with [`rewrite_publish_pid()`].
+    ///
+    /// ```
+    /// # use mqttrs::{*, broker::*};
+    /// let mut interner = PublishInterner::new(4);
+    /// let command = |pid| Publish { dup: false,
+    ///                               qospid: QosPid::AtLeastOnce(pid),
+    ///                               retain: false,
+    ///                               topic_name: "cmd/all",
+    ///                               payload: b"reboot" };
+    /// for pid in 1..=3 {
+    ///     let pid = Pid::new() + pid;
+    ///     let bytes = interner.encode(&command(pid))?;
+    ///     assert_eq!(Some(Packet::Publish(command(pid))), decode_slice(&bytes)?);
+    /// }
+    /// # Ok::<(), Error>(())
+    /// ```
+    ///
+    /// [`rewrite_publish_pid()`]: ../fn.rewrite_publish_pid.html
+    pub fn encode(&mut self, publish: &Publish) -> Result<Bytes, Error> {
+        let encode = || {
+            let mut buf = BytesMut::new();
+            encode_grow(&Packet::Publish(publish.clone()), &mut buf)?;
+            Ok::<_, Error>(buf.freeze())
+        };
+        if self.per_topic == 0 {
+            return encode();
+        }
+        let interned = self.interned(publish);
+        let encoded = match &interned.encoded {
+            Some(encoded) => encoded,
+            None => interned.encoded.insert(encode()?),
+        };
+        match publish.qospid.pid() {
+            None => Ok(encoded.clone()),
+            Some(pid) => {
+                let mut buf = BytesMut::from(&encoded[..]);
+                rewrite_publish_pid(&mut buf, pid, publish.dup)?;
+                Ok(buf.freeze())
+            }
+        }
+    }
+
+    /// Find or insert the interned message identical to `publish`, and mark it as the most
+    /// recently interned one.
+    fn interned(&mut self, publish: &Publish) -> &mut Interned {
+        let same = |i: &Interned| {
+            let m = &i.message;
+            m.dup == publish.dup
+                && m.qospid.qos() == publish.qospid.qos()
+                && m.retain == publish.retain
+                && m.payload == publish.payload
+        };
+        let topic_name = publish.topic_name;
+        if let Some(pos) = self
+            .topics
+            .get(topic_name)
+            .and_then(|messages| messages.iter().position(same))
+        {
+            let messages = self.topics.get_mut(topic_name).expect("topic is present");
+            let interned = messages.remove(pos).expect("position is valid");
+            messages.push_back(interned);
+            return messages.back_mut().expect("message was pushed");
+        }
+
+        let topic = match self.topics.get_key_value(topic_name) {
+            Some((topic, _)) => topic.clone(),
+            None => topic_name.into(),
+        };
+        let message = SharedPublish {
+            topic_name: topic.clone(),
+            ..SharedPublish::new(&Publish {
+                topic_name: "",
+                ..publish.clone()
+            })
+        };
+        let messages = self.topics.entry(topic).or_default();
+        messages.push_back(Interned {
+            message,
+            encoded: None,
+        });
+        if messages.len() > self.per_topic {
+            messages.pop_front();
+        } else {
+            self.len += 1;
+        }
+        messages.back_mut().expect("message was pushed")
+    }
+
+    /// Forget the messages of `topic_name`, for example when its retained message is cleared.
+    /// Returns false if there were none.
+    pub fn remove(&mut self, topic_name: &str) -> bool {
+        match self.topics.remove(topic_name) {
+            Some(messages) => {
+                self.len -= messages.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget all messages.
+    pub fn clear(&mut self) {
+        self.topics.clear();
+        self.len = 0;
+    }
+
+    /// Number of interned messages.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Pid, QosPid};
+
+    #[test]
+    fn intern() {
+        let publish = |topic_name: &'static str, qospid, payload| Publish {
+            dup: false,
+            qospid,
+            retain: false,
+            topic_name,
+            payload,
+        };
+        let mut interner = PublishInterner::new(2);
+        let pid = Pid::new();
+        let a = interner.intern(&publish("t", QosPid::AtLeastOnce(pid), b"a"));
+        // The pid isn't part of the identity, the QoS is.
+        let same = interner.intern(&publish("t", QosPid::AtLeastOnce(pid + 1), b"a"));
+        assert_eq!(QosPid::AtLeastOnce(pid + 1), same.qospid);
+        assert_eq!(a.payload.as_ptr(), same.payload.as_ptr());
+        let qos0 = interner.intern(&publish("t", QosPid::AtMostOnce, b"a"));
+        assert_ne!(a.payload.as_ptr(), qos0.payload.as_ptr());
+        assert!(Arc::ptr_eq(&a.topic_name, &qos0.topic_name));
+        assert_eq!(2, interner.len());
+
+        // `a` was interned again after `qos0`, so `qos0` is forgotten first.
+        interner.intern(&publish("t", QosPid::AtLeastOnce(pid), b"a"));
+        interner.intern(&publish("t", QosPid::AtMostOnce, b"b"));
+        let again = interner.intern(&publish("t", QosPid::AtLeastOnce(pid), b"a"));
+        assert_eq!(a.payload.as_ptr(), again.payload.as_ptr());
+        let forgotten = interner.intern(&publish("t", QosPid::AtMostOnce, b"a"));
+        assert_ne!(qos0.payload.as_ptr(), forgotten.payload.as_ptr());
+        assert_eq!(2, interner.len());
+
+        interner.intern(&publish("u", QosPid::AtMostOnce, b"a"));
+        assert_eq!(3, interner.len());
+        assert!(interner.remove("t"));
+        assert!(!interner.remove("t"));
+        assert_eq!(1, interner.len());
+        interner.clear();
+        assert!(interner.is_empty());
+
+        let mut disabled = PublishInterner::new(0);
+        disabled.intern(&publish("t", QosPid::AtMostOnce, b"a"));
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn encode() {
+        let publish = |qospid| Publish {
+            dup: false,
+            qospid,
+            retain: false,
+            topic_name: "t",
+            payload: b"a",
+        };
+        let mut interner = PublishInterner::new(2);
+        let qos0 = interner.encode(&publish(QosPid::AtMostOnce)).unwrap();
+        let again = interner.encode(&publish(QosPid::AtMostOnce)).unwrap();
+        assert_eq!(qos0.as_ptr(), again.as_ptr());
+
+        let pid = Pid::new();
+        let first = interner.encode(&publish(QosPid::ExactlyOnce(pid))).unwrap();
+        let second = interner
+            .encode(&publish(QosPid::ExactlyOnce(pid + 1)))
+            .unwrap();
+        assert_eq!(&[0b00110100, 6, 0, 1, b't', 0, 1, b'a'], &first[..]);
+        assert_eq!(&[0b00110100, 6, 0, 1, b't', 0, 2, b'a'], &second[..]);
+        // Interning shares the entry, and its encoding.
+        let shared = interner.intern(&publish(QosPid::ExactlyOnce(pid + 2)));
+        assert_eq!(QosPid::ExactlyOnce(pid + 2), shared.qospid);
+        assert_eq!(2, interner.len());
+
+        let mut disabled = PublishInterner::new(0);
+        assert_eq!(qos0, disabled.encode(&publish(QosPid::AtMostOnce)).unwrap());
+        assert!(disabled.is_empty());
+    }
+}
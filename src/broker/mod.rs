@@ -0,0 +1,74 @@
+//! Building blocks for MQTT servers, enabled by the `broker` feature.
+//!
+//! These are sans-io: they take decoded packets and connection identifiers, and return what to
+//! send to whom, leaving networking and storage to the server.
+//!
+//! [`Router`] matches published topics against subscriptions and returns the connections to
+//! deliver to:
+//!
+//! ```
+//! # use mqttrs::{*, broker::*};
+//! let mut router = Router::new();
+//! router.subscribe("sensor-1", &SubscribeTopic::new("cmd/+", QoS::AtLeastOnce)?)?;
+//! router.subscribe("logger", &SubscribeTopic::new("#", QoS::AtMostOnce)?)?;
+//! let publish = Publish { dup: false,
+//!                         qospid: QosPid::ExactlyOnce(Pid::new()),
+//!                         retain: false,
+//!                         topic_name: "cmd/reboot",
+//!                         payload: b"" };
+//! assert_eq!(vec![("logger", QoS::AtMostOnce), ("sensor-1", QoS::AtLeastOnce)],
+//!            router.route(&publish));
+//! # Ok::<(), Error>(())
+//! ```
+//!
+//! An [`Authorizer`] decides which clients may connect, publish and subscribe.
+//! [`RetainStore`] keeps the retained messages to send to new subscriptions, and
+//! [`WillScheduler`] decides when to publish the will of disconnected clients.
+//! [`SessionRegistry`] keeps the session of each client id, handling session takeover.
+//! [`SendQuota`] limits the messages in flight and queued for each client, and
+//! [`OrderedDelivery`] retransmits the unacknowledged ones in order.
+//! [`PublishInterner`] shares the memory of identical messages.
+//! The crate's [`Notifier`], kept for each connection, filters out duplicate messages and
+//! acknowledgements.
+//! [`RateLimiter`] limits how many packets of each type clients may send.
+//! [`TopicAliasAllocator`] chooses the topic aliases of MQTT 5 connections.
+//! [`SysPublisher`] periodically publishes the [`BrokerStats`] under `$SYS/broker/`.
+//!
+//! [`Router`]: struct.Router.html
+//! [`Authorizer`]: trait.Authorizer.html
+//! [`RetainStore`]: trait.RetainStore.html
+//! [`WillScheduler`]: struct.WillScheduler.html
+//! [`SessionRegistry`]: struct.SessionRegistry.html
+//! [`SendQuota`]: struct.SendQuota.html
+//! [`OrderedDelivery`]: struct.OrderedDelivery.html
+//! [`PublishInterner`]: struct.PublishInterner.html
+//! [`Notifier`]: ../struct.Notifier.html
+//! [`RateLimiter`]: struct.RateLimiter.html
+//! [`TopicAliasAllocator`]: struct.TopicAliasAllocator.html
+//! [`SysPublisher`]: struct.SysPublisher.html
+//! [`BrokerStats`]: struct.BrokerStats.html
+
+mod alias;
+mod auth;
+mod intern;
+mod ordered;
+mod quota;
+mod rate;
+mod retain;
+mod router;
+mod sys;
+mod takeover;
+mod will;
+
+pub use crate::OverflowPolicy;
+pub use alias::{AliasPolicy, TopicAlias, TopicAliasAllocator};
+pub use auth::{AllowAll, Authorizer, PublishAccess};
+pub use intern::PublishInterner;
+pub use ordered::OrderedDelivery;
+pub use quota::{Offer, QuotaLimits, SendQuota};
+pub use rate::{RateLimit, RateLimited, RateLimiter};
+pub use retain::{MemoryRetainStore, RetainStore};
+pub use router::Router;
+pub use sys::{BrokerStats, SysPublisher};
+pub use takeover::{ConnectOutcome, SessionRegistry};
+pub use will::{Will, WillScheduler};
@@ -0,0 +1,176 @@
+use crate::{Packet, Pid, QosPid, SharedPublish};
+use std::{collections::VecDeque, vec::Vec};
+
+/// Tracks the unacknowledged `QoS 1` and `QoS 2` messages sent to a client, to retransmit them
+/// in order.
+///
+/// When a client reconnects to a persistent session, the server must resend its unacknowledged
+/// publishes ([MQTT-4.4.0-1]) in the order they were originally sent, and the `Pubrel`s of
+/// unfinished `QoS 2` flows in the order their `Pubrec`s were received ([MQTT-4.6.0-1],
+/// [MQTT-4.6.0-3]). Acknowledgements may arrive in any order; acknowledged messages are removed
+/// without disturbing the order of the others.
+///
+/// Report each publish with [`sent()`] and each acknowledgement with [`puback()`],
+/// [`pubrec()`] and [`pubcomp()`], then call [`resend()`] on reconnection:
+///
+/// ```
+/// # use mqttrs::{*, broker::*};
+/// let publish = |n| SharedPublish::new(&Publish { dup: false,
+///                                                 qospid: QosPid::ExactlyOnce(Pid::new() + n),
+///                                                 retain: false,
+///                                                 topic_name: "a/b",
+///                                                 payload: b"x" });
+/// let mut delivery = OrderedDelivery::new();
+/// for n in 0..4 {
+///     delivery.sent(publish(n));
+/// }
+/// assert!(delivery.pubrec(Pid::new() + 2));
+/// assert!(delivery.pubrec(Pid::new() + 0));
+/// assert!(delivery.pubcomp(Pid::new() + 2));
+///
+/// let resend: Vec<_> = delivery.resend().iter().map(|p| p.get_type()).collect();
+/// assert_eq!(vec![PacketType::Pubrel, PacketType::Publish, PacketType::Publish], resend);
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [MQTT-4.4.0-1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718103
+/// [MQTT-4.6.0-1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718105
+/// [MQTT-4.6.0-3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718105
+/// [`sent()`]: #method.sent
+/// [`puback()`]: #method.puback
+/// [`pubrec()`]: #method.pubrec
+/// [`pubcomp()`]: #method.pubcomp
+/// [`resend()`]: #method.resend
+#[derive(Debug, Clone, Default)]
+pub struct OrderedDelivery {
+    /// Publishes waiting for a `Puback` or `Pubrec`, in sending order.
+    publishes: VecDeque<SharedPublish>,
+    /// Pids waiting for a `Pubcomp`, in `Pubrec` order.
+    pubrels: VecDeque<Pid>,
+}
+
+impl OrderedDelivery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a publish sent to the client. `QoS 0` publishes are ignored.
+    pub fn sent(&mut self, publish: SharedPublish) {
+        if publish.qospid != QosPid::AtMostOnce {
+            self.publishes.push_back(publish);
+        }
+    }
+
+    /// Handle a `Puback`. Returns false if no `QoS 1` publish was waiting for it.
+    pub fn puback(&mut self, pid: Pid) -> bool {
+        self.remove_publish(QosPid::AtLeastOnce(pid)).is_some()
+    }
+
+    /// Handle a `Pubrec`: the publish is done, and the `Pubrel` to send is now tracked.
+    ///
+    /// Returns false if no `QoS 2` publish was waiting for it. A duplicate `Pubrec` for a pid
+    /// already waiting for its `Pubcomp` returns true, as the `Pubrel` must be sent again.
+    pub fn pubrec(&mut self, pid: Pid) -> bool {
+        if self.pubrels.contains(&pid) {
+            return true;
+        }
+        if self.remove_publish(QosPid::ExactlyOnce(pid)).is_none() {
+            return false;
+        }
+        self.pubrels.push_back(pid);
+        true
+    }
+
+    /// Handle a `Pubcomp`. Returns false if no `Pubrel` was waiting for it.
+    pub fn pubcomp(&mut self, pid: Pid) -> bool {
+        match self.pubrels.iter().position(|p| *p == pid) {
+            Some(i) => {
+                self.pubrels.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Return the packets to retransmit after a reconnection: the pending `Pubrel`s, then the
+    /// unacknowledged publishes with their `dup` flag set.
+    pub fn resend(&self) -> Vec<Packet<'_>> {
+        let pubrels = self.pubrels.iter().map(|pid| Packet::Pubrel(*pid));
+        let publishes = self.publishes.iter().map(|publish| {
+            let mut publish = publish.as_publish();
+            publish.dup = true;
+            Packet::Publish(publish)
+        });
+        pubrels.chain(publishes).collect()
+    }
+
+    /// Number of unacknowledged messages, including the `QoS 2` ones waiting for a `Pubcomp`.
+    pub fn len(&self) -> usize {
+        self.publishes.len() + self.pubrels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove_publish(&mut self, qospid: QosPid) -> Option<SharedPublish> {
+        let i = self.publishes.iter().position(|p| p.qospid == qospid)?;
+        self.publishes.remove(i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+
+    fn publish(qospid: QosPid) -> SharedPublish {
+        SharedPublish::new(&Publish {
+            dup: false,
+            qospid,
+            retain: false,
+            topic_name: "t",
+            payload: b"",
+        })
+    }
+
+    #[test]
+    fn partial_acks() {
+        let pid = |n| Pid::new() + n;
+        let mut delivery = OrderedDelivery::new();
+        delivery.sent(publish(QosPid::AtLeastOnce(pid(1))));
+        delivery.sent(publish(QosPid::AtMostOnce));
+        delivery.sent(publish(QosPid::ExactlyOnce(pid(2))));
+        delivery.sent(publish(QosPid::AtLeastOnce(pid(3))));
+        delivery.sent(publish(QosPid::ExactlyOnce(pid(4))));
+        assert_eq!(4, delivery.len());
+
+        // Acks must match the QoS of the publish.
+        assert!(!delivery.puback(pid(2)));
+        assert!(!delivery.pubrec(pid(1)));
+        assert!(delivery.puback(pid(3)));
+        assert!(delivery.pubrec(pid(4)));
+        assert!(delivery.pubrec(pid(4)));
+        assert!(!delivery.pubcomp(pid(2)));
+
+        let resend = delivery.resend();
+        assert_eq!(Packet::Pubrel(pid(4)), resend[0]);
+        let pids: Vec<_> = resend[1..]
+            .iter()
+            .map(|p| match p {
+                Packet::Publish(p) => {
+                    assert!(p.dup);
+                    p.qospid.pid().unwrap()
+                }
+                p => panic!("{:?}", p),
+            })
+            .collect();
+        assert_eq!(vec![pid(1), pid(2)], pids);
+
+        assert!(delivery.pubcomp(pid(4)));
+        assert!(delivery.puback(pid(1)));
+        assert!(delivery.pubrec(pid(2)));
+        assert!(delivery.pubcomp(pid(2)));
+        assert!(delivery.is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{OverflowPolicy, QoS};
+use std::collections::VecDeque;
+
+/// Limits of a [`SendQuota`].
+///
+/// [`SendQuota`]: struct.SendQuota.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaLimits {
+    /// Maximum number of unacknowledged `QoS 1` and `QoS 2` messages sent to the client.
+    pub max_inflight: usize,
+    /// Maximum number of `QoS 1` and `QoS 2` messages waiting for the in-flight ones.
+    pub max_queued: usize,
+    pub overflow: OverflowPolicy,
+}
+
+/// Outcome of [`SendQuota::offer()`], telling the broker what to do with a message.
+///
+/// [`SendQuota::offer()`]: struct.SendQuota.html#method.offer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Offer<T> {
+    /// Send the message now.
+    Send(T),
+    /// The message was queued, it will be returned by [`SendQuota::acknowledged()`].
+    ///
+    /// [`SendQuota::acknowledged()`]: struct.SendQuota.html#method.acknowledged
+    Queued,
+    /// The message was queued, and this oldest queued message was dropped to make room.
+    QueuedDroppedOldest(T),
+    /// The queue is full: the message was dropped.
+    Dropped(T),
+}
+
+/// Per-client limits on in-flight and queued messages, for brokers.
+///
+/// Each message to deliver to the client is [`offer()`]ed: `QoS 0` messages are always sent,
+/// `QoS 1` and `QoS 2` ones are sent while fewer than `max_inflight` are unacknowledged, then
+/// queued up to `max_queued`. Calling [`acknowledged()`] on each `Puback` or `Pubcomp` returns
+/// the next queued message to send.
+///
+/// ```
+/// # use mqttrs::{*, broker::*};
+/// let mut quota = SendQuota::new(QuotaLimits { max_inflight: 1,
+///                                              max_queued: 1,
+///                                              overflow: OverflowPolicy::DropOldest });
+/// assert_eq!(Offer::Send("m1"), quota.offer("m1", QoS::AtLeastOnce));
+/// assert_eq!(Offer::Queued, quota.offer("m2", QoS::AtLeastOnce));
+/// assert_eq!(Offer::QueuedDroppedOldest("m2"), quota.offer("m3", QoS::AtLeastOnce));
+/// assert_eq!(Offer::Send("m4"), quota.offer("m4", QoS::AtMostOnce));
+/// assert_eq!(Some("m3"), quota.acknowledged());
+/// ```
+///
+/// [`offer()`]: #method.offer
+/// [`acknowledged()`]: #method.acknowledged
+#[derive(Debug, Clone)]
+pub struct SendQuota<T> {
+    limits: QuotaLimits,
+    inflight: usize,
+    queue: VecDeque<T>,
+}
+
+impl<T> SendQuota<T> {
+    pub fn new(limits: QuotaLimits) -> Self {
+        SendQuota {
+            limits,
+            inflight: 0,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Decide what to do with `message`, to deliver at `qos`.
+    pub fn offer(&mut self, message: T, qos: QoS) -> Offer<T> {
+        if qos == QoS::AtMostOnce {
+            return Offer::Send(message);
+        }
+        if self.inflight < self.limits.max_inflight && self.queue.is_empty() {
+            self.inflight += 1;
+            return Offer::Send(message);
+        }
+        if self.queue.len() < self.limits.max_queued {
+            self.queue.push_back(message);
+            return Offer::Queued;
+        }
+        match self.limits.overflow {
+            OverflowPolicy::DropOldest => match self.queue.pop_front() {
+                Some(oldest) => {
+                    self.queue.push_back(message);
+                    Offer::QueuedDroppedOldest(oldest)
+                }
+                // `max_queued` is 0.
+                None => Offer::Dropped(message),
+            },
+            OverflowPolicy::DropNewest => Offer::Dropped(message),
+        }
+    }
+
+    /// An in-flight message was acknowledged: return the next queued message to send, if any.
+    pub fn acknowledged(&mut self) -> Option<T> {
+        self.inflight = self.inflight.saturating_sub(1);
+        let next = self.queue.pop_front()?;
+        self.inflight += 1;
+        Some(next)
+    }
+
+    /// Number of unacknowledged messages.
+    pub fn inflight(&self) -> usize {
+        self.inflight
+    }
+
+    /// Number of queued messages.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Forget the in-flight messages and return the queued ones, when the session ends.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.inflight = 0;
+        self.queue.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn send_quota() {
+        let mut quota = SendQuota::new(QuotaLimits {
+            max_inflight: 2,
+            max_queued: 2,
+            overflow: OverflowPolicy::DropNewest,
+        });
+        assert_eq!(Offer::Send(1), quota.offer(1, QoS::AtLeastOnce));
+        assert_eq!(Offer::Send(2), quota.offer(2, QoS::ExactlyOnce));
+        assert_eq!(Offer::Queued, quota.offer(3, QoS::AtLeastOnce));
+        assert_eq!(Offer::Queued, quota.offer(4, QoS::AtLeastOnce));
+        assert_eq!(Offer::Dropped(5), quota.offer(5, QoS::AtLeastOnce));
+        assert_eq!((2, 2), (quota.inflight(), quota.queued()));
+
+        assert_eq!(Some(3), quota.acknowledged());
+        assert_eq!((2, 1), (quota.inflight(), quota.queued()));
+        // Quota is available again, but queued messages go first.
+        assert_eq!(Some(4), quota.acknowledged());
+        assert_eq!(None, quota.acknowledged());
+        assert_eq!(Offer::Send(6), quota.offer(6, QoS::AtLeastOnce));
+        assert_eq!(Offer::Queued, quota.offer(7, QoS::AtLeastOnce));
+        assert_eq!(vec![7], quota.drain().collect::<Vec<_>>());
+        assert_eq!((0, 0), (quota.inflight(), quota.queued()));
+
+        let mut unqueued = SendQuota::new(QuotaLimits {
+            max_inflight: 0,
+            max_queued: 0,
+            overflow: OverflowPolicy::DropOldest,
+        });
+        assert_eq!(Offer::Dropped(1), unqueued.offer(1, QoS::AtLeastOnce));
+    }
+}
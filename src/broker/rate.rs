@@ -0,0 +1,180 @@
+use crate::PacketType;
+use core::time::Duration;
+use std::collections::HashMap;
+
+/// Rate allowed for a packet type by a [`RateLimiter`].
+///
+/// [`RateLimiter`]: struct.RateLimiter.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sustained number of packets per second. Zero rejects every packet of the type.
+    pub per_second: u32,
+    /// Number of packets accepted at once after a quiet period. Zero is treated as one.
+    pub burst: u32,
+}
+
+/// A packet was rejected by [`RateLimiter::check()`].
+///
+/// [`RateLimiter::check()`]: struct.RateLimiter.html#method.check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited {
+    pub packet_type: PacketType,
+    /// Time after which a packet of this type would be accepted, or `None` if never.
+    pub retry_after: Option<Duration>,
+}
+
+/// Per packet type token buckets, for brokers protecting themselves from floods.
+///
+/// Each limited packet type has a bucket of `burst` tokens, refilled at `per_second` tokens per
+/// second. [`check()`] takes a token for each received packet, or returns [`RateLimited`] if the
+/// bucket is empty; MQTT 5 servers then answer with the [`QUOTA_EXCEEDED`] reason code, in the
+/// ack of the packet or in a `Disconnect`. Packet types without a limit are always accepted.
+///
+/// Keep one limiter per connection to limit each client, or one for the whole listener to limit
+/// the total rate of `Connect`s. Times are durations since an arbitrary reference.
+///
+/// ```
+/// # use mqttrs::{*, broker::*};
+/// # use core::time::Duration;
+/// let secs = Duration::from_secs;
+/// let mut limiter = RateLimiter::new();
+/// limiter.set_limit(PacketType::Subscribe, Some(RateLimit { per_second: 1, burst: 2 }));
+/// assert!(limiter.check(PacketType::Subscribe, secs(0)).is_ok());
+/// assert!(limiter.check(PacketType::Subscribe, secs(0)).is_ok());
+/// let limited = limiter.check(PacketType::Subscribe, secs(0)).unwrap_err();
+/// assert_eq!(Some(secs(1)), limited.retry_after);
+/// assert_eq!(0x97, limited.to_reason_code());
+/// assert!(limiter.check(PacketType::Publish, secs(0)).is_ok());
+/// assert!(limiter.check(PacketType::Subscribe, secs(1)).is_ok());
+/// ```
+///
+/// [`check()`]: #method.check
+/// [`RateLimited`]: struct.RateLimited.html
+/// [`QUOTA_EXCEEDED`]: #associatedconstant.QUOTA_EXCEEDED
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    buckets: HashMap<PacketType, Bucket>,
+}
+
+/// Token bucket, stored as the time at which it will be full again.
+#[derive(Debug, Clone)]
+struct Bucket {
+    limit: RateLimit,
+    full_at: Duration,
+}
+
+impl RateLimiter {
+    /// MQTT 5 reason code for a rejected packet ("Quota exceeded").
+    pub const QUOTA_EXCEEDED: u8 = 0x97;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the rate of `packet_type`, or remove its limit with `None`. The bucket starts full.
+    pub fn set_limit(&mut self, packet_type: PacketType, limit: Option<RateLimit>) {
+        match limit {
+            Some(limit) => {
+                let bucket = Bucket {
+                    limit,
+                    full_at: Duration::ZERO,
+                };
+                self.buckets.insert(packet_type, bucket);
+            }
+            None => {
+                self.buckets.remove(&packet_type);
+            }
+        }
+    }
+
+    /// Returns the limit of `packet_type`, if any.
+    pub fn limit(&self, packet_type: PacketType) -> Option<RateLimit> {
+        self.buckets.get(&packet_type).map(|bucket| bucket.limit)
+    }
+
+    /// Take a token for a `packet_type` packet received at `now`.
+    ///
+    /// Nothing is taken when the packet is rejected, so a client ignoring the limit doesn't
+    /// delay itself further.
+    pub fn check(&mut self, packet_type: PacketType, now: Duration) -> Result<(), RateLimited> {
+        let bucket = match self.buckets.get_mut(&packet_type) {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+        let rejected = |retry_after| RateLimited {
+            packet_type,
+            retry_after,
+        };
+        let interval = match bucket.limit.per_second {
+            0 => return Err(rejected(None)),
+            per_second => Duration::from_secs(1) / per_second,
+        };
+        let full_at = bucket.full_at.max(now);
+        // Each token takes `interval` to come back, the bucket is empty if all of them are out.
+        let empty_at = now + interval * bucket.limit.burst.max(1);
+        if full_at + interval > empty_at {
+            return Err(rejected(Some(full_at + interval - empty_at)));
+        }
+        bucket.full_at = full_at + interval;
+        Ok(())
+    }
+}
+
+impl RateLimited {
+    /// Returns the MQTT 5 reason code ("Quota exceeded").
+    pub fn to_reason_code(self) -> u8 {
+        RateLimiter::QUOTA_EXCEEDED
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let ms = Duration::from_millis;
+        let mut limiter = RateLimiter::new();
+        let limit = RateLimit {
+            per_second: 10,
+            burst: 3,
+        };
+        limiter.set_limit(PacketType::Connect, Some(limit));
+        assert_eq!(Some(limit), limiter.limit(PacketType::Connect));
+        for _ in 0..3 {
+            assert!(limiter.check(PacketType::Connect, ms(1000)).is_ok());
+        }
+        let limited = RateLimited {
+            packet_type: PacketType::Connect,
+            retry_after: Some(ms(50)),
+        };
+        assert_eq!(Err(limited), limiter.check(PacketType::Connect, ms(1050)));
+        // One token every 100ms, the rejected packet didn't take one.
+        assert!(limiter.check(PacketType::Connect, ms(1100)).is_ok());
+        assert!(limiter.check(PacketType::Connect, ms(1100)).is_err());
+        // Refilled after a quiet period, but never above the burst.
+        for _ in 0..3 {
+            assert!(limiter.check(PacketType::Connect, ms(5000)).is_ok());
+        }
+        assert!(limiter.check(PacketType::Connect, ms(5000)).is_err());
+
+        limiter.set_limit(PacketType::Connect, None);
+        assert!(limiter.check(PacketType::Connect, ms(5000)).is_ok());
+    }
+
+    #[test]
+    fn zero_rate() {
+        let mut limiter = RateLimiter::new();
+        let limit = RateLimit {
+            per_second: 0,
+            burst: 10,
+        };
+        limiter.set_limit(PacketType::Subscribe, Some(limit));
+        let never = RateLimited {
+            packet_type: PacketType::Subscribe,
+            retry_after: None,
+        };
+        let later = Duration::from_secs(3600);
+        assert_eq!(Err(never), limiter.check(PacketType::Subscribe, later));
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{FilterLevel, Publish, SharedPublish, TopicFilter};
+use std::{collections::BTreeMap, string::String, vec::Vec};
+
+/// Storage of retained messages, one per topic ([MQTT 3.3.1.3]).
+///
+/// Brokers call [`handle_publish()`] for each incoming publish, and send the messages returned by
+/// [`matching()`] to each new subscription.
+///
+/// [MQTT 3.3.1.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718038
+/// [`handle_publish()`]: #method.handle_publish
+/// [`matching()`]: #tymethod.matching
+pub trait RetainStore {
+    /// Store `message` as the retained message of its topic, replacing the previous one.
+    fn set(&mut self, message: SharedPublish);
+
+    /// Remove the retained message of `topic_name`. Returns false if there was none.
+    fn clear(&mut self, topic_name: &str) -> bool;
+
+    /// Return the retained messages whose topic matches `filter`.
+    ///
+    /// Like for subscriptions, topics starting with `$` aren't matched by a filter starting with
+    /// a wildcard.
+    fn matching(&self, filter: &TopicFilter) -> Vec<SharedPublish>;
+
+    /// Update the store with an incoming publish.
+    ///
+    /// Publishes without the retain flag are ignored. A retained publish with an empty payload
+    /// clears the topic ([MQTT-3.3.1-10]), others are stored with `dup` unset.
+    ///
+    /// [MQTT-3.3.1-10]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718038
+    fn handle_publish(&mut self, publish: &Publish) {
+        if !publish.retain {
+            return;
+        }
+        if publish.payload.is_empty() {
+            self.clear(publish.topic_name);
+        } else {
+            let mut message = SharedPublish::new(publish);
+            message.dup = false;
+            self.set(message);
+        }
+    }
+}
+
+/// In-memory [`RetainStore`], indexed by topic level so that wildcard queries only visit
+/// matching topics.
+///
+/// ```
+/// # use mqttrs::{*, broker::*};
+/// let mut store = MemoryRetainStore::new();
+/// for topic in &["home/kitchen/temp", "home/garage/temp", "home/kitchen/light"] {
+///     store.handle_publish(&Publish { dup: false,
+///                                     qospid: QosPid::AtMostOnce,
+///                                     retain: true,
+///                                     topic_name: *topic,
+///                                     payload: b"1" });
+/// }
+/// let temps = store.matching(&TopicFilter::new("home/+/temp")?);
+/// assert_eq!(2, temps.len());
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`RetainStore`]: trait.RetainStore.html
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRetainStore {
+    root: Node,
+    len: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    children: BTreeMap<String, Node>,
+    message: Option<SharedPublish>,
+}
+
+impl MemoryRetainStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of retained messages.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl RetainStore for MemoryRetainStore {
+    fn set(&mut self, message: SharedPublish) {
+        let node = message
+            .topic_name
+            .split('/')
+            .fold(&mut self.root, |node, level| {
+                node.children.entry(level.into()).or_default()
+            });
+        if node.message.replace(message).is_none() {
+            self.len += 1;
+        }
+    }
+
+    fn clear(&mut self, topic_name: &str) -> bool {
+        let levels: Vec<&str> = topic_name.split('/').collect();
+        let cleared = self.root.clear(&levels);
+        if cleared {
+            self.len -= 1;
+        }
+        cleared
+    }
+
+    fn matching(&self, filter: &TopicFilter) -> Vec<SharedPublish> {
+        let levels: Vec<FilterLevel> = filter.levels().collect();
+        let mut out = Vec::new();
+        self.root.collect(&levels, true, &mut out);
+        out
+    }
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.message.is_none()
+    }
+
+    fn clear(&mut self, levels: &[&str]) -> bool {
+        match levels.split_first() {
+            None => self.message.take().is_some(),
+            Some((level, rest)) => match self.children.get_mut(*level) {
+                Some(child) => {
+                    let cleared = child.clear(rest);
+                    if child.is_empty() {
+                        self.children.remove(*level);
+                    }
+                    cleared
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// `root` is true for the first level, where wildcards don't match `$` topics.
+    fn collect(&self, levels: &[FilterLevel], root: bool, out: &mut Vec<SharedPublish>) {
+        match levels.split_first() {
+            None => out.extend(self.message.clone()),
+            Some((FilterLevel::MultiWildcard, _)) => {
+                // `a/#` also matches `a`.
+                out.extend(self.message.clone());
+                for (name, child) in &self.children {
+                    if !(root && name.starts_with('$')) {
+                        child.collect_all(out);
+                    }
+                }
+            }
+            Some((FilterLevel::SingleWildcard, rest)) => {
+                for (name, child) in &self.children {
+                    if !(root && name.starts_with('$')) {
+                        child.collect(rest, false, out);
+                    }
+                }
+            }
+            Some((FilterLevel::Name(name), rest)) => {
+                if let Some(child) = self.children.get(*name) {
+                    child.collect(rest, false, out);
+                }
+            }
+        }
+    }
+
+    fn collect_all(&self, out: &mut Vec<SharedPublish>) {
+        out.extend(self.message.clone());
+        for child in self.children.values() {
+            child.collect_all(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+
+    fn retain(store: &mut MemoryRetainStore, topic: &str, payload: &[u8]) {
+        store.handle_publish(&Publish {
+            dup: true,
+            qospid: QosPid::AtLeastOnce(Pid::new()),
+            retain: true,
+            topic_name: topic,
+            payload,
+        });
+    }
+
+    #[test]
+    fn matching_like_topic_filter() {
+        let topics = [
+            "a",
+            "/",
+            "a/b",
+            "a/b/",
+            "/a",
+            "a//c",
+            "$SYS/uptime",
+            "a/x/c",
+            "b",
+        ];
+        let filters = [
+            "#", "a/#", "+", "+/+", "a/+/c", "a/b", "$SYS/#", "+/uptime", "/",
+        ];
+        let mut store = MemoryRetainStore::new();
+        for topic in topics.iter() {
+            retain(&mut store, topic, b"x");
+        }
+        assert_eq!(topics.len(), store.len());
+        for filter in filters.iter() {
+            let filter = TopicFilter::new(filter).unwrap();
+            let mut found: Vec<String> = store
+                .matching(&filter)
+                .iter()
+                .map(|m| m.topic_name.to_string())
+                .collect();
+            found.sort();
+            let mut expected: Vec<String> = topics
+                .iter()
+                .filter(|t| filter.matches(t))
+                .map(|t| t.to_string())
+                .collect();
+            expected.sort();
+            assert_eq!(expected, found, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn set_and_clear() {
+        let mut store = MemoryRetainStore::new();
+        retain(&mut store, "a/b", b"1");
+        retain(&mut store, "a/b", b"2");
+        assert_eq!(1, store.len());
+        let found = store.matching(&TopicFilter::new("a/b").unwrap());
+        assert_eq!(&b"2"[..], &found[0].payload[..]);
+        assert!(!found[0].dup);
+
+        // Not retained: ignored.
+        store.handle_publish(&Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "a/b",
+            payload: b"",
+        });
+        assert_eq!(1, store.len());
+        retain(&mut store, "a/b", b"");
+        assert!(store.is_empty());
+        assert!(!store.clear("a/b"));
+        assert!(store.root.is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+use crate::{Error, Publish, QoS, SubscribeTopic, TopicError, TopicErrorKind, TopicFilter};
+use std::{collections::BTreeMap, string::String, vec::Vec};
+
+/// Subscription trie dispatching publishes to subscribed connections.
+///
+/// `C` identifies a connection or client, typically a client id or a connection index. Each
+/// connection has at most one subscription per topic filter; subscribing again replaces its
+/// `QoS`.
+///
+/// Shared subscriptions use filters of the form `$share/{group}/{filter}` (from [MQTT 5], and
+/// supported by most MQTT 3.1.1 servers): each matching publish goes to only one member of the
+/// group, chosen in turn.
+///
+/// [MQTT 5]: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901250
+#[derive(Debug, Clone)]
+pub struct Router<C> {
+    root: Node<C>,
+}
+
+#[derive(Debug, Clone)]
+struct Node<C> {
+    children: BTreeMap<String, Node<C>>,
+    subscribers: Vec<(C, QoS)>,
+    groups: Vec<SharedGroup<C>>,
+}
+
+#[derive(Debug, Clone)]
+struct SharedGroup<C> {
+    name: String,
+    members: Vec<(C, QoS)>,
+    /// Index of the member receiving the next publish.
+    next: usize,
+}
+
+impl<C> Default for Router<C> {
+    fn default() -> Self {
+        Router { root: Node::new() }
+    }
+}
+
+impl<C: Clone + Eq> Router<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or update the subscription of `client` to `topic`.
+    ///
+    /// Returns [`Error::InvalidTopicFilter`] for a shared subscription without a group name or
+    /// filter, or with a wildcard in the group name.
+    ///
+    /// [`Error::InvalidTopicFilter`]: ../enum.Error.html#variant.InvalidTopicFilter
+    pub fn subscribe(&mut self, client: C, topic: &SubscribeTopic) -> Result<(), Error> {
+        let (group, filter) = parse_shared(&topic.topic_filter)?;
+        let node = filter.split('/').fold(&mut self.root, |node, level| {
+            node.children.entry(level.into()).or_insert_with(Node::new)
+        });
+        let members = match group {
+            None => &mut node.subscribers,
+            Some(name) => {
+                let pos = match node.groups.iter().position(|g| g.name == name) {
+                    Some(pos) => pos,
+                    None => {
+                        node.groups.push(SharedGroup {
+                            name: name.into(),
+                            members: Vec::new(),
+                            next: 0,
+                        });
+                        node.groups.len() - 1
+                    }
+                };
+                &mut node.groups[pos].members
+            }
+        };
+        let qos = topic.options.qos;
+        match members.iter_mut().find(|(c, _)| *c == client) {
+            Some(member) => member.1 = qos,
+            None => members.push((client, qos)),
+        }
+        Ok(())
+    }
+
+    /// Remove the subscription of `client` to `filter`. Returns false if there was none.
+    pub fn unsubscribe(&mut self, client: &C, filter: &str) -> bool {
+        let filter = match TopicFilter::new(filter) {
+            Ok(filter) => filter,
+            Err(_) => return false,
+        };
+        let (group, filter) = match parse_shared(&filter) {
+            Ok(parsed) => parsed,
+            Err(_) => return false,
+        };
+        let levels: Vec<&str> = filter.split('/').collect();
+        self.root.remove(&levels, group, client)
+    }
+
+    /// Remove all the subscriptions of `client`, typically when its session ends.
+    pub fn remove_client(&mut self, client: &C) {
+        self.root.remove_all(client);
+    }
+
+    /// Returns true if there are no subscriptions.
+    pub fn is_empty(&self) -> bool {
+        self.root.is_empty()
+    }
+
+    /// Return the connections `publish` must be delivered to, with the `QoS` to use.
+    ///
+    /// Each connection appears once, with the maximum `QoS` of its matching subscriptions, capped
+    /// by the `QoS` of the publish ([MQTT-3.3.5-1]). Topics starting with `$` aren't matched by
+    /// filters starting with a wildcard. The order of the connections is unspecified, but doesn't
+    /// change between calls.
+    ///
+    /// [MQTT-3.3.5-1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718043
+    pub fn route(&mut self, publish: &Publish) -> Vec<(C, QoS)> {
+        self.route_topic(publish.topic_name, publish.qospid.qos())
+    }
+
+    /// Same as [`route()`], for a publish of `topic_name` at `qos`.
+    ///
+    /// [`route()`]: #method.route
+    pub fn route_topic(&mut self, topic_name: &str, qos: QoS) -> Vec<(C, QoS)> {
+        let levels: Vec<&str> = topic_name.split('/').collect();
+        let mut targets = Vec::new();
+        self.root
+            .route(&levels, !topic_name.starts_with('$'), qos, &mut targets);
+        targets
+    }
+}
+
+impl<C> Node<C> {
+    fn new() -> Self {
+        Node {
+            children: BTreeMap::new(),
+            subscribers: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl<C: Clone + Eq> Node<C> {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscribers.is_empty() && self.groups.is_empty()
+    }
+
+    /// `wildcards` is false for the first level of a `$` topic.
+    fn route(&mut self, levels: &[&str], wildcards: bool, qos: QoS, out: &mut Vec<(C, QoS)>) {
+        if wildcards {
+            if let Some(child) = self.children.get_mut("#") {
+                child.deliver(qos, out);
+            }
+        }
+        match levels.split_first() {
+            None => self.deliver(qos, out),
+            Some((level, rest)) => {
+                if let Some(child) = self.children.get_mut(*level) {
+                    child.route(rest, true, qos, out);
+                }
+                if wildcards && *level != "+" {
+                    if let Some(child) = self.children.get_mut("+") {
+                        child.route(rest, true, qos, out);
+                    }
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self, qos: QoS, out: &mut Vec<(C, QoS)>) {
+        for (client, sub_qos) in &self.subscribers {
+            add_target(out, client, qos.min(*sub_qos));
+        }
+        for group in &mut self.groups {
+            let (client, sub_qos) = &group.members[group.next % group.members.len()];
+            group.next = (group.next + 1) % group.members.len();
+            add_target(out, client, qos.min(*sub_qos));
+        }
+    }
+
+    fn remove(&mut self, levels: &[&str], group: Option<&str>, client: &C) -> bool {
+        match levels.split_first() {
+            None => match group {
+                None => remove_member(&mut self.subscribers, client),
+                Some(name) => match self.groups.iter().position(|g| g.name == name) {
+                    Some(pos) => {
+                        let removed = remove_member(&mut self.groups[pos].members, client);
+                        if self.groups[pos].members.is_empty() {
+                            self.groups.remove(pos);
+                        }
+                        removed
+                    }
+                    None => false,
+                },
+            },
+            Some((level, rest)) => match self.children.get_mut(*level) {
+                Some(child) => {
+                    let removed = child.remove(rest, group, client);
+                    if child.is_empty() {
+                        self.children.remove(*level);
+                    }
+                    removed
+                }
+                None => false,
+            },
+        }
+    }
+
+    fn remove_all(&mut self, client: &C) {
+        remove_member(&mut self.subscribers, client);
+        for group in &mut self.groups {
+            remove_member(&mut group.members, client);
+        }
+        self.groups.retain(|g| !g.members.is_empty());
+        for child in self.children.values_mut() {
+            child.remove_all(client);
+        }
+        self.children.retain(|_, child| !child.is_empty());
+    }
+}
+
+fn add_target<C: Clone + Eq>(out: &mut Vec<(C, QoS)>, client: &C, qos: QoS) {
+    match out.iter_mut().find(|(c, _)| c == client) {
+        Some(target) => target.1 = target.1.max(qos),
+        None => out.push((client.clone(), qos)),
+    }
+}
+
+fn remove_member<C: Eq>(members: &mut Vec<(C, QoS)>, client: &C) -> bool {
+    let len = members.len();
+    members.retain(|(c, _)| c != client);
+    members.len() != len
+}
+
+/// Split a `$share/{group}/{filter}` filter into its group name and filter.
+fn parse_shared(filter: &TopicFilter) -> Result<(Option<&str>, &str), Error> {
+    match filter.strip_prefix("$share/") {
+        None => Ok((None, filter)),
+        Some(shared) => {
+            // The share name is the second level.
+            let invalid = |kind| Error::InvalidTopicFilter(TopicError { kind, level: 2 });
+            match shared.split_once('/') {
+                Some((group, filter)) if !group.is_empty() && !filter.is_empty() => {
+                    match group.chars().find(|c| *c == '+' || *c == '#') {
+                        Some(c) => Err(invalid(TopicErrorKind::ShareNameWildcard(c))),
+                        None => Ok((Some(group), filter)),
+                    }
+                }
+                _ => Err(invalid(TopicErrorKind::IncompleteShare)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use std::vec;
+
+    fn sub(filter: &str, qos: QoS) -> SubscribeTopic {
+        SubscribeTopic::new(filter, qos).unwrap()
+    }
+
+    #[test]
+    fn matches_like_topic_filter() {
+        let filters = [
+            "#", "a/#", "+", "+/+", "a/+/c", "a/b", "$SYS/#", "+/uptime", "/",
+        ];
+        let topics = [
+            "a",
+            "/",
+            "ab",
+            "a/b",
+            "a/b/",
+            "/a",
+            "a//c",
+            "$SYS/uptime",
+            "a/x/c",
+        ];
+        let mut router = Router::new();
+        for (i, filter) in filters.iter().enumerate() {
+            router.subscribe(i, &sub(filter, QoS::AtMostOnce)).unwrap();
+        }
+        for topic in topics.iter() {
+            let mut routed: Vec<usize> = router
+                .route_topic(topic, QoS::AtMostOnce)
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect();
+            routed.sort_unstable();
+            let expected: Vec<usize> = (0..filters.len())
+                .filter(|i| TopicFilter::new(filters[*i]).unwrap().matches(topic))
+                .collect();
+            assert_eq!(expected, routed, "{}", topic);
+        }
+    }
+
+    #[test]
+    fn qos_and_overlaps() {
+        let mut router = Router::new();
+        router.subscribe("a", &sub("x/+", QoS::AtMostOnce)).unwrap();
+        router
+            .subscribe("a", &sub("x/#", QoS::AtLeastOnce))
+            .unwrap();
+        router
+            .subscribe("b", &sub("x/y", QoS::ExactlyOnce))
+            .unwrap();
+        assert_eq!(
+            vec![("a", QoS::AtLeastOnce), ("b", QoS::ExactlyOnce)],
+            router.route_topic("x/y", QoS::ExactlyOnce)
+        );
+        assert_eq!(
+            vec![("a", QoS::AtLeastOnce), ("b", QoS::AtLeastOnce)],
+            router.route_topic("x/y", QoS::AtLeastOnce)
+        );
+        // Subscribing again replaces the QoS.
+        router.subscribe("b", &sub("x/y", QoS::AtMostOnce)).unwrap();
+        assert_eq!(
+            vec![("a", QoS::AtLeastOnce), ("b", QoS::AtMostOnce)],
+            router.route_topic("x/y", QoS::ExactlyOnce)
+        );
+
+        assert!(router.unsubscribe(&"a", "x/#"));
+        assert!(!router.unsubscribe(&"a", "x/#"));
+        assert!(!router.unsubscribe(&"a", "x/+/"));
+        assert_eq!(
+            vec![("b", QoS::AtMostOnce), ("a", QoS::AtMostOnce)],
+            router.route_topic("x/y", QoS::ExactlyOnce)
+        );
+        router.remove_client(&"a");
+        router.remove_client(&"b");
+        assert!(router.is_empty());
+    }
+
+    #[test]
+    fn shared_subscriptions() {
+        let mut router = Router::new();
+        for client in &["w1", "w2", "w3"] {
+            router
+                .subscribe(*client, &sub("$share/workers/jobs/+", QoS::AtLeastOnce))
+                .unwrap();
+        }
+        router
+            .subscribe("audit", &sub("jobs/#", QoS::AtMostOnce))
+            .unwrap();
+        let mut received = Vec::new();
+        for _ in 0..6 {
+            let targets = router.route_topic("jobs/1", QoS::AtLeastOnce);
+            assert_eq!(2, targets.len());
+            assert!(targets.contains(&("audit", QoS::AtMostOnce)));
+            received.extend(targets.into_iter().filter(|(c, _)| *c != "audit"));
+        }
+        let count = |c| received.iter().filter(|(r, _)| *r == c).count();
+        assert_eq!((2, 2, 2), (count("w1"), count("w2"), count("w3")));
+
+        assert!(router.unsubscribe(&"w2", "$share/workers/jobs/+"));
+        assert!(!router.unsubscribe(&"w2", "jobs/+"));
+        router.remove_client(&"w1");
+        assert_eq!(
+            vec![("w3", QoS::AtLeastOnce)],
+            router
+                .route_topic("jobs/2", QoS::ExactlyOnce)
+                .into_iter()
+                .filter(|(c, _)| *c != "audit")
+                .collect::<Vec<_>>()
+        );
+
+        let invalid = [
+            ("$share/jobs", TopicErrorKind::IncompleteShare),
+            ("$share//jobs", TopicErrorKind::IncompleteShare),
+            ("$share/+/jobs", TopicErrorKind::ShareNameWildcard('+')),
+        ];
+        for (filter, kind) in invalid {
+            assert_eq!(
+                Err(Error::InvalidTopicFilter(TopicError { kind, level: 2 })),
+                router.subscribe("w1", &sub(filter, QoS::AtMostOnce))
+            );
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{QosPid, SharedPublish};
+use bytes::Bytes;
+use core::time::Duration;
+use std::{format, string::ToString, sync::Arc, vec::Vec};
+
+/// Broker statistics, published under `$SYS/broker/` by a [`SysPublisher`].
+///
+/// [`SysPublisher`]: struct.SysPublisher.html
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BrokerStats {
+    pub clients_connected: u64,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub uptime: Duration,
+}
+
+impl BrokerStats {
+    /// Return one retained QoS 0 publish per statistic, using the topic names and decimal text
+    /// payloads conventional among brokers.
+    ///
+    /// ```
+    /// # use mqttrs::broker::*;
+    /// # use core::time::Duration;
+    /// let stats = BrokerStats { clients_connected: 3,
+    ///                           uptime: Duration::from_secs(42),
+    ///                           ..BrokerStats::default() };
+    /// let publishes = stats.to_publishes();
+    /// assert_eq!("$SYS/broker/clients/connected", &*publishes[0].topic_name);
+    /// assert_eq!(b"3", &publishes[0].payload[..]);
+    /// assert_eq!("$SYS/broker/uptime", &*publishes[5].topic_name);
+    /// assert_eq!(b"42 seconds", &publishes[5].payload[..]);
+    /// ```
+    pub fn to_publishes(&self) -> Vec<SharedPublish> {
+        [
+            ("clients/connected", self.clients_connected.to_string()),
+            ("messages/received", self.messages_received.to_string()),
+            ("messages/sent", self.messages_sent.to_string()),
+            ("bytes/received", self.bytes_received.to_string()),
+            ("bytes/sent", self.bytes_sent.to_string()),
+            ("uptime", format!("{} seconds", self.uptime.as_secs())),
+        ]
+        .iter()
+        .map(|(topic, payload)| SharedPublish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: true,
+            topic_name: Arc::from(format!("$SYS/broker/{}", topic)),
+            payload: Bytes::copy_from_slice(payload.as_bytes()),
+        })
+        .collect()
+    }
+}
+
+/// Decides when to publish the [`BrokerStats`].
+///
+/// The returned publishes are retained, so brokers usually pass them to their [`RetainStore`]
+/// as well as to their [`Router`]. Times are durations since an arbitrary reference, like the
+/// start of the program.
+///
+/// ```
+/// # use mqttrs::broker::*;
+/// # use core::time::Duration;
+/// let secs = Duration::from_secs;
+/// let mut sys = SysPublisher::new(secs(10), secs(0));
+/// let stats = BrokerStats::default();
+/// assert!(sys.poll(&stats, secs(9)).is_empty());
+/// assert_eq!(6, sys.poll(&stats, secs(10)).len());
+/// assert_eq!(secs(20), sys.next_due());
+/// ```
+///
+/// [`BrokerStats`]: struct.BrokerStats.html
+/// [`RetainStore`]: trait.RetainStore.html
+/// [`Router`]: struct.Router.html
+#[derive(Debug, Clone)]
+pub struct SysPublisher {
+    interval: Duration,
+    next_due: Duration,
+}
+
+impl SysPublisher {
+    /// Publish every `interval`, starting one interval after `now`.
+    pub fn new(interval: Duration, now: Duration) -> Self {
+        SysPublisher {
+            interval,
+            next_due: now + interval,
+        }
+    }
+
+    /// Time at which [`poll()`] returns the next publishes.
+    ///
+    /// [`poll()`]: #method.poll
+    pub fn next_due(&self) -> Duration {
+        self.next_due
+    }
+
+    /// Return the publishes for `stats` if they are due at `now`, or nothing.
+    ///
+    /// The next publication is scheduled one interval after `now`, so a late poll delays the
+    /// following ones instead of publishing several times in a row.
+    pub fn poll(&mut self, stats: &BrokerStats, now: Duration) -> Vec<SharedPublish> {
+        if now < self.next_due {
+            return Vec::new();
+        }
+        self.next_due = now + self.interval;
+        stats.to_publishes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::broker::{MemoryRetainStore, RetainStore};
+    use crate::TopicFilter;
+
+    #[test]
+    fn retained_stats() {
+        let secs = Duration::from_secs;
+        let mut sys = SysPublisher::new(secs(5), secs(100));
+        let mut store = MemoryRetainStore::new();
+        let mut stats = BrokerStats::default();
+        for now in 100..120 {
+            stats.messages_received += 1;
+            for publish in sys.poll(&stats, secs(now)) {
+                assert!(publish.retain);
+                store.handle_publish(&publish.as_publish());
+            }
+        }
+        assert_eq!(secs(120), sys.next_due());
+
+        let received = store.matching(&TopicFilter::new("$SYS/broker/messages/+").unwrap());
+        assert_eq!(2, received.len());
+        assert_eq!(b"16", &received[0].payload[..]);
+        // Wildcards at the first level don't match `$SYS` topics.
+        assert!(store.matching(&TopicFilter::new("#").unwrap()).is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{Connack, Connect};
+use std::{collections::HashMap, string::String};
+
+/// Sessions of a broker's clients, indexed by client id, implementing session takeover.
+///
+/// When a client connects with the client id of an already connected client, the server must
+/// close the existing connection ([MQTT-3.1.4-2]). [`connect()`] returns that connection as
+/// [`taken_over`], along with the session to use for the new connection: the persisted one, or a
+/// new `S::default()` for a clean session. MQTT 3.1.1 servers just close the old connection;
+/// MQTT 5 servers first send it a `Disconnect` with reason code 0x8E (Session taken over).
+///
+/// `K` identifies a network connection, and `S` is the session state kept by the broker.
+///
+/// ```
+/// # use mqttrs::{*, broker::*};
+/// let mut sessions: SessionRegistry<u32, Vec<String>> = SessionRegistry::new();
+/// let connect = Connect { protocol: Protocol::MQTT311, keep_alive: 30, client_id: "c1",
+///                         clean_session: false, last_will: None, username: None,
+///                         password: None };
+/// let first = sessions.connect(&connect, 1);
+/// assert!(!first.session_present);
+/// first.session.push("a/#".to_string());
+///
+/// let second = sessions.connect(&connect, 2);
+/// assert_eq!(Some(1), second.taken_over);
+/// assert_eq!(Connack::accepted(true), second.connack());
+/// assert_eq!(vec!["a/#"], *second.session);
+///
+/// // The old connection closing doesn't affect the new one.
+/// sessions.disconnected("c1", &1);
+/// assert_eq!(Some(&2), sessions.connection("c1"));
+/// ```
+///
+/// [MQTT-3.1.4-2]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718035
+/// [`connect()`]: #method.connect
+/// [`taken_over`]: struct.ConnectOutcome.html#structfield.taken_over
+#[derive(Debug, Clone)]
+pub struct SessionRegistry<K, S> {
+    clients: HashMap<String, Client<K, S>>,
+}
+
+#[derive(Debug, Clone)]
+struct Client<K, S> {
+    connection: Option<K>,
+    clean_session: bool,
+    session: S,
+}
+
+/// Result of [`SessionRegistry::connect()`].
+///
+/// [`SessionRegistry::connect()`]: struct.SessionRegistry.html#method.connect
+#[derive(Debug)]
+pub struct ConnectOutcome<'r, K, S> {
+    /// Whether a persisted session was resumed, to send in the `Connack`.
+    pub session_present: bool,
+    /// The existing connection with the same client id, which must be closed.
+    pub taken_over: Option<K>,
+    /// The session of the new connection.
+    pub session: &'r mut S,
+}
+
+impl<K, S> ConnectOutcome<'_, K, S> {
+    /// Return the `Connack` accepting the connection.
+    pub fn connack(&self) -> Connack {
+        Connack::accepted(self.session_present)
+    }
+}
+
+impl<K, S> Default for SessionRegistry<K, S> {
+    fn default() -> Self {
+        SessionRegistry {
+            clients: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Clone + Eq, S: Default> SessionRegistry<K, S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attach the client of `connect` to `connection`, taking over its previous connection if
+    /// any.
+    ///
+    /// The client id must be valid, see [`Connect::check_client_id()`].
+    ///
+    /// [`Connect::check_client_id()`]: ../struct.Connect.html#method.check_client_id
+    pub fn connect(&mut self, connect: &Connect, connection: K) -> ConnectOutcome<'_, K, S> {
+        let client = self
+            .clients
+            .entry(connect.client_id.into())
+            .or_insert_with(|| Client {
+                connection: None,
+                clean_session: true,
+                session: S::default(),
+            });
+        let taken_over = client.connection.replace(connection);
+        // New clients are inserted as clean, and a clean session that is being taken over is
+        // still here: neither must be resumed.
+        let session_present = !connect.clean_session && !client.clean_session;
+        if !session_present {
+            client.session = S::default();
+        }
+        client.clean_session = connect.clean_session;
+        ConnectOutcome {
+            session_present,
+            taken_over,
+            session: &mut client.session,
+        }
+    }
+
+    /// The network connection of `client_id` closed.
+    ///
+    /// Does nothing if the client is now attached to another connection. Otherwise, the session
+    /// is kept if the client connected with `clean_session == false`, and discarded otherwise.
+    pub fn disconnected(&mut self, client_id: &str, connection: &K) {
+        if let Some(client) = self.clients.get_mut(client_id) {
+            if client.connection.as_ref() == Some(connection) {
+                if client.clean_session {
+                    self.clients.remove(client_id);
+                } else {
+                    client.connection = None;
+                }
+            }
+        }
+    }
+
+    /// Current connection of `client_id`, if it is connected.
+    pub fn connection(&self, client_id: &str) -> Option<&K> {
+        self.clients.get(client_id)?.connection.as_ref()
+    }
+
+    /// Session of `client_id`, whether it is connected or persisted.
+    pub fn session_mut(&mut self, client_id: &str) -> Option<&mut S> {
+        Some(&mut self.clients.get_mut(client_id)?.session)
+    }
+
+    /// Number of sessions, connected or persisted.
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use std::vec::Vec;
+
+    fn connect(client_id: &str, clean_session: bool) -> Connect<'_> {
+        Connect {
+            protocol: Protocol::MQTT311,
+            keep_alive: 0,
+            client_id,
+            clean_session,
+            last_will: None,
+            username: None,
+            password: None,
+        }
+    }
+
+    #[test]
+    fn takeover() {
+        let mut sessions: SessionRegistry<u8, Vec<u8>> = SessionRegistry::new();
+
+        // A clean session taken over by a persistent one isn't resumed.
+        sessions.connect(&connect("a", true), 1).session.push(1);
+        let outcome = sessions.connect(&connect("a", false), 2);
+        assert_eq!(
+            (false, Some(1)),
+            (outcome.session_present, outcome.taken_over)
+        );
+        assert!(outcome.session.is_empty());
+        outcome.session.push(2);
+
+        // The persistent session is resumed after a disconnection...
+        sessions.disconnected("a", &2);
+        assert_eq!(None, sessions.connection("a"));
+        let outcome = sessions.connect(&connect("a", false), 3);
+        assert_eq!((true, None), (outcome.session_present, outcome.taken_over));
+        assert_eq!(vec![2], *outcome.session);
+
+        // ...and discarded by a clean connection, which is removed when it ends.
+        let outcome = sessions.connect(&connect("a", true), 4);
+        assert_eq!(
+            (false, Some(3)),
+            (outcome.session_present, outcome.taken_over)
+        );
+        assert!(outcome.session.is_empty());
+        sessions.disconnected("a", &3);
+        assert_eq!(1, sessions.len());
+        sessions.disconnected("a", &4);
+        assert!(sessions.is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{LastWill, Publish, QoS, QosPid};
+use core::{hash::Hash, time::Duration};
+use std::{collections::HashMap, string::String, vec::Vec};
+
+/// An owned copy of a client's [`LastWill`].
+///
+/// [`LastWill`]: ../struct.LastWill.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Will {
+    pub topic: String,
+    pub message: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+impl Will {
+    /// Return the publish to route to subscribers. The `qospid` is usually chosen per
+    /// subscriber.
+    pub fn to_publish(&self, qospid: QosPid) -> Publish<'_> {
+        Publish {
+            dup: false,
+            qospid,
+            retain: self.retain,
+            topic_name: &self.topic,
+            payload: &self.message,
+        }
+    }
+}
+
+impl From<&LastWill<'_>> for Will {
+    fn from(will: &LastWill) -> Self {
+        Will {
+            topic: will.topic.into(),
+            message: will.message.into(),
+            qos: will.qos,
+            retain: will.retain,
+        }
+    }
+}
+
+/// Decides when to publish the [`LastWill`] of each client.
+///
+/// The will is published when the connection closes without a `Disconnect` packet ([MQTT
+/// 3.1.2.5]), after an optional delay. MQTT 5 clients choose this delay with the Will Delay
+/// Interval property; MQTT 3.1.1 has none, so servers usually pass `Duration::ZERO` or a value
+/// from their configuration. If the client reconnects before the delay expires, the will is
+/// cancelled. Times are durations since an arbitrary reference, like the start of the program.
+///
+/// ```
+/// # use mqttrs::{*, broker::*};
+/// # use core::time::Duration;
+/// let secs = Duration::from_secs;
+/// let will = LastWill { topic: "status/c1", message: b"offline", qos: QoS::AtLeastOnce,
+///                       retain: true };
+/// let mut wills = WillScheduler::new();
+/// wills.connected("c1", Some(&will), secs(30));
+/// wills.connection_lost(&"c1", secs(100));
+/// assert_eq!(Some(secs(130)), wills.next_due());
+/// assert!(wills.poll(secs(129)).is_empty());
+/// let due = wills.poll(secs(130));
+/// assert_eq!(("c1", Will::from(&will)), due[0]);
+/// ```
+///
+/// [`LastWill`]: ../struct.LastWill.html
+/// [MQTT 3.1.2.5]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028
+#[derive(Debug, Clone)]
+pub struct WillScheduler<C> {
+    /// Wills of connected clients, and their delay.
+    registered: HashMap<C, (Will, Duration)>,
+    /// Wills of disconnected clients, and when to publish them.
+    pending: HashMap<C, (Will, Duration)>,
+}
+
+impl<C> Default for WillScheduler<C> {
+    fn default() -> Self {
+        WillScheduler {
+            registered: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<C: Clone + Eq + Hash> WillScheduler<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the will of a client that just connected, to publish `delay` after losing the
+    /// connection.
+    ///
+    /// Returns true if a pending will of a previous connection of the client was cancelled.
+    pub fn connected(&mut self, client: C, will: Option<&LastWill>, delay: Duration) -> bool {
+        let cancelled = self.pending.remove(&client).is_some();
+        match will {
+            Some(will) => {
+                self.registered.insert(client, (will.into(), delay));
+            }
+            None => {
+                self.registered.remove(&client);
+            }
+        }
+        cancelled
+    }
+
+    /// The client sent a `Disconnect`: its will is discarded ([MQTT-3.14.4-3]).
+    ///
+    /// [MQTT-3.14.4-3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718093
+    pub fn disconnected(&mut self, client: &C) {
+        self.registered.remove(client);
+    }
+
+    /// The connection of the client closed without a `Disconnect`, or was closed by the server
+    /// because of a protocol error or keep alive timeout: schedule its will.
+    pub fn connection_lost(&mut self, client: &C, now: Duration) {
+        if let Some((will, delay)) = self.registered.remove(client) {
+            self.pending.insert(client.clone(), (will, now + delay));
+        }
+    }
+
+    /// Time at which the next will is due, if any.
+    pub fn next_due(&self) -> Option<Duration> {
+        self.pending.values().map(|(_, due)| *due).min()
+    }
+
+    /// Remove and return the wills due at `now`, to publish.
+    pub fn poll(&mut self, now: Duration) -> Vec<(C, Will)> {
+        let due: Vec<C> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, at))| *at <= now)
+            .map(|(client, _)| client.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|client| {
+                let (will, _) = self.pending.remove(&client)?;
+                Some((client, will))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn will_scheduling() {
+        let secs = Duration::from_secs;
+        let will = LastWill {
+            topic: "w",
+            message: b"gone",
+            qos: QoS::AtMostOnce,
+            retain: false,
+        };
+        let mut wills = WillScheduler::new();
+
+        // No delay: due immediately.
+        wills.connected(1, Some(&will), Duration::ZERO);
+        wills.connection_lost(&1, secs(5));
+        assert_eq!(vec![(1, Will::from(&will))], wills.poll(secs(5)));
+        assert!(wills.poll(secs(5)).is_empty());
+
+        // Clean disconnect: no will.
+        wills.connected(2, Some(&will), Duration::ZERO);
+        wills.disconnected(&2);
+        wills.connection_lost(&2, secs(5));
+        assert_eq!(None, wills.next_due());
+
+        // Reconnecting in time cancels the will, and replaces it.
+        wills.connected(3, Some(&will), secs(10));
+        wills.connection_lost(&3, secs(0));
+        assert!(wills.connected(3, None, secs(10)));
+        wills.connection_lost(&3, secs(1));
+        assert!(wills.poll(secs(100)).is_empty());
+
+        let owned = Will::from(&will);
+        let publish = owned.to_publish(QosPid::AtMostOnce);
+        assert_eq!("w", publish.topic_name);
+        assert_eq!(b"gone", publish.payload);
+    }
+}
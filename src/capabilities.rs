@@ -0,0 +1,278 @@
+use crate::*;
+use core::fmt;
+
+/// Features supported by a server.
+///
+/// MQTT 3.1.1 has no way to advertise these, but servers commonly restrict them through their
+/// configuration. MQTT 5 sends them as [CONNACK properties].
+///
+/// [CONNACK properties]: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901080
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    /// Highest `QoS` accepted for publishes and wills.
+    pub max_qos: QoS,
+    /// Whether retained messages and wills are accepted.
+    pub retain_available: bool,
+    /// Keep alive imposed by the server, in seconds, overriding the one requested by clients.
+    pub keep_alive: Option<u16>,
+}
+
+impl Default for ServerCapabilities {
+    /// Everything is supported.
+    fn default() -> Self {
+        ServerCapabilities {
+            max_qos: QoS::ExactlyOnce,
+            retain_available: true,
+            keep_alive: None,
+        }
+    }
+}
+
+impl ServerCapabilities {
+    /// Check that the server can accept the [`LastWill`] of a connecting client.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let caps = ServerCapabilities { max_qos: QoS::AtLeastOnce, ..Default::default() };
+    /// let will = LastWill { topic: "status", message: b"offline", qos: QoS::ExactlyOnce, retain: false };
+    /// let err = caps.check_will(&will).unwrap_err();
+    /// assert_eq!(CapabilityError::QosNotSupported(QoS::ExactlyOnce), err);
+    /// assert_eq!(0x9B, err.to_reason_code());
+    /// assert_eq!(Connack::rejected(ConnectReturnCode::NotAuthorized), err.into());
+    /// ```
+    ///
+    /// [`LastWill`]: struct.LastWill.html
+    pub fn check_will(&self, will: &LastWill) -> Result<(), CapabilityError> {
+        if will.qos > self.max_qos {
+            Err(CapabilityError::QosNotSupported(will.qos))
+        } else if will.retain && !self.retain_available {
+            Err(CapabilityError::RetainNotSupported)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Lower `qos` to [`max_qos`] if needed, typically for a publish or subscription that can be
+    /// downgraded.
+    ///
+    /// [`max_qos`]: #structfield.max_qos
+    pub fn clamp_qos(&self, qos: QoS) -> QoS {
+        qos.min(self.max_qos)
+    }
+
+    /// Check that `qos` is supported, for a publish or subscription that can't be downgraded.
+    pub fn check_qos(&self, qos: QoS) -> Result<(), CapabilityError> {
+        if qos > self.max_qos {
+            Err(CapabilityError::QosNotSupported(qos))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Lower the `QoS` of `publish` to [`max_qos`] if needed. The pid is dropped when downgrading
+    /// to `QoS 0`.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let caps = ServerCapabilities { max_qos: QoS::AtMostOnce, ..Default::default() };
+    /// let mut publish = Publish { dup: true,
+    ///                             qospid: QosPid::AtLeastOnce(Pid::new()),
+    ///                             retain: false,
+    ///                             topic_name: "a/b",
+    ///                             payload: b"hi" };
+    /// caps.clamp_publish(&mut publish);
+    /// assert_eq!(QosPid::AtMostOnce, publish.qospid);
+    /// assert!(!publish.dup);
+    /// ```
+    ///
+    /// [`max_qos`]: #structfield.max_qos
+    pub fn clamp_publish(&self, publish: &mut Publish) {
+        publish.qospid = match (publish.qospid, self.max_qos) {
+            (QosPid::AtMostOnce, _) | (_, QoS::AtMostOnce) => QosPid::AtMostOnce,
+            (QosPid::ExactlyOnce(pid), QoS::AtLeastOnce) => QosPid::AtLeastOnce(pid),
+            (qospid, _) => qospid,
+        };
+        // The dup flag must not be set for `QoS 0` messages.
+        if publish.qospid == QosPid::AtMostOnce {
+            publish.dup = false;
+        }
+    }
+
+    /// Lower the requested `QoS` of each topic of `subscribe` to [`max_qos`] if needed.
+    ///
+    /// [`max_qos`]: #structfield.max_qos
+    pub fn clamp_subscribe(&self, subscribe: &mut Subscribe) {
+        for topic in subscribe.topics.iter_mut() {
+            topic.options.qos = self.clamp_qos(topic.options.qos);
+        }
+    }
+}
+
+/// A feature required by a packet isn't supported by the server.
+///
+/// Returned by the checks of [`ServerCapabilities`]. When checking a will, converts into the
+/// [`Connack`] that refuses the connection.
+///
+/// [`ServerCapabilities`]: struct.ServerCapabilities.html
+/// [`Connack`]: struct.Connack.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilityError {
+    /// The `QoS` is higher than [`ServerCapabilities::max_qos`].
+    ///
+    /// [`ServerCapabilities::max_qos`]: struct.ServerCapabilities.html#structfield.max_qos
+    QosNotSupported(QoS),
+    /// The retain flag is set but the server doesn't support retained messages.
+    RetainNotSupported,
+}
+
+impl CapabilityError {
+    /// Returns the return code to send in the [`Connack`] refusing a will.
+    ///
+    /// MQTT 3.1.1 has no specific code for this, so this is always `NotAuthorized`.
+    ///
+    /// [`Connack`]: struct.Connack.html
+    pub fn return_code(self) -> ConnectReturnCode {
+        ConnectReturnCode::NotAuthorized
+    }
+
+    /// Returns the MQTT 5 connect reason code ("QoS not supported" or "Retain not supported").
+    pub fn to_reason_code(self) -> u8 {
+        match self {
+            CapabilityError::QosNotSupported(_) => 0x9B,
+            CapabilityError::RetainNotSupported => 0x9A,
+        }
+    }
+}
+
+impl fmt::Display for CapabilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapabilityError::QosNotSupported(qos) => write!(f, "{:?} not supported", qos),
+            CapabilityError::RetainNotSupported => f.write_str("retain not supported"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CapabilityError {}
+
+impl From<CapabilityError> for Connack {
+    fn from(err: CapabilityError) -> Self {
+        Connack::rejected(err.return_code())
+    }
+}
+
+/// Builds the [`Connack`] answering a client's [`Connect`], consistently with the
+/// [`ServerCapabilities`].
+///
+/// The connection is refused if the client id is empty without a clean session, or if the
+/// server doesn't assign ids ([MQTT-3.1.3-8]), and if the will isn't supported. Otherwise it's
+/// accepted, with the session present flag cleared for clean sessions ([MQTT-3.2.2-1]), the
+/// keep alive negotiated with [`KeepAlive::negotiate()`] and the client id in effect.
+///
+/// MQTT 5 would also send the server capabilities, keep alive and assigned client id as
+/// CONNACK properties; MQTT 3.1.1 has none, so they're only returned to the server in the
+/// [`ConnectResponse`].
+///
+/// ```
+/// # use mqttrs::*;
+/// let caps = ServerCapabilities { keep_alive: Some(30), ..Default::default() };
+/// let connect = Connect { protocol: Protocol::MQTT311,
+///                         keep_alive: 600,
+///                         client_id: "",
+///                         clean_session: true,
+///                         last_will: None,
+///                         username: None,
+///                         password: None };
+/// let response = ConnackBuilder::new(&caps, &connect)
+///     .assigned_client_id("auto-7")
+///     .session_present(true)
+///     .build();
+/// assert_eq!(Connack::accepted(false), response.connack);
+/// assert_eq!(30, response.keep_alive.secs());
+/// assert_eq!("auto-7", response.client_id);
+///
+/// // Without an assigned id, empty client ids are refused.
+/// let response = ConnackBuilder::new(&caps, &connect).build();
+/// assert_eq!(Connack::rejected(ConnectReturnCode::RefusedIdentifierRejected), response.connack);
+/// ```
+///
+/// [`Connack`]: struct.Connack.html
+/// [`Connect`]: struct.Connect.html
+/// [`ServerCapabilities`]: struct.ServerCapabilities.html
+/// [MQTT-3.1.3-8]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718031
+/// [MQTT-3.2.2-1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718035
+/// [`KeepAlive::negotiate()`]: struct.KeepAlive.html#method.negotiate
+/// [`ConnectResponse`]: struct.ConnectResponse.html
+#[derive(Debug, Clone)]
+pub struct ConnackBuilder<'b> {
+    caps: &'b ServerCapabilities,
+    connect: &'b Connect<'b>,
+    assigned_client_id: Option<&'b str>,
+    session_present: bool,
+}
+
+/// The outcome of a [`ConnackBuilder`].
+///
+/// [`ConnackBuilder`]: struct.ConnackBuilder.html
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectResponse<'b> {
+    /// The packet to send to the client.
+    pub connack: Connack,
+    /// The keep alive in effect for the connection.
+    pub keep_alive: KeepAlive,
+    /// The client id in effect for the connection, assigned if the client sent an empty one.
+    pub client_id: &'b str,
+}
+
+impl<'b> ConnackBuilder<'b> {
+    pub fn new(caps: &'b ServerCapabilities, connect: &'b Connect<'b>) -> Self {
+        ConnackBuilder {
+            caps,
+            connect,
+            assigned_client_id: None,
+            session_present: false,
+        }
+    }
+
+    /// Use `client_id` if the client sent an empty one. Without this, empty client ids are
+    /// refused.
+    pub fn assigned_client_id(mut self, client_id: &'b str) -> Self {
+        self.assigned_client_id = Some(client_id);
+        self
+    }
+
+    /// Set whether the server has a session for this client id. Ignored for clean sessions.
+    pub fn session_present(mut self, session_present: SessionPresent) -> Self {
+        self.session_present = session_present;
+        self
+    }
+
+    pub fn build(self) -> ConnectResponse<'b> {
+        let connect = self.connect;
+        let keep_alive = KeepAlive::negotiate(connect, self.caps);
+        let client_id = match self.assigned_client_id {
+            Some(assigned) => connect.effective_client_id(assigned),
+            None => connect.client_id,
+        };
+        let code = match connect.check_client_id() {
+            Err(code) => code,
+            Ok(()) if client_id.is_empty() => ConnectReturnCode::RefusedIdentifierRejected,
+            Ok(()) => match connect.last_will.as_ref().map(|w| self.caps.check_will(w)) {
+                Some(Err(e)) => e.return_code(),
+                _ => ConnectReturnCode::Accepted,
+            },
+        };
+        let connack = match code {
+            ConnectReturnCode::Accepted => {
+                Connack::accepted(self.session_present && !connect.clean_session)
+            }
+            code => Connack::rejected(code),
+        };
+        ConnectResponse {
+            connack,
+            keep_alive,
+            client_id,
+        }
+    }
+}
@@ -0,0 +1,782 @@
+use crate::{
+    acks_for,
+    client::{DeliveryToken, Event},
+    encode_grow, Connect, Error, KeepAlive, Notification, Notifier, Packet, Pid, PingAction,
+    PingScheduler, Publish, QoS, QosPid, Subscribe, SubscribeReturnCodes, SubscribeTopic,
+    SubscriptionIds, Unsubscribe, UnsubscribeResult,
+};
+use bytes::BytesMut;
+use core::time::Duration;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    vec::Vec,
+};
+
+/// What a subscribe or unsubscribe request is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Awaiting {
+    Suback,
+    Unsuback,
+}
+
+/// A `Subscribe` or `Unsubscribe` waiting for its acknowledgement.
+#[derive(Debug, Clone)]
+struct Request {
+    awaiting: Awaiting,
+    filters: Vec<String>,
+    deadline: Option<Duration>,
+}
+
+/// Sans-io MQTT client, turning the packets received from the server into [`Event`]s.
+///
+/// The client doesn't do any I/O: requests like [`publish()`] encode packets into an internal
+/// buffer, to write to the connection after calling [`take_outgoing()`]. Decode the packets
+/// read from the connection with any decoder and pass them to [`handle_packet()`], then
+/// consume the resulting events with [`poll_event()`]. Call [`handle_timeout()`] when
+/// [`next_wakeup()`] is reached, to send pings and detect a silent server. Acknowledgements
+/// required by the protocol are sent automatically, unless [`set_manual_acks()`] is used to
+/// acknowledge received publishes only once the application processed them.
+///
+/// Times are durations since an arbitrary reference, like the start of the program.
+///
+/// ```
+/// # use mqttrs::{*, client::*};
+/// # use core::time::Duration;
+/// let now = Duration::ZERO;
+/// let mut client = Client::new();
+/// client.connect(&Connect { protocol: Protocol::MQTT311,
+///                           keep_alive: 30,
+///                           client_id: "sensor-1",
+///                           clean_session: true,
+///                           last_will: None,
+///                           username: None,
+///                           password: None }, now)?;
+/// let token = client.publish("temp", b"21.5", QoS::AtLeastOnce, false, now)?.unwrap();
+/// let bytes = client.take_outgoing();
+/// assert_eq!(Some(PacketType::Connect), decode_slice(&bytes)?.map(|p| p.get_type()));
+///
+/// client.handle_packet(&Packet::Connack(Connack::accepted(false)), now)?;
+/// client.handle_packet(&Packet::Puback(token.pid()), now)?;
+/// assert_eq!(Some(Event::ConnAckReceived(Connack::accepted(false))), client.poll_event());
+/// assert_eq!(Some(Event::PublishCompleted(token)), client.poll_event());
+/// assert_eq!(None, client.poll_event());
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`Event`]: enum.Event.html
+/// [`publish()`]: #method.publish
+/// [`take_outgoing()`]: #method.take_outgoing
+/// [`handle_packet()`]: #method.handle_packet
+/// [`poll_event()`]: #method.poll_event
+/// [`handle_timeout()`]: #method.handle_timeout
+/// [`next_wakeup()`]: #method.next_wakeup
+/// [`set_manual_acks()`]: #method.set_manual_acks
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    outgoing: BytesMut,
+    events: VecDeque<Event>,
+    pings: Option<PingScheduler>,
+    next_pid: Pid,
+    /// Publishes sent and received, until they're acknowledged.
+    notifier: Notifier,
+    /// Pending subscribe and unsubscribe requests.
+    requests: BTreeMap<Pid, Request>,
+    /// Identifiers of the subscribed topic filters.
+    subscriptions: SubscriptionIds<()>,
+    request_timeout: Option<Duration>,
+    manual_acks: bool,
+    /// Publishes received in manual acks mode that the application didn't acknowledge yet.
+    unacked: BTreeMap<Pid, Packet<'static>>,
+    /// Tokens of the publishes waiting for an acknowledgement.
+    tokens: BTreeMap<Pid, DeliveryToken>,
+    next_token: u64,
+    /// Maximum number of unacknowledged `QoS 1` and `QoS 2` publishes, if any.
+    receive_maximum: Option<u16>,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only acknowledge received publishes when [`ack()`] is called, instead of when they are
+    /// returned by [`poll_event()`].
+    ///
+    /// A server may stop sending publishes while too many of them aren't acknowledged.
+    ///
+    /// [`ack()`]: #method.ack
+    /// [`poll_event()`]: #method.poll_event
+    pub fn set_manual_acks(&mut self, manual_acks: bool) {
+        self.manual_acks = manual_acks;
+    }
+
+    /// Acknowledge the publish received with `pid`, in manual acks mode.
+    ///
+    /// Does nothing if no publish with this pid is waiting for an acknowledgement.
+    pub fn ack(&mut self, pid: Pid, now: Duration) -> Result<(), Error> {
+        match self.unacked.remove(&pid) {
+            Some(ack) => self.send(&ack, now),
+            None => Ok(()),
+        }
+    }
+
+    /// Report a [`Event::RequestTimeout`] for subscribe and unsubscribe requests that aren't
+    /// answered within `timeout`. Requests never time out by default.
+    ///
+    /// Only applies to the requests sent afterwards.
+    ///
+    /// [`Event::RequestTimeout`]: enum.Event.html#variant.RequestTimeout
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// Allow at most `receive_maximum` unacknowledged `QoS 1` and `QoS 2` publishes, usually the
+    /// server's Receive Maximum. Zero is treated as one.
+    ///
+    /// Publishing more fails with `LimitExceeded` until [`Event::QuotaReleased`] is returned.
+    /// Check [`can_publish()`] to stop producing messages instead of buffering them.
+    ///
+    /// [`Event::QuotaReleased`]: enum.Event.html#variant.QuotaReleased
+    /// [`can_publish()`]: #method.can_publish
+    pub fn set_receive_maximum(&mut self, receive_maximum: u16) {
+        self.receive_maximum = Some(receive_maximum.max(1));
+    }
+
+    /// Returns true if a `QoS 1` or `QoS 2` message can be published now.
+    pub fn can_publish(&self) -> bool {
+        match self.receive_maximum {
+            Some(max) => self.notifier.pending() < max.into(),
+            None => true,
+        }
+    }
+
+    /// Send `connect`, and start the keep alive timer.
+    ///
+    /// A `Pingresp` is expected within half the keep alive interval, after which
+    /// [`Event::PingTimeout`] is returned.
+    ///
+    /// [`Event::PingTimeout`]: enum.Event.html#variant.PingTimeout
+    pub fn connect(&mut self, connect: &Connect, now: Duration) -> Result<(), Error> {
+        let keep_alive = KeepAlive::new(connect.keep_alive);
+        let grace = keep_alive.interval().unwrap_or_default() / 2;
+        self.pings = Some(PingScheduler::new(keep_alive, grace, now));
+        self.send(&Packet::Connect(connect.clone()), now)
+    }
+
+    /// Publish `payload` to `topic_name`.
+    ///
+    /// Returns the token identifying the message in [`Event::PublishCompleted`], or `None` for
+    /// `QoS 0`. Fails with `LimitExceeded` if all 65535 pids are waiting for an acknowledgement.
+    ///
+    /// [`Event::PublishCompleted`]: enum.Event.html#variant.PublishCompleted
+    pub fn publish(
+        &mut self,
+        topic_name: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+        now: Duration,
+    ) -> Result<Option<DeliveryToken>, Error> {
+        if let (Some(max), false) = (self.receive_maximum, qos == QoS::AtMostOnce) {
+            if !self.can_publish() {
+                return Err(Error::LimitExceeded {
+                    field: "receive maximum",
+                    limit: max.into(),
+                    actual: self.notifier.pending() + 1,
+                });
+            }
+        }
+        let qospid = match qos {
+            QoS::AtMostOnce => QosPid::AtMostOnce,
+            QoS::AtLeastOnce => QosPid::AtLeastOnce(self.free_pid()?),
+            QoS::ExactlyOnce => QosPid::ExactlyOnce(self.free_pid()?),
+        };
+        let publish = Publish {
+            dup: false,
+            qospid,
+            retain,
+            topic_name,
+            payload,
+        };
+        self.send(&Packet::Publish(publish), now)?;
+        Ok(qospid.pid().map(|pid| {
+            let token = DeliveryToken::new(self.next_token, pid);
+            self.next_token += 1;
+            self.tokens.insert(pid, token);
+            token
+        }))
+    }
+
+    /// Subscribe to `topics`. Returns the pid identifying the request in
+    /// [`Event::SubAckResult`] and the subscription identifier assigned to each topic filter, in
+    /// order, or fails like [`publish()`] when no pid is free.
+    ///
+    /// Identifiers are never reused, and link the messages received later to their subscription
+    /// through [`subscription_ids()`]. A topic filter refused by the server loses its identifier.
+    ///
+    /// ```
+    /// # use mqttrs::{*, client::*};
+    /// # use core::time::Duration;
+    /// let now = Duration::ZERO;
+    /// let mut client = Client::new();
+    /// let topics = vec![SubscribeTopic::new("sensors/+/temp", QoS::AtLeastOnce)?,
+    ///                   SubscribeTopic::new("sensors/#", QoS::AtMostOnce)?];
+    /// let (_pid, ids) = client.subscribe(topics, now)?;
+    /// let matched: Vec<_> = client.subscription_ids("sensors/kitchen/temp").collect();
+    /// assert_eq!(ids, matched);
+    /// # Ok::<(), Error>(())
+    /// ```
+    ///
+    /// [`publish()`]: #method.publish
+    /// [`subscription_ids()`]: #method.subscription_ids
+    /// [`Event::SubAckResult`]: enum.Event.html#variant.SubAckResult
+    pub fn subscribe(
+        &mut self,
+        topics: Vec<SubscribeTopic>,
+        now: Duration,
+    ) -> Result<(Pid, Vec<u32>), Error> {
+        let pid = self.free_pid()?;
+        let filters = topics
+            .iter()
+            .map(|t| t.topic_filter.as_str().into())
+            .collect();
+        let ids = topics
+            .iter()
+            .map(|t| self.subscriptions.assign(t.topic_filter.clone(), ()))
+            .collect::<Result<_, _>>()?;
+        let subscribe = Subscribe::new(pid, topics.into_iter().collect());
+        self.send(&Packet::Subscribe(subscribe), now)?;
+        self.request(pid, Awaiting::Suback, filters, now);
+        Ok((pid, ids))
+    }
+
+    /// Unsubscribe from `filters`. Returns the pid identifying the request in
+    /// [`Event::UnsubAckResult`], or fails like [`publish()`] when no pid is free.
+    ///
+    /// [`publish()`]: #method.publish
+    /// [`Event::UnsubAckResult`]: enum.Event.html#variant.UnsubAckResult
+    pub fn unsubscribe(&mut self, filters: &[&str], now: Duration) -> Result<Pid, Error> {
+        let pid = self.free_pid()?;
+        let unsubscribe = Unsubscribe::new(pid, filters.iter().map(|f| (*f).into()).collect());
+        self.send(&Packet::Unsubscribe(unsubscribe), now)?;
+        let filters = filters.iter().map(|f| (*f).into()).collect();
+        self.request(pid, Awaiting::Unsuback, filters, now);
+        Ok(pid)
+    }
+
+    /// Identifiers of the subscriptions matching `topic_name`, in subscription order.
+    ///
+    /// MQTT 3.1.1 publishes carry no subscription identifier, so the topic name of an
+    /// [`Event::PublishReceived`] is matched against the subscribed topic filters instead.
+    ///
+    /// [`Event::PublishReceived`]: enum.Event.html#variant.PublishReceived
+    pub fn subscription_ids<'s>(&'s self, topic_name: &'s str) -> impl Iterator<Item = u32> + 's {
+        self.subscriptions.resolve(topic_name).map(|(id, _)| id)
+    }
+
+    /// Send a `Disconnect`, and stop the keep alive timer.
+    pub fn disconnect(&mut self, now: Duration) -> Result<(), Error> {
+        self.send(&Packet::Disconnect, now)?;
+        self.pings = None;
+        Ok(())
+    }
+
+    /// Handle a packet received from the server.
+    ///
+    /// Returns `UnexpectedPacketType` for packets that only clients send.
+    pub fn handle_packet(&mut self, packet: &Packet, now: Duration) -> Result<(), Error> {
+        if let Some(pings) = &mut self.pings {
+            pings.received(packet);
+        }
+        match packet {
+            Packet::Connack(connack) => self.events.push_back(Event::ConnAckReceived(*connack)),
+            Packet::Publish(publish) => {
+                let new = match self.notifier.received(packet) {
+                    Some(Notification::Message(publish)) => {
+                        self.events.push_back(Event::PublishReceived(publish));
+                        true
+                    }
+                    _ => false,
+                };
+                match publish.qospid.pid() {
+                    Some(pid) if self.manual_acks && new => {
+                        self.unacked.extend(acks_for(packet).map(|ack| (pid, ack)));
+                    }
+                    // The application will acknowledge the original.
+                    Some(pid) if self.unacked.contains_key(&pid) => {}
+                    _ => self.send_acks(packet, now)?,
+                }
+            }
+            Packet::Pubrel(_) => {
+                self.notifier.received(packet);
+                self.send_acks(packet, now)?;
+            }
+            Packet::Puback(_) | Packet::Pubrec(_) | Packet::Pubcomp(_) => {
+                let could_publish = self.can_publish();
+                match self.notifier.received(packet) {
+                    Some(Notification::Received(_)) => self.send_acks(packet, now)?,
+                    Some(Notification::Completed(pid)) => {
+                        if let Some(token) = self.tokens.remove(&pid) {
+                            self.events.push_back(Event::PublishCompleted(token));
+                        }
+                        if !could_publish {
+                            self.events.push_back(Event::QuotaReleased);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Packet::Suback(suback) => {
+                if let Some(filters) = self.acknowledge_request(suback.pid, Awaiting::Suback) {
+                    let codes = suback.return_codes.iter().copied();
+                    for (filter, code) in filters.iter().zip(codes.clone()) {
+                        if code == SubscribeReturnCodes::Failure {
+                            self.subscriptions.remove(filter);
+                        }
+                    }
+                    self.events.push_back(Event::SubAckResult {
+                        pid: suback.pid,
+                        results: filters.into_iter().zip(codes).collect(),
+                    });
+                }
+            }
+            Packet::Unsuback(pid) => {
+                if let Some(filters) = self.acknowledge_request(*pid, Awaiting::Unsuback) {
+                    for filter in &filters {
+                        self.subscriptions.remove(filter);
+                    }
+                    let success = |f| (f, UnsubscribeResult::Success);
+                    self.events.push_back(Event::UnsubAckResult {
+                        pid: *pid,
+                        results: filters.into_iter().map(success).collect(),
+                    });
+                }
+            }
+            Packet::Pingresp => {}
+            other => return Err(Error::UnexpectedPacketType(other.get_type())),
+        }
+        Ok(())
+    }
+
+    /// Send a `Pingreq`, report a [`Event::PingTimeout`] or [`Event::RequestTimeout`]s if needed
+    /// at `now`.
+    ///
+    /// [`Event::PingTimeout`]: enum.Event.html#variant.PingTimeout
+    /// [`Event::RequestTimeout`]: enum.Event.html#variant.RequestTimeout
+    pub fn handle_timeout(&mut self, now: Duration) -> Result<(), Error> {
+        let expired: Vec<Pid> = self
+            .requests
+            .iter()
+            .filter(|(_, r)| r.deadline.is_some_and(|d| d <= now))
+            .map(|(pid, _)| *pid)
+            .collect();
+        for pid in expired {
+            if let Some(request) = self.requests.remove(&pid) {
+                self.events.push_back(Event::RequestTimeout {
+                    pid,
+                    filters: request.filters,
+                });
+            }
+        }
+        match self.pings.as_ref().and_then(|p| p.poll(now)) {
+            Some(PingAction::SendPingreq) => self.send(&Packet::Pingreq, now),
+            Some(PingAction::PingrespMissing) => {
+                self.pings = None;
+                self.events.push_back(Event::PingTimeout);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Next time at which [`handle_timeout()`] needs to be called, if any.
+    ///
+    /// [`handle_timeout()`]: #method.handle_timeout
+    pub fn next_wakeup(&self) -> Option<Duration> {
+        let ping = self.pings.as_ref().and_then(|p| p.next_wakeup());
+        let request = self.requests.values().filter_map(|r| r.deadline).min();
+        match (ping, request) {
+            (Some(ping), Some(request)) => Some(ping.min(request)),
+            (ping, request) => ping.or(request),
+        }
+    }
+
+    /// Return the next event, if any.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Take the encoded packets to write to the connection.
+    pub fn take_outgoing(&mut self) -> BytesMut {
+        self.outgoing.split()
+    }
+
+    fn send(&mut self, packet: &Packet, now: Duration) -> Result<(), Error> {
+        encode_grow(packet, &mut self.outgoing)?;
+        self.notifier.sent(packet);
+        if let Some(pings) = &mut self.pings {
+            pings.sent(packet, now);
+        }
+        Ok(())
+    }
+
+    fn send_acks(&mut self, packet: &Packet, now: Duration) -> Result<(), Error> {
+        for ack in acks_for(packet) {
+            self.send(&ack, now)?;
+        }
+        Ok(())
+    }
+
+    fn request(&mut self, pid: Pid, awaiting: Awaiting, filters: Vec<String>, now: Duration) {
+        let deadline = self.request_timeout.map(|t| now + t);
+        let request = Request {
+            awaiting,
+            filters,
+            deadline,
+        };
+        self.requests.insert(pid, request);
+    }
+
+    /// Remove the request with `pid` if it was waiting for `ack`, returning its topic filters.
+    fn acknowledge_request(&mut self, pid: Pid, ack: Awaiting) -> Option<Vec<String>> {
+        if self.requests.get(&pid)?.awaiting != ack {
+            return None;
+        }
+        self.requests.remove(&pid).map(|r| r.filters)
+    }
+
+    /// Return the next pid that isn't waiting for an acknowledgement, or `LimitExceeded` if all
+    /// of them are.
+    fn free_pid(&mut self) -> Result<Pid, Error> {
+        for _ in 0..u16::MAX {
+            self.next_pid = self.next_pid.wrapping_next();
+            let pid = self.next_pid;
+            if !self.notifier.is_pending(pid) && !self.requests.contains_key(&pid) {
+                return Ok(pid);
+            }
+        }
+        Err(Error::LimitExceeded {
+            field: "pids",
+            limit: u16::MAX.into(),
+            actual: usize::from(u16::MAX) + 1,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use core::convert::TryFrom;
+
+    fn connect(client: &mut Client) {
+        let connect = Connect {
+            protocol: Protocol::MQTT311,
+            keep_alive: 10,
+            client_id: "c",
+            clean_session: true,
+            last_will: None,
+            username: None,
+            password: None,
+        };
+        client.connect(&connect, Duration::ZERO).unwrap();
+        client.take_outgoing();
+    }
+
+    fn sent(client: &mut Client) -> Vec<String> {
+        let bytes = client.take_outgoing();
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        while let Some((packet, len)) = decoder::decode_slice_with_len(&bytes[offset..]).unwrap() {
+            packets.push(format!("{:?}", packet));
+            offset += len;
+        }
+        packets
+    }
+
+    #[test]
+    fn qos2_exchanges() {
+        let now = Duration::ZERO;
+        let pid = |n| Pid::try_from(n).unwrap();
+        let mut client = Client::new();
+        connect(&mut client);
+
+        // Inbound: the duplicate isn't reported, both are acknowledged.
+        let publish = Publish {
+            dup: false,
+            qospid: QosPid::ExactlyOnce(pid(7)),
+            retain: false,
+            topic_name: "a",
+            payload: b"x",
+        };
+        client
+            .handle_packet(&Packet::Publish(publish.clone()), now)
+            .unwrap();
+        let dup = Publish {
+            dup: true,
+            ..publish.clone()
+        };
+        client.handle_packet(&Packet::Publish(dup), now).unwrap();
+        client.handle_packet(&Packet::Pubrel(pid(7)), now).unwrap();
+        assert_eq!(
+            Some(Event::PublishReceived(SharedPublish::new(&publish))),
+            client.poll_event()
+        );
+        assert_eq!(None, client.poll_event());
+        assert_eq!(
+            vec!["Pubrec(Pid(7))", "Pubrec(Pid(7))", "Pubcomp(Pid(7))"],
+            sent(&mut client)
+        );
+
+        // Outbound: completed on Pubcomp only.
+        let out = client
+            .publish("b", b"y", QoS::ExactlyOnce, false, now)
+            .unwrap()
+            .unwrap();
+        let out_pid = out.pid();
+        client.handle_packet(&Packet::Puback(out_pid), now).unwrap();
+        client.handle_packet(&Packet::Pubrec(out_pid), now).unwrap();
+        assert_eq!(None, client.poll_event());
+        client
+            .handle_packet(&Packet::Pubcomp(out_pid), now)
+            .unwrap();
+        assert_eq!(Some(Event::PublishCompleted(out)), client.poll_event());
+        assert_eq!(2, sent(&mut client).len());
+
+        assert_eq!(
+            Err(Error::UnexpectedPacketType(PacketType::Pingreq)),
+            client.handle_packet(&Packet::Pingreq, now)
+        );
+    }
+
+    #[test]
+    fn subscriptions_and_pings() {
+        let secs = Duration::from_secs;
+        let mut client = Client::new();
+        connect(&mut client);
+        let topics = vec![SubscribeTopic::new("a/#", QoS::AtLeastOnce).unwrap()];
+        let (sub, ids) = client.subscribe(topics, secs(1)).unwrap();
+        assert_eq!(vec![1], ids);
+        let unsub = client.unsubscribe(&["a/#"], secs(1)).unwrap();
+        assert_ne!(sub, unsub);
+        let suback = Suback::new(sub, vec![SubscribeReturnCodes::Failure]);
+        client
+            .handle_packet(&Packet::Suback(suback), secs(1))
+            .unwrap();
+        client
+            .handle_packet(&Packet::Unsuback(unsub), secs(1))
+            .unwrap();
+        assert_eq!(
+            Some(Event::SubAckResult {
+                pid: sub,
+                results: vec![("a/#".into(), SubscribeReturnCodes::Failure)]
+            }),
+            client.poll_event()
+        );
+        assert_eq!(
+            Some(Event::UnsubAckResult {
+                pid: unsub,
+                results: vec![("a/#".into(), UnsubscribeResult::Success)]
+            }),
+            client.poll_event()
+        );
+
+        assert_eq!(Some(secs(11)), client.next_wakeup());
+        client.take_outgoing();
+        client.handle_timeout(secs(11)).unwrap();
+        assert_eq!(vec!["Pingreq"], sent(&mut client));
+        assert_eq!(Some(secs(16)), client.next_wakeup());
+        client.handle_timeout(secs(16)).unwrap();
+        assert_eq!(Some(Event::PingTimeout), client.poll_event());
+        assert_eq!(None, client.next_wakeup());
+    }
+
+    #[test]
+    fn subscription_ids() {
+        let now = Duration::ZERO;
+        let mut client = Client::new();
+        connect(&mut client);
+        let topic = |f| SubscribeTopic::new(f, QoS::AtLeastOnce).unwrap();
+        let (sub, ids) = client
+            .subscribe(vec![topic("a/+"), topic("a/#"), topic("b")], now)
+            .unwrap();
+        assert_eq!(vec![1, 2, 3], ids);
+        assert_eq!(
+            vec![1, 2],
+            client.subscription_ids("a/x").collect::<Vec<_>>()
+        );
+
+        // Refused filters lose their id, resubscribing assigns a new one.
+        let codes = vec![
+            SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+            SubscribeReturnCodes::Failure,
+            SubscribeReturnCodes::Success(QoS::AtMostOnce),
+        ];
+        client
+            .handle_packet(&Packet::Suback(Suback::new(sub, codes)), now)
+            .unwrap();
+        assert_eq!(vec![1], client.subscription_ids("a/x").collect::<Vec<_>>());
+        let (_, ids) = client.subscribe(vec![topic("a/+")], now).unwrap();
+        assert_eq!(vec![4], ids);
+        assert_eq!(vec![4], client.subscription_ids("a/x").collect::<Vec<_>>());
+
+        // Unsubscribed filters lose their id once acknowledged.
+        let unsub = client.unsubscribe(&["b"], now).unwrap();
+        assert_eq!(1, client.subscription_ids("b").count());
+        client.handle_packet(&Packet::Unsuback(unsub), now).unwrap();
+        assert_eq!(0, client.subscription_ids("b").count());
+    }
+
+    #[test]
+    fn manual_acks() {
+        let now = Duration::ZERO;
+        let pid = |n| Pid::try_from(n).unwrap();
+        let mut client = Client::new();
+        connect(&mut client);
+        client.set_manual_acks(true);
+
+        let publish = |qospid| Publish {
+            dup: false,
+            qospid,
+            retain: false,
+            topic_name: "a",
+            payload: b"x",
+        };
+        let qos1 = Packet::Publish(publish(QosPid::AtLeastOnce(pid(1))));
+        let qos2 = Packet::Publish(publish(QosPid::ExactlyOnce(pid(2))));
+        client.handle_packet(&qos1, now).unwrap();
+        client.handle_packet(&qos2, now).unwrap();
+        client.handle_packet(&qos2, now).unwrap();
+        assert!(sent(&mut client).is_empty());
+
+        client.ack(pid(2), now).unwrap();
+        client.ack(pid(1), now).unwrap();
+        client.ack(pid(1), now).unwrap();
+        assert_eq!(vec!["Pubrec(Pid(2))", "Puback(Pid(1))"], sent(&mut client));
+
+        // Once acknowledged, duplicates are acknowledged automatically.
+        client.handle_packet(&qos2, now).unwrap();
+        client.handle_packet(&Packet::Pubrel(pid(2)), now).unwrap();
+        assert_eq!(vec!["Pubrec(Pid(2))", "Pubcomp(Pid(2))"], sent(&mut client));
+    }
+
+    #[test]
+    fn request_timeout() {
+        let secs = Duration::from_secs;
+        let mut client = Client::new();
+        connect(&mut client);
+        client.set_request_timeout(Some(secs(5)));
+        let unsub = client.unsubscribe(&["a", "b"], secs(1)).unwrap();
+        let later = client.unsubscribe(&["c"], secs(3)).unwrap();
+        assert_eq!(Some(secs(6)), client.next_wakeup());
+
+        client.handle_timeout(secs(6)).unwrap();
+        assert_eq!(
+            Some(Event::RequestTimeout {
+                pid: unsub,
+                filters: vec!["a".into(), "b".into()]
+            }),
+            client.poll_event()
+        );
+        assert_eq!(None, client.poll_event());
+        assert_eq!(Some(secs(8)), client.next_wakeup());
+
+        // The late answer is ignored.
+        client
+            .handle_packet(&Packet::Unsuback(unsub), secs(7))
+            .unwrap();
+        client
+            .handle_packet(&Packet::Unsuback(later), secs(7))
+            .unwrap();
+        assert_eq!(
+            Some(Event::UnsubAckResult {
+                pid: later,
+                results: vec![("c".into(), UnsubscribeResult::Success)]
+            }),
+            client.poll_event()
+        );
+        assert_eq!(None, client.poll_event());
+        // Keep alive 10s after the last request.
+        assert_eq!(Some(secs(13)), client.next_wakeup());
+    }
+
+    #[test]
+    fn receive_maximum() {
+        let now = Duration::ZERO;
+        let mut client = Client::new();
+        connect(&mut client);
+        client.set_receive_maximum(1);
+        assert!(client.can_publish());
+        let first = client
+            .publish("a", b"x", QoS::AtLeastOnce, false, now)
+            .unwrap()
+            .unwrap();
+        assert!(!client.can_publish());
+        assert_eq!(
+            Err(Error::LimitExceeded {
+                field: "receive maximum",
+                limit: 1,
+                actual: 2
+            }),
+            client.publish("a", b"y", QoS::ExactlyOnce, false, now)
+        );
+        assert_eq!(
+            Ok(None),
+            client.publish("a", b"z", QoS::AtMostOnce, false, now)
+        );
+
+        client
+            .handle_packet(&Packet::Puback(first.pid()), now)
+            .unwrap();
+        assert_eq!(Some(Event::PublishCompleted(first)), client.poll_event());
+        assert_eq!(Some(Event::QuotaReleased), client.poll_event());
+        assert!(client.can_publish());
+        assert_eq!(None, client.poll_event());
+    }
+
+    #[test]
+    fn pids_exhausted() {
+        let now = Duration::ZERO;
+        let mut client = Client::new();
+        connect(&mut client);
+        let first = client
+            .publish("a", b"x", QoS::AtLeastOnce, false, now)
+            .unwrap()
+            .unwrap();
+        // Half of the pids are taken by publishes, the other half by requests.
+        for _ in 0..u16::MAX / 2 {
+            client
+                .publish("a", b"x", QoS::AtLeastOnce, false, now)
+                .unwrap();
+            client.unsubscribe(&["a"], now).unwrap();
+        }
+        client.take_outgoing();
+
+        let exhausted = Err(Error::LimitExceeded {
+            field: "pids",
+            limit: 65535,
+            actual: 65536,
+        });
+        assert_eq!(
+            exhausted,
+            client.publish("a", b"x", QoS::AtLeastOnce, false, now)
+        );
+        let topics = vec![SubscribeTopic::new("a", QoS::AtMostOnce).unwrap()];
+        assert_eq!(
+            exhausted.map(|_| ()),
+            client.subscribe(topics, now).map(|_| ())
+        );
+        assert_eq!(
+            Ok(None),
+            client.publish("a", b"x", QoS::AtMostOnce, false, now)
+        );
+
+        // Freed pids are reused.
+        client
+            .handle_packet(&Packet::Puback(first.pid()), now)
+            .unwrap();
+        assert_eq!(Ok(first.pid()), client.unsubscribe(&["a"], now));
+        assert!(client.unsubscribe(&["a"], now).is_err());
+    }
+}
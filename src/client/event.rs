@@ -0,0 +1,61 @@
+use crate::{Connack, Pid, SharedPublish, SubscribeReturnCodes, UnsubscribeResult};
+use std::{string::String, vec::Vec};
+
+/// Something that happened on a [`Client`] connection, returned by [`Client::poll_event()`].
+///
+/// [`Client`]: struct.Client.html
+/// [`Client::poll_event()`]: struct.Client.html#method.poll_event
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The server answered the `Connect`. The connection is usable if the code is `Accepted`.
+    ConnAckReceived(Connack),
+    /// A message was published to a subscribed topic.
+    ///
+    /// `QoS 2` messages are only returned once, even if the server sends them again.
+    PublishReceived(SharedPublish),
+    /// The server acknowledged a `QoS 1` publish or completed a `QoS 2` one.
+    PublishCompleted(DeliveryToken),
+    /// A publish completed while the receive maximum was reached: `QoS 1` and `QoS 2` messages can
+    /// be published again.
+    QuotaReleased,
+    /// The server answered a `Subscribe`, with the return code of each topic filter, in order.
+    SubAckResult {
+        pid: Pid,
+        results: Vec<(String, SubscribeReturnCodes)>,
+    },
+    /// The server answered an `Unsubscribe`, with the result of each topic filter, in order.
+    UnsubAckResult {
+        pid: Pid,
+        results: Vec<(String, UnsubscribeResult)>,
+    },
+    /// The server didn't answer a `Subscribe` or `Unsubscribe` within the request timeout. A late
+    /// answer is ignored.
+    RequestTimeout { pid: Pid, filters: Vec<String> },
+    /// The server didn't answer a `Pingreq` in time: the connection should be closed.
+    PingTimeout,
+}
+
+/// Identifies a `QoS 1` or `QoS 2` message published with [`Client::publish()`], until its
+/// [`Event::PublishCompleted`].
+///
+/// Unlike pids, tokens are never reused by a `Client`, so runtimes can use them as keys to
+/// resolve futures or callbacks.
+///
+/// [`Client::publish()`]: struct.Client.html#method.publish
+/// [`Event::PublishCompleted`]: enum.Event.html#variant.PublishCompleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeliveryToken {
+    id: u64,
+    pid: Pid,
+}
+
+impl DeliveryToken {
+    pub(crate) fn new(id: u64, pid: Pid) -> Self {
+        DeliveryToken { id, pid }
+    }
+
+    /// Pid the message was sent with.
+    pub fn pid(self) -> Pid {
+        self.pid
+    }
+}
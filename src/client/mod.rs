@@ -0,0 +1,19 @@
+//! Sans-io MQTT client, enabled by the `client` feature.
+//!
+//! [`Client`] handles the protocol bookkeeping of a client connection: packet identifiers,
+//! acknowledgements and keep alive. Applications feed it the packets they receive and consume
+//! [`Event`]s, leaving networking to the runtime of their choice.
+//!
+//! [`OutgoingQueue`] schedules the messages to publish over constrained links.
+//!
+//! [`Client`]: struct.Client.html
+//! [`Event`]: enum.Event.html
+//! [`OutgoingQueue`]: struct.OutgoingQueue.html
+
+mod engine;
+mod event;
+mod queue;
+
+pub use engine::Client;
+pub use event::{DeliveryToken, Event};
+pub use queue::{OutgoingQueue, QueueLimits, QueuePriority};
@@ -0,0 +1,224 @@
+use crate::{OverflowPolicy, QoS};
+use std::collections::VecDeque;
+
+/// Which messages an [`OutgoingQueue`] sends first.
+///
+/// [`OutgoingQueue`]: struct.OutgoingQueue.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePriority {
+    /// Send messages in the order they were pushed.
+    Fifo,
+    /// Send `QoS 1` and `QoS 2` messages first, while the window allows it.
+    Reliable,
+    /// Send `QoS 0` messages first, typically fresh sensor readings.
+    Fresh,
+}
+
+/// Limits of an [`OutgoingQueue`].
+///
+/// [`OutgoingQueue`]: struct.OutgoingQueue.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimits {
+    /// Maximum number of unacknowledged `QoS 1` and `QoS 2` messages, usually the server's receive
+    /// maximum.
+    pub window: usize,
+    /// Maximum number of queued `QoS 0` messages.
+    pub max_qos0: usize,
+    /// What to do with a `QoS 0` message when `max_qos0` are queued.
+    pub overflow: OverflowPolicy,
+    pub priority: QueuePriority,
+}
+
+/// Queue of outgoing messages, for clients on constrained links.
+///
+/// `QoS 0` messages may be dropped when too many are waiting, `QoS 1` and `QoS 2` ones are never
+/// dropped but at most `window` of them are sent without being acknowledged. [`pop()`] returns
+/// the next message to send according to the [`QueuePriority`], and [`acknowledged()`] must be
+/// called on each `Puback` or `Pubcomp` to open the window again.
+///
+/// ```
+/// # use mqttrs::{*, client::*};
+/// let mut queue = OutgoingQueue::new(QueueLimits { window: 1,
+///                                                  max_qos0: 1,
+///                                                  overflow: OverflowPolicy::DropOldest,
+///                                                  priority: QueuePriority::Reliable });
+/// assert_eq!(None, queue.push("temp 21.5", QoS::AtMostOnce));
+/// assert_eq!(Some("temp 21.5"), queue.push("temp 21.6", QoS::AtMostOnce));
+/// assert_eq!(None, queue.push("alarm", QoS::AtLeastOnce));
+/// assert_eq!(None, queue.push("alarm off", QoS::AtLeastOnce));
+/// assert_eq!(Some("alarm"), queue.pop());
+/// // The window is full.
+/// assert_eq!(Some("temp 21.6"), queue.pop());
+/// assert_eq!(None, queue.pop());
+/// queue.acknowledged();
+/// assert_eq!(Some("alarm off"), queue.pop());
+/// ```
+///
+/// [`pop()`]: #method.pop
+/// [`acknowledged()`]: #method.acknowledged
+/// [`QueuePriority`]: enum.QueuePriority.html
+#[derive(Debug, Clone)]
+pub struct OutgoingQueue<T> {
+    limits: QueueLimits,
+    inflight: usize,
+    /// Number of messages pushed so far, to send them in order with `QueuePriority::Fifo`.
+    pushed: u64,
+    qos0: VecDeque<(u64, T)>,
+    reliable: VecDeque<(u64, T)>,
+}
+
+impl<T> OutgoingQueue<T> {
+    pub fn new(limits: QueueLimits) -> Self {
+        OutgoingQueue {
+            limits,
+            inflight: 0,
+            pushed: 0,
+            qos0: VecDeque::new(),
+            reliable: VecDeque::new(),
+        }
+    }
+
+    /// Queue `message`, to send at `qos`. Returns the `QoS 0` message dropped to respect
+    /// `max_qos0`, if any.
+    pub fn push(&mut self, message: T, qos: QoS) -> Option<T> {
+        let entry = (self.pushed, message);
+        self.pushed += 1;
+        if qos != QoS::AtMostOnce {
+            self.reliable.push_back(entry);
+            return None;
+        }
+        if self.qos0.len() < self.limits.max_qos0 {
+            self.qos0.push_back(entry);
+            return None;
+        }
+        match self.limits.overflow {
+            OverflowPolicy::DropOldest if self.limits.max_qos0 > 0 => {
+                self.qos0.push_back(entry);
+                self.qos0.pop_front().map(|(_, m)| m)
+            }
+            _ => Some(entry.1),
+        }
+    }
+
+    /// Return the next message to send, if any.
+    ///
+    /// `QoS 1` and `QoS 2` messages are only returned while fewer than `window` are unacknowledged.
+    pub fn pop(&mut self) -> Option<T> {
+        let reliable = match self.reliable.front() {
+            Some(&(seq, _)) if self.inflight < self.limits.window => Some(seq),
+            _ => None,
+        };
+        let qos0 = self.qos0.front().map(|&(seq, _)| seq);
+        let take_reliable = match (reliable, qos0) {
+            (None, None) => return None,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some(reliable), Some(qos0)) => match self.limits.priority {
+                QueuePriority::Fifo => reliable < qos0,
+                QueuePriority::Reliable => true,
+                QueuePriority::Fresh => false,
+            },
+        };
+        if take_reliable {
+            self.inflight += 1;
+            self.reliable.pop_front().map(|(_, m)| m)
+        } else {
+            self.qos0.pop_front().map(|(_, m)| m)
+        }
+    }
+
+    /// A `QoS 1` or `QoS 2` message was acknowledged, making room in the window.
+    pub fn acknowledged(&mut self) {
+        self.inflight = self.inflight.saturating_sub(1);
+    }
+
+    /// Number of unacknowledged messages.
+    pub fn inflight(&self) -> usize {
+        self.inflight
+    }
+
+    /// Number of queued messages.
+    pub fn len(&self) -> usize {
+        self.qos0.len() + self.reliable.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.qos0.is_empty() && self.reliable.is_empty()
+    }
+
+    /// Forget the in-flight messages and return the queued `QoS 1` and `QoS 2` ones, when the
+    /// connection is lost. `QoS 0` messages are dropped.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.inflight = 0;
+        self.qos0.clear();
+        self.reliable.drain(..).map(|(_, m)| m)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    fn queue(priority: QueuePriority, overflow: OverflowPolicy) -> OutgoingQueue<u8> {
+        let mut queue = OutgoingQueue::new(QueueLimits {
+            window: 2,
+            max_qos0: 2,
+            overflow,
+            priority,
+        });
+        for (message, qos) in [
+            (1, QoS::AtMostOnce),
+            (2, QoS::AtLeastOnce),
+            (3, QoS::AtMostOnce),
+            (4, QoS::ExactlyOnce),
+            (5, QoS::AtLeastOnce),
+        ] {
+            assert_eq!(None, queue.push(message, qos));
+        }
+        queue
+    }
+
+    fn popped(queue: &mut OutgoingQueue<u8>) -> Vec<u8> {
+        core::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn priorities() {
+        let mut fifo = queue(QueuePriority::Fifo, OverflowPolicy::DropOldest);
+        assert_eq!(vec![1, 2, 3, 4], popped(&mut fifo));
+        assert_eq!((2, 1), (fifo.inflight(), fifo.len()));
+        fifo.acknowledged();
+        assert_eq!(vec![5], popped(&mut fifo));
+
+        let mut reliable = queue(QueuePriority::Reliable, OverflowPolicy::DropOldest);
+        assert_eq!(vec![2, 4, 1, 3], popped(&mut reliable));
+        let mut fresh = queue(QueuePriority::Fresh, OverflowPolicy::DropOldest);
+        assert_eq!(vec![1, 3, 2, 4], popped(&mut fresh));
+        assert_eq!(vec![5], fresh.drain().collect::<Vec<_>>());
+        assert!(fresh.is_empty());
+        assert_eq!(0, fresh.inflight());
+    }
+
+    #[test]
+    fn overflow() {
+        let mut oldest = queue(QueuePriority::Fifo, OverflowPolicy::DropOldest);
+        assert_eq!(Some(1), oldest.push(6, QoS::AtMostOnce));
+        assert_eq!(None, oldest.push(7, QoS::AtLeastOnce));
+        assert_eq!(vec![2, 3, 4, 6], popped(&mut oldest));
+
+        let mut newest = queue(QueuePriority::Fifo, OverflowPolicy::DropNewest);
+        assert_eq!(Some(6), newest.push(6, QoS::AtMostOnce));
+        assert_eq!(vec![1, 2, 3, 4], popped(&mut newest));
+
+        let mut unqueued = OutgoingQueue::new(QueueLimits {
+            window: 0,
+            max_qos0: 0,
+            overflow: OverflowPolicy::DropOldest,
+            priority: QueuePriority::Fifo,
+        });
+        assert_eq!(Some(1), unqueued.push(1, QoS::AtMostOnce));
+        assert_eq!(None, unqueued.push(2, QoS::AtLeastOnce));
+        assert_eq!(None, unqueued.pop());
+    }
+}
@@ -0,0 +1,359 @@
+//! Check decoded packets against numbered [MQTT 3.1.1] conformance requirements.
+//!
+//! The decoder already rejects packets that can't be represented (bad flags, invalid QoS,
+//! unknown protocol...). The rules checked here are the ones that a syntactically valid packet
+//! can still violate, such as a wildcard in a `Publish` topic or a `Suback` with the wrong number
+//! of return codes. This is meant to be used as a compliance gate in client and broker test
+//! suites:
+//!
+//! ```
+//! # use mqttrs::{*, conformance::*};
+//! let publish = Packet::Publish(Publish { dup: true,
+//!                                         qospid: QosPid::AtMostOnce,
+//!                                         retain: false,
+//!                                         topic_name: "a/#",
+//!                                         payload: b"" });
+//! let ids: Vec<_> = check_packet(&publish).iter().map(|r| r.id).collect();
+//! assert_eq!(ids, ["MQTT-3.3.1-2", "MQTT-3.3.2-2"]);
+//! ```
+//!
+//! [MQTT 3.1.1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html
+
+use crate::*;
+use std::{collections::HashMap, vec::Vec};
+
+/// A normative requirement of the MQTT spec.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Rule {
+    /// Requirement id as written in the spec, like `"MQTT-3.8.3-3"`.
+    pub id: &'static str,
+    /// Summary of the requirement.
+    pub description: &'static str,
+}
+
+macro_rules! rules {
+    ($($name:ident: $id:literal, $description:literal;)+) => {
+        $(
+            pub static $name: Rule = Rule { id: $id, description: $description };
+        )+
+        /// All the rules that this module checks.
+        pub static RULES: &[&Rule] = &[$(&$name),+];
+    };
+}
+
+rules! {
+    FIRST_PACKET_CONNECT: "MQTT-3.1.0-1",
+        "The first packet sent from the client to the server must be a CONNECT.";
+    SINGLE_CONNECT: "MQTT-3.1.0-2",
+        "A client must not send a second CONNECT on the same connection.";
+    PASSWORD_WITHOUT_USERNAME: "MQTT-3.1.2-22",
+        "If the username flag is 0, the password flag must be 0.";
+    EMPTY_CLIENT_ID_CLEAN: "MQTT-3.1.3-7",
+        "A zero-byte client id requires clean session to be set.";
+    REFUSED_SESSION_PRESENT: "MQTT-3.2.2-4",
+        "A CONNACK with a non-zero return code must have session present set to 0.";
+    QOS0_DUP: "MQTT-3.3.1-2",
+        "The DUP flag must be 0 for QoS 0 messages.";
+    PUBLISH_WILDCARD: "MQTT-3.3.2-2",
+        "The topic name in a PUBLISH must not contain wildcard characters.";
+    SUBSCRIBE_EMPTY: "MQTT-3.8.3-3",
+        "A SUBSCRIBE must contain at least one topic filter.";
+    SUBSCRIBE_RESERVED: "MQTT-3.8.3-4",
+        "The reserved bits of each subscription options byte must be 0 (no MQTT 5 options).";
+    SUBACK_COUNT: "MQTT-3.8.4-5",
+        "A SUBACK must contain one return code per topic filter of the SUBSCRIBE.";
+    UNSUBSCRIBE_EMPTY: "MQTT-3.10.3-2",
+        "An UNSUBSCRIBE must contain at least one topic filter.";
+    MULTI_LEVEL_WILDCARD: "MQTT-4.7.1-2",
+        "The multi-level wildcard must be the last character of the filter and occupy a whole level.";
+    SINGLE_LEVEL_WILDCARD: "MQTT-4.7.1-3",
+        "The single-level wildcard must occupy a whole level of the filter.";
+    TOPIC_EMPTY: "MQTT-4.7.3-1",
+        "Topic names and topic filters must be at least one character long.";
+    TOPIC_NULL: "MQTT-4.7.3-2",
+        "Topic names and topic filters must not contain the null character.";
+}
+
+fn check_topic(topic: &str, out: &mut Vec<&'static Rule>) {
+    if topic.is_empty() {
+        out.push(&TOPIC_EMPTY);
+    }
+    if topic.contains('\0') {
+        out.push(&TOPIC_NULL);
+    }
+}
+
+fn check_filter(filter: &str, out: &mut Vec<&'static Rule>) {
+    check_topic(filter, out);
+    let levels: Vec<_> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        if level.contains('#') && (*level != "#" || i != levels.len() - 1) {
+            out.push(&MULTI_LEVEL_WILDCARD);
+        }
+        if level.contains('+') && *level != "+" {
+            out.push(&SINGLE_LEVEL_WILDCARD);
+        }
+    }
+}
+
+/// Return the rules violated by a single packet, in the order of the spec.
+///
+/// The packet is checked as sent with MQTT 3.1.1, so MQTT 5 subscription options are flagged; use
+/// [`Packet::validate()`] for other protocol versions. Rules that depend on other packets of the
+/// connection are checked by [`SequenceChecker`].
+///
+/// [`Packet::validate()`]: ../enum.Packet.html#method.validate
+/// [`SequenceChecker`]: struct.SequenceChecker.html
+pub fn check_packet(packet: &Packet) -> Vec<&'static Rule> {
+    check(packet, false)
+}
+
+/// Check `packet`, flagging MQTT 5 subscription options unless `v5_options` is set.
+fn check(packet: &Packet, v5_options: bool) -> Vec<&'static Rule> {
+    let mut out = Vec::new();
+    match packet {
+        Packet::Connect(c) => {
+            if c.password.is_some() && c.username.is_none() {
+                out.push(&PASSWORD_WITHOUT_USERNAME);
+            }
+            if c.client_id.is_empty() && !c.clean_session {
+                out.push(&EMPTY_CLIENT_ID_CLEAN);
+            }
+            if let Some(will) = &c.last_will {
+                check_topic(will.topic, &mut out);
+                if will.topic.contains(&['+', '#'][..]) {
+                    out.push(&PUBLISH_WILDCARD);
+                }
+            }
+        }
+        Packet::Connack(c) if c.code != ConnectReturnCode::Accepted && c.session_present => {
+            out.push(&REFUSED_SESSION_PRESENT);
+        }
+        Packet::Publish(p) => {
+            if p.dup && p.qospid == QosPid::AtMostOnce {
+                out.push(&QOS0_DUP);
+            }
+            if p.topic_name.contains(&['+', '#'][..]) {
+                out.push(&PUBLISH_WILDCARD);
+            }
+            check_topic(p.topic_name, &mut out);
+        }
+        Packet::Subscribe(s) => {
+            if s.topics.is_empty() {
+                out.push(&SUBSCRIBE_EMPTY);
+            }
+            if !v5_options && s.topics.iter().any(|t| !t.options.is_v3_compatible()) {
+                out.push(&SUBSCRIBE_RESERVED);
+            }
+            for topic in &s.topics {
+                check_filter(&topic.topic_filter, &mut out);
+            }
+        }
+        Packet::Unsubscribe(u) => {
+            if u.topics.is_empty() {
+                out.push(&UNSUBSCRIBE_EMPTY);
+            }
+            for topic in &u.topics {
+                check_filter(topic, &mut out);
+            }
+        }
+        _ => (),
+    }
+    out
+}
+
+impl Packet<'_> {
+    /// Return the rules violated by this packet when sent with `protocol`, in the order of the
+    /// spec.
+    ///
+    /// This checks the same rules as [`check_packet()`], which assumes MQTT 3.1.1, but only flags
+    /// MQTT 5 subscription options for older protocol versions. Use it on packets built from
+    /// struct literals, which skip the checks of the constructors:
+    ///
+    /// ```
+    /// # use mqttrs::{*, conformance::*};
+    /// let options = SubscriptionOptions { no_local: true, ..QoS::AtMostOnce.into() };
+    /// let topic = SubscribeTopic { topic_filter: TopicFilter::new("a/#")?, options };
+    /// let subscribe = Packet::Subscribe(Subscribe::new(Pid::new(), vec![topic]));
+    /// assert_eq!(subscribe.validate(Protocol::MQTT311), [&SUBSCRIBE_RESERVED]);
+    /// assert!(subscribe.validate(Protocol::custom("MQTT", 5)?).is_empty());
+    /// # Ok::<(), Error>(())
+    /// ```
+    ///
+    /// [`check_packet()`]: conformance/fn.check_packet.html
+    pub fn validate(&self, protocol: Protocol) -> Vec<&'static Rule> {
+        check(self, protocol.level() >= 5)
+    }
+}
+
+/// Checks a sequence of packets exchanged on one connection.
+///
+/// Feed it every packet in the order they are sent, with `from_client` telling the direction.
+/// Each call returns the rules violated by that packet, including those of [`check_packet()`].
+///
+/// ```
+/// # use mqttrs::{*, conformance::*};
+/// let mut checker = SequenceChecker::new();
+/// assert_eq!(checker.check(&Packet::Pingreq, true), [&FIRST_PACKET_CONNECT]);
+/// ```
+///
+/// [`check_packet()`]: fn.check_packet.html
+#[derive(Debug, Clone, Default)]
+pub struct SequenceChecker {
+    connected: bool,
+    /// Number of topic filters of each unacknowledged SUBSCRIBE.
+    subscribes: HashMap<Pid, usize>,
+}
+
+impl SequenceChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check the next packet of the connection.
+    pub fn check(&mut self, packet: &Packet, from_client: bool) -> Vec<&'static Rule> {
+        let mut out = Vec::new();
+        if from_client {
+            match (packet, self.connected) {
+                (Packet::Connect(_), true) => out.push(&SINGLE_CONNECT),
+                (Packet::Connect(_), false) => self.connected = true,
+                (_, false) => out.push(&FIRST_PACKET_CONNECT),
+                _ => (),
+            }
+        }
+        out.extend(check_packet(packet));
+        match packet {
+            Packet::Subscribe(s) if from_client => {
+                self.subscribes.insert(s.pid, s.topics.len());
+            }
+            Packet::Suback(s) if !from_client => {
+                if let Some(count) = self.subscribes.remove(&s.pid) {
+                    if count != s.return_codes.len() {
+                        out.push(&SUBACK_COUNT);
+                    }
+                }
+            }
+            _ => (),
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec;
+
+    fn ids(rules: Vec<&Rule>) -> Vec<&str> {
+        rules.iter().map(|r| r.id).collect()
+    }
+
+    #[test]
+    fn filters() {
+        // `Subscribe` topic filters are validated on construction, `Unsubscribe` ones aren't.
+        let unsub = |filters: &[&str]| {
+            let topics = filters.iter().map(|f| (*f).into()).collect();
+            ids(check_packet(&Unsubscribe::new(Pid::new(), topics).into()))
+        };
+        assert!(unsub(&["#", "+", "a/+/b", "a/#", "+/+", "/"]).is_empty());
+        assert_eq!(unsub(&[]), ["MQTT-3.10.3-2"]);
+        assert_eq!(unsub(&["a#"]), ["MQTT-4.7.1-2"]);
+        assert_eq!(unsub(&["#/a"]), ["MQTT-4.7.1-2"]);
+        assert_eq!(unsub(&["a/b+"]), ["MQTT-4.7.1-3"]);
+        assert_eq!(unsub(&["", "a\0"]), ["MQTT-4.7.3-1", "MQTT-4.7.3-2"]);
+        let sub = Subscribe::new(Pid::new(), vec![]).into();
+        assert_eq!(ids(check_packet(&sub)), ["MQTT-3.8.3-3"]);
+    }
+
+    #[test]
+    fn connect() {
+        let connect = Connect {
+            protocol: Protocol::MQTT311,
+            keep_alive: 0,
+            client_id: "",
+            clean_session: false,
+            last_will: None,
+            username: None,
+            password: Some(b"secret"),
+        };
+        assert_eq!(
+            ids(check_packet(&connect.into())),
+            ["MQTT-3.1.2-22", "MQTT-3.1.3-7"]
+        );
+        let connack = Connack {
+            session_present: true,
+            code: ConnectReturnCode::ServerUnavailable,
+        };
+        assert_eq!(ids(check_packet(&connack.into())), ["MQTT-3.2.2-4"]);
+    }
+
+    #[test]
+    fn validate() {
+        let v5 = Protocol::custom("MQTT", 5).unwrap();
+        let publish = Packet::Publish(Publish {
+            dup: true,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "",
+            payload: b"",
+        });
+        for protocol in [Protocol::MQIsdp, Protocol::MQTT311, v5] {
+            assert_eq!(
+                ids(publish.validate(protocol)),
+                ["MQTT-3.3.1-2", "MQTT-4.7.3-1"]
+            );
+        }
+
+        let options = SubscriptionOptions {
+            retain_handling: RetainHandling::DoNotSend,
+            ..QoS::AtLeastOnce.into()
+        };
+        let topics = vec![
+            SubscribeTopic::new("a", QoS::AtMostOnce).unwrap(),
+            SubscribeTopic {
+                topic_filter: TopicFilter::new("a/+").unwrap(),
+                options,
+            },
+        ];
+        let subscribe = Subscribe::new(Pid::new(), topics).into();
+        assert_eq!(ids(check_packet(&subscribe)), ["MQTT-3.8.3-4"]);
+        assert_eq!(ids(subscribe.validate(Protocol::MQTT311)), ["MQTT-3.8.3-4"]);
+        assert!(subscribe.validate(v5).is_empty());
+    }
+
+    #[test]
+    fn sequence() {
+        let connect = Packet::Connect(Connect {
+            protocol: Protocol::MQTT311,
+            keep_alive: 0,
+            client_id: "c",
+            clean_session: true,
+            last_will: None,
+            username: None,
+            password: None,
+        });
+        let pid = Pid::new();
+        let subscribe = Subscribe::new(
+            pid,
+            vec![SubscribeTopic::new("a", QoS::AtMostOnce).unwrap()],
+        )
+        .into();
+        let suback = Suback::new(pid, vec![]).into();
+
+        let mut checker = SequenceChecker::new();
+        assert_eq!(ids(checker.check(&Packet::Pingreq, true)), ["MQTT-3.1.0-1"]);
+        assert!(checker.check(&connect, true).is_empty());
+        assert_eq!(ids(checker.check(&connect, true)), ["MQTT-3.1.0-2"]);
+        assert!(checker.check(&subscribe, true).is_empty());
+        assert_eq!(ids(checker.check(&suback, false)), ["MQTT-3.8.4-5"]);
+        assert!(checker.check(&Packet::Pingresp, false).is_empty());
+    }
+
+    #[test]
+    fn rules_are_unique() {
+        let mut ids: Vec<_> = RULES.iter().map(|r| r.id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(RULES.len(), ids.len());
+    }
+}
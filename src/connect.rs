@@ -1,4 +1,7 @@
 use crate::{decoder::*, encoder::*, *};
+use core::fmt;
+#[cfg(feature = "derive")]
+use serde::{Deserialize, Serialize};
 
 /// Protocol version.
 ///
@@ -6,6 +9,7 @@ use crate::{decoder::*, encoder::*, *};
 ///
 /// [`Connect`]: struct.Connect.html
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "derive", derive(Serialize))]
 pub enum Protocol {
     /// [MQTT 3.1.1] is the most commonly implemented version. [MQTT 5] isn't yet supported my by
     /// `mqttrs`.
@@ -17,8 +21,58 @@ pub enum Protocol {
     /// 3.1.1, but you should watch out for implementation discrepancies. `Mqttrs` handles it like
     /// standard MQTT 3.1.1.
     MQIsdp,
+    /// Any other protocol name and level, built with [`Protocol::custom()`].
+    ///
+    /// This allows encoding proprietary or future protocol identifiers. The packet is otherwise
+    /// encoded like MQTT 3.1.1. Decoding a `Connect` packet with an unknown protocol still fails
+    /// with [`Error::InvalidProtocol`]. With the `derive` feature, this variant can be serialized
+    /// but not deserialized.
+    ///
+    /// [`Protocol::custom()`]: #method.custom
+    /// [`Error::InvalidProtocol`]: enum.Error.html#variant.InvalidProtocol
+    Custom { name: &'static str, level: u8 },
 }
 impl Protocol {
+    /// Build a protocol from its name and level.
+    ///
+    /// Known protocols are returned as their own variant, so that they compare equal to decoded
+    /// values. Fails with [`Error::InvalidLength`] if `name` is too long to be encoded.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// assert_eq!(Ok(Protocol::MQTT311), Protocol::custom("MQTT", 4));
+    /// let proto = Protocol::custom("MQTT-ext", 0x84).unwrap();
+    /// assert_eq!(("MQTT-ext", 0x84), (proto.name(), proto.level()));
+    /// ```
+    ///
+    /// [`Error::InvalidLength`]: enum.Error.html#variant.InvalidLength
+    pub fn custom(name: &'static str, level: u8) -> Result<Protocol, Error> {
+        match Protocol::new(name, level) {
+            Ok(p) => Ok(p),
+            Err(_) if name.len() > u16::MAX as usize => Err(Error::InvalidLength),
+            Err(_) => Ok(Protocol::Custom { name, level }),
+        }
+    }
+    /// Protocol name, as sent in the [`Connect`] packet.
+    ///
+    /// [`Connect`]: struct.Connect.html
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::MQTT311 => "MQTT",
+            Protocol::MQIsdp => "MQIsdp",
+            Protocol::Custom { name, .. } => name,
+        }
+    }
+    /// Protocol level, as sent in the [`Connect`] packet.
+    ///
+    /// [`Connect`]: struct.Connect.html
+    pub fn level(self) -> u8 {
+        match self {
+            Protocol::MQTT311 => 4,
+            Protocol::MQIsdp => 3,
+            Protocol::Custom { level, .. } => level,
+        }
+    }
     pub(crate) fn new(name: &str, level: u8) -> Result<Protocol, Error> {
         match (name, level) {
             ("MQIsdp", 3) => Ok(Protocol::MQIsdp),
@@ -26,32 +80,38 @@ impl Protocol {
             _ => Err(Error::InvalidProtocol(name.into(), level)),
         }
     }
-    pub(crate) fn from_buffer<'a>(buf: &'a [u8], offset: &mut usize) -> Result<Self, Error> {
-        let protocol_name = read_str(buf, offset)?;
-        let protocol_level = buf[*offset];
-        *offset += 1;
+    pub(crate) fn from_buffer(buf: &[u8], offset: &mut usize) -> Result<Self, Error> {
+        let protocol_name = read_str(buf, offset, "protocol name")?;
+        let protocol_level = read_u8(buf, offset)?;
 
         Protocol::new(protocol_name, protocol_level)
     }
-    pub(crate) fn to_buffer(&self, buf: &mut [u8], offset: &mut usize) -> Result<usize, Error> {
-        match self {
-            Protocol::MQTT311 => {
-                let slice = &[0u8, 4, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8, 4];
-                for &byte in slice {
-                    write_u8(buf, offset, byte)?;
-                }
-                Ok(slice.len())
-            }
-            Protocol::MQIsdp => {
-                let slice = &[
-                    0u8, 4, 'M' as u8, 'Q' as u8, 'i' as u8, 's' as u8, 'd' as u8, 'p' as u8, 4,
-                ];
-                for &byte in slice {
-                    write_u8(buf, offset, byte)?;
-                }
-                Ok(slice.len())
-            }
+    /// Encoded length of the protocol name and level.
+    pub(crate) fn len(self) -> usize {
+        2 + self.name().len() + 1
+    }
+    pub(crate) fn to_buffer(self, buf: &mut [u8], offset: &mut usize) -> Result<usize, Error> {
+        write_string(buf, offset, self.name())?;
+        write_u8(buf, offset, self.level())?;
+        Ok(self.len())
+    }
+}
+
+// Custom protocol names can't be deserialized into a `&'static str`, so only the known protocols
+// are.
+#[cfg(feature = "derive")]
+impl<'de> Deserialize<'de> for Protocol {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "Protocol")]
+        enum Known {
+            MQTT311,
+            MQIsdp,
         }
+        Ok(match Known::deserialize(deserializer)? {
+            Known::MQTT311 => Protocol::MQTT311,
+            Known::MQIsdp => Protocol::MQIsdp,
+        })
     }
 }
 
@@ -59,23 +119,74 @@ impl Protocol {
 ///
 /// Sent by the client in the [Connect] packet. [MQTT 3.1.3.3].
 ///
+/// The `Debug` impl shows the will message, use [`LastWill::redacted()`] to hide it.
+///
 /// [Connect]: struct.Connect.html
 /// [MQTT 3.1.3.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718031
+/// [`LastWill::redacted()`]: #method.redacted
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub struct LastWill<'a> {
     pub topic: &'a str,
+    #[cfg_attr(feature = "derive", serde(borrow, with = "crate::utils::serde_bytes"))]
     pub message: &'a [u8],
     pub qos: QoS,
     pub retain: bool,
 }
 
+impl<'a> LastWill<'a> {
+    /// Return a `Debug` formatter that shows the length of the will message instead of its
+    /// content.
+    pub fn redacted(&self) -> Redacted<'_, Self> {
+        Redacted(self)
+    }
+}
+
+/// `Debug` formatter hiding the payloads of a packet.
+///
+/// Returned by [`Connect::redacted()`] and [`LastWill::redacted()`].
+///
+/// [`Connect::redacted()`]: struct.Connect.html#method.redacted
+/// [`LastWill::redacted()`]: struct.LastWill.html#method.redacted
+#[derive(Clone, Copy)]
+pub struct Redacted<'r, T>(&'r T);
+
+impl fmt::Debug for Redacted<'_, LastWill<'_>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LastWill")
+            .field("topic", &self.0.topic)
+            .field("message", &ByteCount(self.0.message.len()))
+            .field("qos", &self.0.qos)
+            .field("retain", &self.0.retain)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Redacted<'_, Connect<'_>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_debug(f, true)
+    }
+}
+
+/// Shows a redacted byte string.
+struct ByteCount(usize);
+
+impl fmt::Debug for ByteCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} bytes>", self.0)
+    }
+}
+
 /// Sucess value of a [Connack] packet.
 ///
-/// See [MQTT 3.2.2.3] for interpretations.
+/// See [MQTT 3.2.2.3] for interpretations. This enum is exhaustive: the other values are reserved
+/// and rejected with [`Error::InvalidConnectReturnCode`].
 ///
 /// [Connack]: struct.Connack.html
+/// [`Error::InvalidConnectReturnCode`]: enum.Error.html#variant.InvalidConnectReturnCode
 /// [MQTT 3.2.2.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718035
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub enum ConnectReturnCode {
     Accepted,
     RefusedProtocolVersion,
@@ -85,8 +196,8 @@ pub enum ConnectReturnCode {
     NotAuthorized,
 }
 impl ConnectReturnCode {
-    fn to_u8(&self) -> u8 {
-        match *self {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
             ConnectReturnCode::Accepted => 0,
             ConnectReturnCode::RefusedProtocolVersion => 1,
             ConnectReturnCode::RefusedIdentifierRejected => 2,
@@ -106,26 +217,237 @@ impl ConnectReturnCode {
             n => Err(Error::InvalidConnectReturnCode(n)),
         }
     }
+
+    /// Returns true if the connection was accepted.
+    pub fn is_success(self) -> bool {
+        self == ConnectReturnCode::Accepted
+    }
+
+    /// Returns the equivalent [MQTT 5 connect reason code].
+    ///
+    /// [MQTT 5 connect reason code]: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901079
+    pub fn to_reason_code(self) -> u8 {
+        match self {
+            ConnectReturnCode::Accepted => 0x00,
+            ConnectReturnCode::RefusedProtocolVersion => 0x84,
+            ConnectReturnCode::RefusedIdentifierRejected => 0x85,
+            ConnectReturnCode::ServerUnavailable => 0x88,
+            ConnectReturnCode::BadUsernamePassword => 0x86,
+            ConnectReturnCode::NotAuthorized => 0x87,
+        }
+    }
+
+    /// Convert an [MQTT 5 connect reason code], for example to forward a rejection from an MQTT 5
+    /// upstream to an MQTT 3.1.1 client.
+    ///
+    /// Besides the direct equivalents of [`to_reason_code()`], "Server busy" maps to
+    /// `ServerUnavailable` and "Banned" to `NotAuthorized`. Returns `None` for reason codes
+    /// without a 3.1.1 equivalent.
+    ///
+    /// [MQTT 5 connect reason code]: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901079
+    /// [`to_reason_code()`]: #method.to_reason_code
+    pub fn from_reason_code(code: u8) -> Option<ConnectReturnCode> {
+        match code {
+            0x00 => Some(ConnectReturnCode::Accepted),
+            0x84 => Some(ConnectReturnCode::RefusedProtocolVersion),
+            0x85 => Some(ConnectReturnCode::RefusedIdentifierRejected),
+            0x86 => Some(ConnectReturnCode::BadUsernamePassword),
+            0x87 | 0x8A => Some(ConnectReturnCode::NotAuthorized),
+            0x88 | 0x89 => Some(ConnectReturnCode::ServerUnavailable),
+            _ => None,
+        }
+    }
+}
+
+/// Uses the descriptions of [MQTT 3.2.2.3].
+///
+/// [MQTT 3.2.2.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718035
+impl fmt::Display for ConnectReturnCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectReturnCode::Accepted => "connection accepted",
+            ConnectReturnCode::RefusedProtocolVersion => {
+                "connection refused, unacceptable protocol version"
+            }
+            ConnectReturnCode::RefusedIdentifierRejected => {
+                "connection refused, identifier rejected"
+            }
+            ConnectReturnCode::ServerUnavailable => "connection refused, server unavailable",
+            ConnectReturnCode::BadUsernamePassword => {
+                "connection refused, bad user name or password"
+            }
+            ConnectReturnCode::NotAuthorized => "connection refused, not authorized",
+        })
+    }
 }
 
 /// Connect packet ([MQTT 3.1]).
 ///
+/// The `Debug` impl never shows the password, and [`redacted()`] also hides the will message.
+///
+/// ```
+/// # use mqttrs::*;
+/// let connect = Connect { protocol: Protocol::MQTT311,
+///                         keep_alive: 30,
+///                         client_id: "c",
+///                         clean_session: true,
+///                         last_will: None,
+///                         username: Some("bob"),
+///                         password: Some(b"secret") };
+/// assert!(format!("{:?}", connect).contains("password: Some(<6 bytes>)"));
+/// ```
+///
 /// [MQTT 3.1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028
-#[derive(Debug, Clone, PartialEq)]
+/// [`redacted()`]: #method.redacted
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub struct Connect<'a> {
     pub protocol: Protocol,
     pub keep_alive: u16,
     pub client_id: &'a str,
     pub clean_session: bool,
+    #[cfg_attr(feature = "derive", serde(borrow))]
     pub last_will: Option<LastWill<'a>>,
+    #[cfg_attr(feature = "derive", serde(borrow))]
     pub username: Option<&'a str>,
+    #[cfg_attr(
+        feature = "derive",
+        serde(borrow, with = "crate::utils::serde_opt_bytes")
+    )]
     pub password: Option<&'a [u8]>,
 }
 
+/// Username and password of a [`Connect`] packet, as raw bytes.
+///
+/// Both point into the buffer the packet was decoded from, so they can be passed to an
+/// authentication backend without copying them. Use [`Connect::credentials()`] to get them.
+///
+/// The `Debug` impl doesn't show the password.
+///
+/// [`Connect`]: struct.Connect.html
+/// [`Connect::credentials()`]: struct.Connect.html#method.credentials
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Credentials<'a> {
+    pub username: &'a [u8],
+    pub password: Option<&'a [u8]>,
+}
+
+impl fmt::Debug for Connect<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_debug(f, false)
+    }
+}
+
+impl fmt::Debug for Credentials<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &self.password.map(|p| ByteCount(p.len())))
+            .finish()
+    }
+}
+
+/// When the server discards a session after the network connection is closed.
+///
+/// This is the [MQTT 5 session expiry interval]. MQTT 3.1.1 can only express `OnDisconnect` and
+/// `Never`, through [`Connect::clean_session`].
+///
+/// [MQTT 5 session expiry interval]: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901048
+/// [`Connect::clean_session`]: struct.Connect.html#structfield.clean_session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+pub enum SessionExpiry {
+    /// The session ends with the network connection.
+    OnDisconnect,
+    /// The session is kept for this many seconds after the network connection is closed.
+    After(u32),
+    /// The session is kept until the client connects with a clean start.
+    Never,
+}
+
+impl SessionExpiry {
+    /// Convert from the MQTT 5 session expiry interval, in seconds.
+    pub fn from_secs(secs: u32) -> Self {
+        match secs {
+            0 => SessionExpiry::OnDisconnect,
+            u32::MAX => SessionExpiry::Never,
+            n => SessionExpiry::After(n),
+        }
+    }
+
+    /// Convert to the MQTT 5 session expiry interval, in seconds.
+    pub fn as_secs(self) -> u32 {
+        match self {
+            SessionExpiry::OnDisconnect => 0,
+            SessionExpiry::After(n) => n,
+            SessionExpiry::Never => u32::MAX,
+        }
+    }
+}
+
+/// Session handling requested by a connecting client, modeled like MQTT 5.
+///
+/// MQTT 3.1.1 has a single clean session flag, where MQTT 5 separates discarding the existing
+/// session (clean start) from how long the new one lasts (session expiry). Use this type in
+/// session-persistence code to handle both the same way.
+///
+/// ```
+/// # use mqttrs::*;
+/// let config = SessionConfig::from_clean_session(false);
+/// assert!(!config.clean_start);
+/// assert!(config.outlives_connection());
+///
+/// let config = SessionConfig { clean_start: true, expiry: SessionExpiry::After(3600) };
+/// assert!(config.outlives_connection());
+/// assert!(config.to_clean_session());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+pub struct SessionConfig {
+    /// Discard any existing session before connecting.
+    pub clean_start: bool,
+    /// When the session is discarded after disconnection.
+    pub expiry: SessionExpiry,
+}
+
+impl SessionConfig {
+    /// Convert from the MQTT 3.1.1 clean session flag.
+    ///
+    /// A clean session starts clean and ends with the connection, other sessions are resumed and
+    /// never expire ([MQTT 3.1.2.4]).
+    ///
+    /// [MQTT 3.1.2.4]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028
+    pub fn from_clean_session(clean_session: bool) -> Self {
+        SessionConfig {
+            clean_start: clean_session,
+            expiry: if clean_session {
+                SessionExpiry::OnDisconnect
+            } else {
+                SessionExpiry::Never
+            },
+        }
+    }
+
+    /// Convert to the MQTT 3.1.1 clean session flag.
+    ///
+    /// This is `clean_start`: only a session that isn't discarded on connection can be resumed.
+    /// The expiry can't be expressed in MQTT 3.1.1, so a clean start with a non-zero expiry maps
+    /// to a session that ends with the connection.
+    pub fn to_clean_session(self) -> bool {
+        self.clean_start
+    }
+
+    /// Returns true if the session must be kept after the network connection is closed.
+    pub fn outlives_connection(self) -> bool {
+        self.expiry != SessionExpiry::OnDisconnect
+    }
+}
+
 /// Connack packet ([MQTT 3.2]).
 ///
 /// [MQTT 3.2]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718033
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub struct Connack {
     pub session_present: bool,
     pub code: ConnectReturnCode,
@@ -135,14 +457,13 @@ impl<'a> Connect<'a> {
     pub(crate) fn from_buffer(buf: &'a [u8], offset: &mut usize) -> Result<Self, Error> {
         let protocol = Protocol::from_buffer(buf, offset)?;
 
-        let connect_flags = buf[*offset];
-        let keep_alive = ((buf[*offset + 1] as u16) << 8) | buf[*offset + 2] as u16;
-        *offset += 3;
+        let connect_flags = read_u8(buf, offset)?;
+        let keep_alive = read_u16(buf, offset)?;
 
-        let client_id = read_str(buf, offset)?;
+        let client_id = read_str(buf, offset, "client id")?;
 
         let last_will = if connect_flags & 0b100 != 0 {
-            let will_topic = read_str(buf, offset)?;
+            let will_topic = read_str(buf, offset, "will topic")?;
             let will_message = read_bytes(buf, offset)?;
             let will_qod = QoS::from_u8((connect_flags & 0b11000) >> 3)?;
             Some(LastWill {
@@ -156,7 +477,7 @@ impl<'a> Connect<'a> {
         };
 
         let username = if connect_flags & 0b10000000 != 0 {
-            Some(read_str(buf, offset)?)
+            Some(read_str(buf, offset, "username")?)
         } else {
             None
         };
@@ -180,24 +501,138 @@ impl<'a> Connect<'a> {
         })
     }
 
+    /// Returns true if the server must assign a client id, because the client sent an empty one
+    /// ([MQTT-3.1.3-6]).
+    ///
+    /// An empty client id is only allowed with a clean session, use [`check_client_id()`] to
+    /// reject the connection otherwise.
+    ///
+    /// MQTT 3.1.1 has no way to tell the client which id was assigned (MQTT 5 sends it as a
+    /// CONNACK property), so the id only matters to the server, for the duration of the session.
+    ///
+    /// [MQTT-3.1.3-6]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718031
+    /// [`check_client_id()`]: #method.check_client_id
+    pub fn needs_assigned_client_id(&self) -> bool {
+        self.client_id.is_empty()
+    }
+
+    /// Check that the server can accept the client id.
+    ///
+    /// Returns `RefusedIdentifierRejected` for an empty client id without a clean session
+    /// ([MQTT-3.1.3-8]).
+    ///
+    /// [MQTT-3.1.3-8]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718031
+    pub fn check_client_id(&self) -> Result<(), ConnectReturnCode> {
+        if self.client_id.is_empty() && !self.clean_session {
+            Err(ConnectReturnCode::RefusedIdentifierRejected)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return the client id in effect for this connection: the one sent by the client, or
+    /// `assigned` if it was empty.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let connect = Connect { protocol: Protocol::MQTT311,
+    ///                         keep_alive: 30,
+    ///                         client_id: "",
+    ///                         clean_session: true,
+    ///                         last_will: None,
+    ///                         username: None,
+    ///                         password: None };
+    /// assert!(connect.needs_assigned_client_id());
+    /// assert_eq!(Ok(()), connect.check_client_id());
+    /// assert_eq!("auto-1", connect.effective_client_id("auto-1"));
+    /// ```
+    pub fn effective_client_id<'b>(&self, assigned: &'b str) -> &'b str
+    where
+        'a: 'b,
+    {
+        if self.client_id.is_empty() {
+            assigned
+        } else {
+            self.client_id
+        }
+    }
+
+    /// Return the session handling requested by the client.
+    pub fn session_config(&self) -> SessionConfig {
+        SessionConfig::from_clean_session(self.clean_session)
+    }
+
+    /// Return a `Debug` formatter that also hides the will message.
+    pub fn redacted(&self) -> Redacted<'_, Self> {
+        Redacted(self)
+    }
+
+    fn fmt_debug(&self, f: &mut fmt::Formatter<'_>, hide_payloads: bool) -> fmt::Result {
+        let mut d = f.debug_struct("Connect");
+        d.field("protocol", &self.protocol)
+            .field("keep_alive", &self.keep_alive)
+            .field("client_id", &self.client_id)
+            .field("clean_session", &self.clean_session);
+        match &self.last_will {
+            Some(w) if hide_payloads => d.field("last_will", &Some(w.redacted())),
+            w => d.field("last_will", w),
+        };
+        d.field("username", &self.username)
+            .field("password", &self.password.map(|p| ByteCount(p.len())))
+            .finish()
+    }
+
+    /// Return the username and password, if a username was sent.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let buf = [0x10, 23, 0, 4, b'M', b'Q', b'T', b'T', 4, 0b11000010, 0, 10, 0, 1, b'c',
+    ///            0, 3, b'b', b'o', b'b', 0, 3, b'p', b'w', b'd'];
+    /// if let Ok(Some(Packet::Connect(c))) = decode_slice(&buf) {
+    ///     let creds = c.credentials().unwrap();
+    ///     assert_eq!(b"bob", creds.username);
+    ///     assert_eq!(Some(&b"pwd"[..]), creds.password);
+    ///     // The credentials borrow the input buffer.
+    ///     assert_eq!(buf[22..].as_ptr(), creds.password.unwrap().as_ptr());
+    /// }
+    /// # else { panic!() }
+    /// ```
+    pub fn credentials(&self) -> Option<Credentials<'a>> {
+        self.username.map(|username| Credentials {
+            username: username.as_bytes(),
+            password: self.password,
+        })
+    }
+
+    pub(crate) fn remaining_len(&self) -> usize {
+        // NOTE: protocol name and level + flags(1) + keep alive(2)
+        let mut length = self.protocol.len() + 1 + 2;
+        length += 2 + self.client_id.len();
+        if let Some(username) = self.username {
+            length += 2 + username.len();
+        };
+        if let Some(password) = self.password {
+            length += 2 + password.len();
+        };
+        if let Some(last_will) = &self.last_will {
+            length += 2 + last_will.topic.len();
+            length += 2 + last_will.message.len();
+        };
+        length
+    }
+
     pub(crate) fn to_buffer(&self, buf: &mut [u8], offset: &mut usize) -> Result<usize, Error> {
         let header: u8 = 0b00010000;
-        let mut length: usize = 6 + 1 + 1; // NOTE: protocol_name(6) + protocol_level(1) + flags(1);
+        let length = self.remaining_len();
         let mut connect_flags: u8 = 0b00000000;
         if self.clean_session {
             connect_flags |= 0b10;
         };
-        length += 2 + self.client_id.len();
-        length += 2; // keep alive
-        if let Some(username) = self.username {
+        if self.username.is_some() {
             connect_flags |= 0b10000000;
-            length += username.len();
-            length += 2;
         };
-        if let Some(password) = self.password {
+        if self.password.is_some() {
             connect_flags |= 0b01000000;
-            length += password.len();
-            length += 2;
         };
         if let Some(last_will) = &self.last_will {
             connect_flags |= 0b00000100;
@@ -205,9 +640,6 @@ impl<'a> Connect<'a> {
             if last_will.retain {
                 connect_flags |= 0b00100000;
             };
-            length += last_will.message.len();
-            length += last_will.topic.len();
-            length += 4;
         };
         check_remaining(buf, offset, length + 1)?;
 
@@ -224,7 +656,7 @@ impl<'a> Connect<'a> {
 
         if let Some(last_will) = &self.last_will {
             write_string(buf, offset, last_will.topic)?;
-            write_bytes(buf, offset, &last_will.message)?;
+            write_bytes(buf, offset, last_will.message)?;
         };
 
         if let Some(username) = self.username {
@@ -238,17 +670,80 @@ impl<'a> Connect<'a> {
     }
 }
 
+/// Whether the server had a session for the client, as returned in an accepted [`Connack`].
+///
+/// [`Connack`]: struct.Connack.html
+pub type SessionPresent = bool;
+
+/// The server refused the connection.
+///
+/// Returned when converting a rejected [`Connack`] into a `Result`.
+///
+/// [`Connack`]: struct.Connack.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectError {
+    pub code: ConnectReturnCode,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.code.fmt(f)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ConnectError {}
+
+/// Check the outcome of the connect handshake.
+///
+/// ```
+/// # use mqttrs::*;
+/// let res: Result<SessionPresent, ConnectError> = Connack::accepted(true).into();
+/// assert_eq!(Ok(true), res);
+/// let res: Result<SessionPresent, ConnectError> =
+///     Connack::rejected(ConnectReturnCode::NotAuthorized).into();
+/// assert_eq!(Err(ConnectError { code: ConnectReturnCode::NotAuthorized }), res);
+/// ```
+impl From<Connack> for Result<SessionPresent, ConnectError> {
+    fn from(connack: Connack) -> Self {
+        match connack.code {
+            ConnectReturnCode::Accepted => Ok(connack.session_present),
+            code => Err(ConnectError { code }),
+        }
+    }
+}
+
 impl Connack {
-    pub(crate) fn from_buffer<'a>(buf: &'a [u8], offset: &mut usize) -> Result<Self, Error> {
-        let flags = buf[*offset];
-        let return_code = buf[*offset + 1];
-        *offset += 2;
+    /// Accept the connection.
+    pub fn accepted(session_present: SessionPresent) -> Self {
+        Connack {
+            session_present,
+            code: ConnectReturnCode::Accepted,
+        }
+    }
+
+    /// Refuse the connection with `code`.
+    ///
+    /// The session present flag is cleared, as required by [MQTT-3.2.2-4]. Passing
+    /// `ConnectReturnCode::Accepted` is equivalent to `Connack::accepted(false)`.
+    ///
+    /// [MQTT-3.2.2-4]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718035
+    pub fn rejected(code: ConnectReturnCode) -> Self {
+        Connack {
+            session_present: false,
+            code,
+        }
+    }
+
+    pub(crate) fn from_buffer(buf: &[u8], offset: &mut usize) -> Result<Self, Error> {
+        let flags = read_u8(buf, offset)?;
+        let return_code = read_u8(buf, offset)?;
         Ok(Connack {
             session_present: (flags & 0b1 == 1),
             code: ConnectReturnCode::from_u8(return_code)?,
         })
     }
-    pub(crate) fn to_buffer(&self, buf: &mut [u8], offset: &mut usize) -> Result<usize, Error> {
+    pub(crate) fn to_buffer(self, buf: &mut [u8], offset: &mut usize) -> Result<usize, Error> {
         check_remaining(buf, offset, 4)?;
         let header: u8 = 0b00100000;
         let length: u8 = 2;
@@ -52,17 +52,274 @@ pub fn clone_packet(input: &[u8], output: &mut [u8]) -> Result<usize, Error> {
 /// [Packet]: ../enum.Packet.html
 /// [BytesMut]: https://docs.rs/bytes/1.0.0/bytes/struct.BytesMut.html
 pub fn decode_slice<'a>(buf: &'a [u8]) -> Result<Option<Packet<'a>>, Error> {
+    Ok(decode_slice_with_len(buf)?.map(|(packet, _)| packet))
+}
+
+/// Limits checked by [`decode_slice_with_limits()`].
+///
+/// The default doesn't limit anything.
+///
+/// [`decode_slice_with_limits()`]: fn.decode_slice_with_limits.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DecodeLimits {
+    /// Maximum length of a `Publish` payload. Other packets, like a big `Subscribe`, aren't
+    /// affected.
+    pub max_payload_len: Option<usize>,
+}
+
+/// Same as `decode_slice()`, but fails with `Error::LimitExceeded` if the packet goes over
+/// `limits`.
+///
+/// An oversized publish is rejected as soon as its topic and pid are in `buf`, without waiting
+/// for the rest of the payload. The caller can then close the connection instead of buffering
+/// it.
+///
+/// ```
+/// # use mqttrs::*;
+/// let limits = DecodeLimits { max_payload_len: Some(1024) };
+/// // Start of a publish with a 1000000-byte payload.
+/// let buf = [0b00110000, 0xc5, 0x84, 0x3d, 0, 3, b'a', b'/', b'b'];
+/// assert_eq!(Err(Error::LimitExceeded { field: "payload", limit: 1024, actual: 1_000_000 }),
+///            decode_slice_with_limits(&buf, &limits));
+/// ```
+pub fn decode_slice_with_limits<'a>(
+    buf: &'a [u8],
+    limits: &DecodeLimits,
+) -> Result<Option<Packet<'a>>, Error> {
+    if let Some(limit) = limits.max_payload_len {
+        if buf.first().map(|b| b >> 4) == Some(3) {
+            match decode_publish_header(buf)? {
+                Some((header, _)) if header.payload_len > limit => {
+                    return Err(Error::LimitExceeded {
+                        field: "payload",
+                        limit,
+                        actual: header.payload_len,
+                    })
+                }
+                Some(_) => (),
+                None => return Ok(None),
+            }
+        }
+    }
+    decode_slice(buf)
+}
+
+/// Same as `decode_slice()`, also returning the length of the decoded packet.
+pub(crate) fn decode_slice_with_len(buf: &[u8]) -> Result<Option<(Packet<'_>, usize)>, Error> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("decode", buf_len = buf.len()).entered();
+    #[cfg(feature = "alloc-stats")]
+    let _measure = crate::alloc_stats::Measure::start(crate::metrics::Direction::Decode);
+    let res = read_slice(buf);
+    #[cfg(feature = "tracing")]
+    crate::trace::decoded(buf, &res);
+    res
+}
+
+fn read_slice(buf: &[u8]) -> Result<Option<(Packet<'_>, usize)>, Error> {
+    match decode_slice_partial(buf) {
+        Ok(Some((packet, header))) => Ok(Some((packet, header.packet_len()))),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e.error),
+    }
+}
+
+/// Fixed header of a packet: packet type, flags and remaining length.
+///
+/// Returned by [`decode_slice_partial()`]. Proxies can also [`decode()`] it alone, change its
+/// flags and [`encode()`] it again, leaving the rest of the packet untouched:
+///
+/// ```
+/// # use mqttrs::*;
+/// // A QoS 1 retained Publish, to forward with QoS 0 and without the retain flag.
+/// let buf = [0b0011_0011, 7, 0, 1, b'a', 0, 5, b'h', b'i'];
+/// let mut header = FixedHeader::decode(&buf)?.unwrap();
+/// assert_eq!((PacketType::Publish, QoS::AtLeastOnce, true),
+///            (header.typ, header.qos()?, header.retain()));
+/// header.set_retain(false);
+/// header.set_qos(QoS::AtMostOnce);
+///
+/// // Without a pid, the body loses 2 bytes.
+/// header.remaining_len -= 2;
+/// let mut out = [0; 7];
+/// let len = header.encode(&mut out)?;
+/// out[len..len + 3].copy_from_slice(&buf[2..5]);
+/// out[len + 3..].copy_from_slice(&buf[7..]);
+/// match decode_slice(&out)? {
+///     Some(Packet::Publish(p)) => assert_eq!(QosPid::AtMostOnce, p.qospid),
+///     other => panic!("unexpected {:?}", other),
+/// }
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`decode_slice_partial()`]: fn.decode_slice_partial.html
+/// [`decode()`]: #method.decode
+/// [`encode()`]: #method.encode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedHeader {
+    pub typ: PacketType,
+    /// The 4 flag bits of the first byte (dup, qos and retain for `Publish`).
+    pub flags: u8,
+    /// Length of the packet after the fixed header.
+    pub remaining_len: usize,
+    /// Length of the fixed header itself (2 to 5 bytes).
+    pub header_len: usize,
+}
+
+impl FixedHeader {
+    /// Decode the fixed header at the start of `buf`, checking the flags like [`decode_slice()`].
+    ///
+    /// Returns `Ok(None)` if `buf` ends before the fixed header does. The rest of the packet
+    /// doesn't need to be in `buf`.
+    ///
+    /// [`decode_slice()`]: fn.decode_slice.html
+    pub fn decode(buf: &[u8]) -> Result<Option<Self>, Error> {
+        let first = match buf.first() {
+            Some(first) => *first,
+            None => return Ok(None),
+        };
+        let header = Header::new(first)?;
+        Ok(
+            read_length(buf, 0)?.map(|(header_len, remaining_len)| FixedHeader {
+                typ: header.typ,
+                flags: first & 0x0f,
+                remaining_len,
+                header_len,
+            }),
+        )
+    }
+
+    /// Encode the fixed header at the start of `buf`, returning the number of bytes written.
+    ///
+    /// The remaining length is always encoded on as few bytes as possible, so this may differ
+    /// from `header_len`. Fails like [`decode()`] if the flags aren't valid for the packet type,
+    /// `LimitExceeded` if `remaining_len` is too large, or `WriteZero` if `buf` is too small.
+    ///
+    /// [`decode()`]: #method.decode
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.flags > 0x0f {
+            return Err(Error::InvalidFlags {
+                packet_type: self.typ,
+                flags: self.flags,
+            });
+        }
+        Header::new(self.first_byte())?;
+        let len = 1 + remaining_length::encoded_len(self.remaining_len)?;
+        if buf.len() < len {
+            return Err(Error::WriteZero {
+                required: len,
+                available: buf.len(),
+            });
+        }
+        buf[0] = self.first_byte();
+        remaining_length::encode(self.remaining_len, &mut buf[1..])?;
+        Ok(len)
+    }
+
+    /// Total length of the packet, fixed header included.
+    pub fn packet_len(&self) -> usize {
+        self.header_len + self.remaining_len
+    }
+
+    /// First byte of the packet: control type and flags.
+    pub fn first_byte(&self) -> u8 {
+        (self.control_type() << 4) | (self.flags & 0x0f)
+    }
+
+    /// Control type, the 4 high bits of the first byte.
+    pub fn control_type(&self) -> u8 {
+        self.typ.control_type()
+    }
+
+    /// Dup flag of a `Publish`.
+    pub fn dup(&self) -> bool {
+        self.flags & 0b1000 != 0
+    }
+
+    /// QoS of a `Publish`. Fails with `InvalidQos` if both QoS bits are set.
+    pub fn qos(&self) -> Result<QoS, Error> {
+        QoS::from_u8((self.flags & 0b0110) >> 1)
+    }
+
+    /// Retain flag of a `Publish`.
+    pub fn retain(&self) -> bool {
+        self.flags & 0b0001 != 0
+    }
+
+    pub fn set_dup(&mut self, dup: bool) {
+        self.flags = (self.flags & !0b1000) | ((dup as u8) << 3);
+    }
+
+    pub fn set_qos(&mut self, qos: QoS) {
+        self.flags = (self.flags & !0b0110) | (qos.to_u8() << 1);
+    }
+
+    pub fn set_retain(&mut self, retain: bool) {
+        self.flags = (self.flags & !0b0001) | retain as u8;
+    }
+}
+
+/// Error returned by [`decode_slice_partial()`].
+///
+/// [`decode_slice_partial()`]: fn.decode_slice_partial.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialDecodeError {
+    pub error: Error,
+    /// The fixed header, if it was valid and only the rest of the packet failed to decode.
+    pub header: Option<FixedHeader>,
+}
+
+/// Same as `decode_slice()`, also returning the [`FixedHeader`], even when decoding fails.
+///
+/// When only the body of the packet is invalid, the error contains the fixed header. This lets
+/// lenient servers skip exactly that packet and continue with the rest of the stream:
+///
+/// ```
+/// # use mqttrs::*;
+/// // A Puback with an invalid pid of 0, followed by a Pingreq.
+/// let buf = [0b01000000, 2, 0, 0, 0b11000000, 0];
+/// let err = decode_slice_partial(&buf).unwrap_err();
+/// assert_eq!(Error::InvalidPid, err.error);
+/// let skip = err.header.unwrap().packet_len();
+/// let (packet, _) = decode_slice_partial(&buf[skip..]).unwrap().unwrap();
+/// assert_eq!(Packet::Pingreq, packet);
+/// ```
+///
+/// The body is decoded from the `remaining_len` bytes following the fixed header only.
+///
+/// [`FixedHeader`]: struct.FixedHeader.html
+pub fn decode_slice_partial(
+    buf: &[u8],
+) -> Result<Option<(Packet<'_>, FixedHeader)>, PartialDecodeError> {
     let mut offset = 0;
-    if let Some((header, remaining_len)) = read_header(buf, &mut offset)? {
-        let r = read_packet(header, remaining_len, buf, &mut offset)?;
-        Ok(Some(r))
-    } else {
+    let (header, remaining_len) = match read_header(buf, &mut offset) {
+        Ok(Some(h)) => h,
         // Don't have a full packet
-        Ok(None)
+        Ok(None) => return Ok(None),
+        Err(error) => {
+            return Err(PartialDecodeError {
+                error,
+                header: None,
+            })
+        }
+    };
+    let fixed = FixedHeader {
+        typ: header.typ,
+        flags: buf[0] & 0x0f,
+        remaining_len,
+        header_len: offset,
+    };
+    let packet_buf = &buf[..fixed.packet_len()];
+    match read_packet(header, remaining_len, packet_buf, &mut offset) {
+        Ok(packet) => Ok(Some((packet, fixed))),
+        Err(error) => Err(PartialDecodeError {
+            error,
+            header: Some(fixed),
+        }),
     }
 }
 
-fn read_packet<'a>(
+pub(crate) fn read_packet<'a>(
     header: Header,
     remaining_len: usize,
     buf: &'a [u8],
@@ -88,33 +345,91 @@ fn read_packet<'a>(
 
 /// Read the parsed header and remaining_len from the buffer. Only return Some() and advance the
 /// buffer position if there is enough data in the buffer to read the full packet.
-pub(crate) fn read_header<'a>(
-    buf: &'a [u8],
+pub(crate) fn read_header(
+    buf: &[u8],
     offset: &mut usize,
 ) -> Result<Option<(Header, usize)>, Error> {
-    let mut len: usize = 0;
-    for pos in 0..=3 {
-        if buf.len() > *offset + pos + 1 {
-            let byte = buf[*offset + pos + 1];
-            len += (byte as usize & 0x7F) << (pos * 7);
-            if (byte & 0x80) == 0 {
-                // Continuation bit == 0, length is parsed
-                if buf.len() < *offset + 2 + pos + len {
-                    // Won't be able to read full packet
-                    return Ok(None);
-                }
-                // Parse header byte, skip past the header, and return
-                let header = Header::new(buf[*offset])?;
-                *offset += pos + 2;
-                return Ok(Some((header, len)));
-            }
-        } else {
-            // Couldn't read full length
-            return Ok(None);
+    match read_length(buf, *offset)? {
+        Some((header_len, len)) if buf.len() >= *offset + header_len + len => {
+            // Parse header byte, skip past the header, and return
+            let header = Header::new(buf[*offset])?;
+            *offset += header_len;
+            Ok(Some((header, len)))
         }
+        // Won't be able to read full packet
+        _ => Ok(None),
+    }
+}
+
+/// Read the remaining_len of the packet starting at `offset`, without checking the header byte.
+/// Returns the length of the fixed header and the remaining_len, if they are both in the buffer.
+pub(crate) fn read_length(buf: &[u8], offset: usize) -> Result<Option<(usize, usize)>, Error> {
+    match buf.get(offset + 1..) {
+        Some(field) => Ok(remaining_length::decode(field)?.map(|(len, size)| (size + 1, len))),
+        None => Ok(None),
+    }
+}
+
+/// Decode the beginning of a `Publish` packet, before its whole payload is available.
+///
+/// Returns the [`PublishHeader`] and the number of bytes it used, as soon as `buf` contains the
+/// topic and pid. The `payload_len` bytes following it are the payload, which can be forwarded
+/// in chunks as they arrive, keeping memory use bounded for large publishes. Returns `Ok(None)`
+/// if more data is needed, and `Error::UnexpectedPacketType` if `buf` starts with another type of
+/// packet (use [`decode_slice()`] for those).
+///
+/// ```
+/// # use mqttrs::*;
+/// // Only the start of a publish with a 1000-byte payload has arrived.
+/// let buf = [0b00110000, 0xec, 0x07, 0, 2, b'f', b'w', 0xab, 0xab];
+/// let (header, len) = decode_publish_header(&buf).unwrap().unwrap();
+/// assert_eq!("fw", header.topic_name);
+/// assert_eq!(1000, header.payload_len);
+/// let first_chunk = &buf[len..];
+/// assert_eq!(Ok(None), decode_publish_header(&buf[..4]));
+/// ```
+///
+/// [`PublishHeader`]: struct.PublishHeader.html
+/// [`decode_slice()`]: fn.decode_slice.html
+pub fn decode_publish_header(buf: &[u8]) -> Result<Option<(PublishHeader<'_>, usize)>, Error> {
+    let (header_len, remaining_len) = match read_length(buf, 0)? {
+        Some(l) => l,
+        None => return Ok(None),
+    };
+    let header = Header::new(buf[0])?;
+    if header.typ != PacketType::Publish {
+        return Err(Error::UnexpectedPacketType(header.typ));
+    }
+    let topic_len = match buf.get(header_len..header_len + 2) {
+        Some(b) => ((b[0] as usize) << 8) | b[1] as usize,
+        None => return Ok(None),
+    };
+    let pid_len = match header.qos {
+        QoS::AtMostOnce => 0,
+        _ => 2,
+    };
+    let variable_len = 2 + topic_len + pid_len;
+    if variable_len > remaining_len {
+        return Err(Error::InvalidLength);
     }
-    // Continuation byte == 1 four times, that's illegal.
-    Err(Error::InvalidHeader)
+    if buf.len() < header_len + variable_len {
+        return Ok(None);
+    }
+    let mut offset = header_len;
+    let topic_name = read_str(buf, &mut offset, "topic name")?;
+    let qospid = match header.qos {
+        QoS::AtMostOnce => QosPid::AtMostOnce,
+        QoS::AtLeastOnce => QosPid::AtLeastOnce(Pid::from_buffer(buf, &mut offset)?),
+        QoS::ExactlyOnce => QosPid::ExactlyOnce(Pid::from_buffer(buf, &mut offset)?),
+    };
+    let publish = PublishHeader {
+        dup: header.dup,
+        qospid,
+        retain: header.retain,
+        topic_name,
+        payload_len: remaining_len - variable_len,
+    };
+    Ok(Some((publish, offset)))
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -126,25 +441,19 @@ pub(crate) struct Header {
 }
 impl Header {
     pub fn new(hd: u8) -> Result<Header, Error> {
-        let (typ, flags_ok) = match hd >> 4 {
-            1 => (PacketType::Connect, hd & 0b1111 == 0),
-            2 => (PacketType::Connack, hd & 0b1111 == 0),
-            3 => (PacketType::Publish, true),
-            4 => (PacketType::Puback, hd & 0b1111 == 0),
-            5 => (PacketType::Pubrec, hd & 0b1111 == 0),
-            6 => (PacketType::Pubrel, hd & 0b1111 == 0b0010),
-            7 => (PacketType::Pubcomp, hd & 0b1111 == 0),
-            8 => (PacketType::Subscribe, hd & 0b1111 == 0b0010),
-            9 => (PacketType::Suback, hd & 0b1111 == 0),
-            10 => (PacketType::Unsubscribe, hd & 0b1111 == 0b0010),
-            11 => (PacketType::Unsuback, hd & 0b1111 == 0),
-            12 => (PacketType::Pingreq, hd & 0b1111 == 0),
-            13 => (PacketType::Pingresp, hd & 0b1111 == 0),
-            14 => (PacketType::Disconnect, hd & 0b1111 == 0),
-            _ => (PacketType::Connect, false),
+        let typ = PacketType::from_first_byte(hd)?;
+        let flags_ok = match typ {
+            PacketType::Publish => true,
+            PacketType::Pubrel | PacketType::Subscribe | PacketType::Unsubscribe => {
+                hd & 0b1111 == 0b0010
+            }
+            _ => hd & 0b1111 == 0,
         };
         if !flags_ok {
-            return Err(Error::InvalidHeader);
+            return Err(Error::InvalidFlags {
+                packet_type: typ,
+                flags: hd & 0b1111,
+            });
         }
         Ok(Header {
             typ,
@@ -155,21 +464,66 @@ impl Header {
     }
 }
 
-pub(crate) fn read_str<'a>(buf: &'a [u8], offset: &mut usize) -> Result<&'a str, Error> {
-    core::str::from_utf8(read_bytes(buf, offset)?).map_err(|e| Error::InvalidString(e))
+pub(crate) fn read_u8(buf: &[u8], offset: &mut usize) -> Result<u8, Error> {
+    let byte = *buf.get(*offset).ok_or(Error::InvalidLength)?;
+    *offset += 1;
+    Ok(byte)
+}
+
+pub(crate) fn read_u16(buf: &[u8], offset: &mut usize) -> Result<u16, Error> {
+    Ok(((read_u8(buf, offset)? as u16) << 8) | read_u8(buf, offset)? as u16)
+}
+
+/// Read a string, `field` names it in the `InvalidString` error.
+pub(crate) fn read_str<'a>(
+    buf: &'a [u8],
+    offset: &mut usize,
+    field: &'static str,
+) -> Result<&'a str, Error> {
+    core::str::from_utf8(read_bytes(buf, offset)?)
+        .map_err(|error| Error::InvalidString { field, error })
 }
 
 pub(crate) fn read_bytes<'a>(buf: &'a [u8], offset: &mut usize) -> Result<&'a [u8], Error> {
-    if buf[*offset..].len() < 2 {
+    let rest = buf.get(*offset..).ok_or(Error::InvalidLength)?;
+    if rest.len() < 2 {
         return Err(Error::InvalidLength);
     }
-    let len = ((buf[*offset] as usize) << 8) | buf[*offset + 1] as usize;
-    *offset += 2;
-    if len > buf[*offset..].len() {
-        Err(Error::InvalidLength)
-    } else {
-        let bytes = &buf[*offset..*offset + len];
-        *offset += len;
-        Ok(bytes)
-    }
+    let len = ((rest[0] as usize) << 8) | rest[1] as usize;
+    let bytes = rest.get(2..2 + len).ok_or(Error::InvalidLength)?;
+    *offset += 2 + len;
+    Ok(bytes)
+}
+
+/// Read a length-prefixed UTF-8 string at `offset` in `buf`, and advance `offset` past it
+/// ([MQTT 1.5.3]).
+///
+/// This is how packets encode topics, client ids and other strings, and can be reused to decode
+/// extensions. Fails with `InvalidLength` if `buf` is too short, or `InvalidString` if the string
+/// isn't valid UTF-8. `offset` isn't modified on error.
+///
+/// ```
+/// # use mqttrs::*;
+/// let buf = [0, 3, b'a', b'/', b'b', 0, 0];
+/// let mut offset = 0;
+/// assert_eq!(Ok("a/b"), read_mqtt_string(&buf, &mut offset));
+/// assert_eq!(Ok(""), read_mqtt_string(&buf, &mut offset));
+/// assert_eq!(Err(Error::InvalidLength), read_mqtt_string(&buf, &mut offset));
+/// assert_eq!(7, offset);
+/// ```
+///
+/// [MQTT 1.5.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718016
+pub fn read_mqtt_string<'a>(buf: &'a [u8], offset: &mut usize) -> Result<&'a str, Error> {
+    let mut end = *offset;
+    let string = read_str(buf, &mut end, "string")?;
+    *offset = end;
+    Ok(string)
+}
+
+/// Read length-prefixed binary data at `offset` in `buf`, and advance `offset` past it.
+///
+/// This is how packets encode passwords and will payloads. Fails with `InvalidLength` if `buf` is
+/// too short. `offset` isn't modified on error.
+pub fn read_binary_data<'a>(buf: &'a [u8], offset: &mut usize) -> Result<&'a [u8], Error> {
+    read_bytes(buf, offset)
 }
@@ -51,8 +51,17 @@ fn header_firstbyte() {
         let res = match valid.iter().find(|(byte, _)| *byte == n) {
             Some((_, header)) => Ok(Some((*header, 0))),
             None if ((n & 0b110) == 0b110) && (n >> 4 == 3) => Err(Error::InvalidQos(3)),
-            None => Err(Error::InvalidHeader),
+            None if n >> 4 == 0 || n >> 4 == 15 => Err(Error::UnknownPacketType(n >> 4)),
+            None => Err(Error::InvalidFlags {
+                packet_type: valid.iter().find(|(b, _)| b >> 4 == n >> 4).unwrap().1.typ,
+                flags: n & 0b1111,
+            }),
         };
+        let typ = match n >> 4 {
+            0 | 15 => Err(Error::UnknownPacketType(n >> 4)),
+            t => Ok(valid.iter().find(|(b, _)| b >> 4 == t).unwrap().1.typ),
+        };
+        assert_eq!(typ, PacketType::from_first_byte(n), "{:08b}", n);
         let mut buf: &[u8] = &[n, 0];
         let mut offset = 0;
         assert_eq!(
@@ -83,7 +92,7 @@ fn header_len() {
         (Ok(Some((h, 128))),        vec![1 << 4, 0x80, 1], 131),
         (Ok(None),                  vec![1 << 4, 0x80+16, 78], 10002),
         (Ok(Some((h, 10000))),      vec![1 << 4, 0x80+16, 78], 10003),
-        (Err(Error::InvalidHeader), vec![1 << 4, 0x80, 0x80, 0x80, 0x80], 10),
+        (Err(Error::MalformedRemainingLength), vec![1 << 4, 0x80, 0x80, 0x80, 0x80], 10),
     ] {
         let offset_expectation = bytes.len();
         bytes.resize(buflen, 0);
@@ -105,7 +114,10 @@ fn non_utf8_string() {
         'h' as u8, 'e' as u8, 'l' as u8, 'l' as u8, 'o' as u8, // payload
     ];
     assert!(match decode_slice(&mut data) {
-        Err(Error::InvalidString(_)) => true,
+        Err(Error::InvalidString {
+            field: "topic name",
+            ..
+        }) => true,
         _ => false,
     });
 }
@@ -135,6 +147,47 @@ fn inner_length_too_long() {
     // assert_eq!(slice, []);
 }
 
+/// The body can't extend past the remaining_len, even if the buffer has more data.
+#[test]
+fn body_bounded_by_remaining_len() {
+    let buf = [0b01000000, 1, 0, 10, 0b11000000, 0];
+    let err = decode_slice_partial(&buf).unwrap_err();
+    assert_eq!(Error::InvalidLength, err.error);
+    let header = err.header.unwrap();
+    assert_eq!(
+        FixedHeader {
+            typ: PacketType::Puback,
+            flags: 0,
+            remaining_len: 1,
+            header_len: 2,
+        },
+        header
+    );
+    assert_eq!(3, header.packet_len());
+    assert_eq!(Err(Error::InvalidLength), decode_slice(&buf));
+
+    let err = decode_slice_partial(&[0xf0, 0]).unwrap_err();
+    assert_eq!(
+        PartialDecodeError {
+            error: Error::UnknownPacketType(15),
+            header: None
+        },
+        err
+    );
+    assert_eq!(Ok(None), decode_slice_partial(&buf[..2]));
+}
+
+/// Fixed-size fields cut short by the remaining_len are an error, not a panic.
+#[test]
+fn truncated_field() {
+    assert_eq!(Err(Error::InvalidLength), decode_slice(&[0b01000000, 1, 0]));
+    assert_eq!(Err(Error::InvalidLength), decode_slice(&[0b00100000, 1, 0]));
+    assert_eq!(
+        Err(Error::InvalidLength),
+        decode_slice(&[0b10000010, 5, 0, 1, 0, 1, b'a'])
+    );
+}
+
 #[test]
 fn test_half_connect() {
     let mut data: &[u8] = &[
@@ -377,10 +430,7 @@ fn test_subscribe() {
     match decode_slice(&mut data) {
         Ok(Some(Packet::Subscribe(s))) => {
             assert_eq!(s.pid.get(), 10);
-            let t = SubscribeTopic {
-                topic_path: LimitedString::from("a/b"),
-                qos: QoS::AtMostOnce,
-            };
+            let t = SubscribeTopic::new("a/b", QoS::AtMostOnce).unwrap();
             assert_eq!(s.topics.get(0), Some(&t));
         }
         other => panic!("Failed decode: {:?}", other),
@@ -424,3 +474,404 @@ fn test_unsub_ack() {
         other => panic!("Failed decode: {:?}", other),
     }
 }
+
+#[test]
+fn publish_header_incremental() {
+    let payload = [0xab; 300];
+    let packet = Packet::Publish(Publish {
+        dup: false,
+        qospid: QosPid::AtLeastOnce(Pid::new() + 9),
+        retain: true,
+        topic_name: "a/b",
+        payload: &payload,
+    });
+    let mut buf = [0u8; 512];
+    let len = encode_slice(&packet, &mut buf).unwrap();
+
+    // Header is 1 + 2 (remaining length) + 2 + 3 (topic) + 2 (pid) bytes.
+    for l in 0..10 {
+        assert_eq!(Ok(None), decode_publish_header(&buf[..l]), "{}", l);
+    }
+    for l in (10..len).step_by(50) {
+        let (header, header_len) = decode_publish_header(&buf[..l]).unwrap().unwrap();
+        assert_eq!(10, header_len);
+        assert_eq!(300, header.payload_len);
+        assert_eq!(header, packet_header(&packet));
+    }
+
+    assert_eq!(
+        Err(Error::UnexpectedPacketType(PacketType::Pingreq)),
+        decode_publish_header(&[0b11000000, 0])
+    );
+    // Topic longer than the remaining length.
+    assert_eq!(
+        Err(Error::InvalidLength),
+        decode_publish_header(&[0b00110000, 3, 0, 5, b'a'])
+    );
+}
+
+fn packet_header<'a>(packet: &Packet<'a>) -> PublishHeader<'a> {
+    match packet {
+        Packet::Publish(p) => p.header(),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn payload_limit() {
+    let limits = DecodeLimits {
+        max_payload_len: Some(3),
+    };
+    let publish = |payload: &'static [u8]| {
+        let mut buf = [0u8; 32];
+        let packet = Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "a",
+            payload,
+        });
+        let len = encode_slice(&packet, &mut buf).unwrap();
+        (packet, buf, len)
+    };
+
+    let (packet, buf, len) = publish(b"abc");
+    assert_eq!(
+        Ok(Some(packet)),
+        decode_slice_with_limits(&buf[..len], &limits)
+    );
+    assert_eq!(Ok(None), decode_slice_with_limits(&buf[..len - 1], &limits));
+
+    let (_, buf, len) = publish(b"abcd");
+    let err = Err(Error::LimitExceeded {
+        field: "payload",
+        limit: 3,
+        actual: 4,
+    });
+    assert_eq!(err, decode_slice_with_limits(&buf[..len], &limits));
+    assert_eq!(err, decode_slice_with_limits(&buf[..5], &limits));
+    assert_eq!(Ok(None), decode_slice_with_limits(&buf[..4], &limits));
+
+    // Other packets aren't limited.
+    let unsub = Unsubscribe::new(Pid::new(), vec!["abcdef".into()]).into();
+    let mut buf = [0u8; 32];
+    let len = encode_slice(&unsub, &mut buf).unwrap();
+    assert_eq!(
+        Ok(Some(unsub)),
+        decode_slice_with_limits(&buf[..len], &limits)
+    );
+}
+
+#[test]
+fn connect_return_code_helpers() {
+    for byte in 0..=5 {
+        let code = match decode_slice(&[0b00100000, 2, 0, byte]) {
+            Ok(Some(Packet::Connack(c))) => c.code,
+            other => panic!("Failed decoding connack: {:?}", other),
+        };
+        assert_eq!(byte == 0, code.is_success());
+        assert_eq!(
+            Some(code),
+            ConnectReturnCode::from_reason_code(code.to_reason_code())
+        );
+    }
+    assert_eq!(
+        0x86,
+        ConnectReturnCode::BadUsernamePassword.to_reason_code()
+    );
+    assert_eq!(
+        Some(ConnectReturnCode::ServerUnavailable),
+        ConnectReturnCode::from_reason_code(0x89)
+    );
+    assert_eq!(None, ConnectReturnCode::from_reason_code(0x80));
+    assert_eq!(
+        "connection refused, not authorized",
+        ConnectReturnCode::NotAuthorized.to_string()
+    );
+}
+
+#[test]
+fn connect_client_id() {
+    let mut connect = Connect {
+        protocol: Protocol::MQTT311,
+        keep_alive: 10,
+        client_id: "",
+        clean_session: false,
+        last_will: None,
+        username: None,
+        password: None,
+    };
+    assert_eq!(
+        Err(ConnectReturnCode::RefusedIdentifierRejected),
+        connect.check_client_id()
+    );
+    connect.client_id = "dev-7";
+    assert!(!connect.needs_assigned_client_id());
+    assert_eq!(Ok(()), connect.check_client_id());
+    assert_eq!("dev-7", connect.effective_client_id("auto-1"));
+}
+
+#[test]
+fn keep_alive_negotiation() {
+    use core::time::Duration;
+    let connect = Connect {
+        protocol: Protocol::MQTT311,
+        keep_alive: 0,
+        client_id: "c",
+        clean_session: true,
+        last_will: None,
+        username: None,
+        password: None,
+    };
+    let keep_alive = KeepAlive::negotiate(&connect, &ServerCapabilities::default());
+    assert_eq!(None, keep_alive.interval());
+    assert_eq!(None, keep_alive.timeout());
+
+    let caps = ServerCapabilities {
+        keep_alive: Some(15),
+        ..Default::default()
+    };
+    let keep_alive = KeepAlive::negotiate(&connect, &caps);
+    assert_eq!(0, keep_alive.requested());
+    assert_eq!(Some(Duration::from_millis(22_500)), keep_alive.timeout());
+}
+
+#[test]
+fn connack_builder() {
+    let will = LastWill {
+        topic: "status",
+        message: b"offline",
+        qos: QoS::ExactlyOnce,
+        retain: true,
+    };
+    let mut connect = Connect {
+        protocol: Protocol::MQTT311,
+        keep_alive: 60,
+        client_id: "",
+        clean_session: false,
+        last_will: None,
+        username: None,
+        password: None,
+    };
+    let caps = ServerCapabilities::default();
+    // An assigned id doesn't make an empty id acceptable without a clean session.
+    let response = ConnackBuilder::new(&caps, &connect)
+        .assigned_client_id("auto-1")
+        .build();
+    assert_eq!(
+        Connack::rejected(ConnectReturnCode::RefusedIdentifierRejected),
+        response.connack
+    );
+
+    connect.client_id = "c1";
+    connect.last_will = Some(will);
+    let response = ConnackBuilder::new(&caps, &connect)
+        .assigned_client_id("auto-1")
+        .session_present(true)
+        .build();
+    assert_eq!(Connack::accepted(true), response.connack);
+    assert_eq!("c1", response.client_id);
+    assert_eq!(60, response.keep_alive.secs());
+
+    let caps = ServerCapabilities {
+        retain_available: false,
+        ..Default::default()
+    };
+    let response = ConnackBuilder::new(&caps, &connect)
+        .session_present(true)
+        .build();
+    assert_eq!(
+        Connack::rejected(ConnectReturnCode::NotAuthorized),
+        response.connack
+    );
+}
+
+#[test]
+fn ping_scheduler() {
+    use core::time::Duration;
+    let secs = Duration::from_secs;
+    let disabled = PingScheduler::new(KeepAlive::new(0), secs(5), secs(0));
+    assert_eq!(None, disabled.next_wakeup());
+    assert_eq!(None, disabled.poll(secs(1000)));
+
+    let mut pings = PingScheduler::new(KeepAlive::new(10), secs(3), secs(0));
+    // Other packets postpone the ping.
+    pings.sent(&Packet::Puback(Pid::new()), secs(8));
+    assert_eq!(None, pings.poll(secs(17)));
+    assert_eq!(Some(PingAction::SendPingreq), pings.poll(secs(18)));
+    pings.sent(&Packet::Pingreq, secs(18));
+    assert!(pings.is_waiting());
+    // Other packets don't count as a Pingresp, and don't postpone the grace period.
+    pings.received(&Packet::Puback(Pid::new()));
+    pings.sent(&Packet::Puback(Pid::new()), secs(20));
+    assert_eq!(Some(secs(21)), pings.next_wakeup());
+    assert_eq!(None, pings.poll(secs(20)));
+    assert_eq!(Some(PingAction::PingrespMissing), pings.poll(secs(21)));
+    pings.received(&Packet::Pingresp);
+    assert!(!pings.is_waiting());
+    assert_eq!(Some(secs(30)), pings.next_wakeup());
+}
+
+#[test]
+fn test_subscribe_invalid_filter() {
+    let invalid = |kind| Err(Error::InvalidTopicFilter(TopicError { kind, level: 1 }));
+    let data: &[u8] = &[0b10000010, 7, 0, 10, 0, 2, b'a', b'#', 0];
+    assert_eq!(
+        invalid(TopicErrorKind::MisplacedWildcard('#')),
+        decode_slice(data)
+    );
+    let data: &[u8] = &[0b10000010, 5, 0, 10, 0, 0, 0];
+    assert_eq!(invalid(TopicErrorKind::Empty), decode_slice(data));
+}
+
+#[test]
+fn suback_pair_with() {
+    let pid = Pid::new() + 9;
+    let subscribe = Subscribe::new(
+        pid,
+        [
+            SubscribeTopic::new("a", QoS::ExactlyOnce).unwrap(),
+            SubscribeTopic::new("b", QoS::AtLeastOnce).unwrap(),
+        ]
+        .to_vec(),
+    );
+    let data: &[u8] = &[0b10010000, 4, 0, 10, 0x01, 0x80];
+    let suback = match decode_slice(data) {
+        Ok(Some(Packet::Suback(s))) => s,
+        other => panic!("Failed decode: {:?}", other),
+    };
+    let pairs: Vec<_> = suback
+        .pair_with(&subscribe)
+        .unwrap()
+        .map(|(t, code)| (t.topic_filter.as_str(), code.granted_qos()))
+        .collect();
+    assert_eq!(vec![("a", Some(QoS::AtLeastOnce)), ("b", None)], pairs);
+    assert_eq!(1, suback.rejected(&subscribe).unwrap().count());
+
+    let short = Suback::new(pid, [SubscribeReturnCodes::Failure].to_vec());
+    assert!(short.pair_with(&subscribe).is_none());
+}
+
+#[test]
+fn suback_respond_to() {
+    let subscribe = Subscribe::new(
+        Pid::new() + 4,
+        [
+            SubscribeTopic::new("a", QoS::AtMostOnce).unwrap(),
+            SubscribeTopic::new("b/#", QoS::ExactlyOnce).unwrap(),
+            SubscribeTopic::new("c", QoS::ExactlyOnce).unwrap(),
+        ]
+        .to_vec(),
+    );
+    let mut calls = 0;
+    let suback = Suback::respond_to(&subscribe, |topic| {
+        calls += 1;
+        match topic.topic_filter.as_str() {
+            "c" => SubscribeReturnCodes::Failure,
+            _ => SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+        }
+    });
+    assert_eq!(3, calls);
+    let codes: Vec<_> = suback
+        .pair_with(&subscribe)
+        .unwrap()
+        .map(|(_, c)| c)
+        .collect();
+    assert_eq!(
+        vec![
+            SubscribeReturnCodes::Success(QoS::AtMostOnce),
+            SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+            SubscribeReturnCodes::Failure
+        ],
+        codes
+    );
+}
+
+#[test]
+fn topic_filter_matches() {
+    let cases = [
+        ("#", "a", true),
+        ("#", "/", true),
+        ("a/#", "a", true),
+        ("a/#", "ab", false),
+        ("+", "a", true),
+        ("+", "a/b", false),
+        ("+/+", "/a", true),
+        ("a/+/c", "a//c", true),
+        ("a/b", "a/b/", false),
+        ("+/uptime", "$SYS/uptime", false),
+        ("$SYS/#", "$SYS/uptime", true),
+    ];
+    for (filter, topic, expected) in cases.iter() {
+        let f = TopicFilter::new(filter).unwrap();
+        assert_eq!(*expected, f.matches(topic), "{} {}", filter, topic);
+    }
+}
+
+#[test]
+fn fixed_header() {
+    assert_eq!(Ok(None), FixedHeader::decode(&[]));
+    assert_eq!(Ok(None), FixedHeader::decode(&[0b0011_0000, 0x80]));
+    assert_eq!(
+        Err(Error::UnknownPacketType(0)),
+        FixedHeader::decode(&[0, 0])
+    );
+    assert_eq!(
+        Err(Error::InvalidQos(3)),
+        FixedHeader::decode(&[0b0011_0110, 0])
+    );
+
+    // Only the fixed header needs to be in the buffer.
+    let mut header = FixedHeader::decode(&[0b0011_1010, 0x80, 0x01])
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        (128, 3, 131),
+        (header.remaining_len, header.header_len, header.packet_len())
+    );
+    assert_eq!(
+        (3, true, Ok(QoS::AtLeastOnce), false),
+        (
+            header.control_type(),
+            header.dup(),
+            header.qos(),
+            header.retain()
+        )
+    );
+    header.set_dup(false);
+    header.set_qos(QoS::ExactlyOnce);
+    header.set_retain(true);
+    assert_eq!(0b0011_0101, header.first_byte());
+    let mut buf = [0; 3];
+    assert_eq!(Ok(3), header.encode(&mut buf));
+    assert_eq!([0b0011_0101, 0x80, 0x01], buf);
+    assert_eq!(
+        Err(Error::WriteZero {
+            required: 3,
+            available: 2
+        }),
+        header.encode(&mut buf[..2])
+    );
+
+    // Flags are checked like when decoding.
+    let pingreq = FixedHeader {
+        typ: PacketType::Pingreq,
+        flags: 0b0001,
+        remaining_len: 0,
+        header_len: 2,
+    };
+    let invalid = Err(Error::InvalidFlags {
+        packet_type: PacketType::Pingreq,
+        flags: 0b0001,
+    });
+    assert_eq!(invalid, pingreq.encode(&mut buf));
+    assert_eq!(
+        Ok(2),
+        FixedHeader {
+            flags: 0,
+            ..pingreq
+        }
+        .encode(&mut buf)
+    );
+    assert_eq!([0b1100_0000, 0], buf[..2]);
+}
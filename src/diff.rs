@@ -0,0 +1,245 @@
+use crate::*;
+use core::fmt::Debug;
+use std::{format, string::String, vec::Vec};
+
+/// Fields to ignore when comparing packets with [`Packet::diff()`].
+///
+/// The default compares everything.
+///
+/// [`Packet::diff()`]: enum.Packet.html#method.diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DiffOptions {
+    /// Ignore packet identifiers (but not whether a `Publish` has one).
+    pub ignore_pid: bool,
+    /// Ignore the `Publish` dup flag.
+    pub ignore_dup: bool,
+    /// Compare `Subscribe` and `Unsubscribe` topics as sets rather than lists.
+    pub ignore_topic_order: bool,
+}
+
+/// Field-level difference between two packets, returned by [`Packet::diff()`].
+///
+/// [`Packet::diff()`]: enum.Packet.html#method.diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difference {
+    /// Path of the field, like `"topic_name"` or `"topics[1]"`.
+    pub field: String,
+    /// `Debug` representation of the value in each packet.
+    pub left: String,
+    pub right: String,
+}
+
+struct Differ<'o> {
+    opts: &'o DiffOptions,
+    out: Vec<Difference>,
+}
+
+impl Differ<'_> {
+    fn field<T: Debug + PartialEq + ?Sized>(&mut self, field: &str, left: &T, right: &T) {
+        if left != right {
+            self.out.push(Difference {
+                field: field.into(),
+                left: format!("{:?}", left),
+                right: format!("{:?}", right),
+            });
+        }
+    }
+
+    fn pid(&mut self, left: Pid, right: Pid) {
+        if !self.opts.ignore_pid {
+            self.field("pid", &left, &right);
+        }
+    }
+
+    /// Compare two lists, element by element or as sets.
+    fn list<T: Debug + PartialEq>(&mut self, field: &str, left: &[T], right: &[T], as_set: bool) {
+        if as_set {
+            for (side, a, b) in [("left", left, right), ("right", right, left)] {
+                for (i, item) in a.iter().enumerate() {
+                    if !b.contains(item) {
+                        let (l, r) = match side {
+                            "left" => (format!("{:?}", item), String::from("<missing>")),
+                            _ => (String::from("<missing>"), format!("{:?}", item)),
+                        };
+                        self.out.push(Difference {
+                            field: format!("{}[{}]", field, i),
+                            left: l,
+                            right: r,
+                        });
+                    }
+                }
+            }
+        } else {
+            for i in 0..left.len().max(right.len()) {
+                match (left.get(i), right.get(i)) {
+                    (Some(l), Some(r)) => self.field(&format!("{}[{}]", field, i), l, r),
+                    (l, r) => self.out.push(Difference {
+                        field: format!("{}[{}]", field, i),
+                        left: l.map_or("<missing>".into(), |l| format!("{:?}", l)),
+                        right: r.map_or("<missing>".into(), |r| format!("{:?}", r)),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+impl Packet<'_> {
+    /// List the field-level differences between two packets, ignoring the fields selected by
+    /// `opts`.
+    ///
+    /// Packets of different types only report a `"type"` difference.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// # use core::convert::TryFrom;
+    /// let sent = Packet::Publish(Publish { dup: false,
+    ///                                      qospid: QosPid::AtLeastOnce(Pid::try_from(1).unwrap()),
+    ///                                      retain: false,
+    ///                                      topic_name: "a/b",
+    ///                                      payload: b"on" });
+    /// let received = Packet::Publish(Publish { dup: true,
+    ///                                          qospid: QosPid::AtLeastOnce(Pid::try_from(7).unwrap()),
+    ///                                          retain: false,
+    ///                                          topic_name: "a/b",
+    ///                                          payload: b"off" });
+    /// let opts = DiffOptions { ignore_pid: true, ignore_dup: true, ..DiffOptions::default() };
+    /// let diff = sent.diff(&received, &opts);
+    /// assert_eq!(diff.len(), 1);
+    /// assert_eq!(diff[0].field, "payload");
+    /// assert!(!sent.semantic_eq(&received, &opts));
+    /// ```
+    pub fn diff(&self, other: &Packet, opts: &DiffOptions) -> Vec<Difference> {
+        let mut d = Differ {
+            opts,
+            out: Vec::new(),
+        };
+        match (self, other) {
+            (Packet::Connect(l), Packet::Connect(r)) => {
+                d.field("protocol", &l.protocol, &r.protocol);
+                d.field("keep_alive", &l.keep_alive, &r.keep_alive);
+                d.field("client_id", l.client_id, r.client_id);
+                d.field("clean_session", &l.clean_session, &r.clean_session);
+                d.field("last_will", &l.last_will, &r.last_will);
+                d.field("username", &l.username, &r.username);
+                d.field("password", &l.password, &r.password);
+            }
+            (Packet::Connack(l), Packet::Connack(r)) => {
+                d.field("session_present", &l.session_present, &r.session_present);
+                d.field("code", &l.code, &r.code);
+            }
+            (Packet::Publish(l), Packet::Publish(r)) => {
+                if !opts.ignore_dup {
+                    d.field("dup", &l.dup, &r.dup);
+                }
+                d.field("qos", &l.qospid.qos(), &r.qospid.qos());
+                if let (Some(lp), Some(rp)) = (l.qospid.pid(), r.qospid.pid()) {
+                    d.pid(lp, rp);
+                }
+                d.field("retain", &l.retain, &r.retain);
+                d.field("topic_name", l.topic_name, r.topic_name);
+                d.field("payload", l.payload, r.payload);
+            }
+            (Packet::Puback(l), Packet::Puback(r))
+            | (Packet::Pubrec(l), Packet::Pubrec(r))
+            | (Packet::Pubrel(l), Packet::Pubrel(r))
+            | (Packet::Pubcomp(l), Packet::Pubcomp(r))
+            | (Packet::Unsuback(l), Packet::Unsuback(r)) => d.pid(*l, *r),
+            (Packet::Subscribe(l), Packet::Subscribe(r)) => {
+                d.pid(l.pid, r.pid);
+                d.list("topics", &l.topics, &r.topics, opts.ignore_topic_order);
+            }
+            (Packet::Suback(l), Packet::Suback(r)) => {
+                d.pid(l.pid, r.pid);
+                d.list("return_codes", &l.return_codes, &r.return_codes, false);
+            }
+            (Packet::Unsubscribe(l), Packet::Unsubscribe(r)) => {
+                d.pid(l.pid, r.pid);
+                d.list("topics", &l.topics, &r.topics, opts.ignore_topic_order);
+            }
+            (l, r) => d.field("type", &l.get_type(), &r.get_type()),
+        }
+        d.out
+    }
+
+    /// Returns true if [`diff()`] finds no difference.
+    ///
+    /// [`diff()`]: #method.diff
+    pub fn semantic_eq(&self, other: &Packet, opts: &DiffOptions) -> bool {
+        self.diff(other, opts).is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use core::convert::TryFrom;
+    use std::vec;
+
+    fn sub(pid: u16, topics: &[(&str, QoS)]) -> Packet<'static> {
+        let topics = topics
+            .iter()
+            .map(|(t, qos)| SubscribeTopic::new(t, *qos).unwrap())
+            .collect();
+        Subscribe::new(Pid::try_from(pid).unwrap(), topics).into()
+    }
+
+    #[test]
+    fn diff_subscribe() {
+        let a = sub(1, &[("a", QoS::AtMostOnce), ("b", QoS::AtLeastOnce)]);
+        let b = sub(2, &[("b", QoS::AtLeastOnce), ("a", QoS::AtMostOnce)]);
+        let fields = |opts| -> Vec<_> { a.diff(&b, &opts).into_iter().map(|d| d.field).collect() };
+
+        assert_eq!(
+            fields(DiffOptions::default()),
+            ["pid", "topics[0]", "topics[1]"]
+        );
+        let opts = DiffOptions {
+            ignore_pid: true,
+            ignore_topic_order: true,
+            ..DiffOptions::default()
+        };
+        assert!(a.semantic_eq(&b, &opts));
+
+        let c = sub(1, &[("a", QoS::AtMostOnce)]);
+        let diff = a.diff(&c, &opts);
+        assert_eq!(1, diff.len());
+        assert_eq!("topics[1]", diff[0].field);
+        assert_eq!("<missing>", diff[0].right);
+    }
+
+    #[test]
+    fn diff_types() {
+        let diff = Packet::Pingreq.diff(&Packet::Pingresp, &DiffOptions::default());
+        assert_eq!(
+            diff,
+            vec![Difference {
+                field: "type".into(),
+                left: "Pingreq".into(),
+                right: "Pingresp".into(),
+            }]
+        );
+        assert!(Packet::Pingreq.semantic_eq(&Packet::Pingreq, &DiffOptions::default()));
+    }
+
+    #[test]
+    fn diff_publish_qos() {
+        let publish = |qospid| {
+            Packet::Publish(Publish {
+                dup: false,
+                qospid,
+                retain: false,
+                topic_name: "t",
+                payload: b"",
+            })
+        };
+        let opts = DiffOptions {
+            ignore_pid: true,
+            ..DiffOptions::default()
+        };
+        let a = publish(QosPid::AtMostOnce);
+        let b = publish(QosPid::AtLeastOnce(Pid::new()));
+        let fields: Vec<_> = a.diff(&b, &opts).into_iter().map(|d| d.field).collect();
+        assert_eq!(fields, ["qos"]);
+    }
+}
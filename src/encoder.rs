@@ -1,4 +1,11 @@
-use crate::{Error, Packet};
+use crate::{remaining_length, Error, Packet, Pid, PublishHeader, QoS, TopicFilter};
+#[cfg(feature = "std")]
+use bytes::BytesMut;
+#[cfg(feature = "std")]
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    vec,
+};
 
 /// Encode a [Packet] enum into a [BufMut] buffer.
 ///
@@ -31,8 +38,247 @@ use crate::{Error, Packet};
 //     let mut offset = 0;
 //     encode_slice(packet, buf.bytes_mut(), &mut offset)
 // }
-
 pub fn encode_slice(packet: &Packet, buf: &mut [u8]) -> Result<usize, Error> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("encode", packet_type = ?packet.get_type()).entered();
+    #[cfg(feature = "alloc-stats")]
+    let _measure = crate::alloc_stats::Measure::start(crate::metrics::Direction::Encode);
+    let res = write_packet(packet, buf).map_err(|e| match e {
+        Error::WriteZero { .. } => Error::WriteZero {
+            required: packet.encoded_len(),
+            available: buf.len(),
+        },
+        e => e,
+    });
+    #[cfg(feature = "tracing")]
+    crate::trace::encoded(packet, buf, &res);
+    res
+}
+
+/// Encode a [Packet] at the end of a [BytesMut], growing it as needed.
+///
+/// The buffer is grown by exactly [`Packet::encoded_len()`] bytes, so this never fails with
+/// `WriteZero`. Use [encode_slice()] to encode without allocating.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use bytes::*;
+/// let mut buf = BytesMut::new();
+/// let len = encode_grow(&Packet::Pingreq, &mut buf).unwrap();
+/// assert_eq!(len, 2);
+/// assert_eq!(&buf[..], &[0b11000000, 0]);
+/// ```
+///
+/// [Packet]: ../enum.Packet.html
+/// [BytesMut]: https://docs.rs/bytes/1.0.0/bytes/struct.BytesMut.html
+/// [`Packet::encoded_len()`]: enum.Packet.html#method.encoded_len
+/// [encode_slice()]: fn.encode_slice.html
+#[cfg(feature = "std")]
+pub fn encode_grow(packet: &Packet, buf: &mut BytesMut) -> Result<usize, Error> {
+    length_len(packet.remaining_len())?;
+    let start = buf.len();
+    buf.resize(start + packet.encoded_len(), 0);
+    let res = encode_slice(packet, &mut buf[start..]);
+    buf.truncate(start + *res.as_ref().unwrap_or(&0));
+    res
+}
+
+/// Encode a batch of packets at the end of a [BytesMut] buffer, reserving capacity only once.
+///
+/// This is useful to send several packets with a single write, like the acknowledgements of all
+/// the publishes received in one read. Returns the total number of bytes written. If a packet
+/// can't be encoded, the error is returned and the buffer is left unchanged.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use bytes::*;
+/// let acks = [Packet::Puback(Pid::new()), Packet::Puback(Pid::new() + 1)];
+/// let mut buf = BytesMut::new();
+/// assert_eq!(8, encode_all(&acks, &mut buf)?);
+/// assert_eq!(&buf[..], &[0b01000000, 2, 0, 1, 0b01000000, 2, 0, 2]);
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [BytesMut]: https://docs.rs/bytes/1.0.0/bytes/struct.BytesMut.html
+#[cfg(feature = "std")]
+pub fn encode_all(packets: &[Packet], buf: &mut BytesMut) -> Result<usize, Error> {
+    let mut total = 0;
+    for packet in packets {
+        length_len(packet.remaining_len())?;
+        total += packet.encoded_len();
+    }
+    let start = buf.len();
+    buf.resize(start + total, 0);
+    let mut offset = start;
+    for packet in packets {
+        match encode_slice(packet, &mut buf[offset..]) {
+            Ok(len) => offset += len,
+            Err(e) => {
+                buf.truncate(start);
+                return Err(e);
+            }
+        }
+    }
+    buf.truncate(offset);
+    Ok(offset - start)
+}
+
+/// Encode a [PublishHeader], to be followed by `payload_len` bytes of payload.
+///
+/// This lets you send a large payload in chunks, without holding it in a [Publish] packet:
+///
+/// ```
+/// # use mqttrs::*;
+/// let firmware = [0xab; 1000];
+/// let header = PublishHeader { dup: false,
+///                              qospid: QosPid::AtMostOnce,
+///                              retain: false,
+///                              topic_name: "fw",
+///                              payload_len: firmware.len() };
+/// let mut buf = [0u8; 16];
+/// let len = encode_publish_header(&header, &mut buf).unwrap();
+/// assert_eq!(&buf[..len], &[0b00110000, 0xec, 0x07, 0, 2, b'f', b'w']);
+/// // Now send `&buf[..len]`, followed by the chunks of `firmware`.
+/// ```
+///
+/// [PublishHeader]: struct.PublishHeader.html
+/// [Publish]: struct.Publish.html
+pub fn encode_publish_header(header: &PublishHeader, buf: &mut [u8]) -> Result<usize, Error> {
+    header.to_buffer(buf, &mut 0).map_err(|e| match e {
+        Error::WriteZero { .. } => Error::WriteZero {
+            required: header.encoded_len(),
+            available: buf.len(),
+        },
+        e => e,
+    })
+}
+
+/// Encode a [Subscribe] packet directly from `(topic filter, QoS)` pairs.
+///
+/// This avoids collecting the topics into a [`SubscribeTopic`] `Vec` first, which is useful for
+/// constrained clients subscribing to many topics. The iterator is walked twice (to compute the
+/// length, then to write), and each filter is validated like [`TopicFilter::new()`] does.
+///
+/// ```
+/// # use mqttrs::*;
+/// let topics = [("a/+", QoS::AtMostOnce), ("b/#", QoS::AtLeastOnce)];
+/// let mut buf = [0u8; 32];
+/// let len = encode_subscribe(Pid::new(), topics.iter().copied(), &mut buf).unwrap();
+/// match decode_slice(&buf[..len]) {
+///     Ok(Some(Packet::Subscribe(s))) => assert_eq!("b/#", s.topics[1].topic_filter.as_str()),
+///     other => panic!("{:?}", other),
+/// }
+/// ```
+///
+/// [Subscribe]: struct.Subscribe.html
+/// [`SubscribeTopic`]: struct.SubscribeTopic.html
+/// [`TopicFilter::new()`]: struct.TopicFilter.html#method.new
+pub fn encode_subscribe<'t, I>(pid: Pid, topics: I, buf: &mut [u8]) -> Result<usize, Error>
+where
+    I: IntoIterator<Item = (&'t str, QoS)>,
+    I::IntoIter: Clone,
+{
+    let topics = topics.into_iter();
+    let mut length = 2;
+    for (filter, _) in topics.clone() {
+        TopicFilter::validate(filter)?;
+        length += 2 + filter.len() + 1;
+    }
+    let required = 1 + length_len(length)? + length;
+    if buf.len() < required {
+        return Err(Error::WriteZero {
+            required,
+            available: buf.len(),
+        });
+    }
+
+    let offset = &mut 0;
+    write_u8(buf, offset, 0b10000010)?;
+    write_length(buf, offset, length)?;
+    pid.to_buffer(buf, offset)?;
+    for (filter, qos) in topics {
+        write_string(buf, offset, filter)?;
+        write_u8(buf, offset, qos.to_u8())?;
+    }
+    Ok(*offset)
+}
+
+/// Encode a `Publish` packet into `out`, copying its payload from `payload`.
+///
+/// Exactly `header.payload_len` bytes are read from `payload`, so that the whole payload never
+/// needs to be in memory. Fails with an `UnexpectedEof` `IoError` if `payload` is shorter than
+/// that. Returns the total number of bytes written.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use std::io::repeat;
+/// let header = PublishHeader { dup: false,
+///                              qospid: QosPid::AtMostOnce,
+///                              retain: false,
+///                              topic_name: "fw",
+///                              payload_len: 3_000_000 };
+/// let mut out = Vec::new();
+/// let len = encode_publish_from(&header, repeat(0xab), &mut out).unwrap();
+/// assert_eq!(len, out.len());
+/// ```
+#[cfg(feature = "std")]
+pub fn encode_publish_from(
+    header: &PublishHeader,
+    payload: impl Read,
+    mut out: impl Write,
+) -> Result<usize, Error> {
+    let mut buf = vec![0; header.encoded_len()];
+    let len = encode_publish_header(header, &mut buf)?;
+    out.write_all(&buf[..len])?;
+    let copied = io::copy(&mut payload.take(header.payload_len as u64), &mut out)?;
+    if copied != header.payload_len as u64 {
+        return Err(Error::IoError(
+            ErrorKind::UnexpectedEof,
+            "payload shorter than payload_len".into(),
+        ));
+    }
+    Ok(len + header.payload_len)
+}
+
+impl Packet<'_> {
+    /// Length of the remaining length field's content, excluding the fixed header.
+    pub(crate) fn remaining_len(&self) -> usize {
+        match self {
+            Packet::Connect(connect) => connect.remaining_len(),
+            Packet::Connack(_) => 2,
+            Packet::Publish(publish) => publish.remaining_len(),
+            Packet::Puback(_)
+            | Packet::Pubrec(_)
+            | Packet::Pubrel(_)
+            | Packet::Pubcomp(_)
+            | Packet::Unsuback(_) => 2,
+            Packet::Subscribe(subscribe) => subscribe.remaining_len(),
+            Packet::Suback(suback) => 2 + suback.return_codes.len(),
+            Packet::Unsubscribe(unsub) => unsub.remaining_len(),
+            Packet::Pingreq | Packet::Pingresp | Packet::Disconnect => 0,
+        }
+    }
+
+    /// Number of bytes that [`encode_slice()`] will write for this packet.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let packet = Packet::Publish(Publish { dup: false,
+    ///                                        qospid: QosPid::AtMostOnce,
+    ///                                        retain: false,
+    ///                                        topic_name: "test",
+    ///                                        payload: b"hello" });
+    /// assert_eq!(packet.encoded_len(), 13);
+    /// ```
+    ///
+    /// [`encode_slice()`]: fn.encode_slice.html
+    pub fn encoded_len(&self) -> usize {
+        let len = self.remaining_len();
+        1 + length_len(len).unwrap_or(4) + len
+    }
+}
+
+fn write_packet(packet: &Packet, buf: &mut [u8]) -> Result<usize, Error> {
     let mut offset = 0;
 
     match packet {
@@ -117,48 +363,43 @@ pub fn encode_slice(packet: &Packet, buf: &mut [u8]) -> Result<usize, Error> {
 /// Check wether buffer has `len` bytes of write capacity left. Use this to return a clean
 /// Result::Err instead of panicking.
 pub(crate) fn check_remaining(buf: &mut [u8], offset: &mut usize, len: usize) -> Result<(), Error> {
-    if buf[*offset..].len() < len {
-        Err(Error::WriteZero)
+    if buf.len().saturating_sub(*offset) < len {
+        Err(Error::WriteZero {
+            required: *offset + len,
+            available: buf.len(),
+        })
     } else {
         Ok(())
     }
 }
 
+/// Size of the remaining length field for a remaining length of `len`.
+pub(crate) fn length_len(len: usize) -> Result<usize, Error> {
+    remaining_length::encoded_len(len)
+}
+
+/// Write the remaining length field, checking that the buffer also has room for the `len` bytes
+/// that follow it.
+///
 /// http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718023
 pub(crate) fn write_length(buf: &mut [u8], offset: &mut usize, len: usize) -> Result<usize, Error> {
-    let write_len = match len {
-        0..=127 => {
-            check_remaining(buf, offset, len + 1)?;
-            len + 1
-        }
-        128..=16383 => {
-            check_remaining(buf, offset, len + 2)?;
-            len + 2
-        }
-        16384..=2097151 => {
-            check_remaining(buf, offset, len + 3)?;
-            len + 3
-        }
-        2097152..=268435455 => {
-            check_remaining(buf, offset, len + 4)?;
-            len + 4
-        }
-        _ => return Err(Error::InvalidLength),
-    };
-    let mut done = false;
-    let mut x = len;
-    while !done {
-        let mut byte = (x % 128) as u8;
-        x = x / 128;
-        if x > 0 {
-            byte = byte | 128;
-        }
-        write_u8(buf, offset, byte)?;
-        done = x <= 0;
-    }
+    let write_len = len + length_len(len)?;
+    check_remaining(buf, offset, write_len)?;
+    write_length_field(buf, offset, len)?;
     Ok(write_len)
 }
 
+/// Write only the remaining length field.
+pub(crate) fn write_length_field(
+    buf: &mut [u8],
+    offset: &mut usize,
+    len: usize,
+) -> Result<(), Error> {
+    check_remaining(buf, offset, length_len(len)?)?;
+    *offset += remaining_length::encode(len, &mut buf[*offset..])?;
+    Ok(())
+}
+
 pub(crate) fn write_u8(buf: &mut [u8], offset: &mut usize, val: u8) -> Result<(), Error> {
     buf[*offset] = val;
     *offset += 1;
@@ -171,6 +412,13 @@ pub(crate) fn write_u16(buf: &mut [u8], offset: &mut usize, val: u16) -> Result<
 }
 
 pub(crate) fn write_bytes(buf: &mut [u8], offset: &mut usize, bytes: &[u8]) -> Result<(), Error> {
+    if bytes.len() > u16::MAX as usize {
+        return Err(Error::LimitExceeded {
+            field: "string length",
+            limit: u16::MAX as usize,
+            actual: bytes.len(),
+        });
+    }
     write_u16(buf, offset, bytes.len() as u16)?;
 
     for &byte in bytes {
@@ -182,3 +430,38 @@ pub(crate) fn write_bytes(buf: &mut [u8], offset: &mut usize, bytes: &[u8]) -> R
 pub(crate) fn write_string(buf: &mut [u8], offset: &mut usize, string: &str) -> Result<(), Error> {
     write_bytes(buf, offset, string.as_bytes())
 }
+
+/// Write `string` with its length prefix at `offset` in `buf`, and advance `offset` past it
+/// ([MQTT 1.5.3]).
+///
+/// This is the encoding of [`read_mqtt_string()`]. Fails with `LimitExceeded` if `string` is
+/// longer than 65535 bytes, or `WriteZero` if `buf` is too small.
+///
+/// ```
+/// # use mqttrs::*;
+/// let mut buf = [0; 8];
+/// let mut offset = 0;
+/// write_mqtt_string(&mut buf, &mut offset, "a/b")?;
+/// assert_eq!([0, 3, b'a', b'/', b'b'], buf[..offset]);
+/// assert!(write_mqtt_string(&mut buf, &mut offset, "abc").is_err());
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [MQTT 1.5.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718016
+/// [`read_mqtt_string()`]: fn.read_mqtt_string.html
+pub fn write_mqtt_string(buf: &mut [u8], offset: &mut usize, string: &str) -> Result<(), Error> {
+    write_binary_data(buf, offset, string.as_bytes())
+}
+
+/// Write `bytes` with its length prefix at `offset` in `buf`, and advance `offset` past it.
+///
+/// This is the encoding of [`read_binary_data()`]. Fails with `LimitExceeded` if `bytes` is
+/// longer than 65535 bytes, or `WriteZero` if `buf` is too small.
+///
+/// [`read_binary_data()`]: fn.read_binary_data.html
+pub fn write_binary_data(buf: &mut [u8], offset: &mut usize, bytes: &[u8]) -> Result<(), Error> {
+    if bytes.len() <= u16::MAX as usize {
+        check_remaining(buf, offset, 2 + bytes.len())?;
+    }
+    write_bytes(buf, offset, bytes)
+}
@@ -26,6 +26,7 @@ macro_rules! assert_decode_slice {
         let mut slice = [0u8; 512];
         let written = encode_slice($pkt, &mut slice).unwrap();
         assert_eq!(written, $written_exp);
+        assert_eq!(written, $pkt.encoded_len());
         match decode_slice(&slice[..written]) {
             Ok(Some($res)) => (),
             err => assert!(
@@ -70,7 +71,13 @@ fn test_write_zero() {
     let mut slice = [0u8; 8];
     match encode_slice(&packet, &mut slice) {
         Ok(_) => panic!("Expected Error::WriteZero, as input slice is too small"),
-        Err(e) => assert_eq!(e, Error::WriteZero),
+        Err(e) => assert_eq!(
+            e,
+            Error::WriteZero {
+                required: 18,
+                available: 8
+            }
+        ),
     }
 
     let mut buf = [0u8; 80];
@@ -78,6 +85,30 @@ fn test_write_zero() {
     assert_eq!(written, 18);
 }
 
+#[test]
+fn test_limit_exceeded() {
+    let payload = vec![0u8; 65536];
+    let packet = Publish {
+        dup: false,
+        qospid: QosPid::AtMostOnce,
+        retain: false,
+        topic_name: core::str::from_utf8(&payload).unwrap(),
+        payload: b"",
+    }
+    .into();
+    let mut buf = vec![0u8; 70000];
+    let err = encode_slice(&packet, &mut buf).unwrap_err();
+    assert_eq!(
+        Error::LimitExceeded {
+            field: "string length",
+            limit: 65535,
+            actual: 65536
+        },
+        err
+    );
+    assert_eq!(ErrorClass::LimitExceeded, err.class());
+}
+
 #[test]
 fn test_connack() {
     let packet = Connack {
@@ -133,10 +164,7 @@ fn test_pubcomp() {
 
 #[test]
 fn test_subscribe() {
-    let stopic = SubscribeTopic {
-        topic_path: LimitedString::from("a/b"),
-        qos: QoS::ExactlyOnce,
-    };
+    let stopic = SubscribeTopic::new("a/b", QoS::ExactlyOnce).unwrap();
     let topics: LimitedVec<SubscribeTopic> = [stopic].iter().cloned().collect();
     let packet = Subscribe::new(Pid::try_from(345).unwrap(), topics).into();
     // assert_decode!(Packet::Subscribe(_), &packet);
@@ -187,3 +215,498 @@ fn test_disconnect() {
     // assert_decode!(Packet::Disconnect, &Packet::Disconnect);
     assert_decode_slice!(Packet::Disconnect, &Packet::Disconnect, 2);
 }
+
+#[test]
+fn test_connect_mqisdp() {
+    let packet = Connect {
+        protocol: Protocol::MQIsdp,
+        keep_alive: 60,
+        client_id: "c",
+        clean_session: true,
+        last_will: None,
+        username: None,
+        password: None,
+    }
+    .into();
+    assert_decode_slice!(
+        Packet::Connect(Connect {
+            protocol: Protocol::MQIsdp,
+            ..
+        }),
+        &packet,
+        17
+    );
+}
+
+#[test]
+fn test_encode_grow() {
+    let mut buf = bytes::BytesMut::from(&[0xff][..]);
+    let packet = Publish {
+        dup: false,
+        qospid: QosPid::AtLeastOnce(Pid::try_from(10).unwrap()),
+        retain: false,
+        topic_name: "a",
+        payload: &[0; 200],
+    }
+    .into();
+    assert_eq!(Ok(208), encode_grow(&packet, &mut buf));
+    assert_eq!(209, buf.len());
+    assert_eq!(Ok(Some(packet)), decode_slice(&buf[1..]));
+
+    let packet = Unsubscribe::new(
+        Pid::new(),
+        vec![String::from_utf8(vec![b'a'; 65536]).unwrap()],
+    );
+    assert!(matches!(
+        encode_grow(&packet.into(), &mut buf),
+        Err(Error::LimitExceeded { .. })
+    ));
+    assert_eq!(209, buf.len());
+}
+
+#[test]
+fn test_encode_all() {
+    let mut buf = bytes::BytesMut::from(&[0xff][..]);
+    let publish = Publish {
+        dup: false,
+        qospid: QosPid::AtMostOnce,
+        retain: false,
+        topic_name: "a",
+        payload: &[1; 200],
+    }
+    .into();
+    let packets = [Packet::Pubrec(Pid::new()), publish, Packet::Pingreq];
+    assert_eq!(Ok(4 + 206 + 2), encode_all(&packets, &mut buf));
+    assert_eq!(213, buf.len());
+    let mut offset = 1;
+    for packet in &packets {
+        assert_eq!(Ok(Some(packet.clone())), decode_slice(&buf[offset..]));
+        offset += packet.encoded_len();
+    }
+    assert_eq!(Ok(0), encode_all(&[], &mut buf));
+
+    let invalid = Unsubscribe::new(
+        Pid::new(),
+        vec![String::from_utf8(vec![b'a'; 65536]).unwrap()],
+    );
+    assert!(matches!(
+        encode_all(&[Packet::Pingreq, invalid.into()], &mut buf),
+        Err(Error::LimitExceeded { .. })
+    ));
+    assert_eq!(213, buf.len());
+}
+
+#[test]
+fn test_encode_publish_from() {
+    let payload = vec![0xab; 20_000];
+    let packet = Publish {
+        dup: true,
+        qospid: QosPid::ExactlyOnce(Pid::try_from(7).unwrap()),
+        retain: true,
+        topic_name: "fw/update",
+        payload: &payload,
+    };
+    let mut expected = vec![0; 20_100];
+    let len = encode_slice(&packet.clone().into(), &mut expected).unwrap();
+
+    let mut out = Vec::new();
+    let header = packet.header();
+    assert_eq!(
+        Ok(len),
+        encode_publish_from(&header, &payload[..], &mut out)
+    );
+    assert_eq!(&expected[..len], &out[..]);
+    assert_eq!(len - payload.len(), header.encoded_len());
+
+    let mut small = [0; 8];
+    assert_eq!(
+        Err(Error::WriteZero {
+            required: 17,
+            available: 8
+        }),
+        encode_publish_header(&header, &mut small)
+    );
+    assert!(matches!(
+        encode_publish_from(&header, &payload[1..], Vec::new()),
+        Err(Error::IoError(std::io::ErrorKind::UnexpectedEof, _))
+    ));
+}
+
+#[test]
+fn test_publish_qos0_dup() {
+    let mut publish = Publish {
+        dup: false,
+        qospid: QosPid::AtMostOnce,
+        retain: true,
+        topic_name: "a",
+        payload: b"",
+    };
+    assert_eq!(
+        Err(Error::InvalidFlags {
+            packet_type: PacketType::Publish,
+            flags: 0b1001
+        }),
+        publish.set_dup(true)
+    );
+    publish.dup = true;
+    let mut buf = [0; 16];
+    assert_eq!(
+        Err(Error::InvalidFlags {
+            packet_type: PacketType::Publish,
+            flags: 0b1001
+        }),
+        encode_slice(&publish.clone().into(), &mut buf)
+    );
+
+    publish.qospid = QosPid::AtLeastOnce(Pid::new());
+    publish.clear_retain_for_forwarding();
+    assert_eq!(Ok(()), publish.set_dup(true));
+    assert_eq!(Ok(7), encode_slice(&publish.clone().into(), &mut buf));
+    assert_eq!(0b00111010, buf[0]);
+    assert!(publish.with_retain(true).retain);
+}
+
+#[test]
+fn test_publish_template() {
+    let payload = vec![0x5a; 200];
+    let publish = Publish {
+        dup: false,
+        qospid: QosPid::AtMostOnce,
+        retain: true,
+        topic_name: "a/b/c",
+        payload: &payload,
+    };
+    let template = PublishTemplate::new(&publish).unwrap();
+    let pid = Pid::try_from(300).unwrap();
+    for (qospid, dup) in [
+        (QosPid::AtMostOnce, false),
+        (QosPid::AtLeastOnce(pid), false),
+        (QosPid::ExactlyOnce(pid), true),
+    ] {
+        let mut expected = [0u8; 256];
+        let packet = Publish {
+            dup,
+            qospid,
+            ..publish.clone()
+        };
+        let len = encode_slice(&packet.into(), &mut expected).unwrap();
+
+        let mut buf = [0u8; 16];
+        let header_len = template.write_header(qospid, dup, &mut buf).unwrap();
+        assert_eq!(template.header_len(qospid), header_len);
+        assert_eq!(&expected[..header_len], &buf[..header_len]);
+        assert_eq!(&expected[header_len..len], template.payload());
+    }
+
+    let mut small = [0u8; 4];
+    assert_eq!(
+        Err(Error::WriteZero {
+            required: 12,
+            available: 4
+        }),
+        template.write_header(QosPid::AtLeastOnce(pid), false, &mut small)
+    );
+    assert!(matches!(
+        template.write_header(QosPid::AtMostOnce, true, &mut small),
+        Err(Error::InvalidFlags { .. })
+    ));
+}
+
+#[test]
+fn test_rewrite_publish_pid() {
+    let mut publish = Publish {
+        dup: false,
+        qospid: QosPid::ExactlyOnce(Pid::new()),
+        retain: false,
+        topic_name: "a/b",
+        payload: b"payload",
+    };
+    let mut buf = [0u8; 32];
+    let len = encode_slice(&publish.clone().into(), &mut buf).unwrap();
+
+    let pid = Pid::try_from(0x1234).unwrap();
+    rewrite_publish_pid(&mut buf[..len], pid, true).unwrap();
+    publish.qospid = QosPid::ExactlyOnce(pid);
+    publish.dup = true;
+    assert_eq!(Ok(Some(publish.clone().into())), decode_slice(&buf[..len]));
+
+    rewrite_publish_pid(&mut buf[..len], pid, false).unwrap();
+    publish.dup = false;
+    assert_eq!(Ok(Some(publish.into())), decode_slice(&buf[..len]));
+
+    let pid = Pid::new();
+    assert_eq!(
+        Err(Error::InvalidFlags {
+            packet_type: PacketType::Publish,
+            flags: 0b0001
+        }),
+        rewrite_publish_pid(&mut [0b00110001, 3, 0, 1, b'a'], pid, false)
+    );
+    assert_eq!(
+        Err(Error::InvalidLength),
+        rewrite_publish_pid(&mut [0b00110010, 4, 0, 1, b'a', 0], pid, false)
+    );
+    assert_eq!(
+        Err(Error::UnexpectedPacketType(PacketType::Puback)),
+        rewrite_publish_pid(&mut [0b01000000, 2, 0, 1], pid, false)
+    );
+}
+
+#[test]
+fn test_retained_message() {
+    use core::time::Duration;
+    let publish = Publish {
+        dup: true,
+        qospid: QosPid::ExactlyOnce(Pid::try_from(3).unwrap()),
+        retain: true,
+        topic_name: "a/b",
+        payload: b"on",
+    };
+    let retained = RetainedMessage {
+        publish,
+        received_at: Duration::from_secs(10),
+        expiry: None,
+    };
+    assert!(!retained.is_expired(Duration::from_secs(1_000_000)));
+    assert_eq!(None, retained.remaining_expiry(Duration::from_secs(5)));
+    let fresh = retained.to_publish(QosPid::AtMostOnce);
+    assert!(!fresh.dup && fresh.retain);
+    assert_eq!(QosPid::AtMostOnce, fresh.qospid);
+    assert_eq!("a/b", fresh.topic_name);
+
+    let retained = RetainedMessage {
+        expiry: Some(Duration::from_secs(5)),
+        ..retained
+    };
+    // Clock going backwards doesn't extend the expiry.
+    assert_eq!(
+        Some(Duration::from_secs(5)),
+        retained.remaining_expiry(Duration::from_secs(1))
+    );
+    assert!(retained.is_expired(Duration::from_secs(15)));
+}
+
+#[test]
+fn test_clamp_qos() {
+    let caps = ServerCapabilities {
+        max_qos: QoS::AtLeastOnce,
+        ..Default::default()
+    };
+    assert_eq!(QoS::AtLeastOnce, caps.clamp_qos(QoS::ExactlyOnce));
+    assert_eq!(Ok(()), caps.check_qos(QoS::AtLeastOnce));
+    assert_eq!(
+        Err(CapabilityError::QosNotSupported(QoS::ExactlyOnce)),
+        caps.check_qos(QoS::ExactlyOnce)
+    );
+
+    let pid = Pid::try_from(7).unwrap();
+    let mut publish = Publish {
+        dup: true,
+        qospid: QosPid::ExactlyOnce(pid),
+        retain: false,
+        topic_name: "a",
+        payload: b"",
+    };
+    caps.clamp_publish(&mut publish);
+    assert_eq!(QosPid::AtLeastOnce(pid), publish.qospid);
+    assert!(publish.dup);
+
+    let mut subscribe = Subscribe {
+        pid,
+        topics: vec![
+            SubscribeTopic::new("a/#", QoS::ExactlyOnce).unwrap(),
+            SubscribeTopic::new("b", QoS::AtMostOnce).unwrap(),
+        ],
+    };
+    caps.clamp_subscribe(&mut subscribe);
+    let qos: Vec<QoS> = subscribe.topics.iter().map(|t| t.options.qos).collect();
+    assert_eq!(vec![QoS::AtLeastOnce, QoS::AtMostOnce], qos);
+}
+
+#[test]
+fn test_redacted_debug() {
+    let will = LastWill {
+        topic: "a/b",
+        message: b"bye",
+        qos: QoS::AtMostOnce,
+        retain: false,
+    };
+    let connect = Connect {
+        protocol: Protocol::MQTT311,
+        keep_alive: 0,
+        client_id: "c",
+        clean_session: true,
+        last_will: Some(will.clone()),
+        username: Some("u"),
+        password: Some(b"pw"),
+    };
+    let debug = format!("{:?}", connect);
+    assert!(debug.contains("message: [98, 121, 101]"), "{}", debug);
+    assert!(debug.contains("password: Some(<2 bytes>)"), "{}", debug);
+    let redacted = format!("{:?}", connect.redacted());
+    assert!(redacted.contains("message: <3 bytes>"), "{}", redacted);
+    assert!(
+        redacted.contains("password: Some(<2 bytes>)"),
+        "{}",
+        redacted
+    );
+    assert_eq!(
+        "LastWill { topic: \"a/b\", message: <3 bytes>, qos: AtMostOnce, retain: false }",
+        format!("{:?}", will.redacted())
+    );
+}
+
+#[test]
+fn test_connect_custom_protocol() {
+    let packet = Packet::Connect(Connect {
+        protocol: Protocol::custom("MQTT", 0x84).unwrap(),
+        keep_alive: 10,
+        client_id: "c",
+        clean_session: true,
+        last_will: None,
+        username: None,
+        password: None,
+    });
+    let mut buf = [0u8; 32];
+    let len = encode_slice(&packet, &mut buf).unwrap();
+    assert_eq!(packet.encoded_len(), len);
+    assert_eq!(
+        &[0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 0x84, 0b10, 0, 10, 0, 1, b'c'],
+        &buf[..len]
+    );
+    assert_eq!(
+        Err(Error::InvalidProtocol("MQTT".into(), 0x84)),
+        decode_slice(&buf[..len])
+    );
+}
+
+#[test]
+fn test_encode_subscribe_iter() {
+    let pid = Pid::try_from(12).unwrap();
+    let topics = ["a/b", "c/+", "#"];
+    let iter = topics.iter().map(|t| (*t, QoS::ExactlyOnce));
+    let mut buf = [0u8; 64];
+    let len = encode_subscribe(pid, iter.clone(), &mut buf).unwrap();
+
+    let subscribe = Subscribe::new(
+        pid,
+        topics
+            .iter()
+            .map(|t| SubscribeTopic::new(t, QoS::ExactlyOnce).unwrap())
+            .collect(),
+    );
+    let mut expected = [0u8; 64];
+    let expected_len = encode_slice(&subscribe.into(), &mut expected).unwrap();
+    assert_eq!(&expected[..expected_len], &buf[..len]);
+
+    assert_eq!(
+        Err(Error::WriteZero {
+            required: len,
+            available: 4
+        }),
+        encode_subscribe(pid, iter, &mut buf[..4])
+    );
+    let misplaced = TopicError {
+        kind: TopicErrorKind::MisplacedWildcard('+'),
+        level: 1,
+    };
+    assert_eq!(
+        Err(Error::InvalidTopicFilter(misplaced)),
+        encode_subscribe(pid, [("a+", QoS::AtMostOnce)].iter().copied(), &mut buf)
+    );
+}
+
+#[test]
+fn test_unsubscribe_builder() {
+    let pid = Pid::try_from(3).unwrap();
+    let unsubscribe = UnsubscribeBuilder::new(pid)
+        .topic("a/#")
+        .unwrap()
+        .topic("b")
+        .unwrap()
+        .topic("a/#")
+        .unwrap()
+        .build();
+    assert_eq!(
+        Some(vec!["a/#", "b"]),
+        unsubscribe.acknowledged_by(pid).map(|t| t.collect())
+    );
+    assert!(unsubscribe.acknowledged_by(Pid::new()).is_none());
+    let misplaced = TopicError {
+        kind: TopicErrorKind::MisplacedWildcard('#'),
+        level: 1,
+    };
+    assert_eq!(
+        Err(Error::InvalidTopicFilter(misplaced)),
+        UnsubscribeBuilder::new(pid).topic("#/a").map(|b| b.build())
+    );
+}
+
+#[test]
+fn test_subscription_option_profiles() {
+    let mut buf = [0u8; 16];
+    for (options, field) in &[
+        (SubscriptionOptions::v5_strict(QoS::AtMostOnce), "no local"),
+        (SubscriptionOptions::bridge(QoS::AtMostOnce), "no local"),
+        (
+            SubscriptionOptions {
+                retain_handling: RetainHandling::DoNotSend,
+                ..QoS::AtMostOnce.into()
+            },
+            "retain handling",
+        ),
+    ] {
+        assert!(!options.is_v3_compatible());
+        let topic = SubscribeTopic::new("a", *options).unwrap();
+        let packet = Subscribe::new(Pid::new(), vec![topic]).into();
+        assert_eq!(
+            Err(Error::UnsupportedOption { field }),
+            encode_slice(&packet, &mut buf)
+        );
+    }
+    let topic = SubscribeTopic::new("a", SubscriptionOptions::v3(QoS::ExactlyOnce)).unwrap();
+    let packet = Subscribe::new(Pid::new(), vec![topic]).into();
+    assert_eq!(Ok(8), encode_slice(&packet, &mut buf));
+}
+
+#[test]
+fn test_mqtt_string_codecs() {
+    let mut buf = vec![0u8; 65540];
+    let long = vec![b'a'; 65535];
+    let mut offset = 1;
+    assert_eq!(Ok(()), write_binary_data(&mut buf, &mut offset, &long));
+    assert_eq!(65538, offset);
+    assert_eq!(
+        Err(Error::WriteZero {
+            required: 65541,
+            available: 65539
+        }),
+        write_mqtt_string(&mut buf[..65539], &mut offset, "a")
+    );
+    assert_eq!(Ok(()), write_mqtt_string(&mut buf, &mut offset, ""));
+    assert_eq!(
+        Err(Error::LimitExceeded {
+            field: "string length",
+            limit: 65535,
+            actual: 65536
+        }),
+        write_binary_data(&mut buf, &mut 0, &[0; 65536])
+    );
+
+    let mut offset = 1;
+    assert_eq!(Ok(&long[..]), read_binary_data(&buf, &mut offset));
+    assert_eq!(Ok(""), read_mqtt_string(&buf, &mut offset));
+    assert_eq!(65540, offset);
+    assert_eq!(
+        Err(Error::InvalidLength),
+        read_binary_data(&buf, &mut 65541)
+    );
+    let invalid = read_mqtt_string(&[0, 1, 0xFF], &mut 0);
+    assert!(matches!(
+        invalid,
+        Err(Error::InvalidString {
+            field: "string",
+            ..
+        })
+    ));
+}
@@ -0,0 +1,531 @@
+use crate::{
+    decoder::{decode_slice_partial, read_length, read_u16, read_u8, Header},
+    *,
+};
+use core::{fmt, ops::Range};
+use std::{format, string::String, vec::Vec};
+
+/// Byte range of an encoded packet, with the field it encodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    /// Position of the field in the buffer.
+    pub range: Range<usize>,
+    /// Field name, like `"remaining length"` or `"topic"`.
+    pub name: &'static str,
+    /// Decoded value of the field.
+    pub value: String,
+}
+
+/// Field that failed to decode, in an [`Explanation`].
+///
+/// [`Explanation`]: struct.Explanation.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    /// Position of the field in the buffer, as far as it could be determined.
+    pub range: Range<usize>,
+    /// Field name, like `"protocol level"` or `"topic"`.
+    pub name: &'static str,
+    pub error: Error,
+}
+
+/// Annotated dump of an encoded packet, returned by [`Packet::explain()`].
+///
+/// Each byte of a valid packet is covered by exactly one [`Annotation`], in order. For a
+/// malformed packet, the annotations stop at the field that failed to decode, which is described
+/// by the [`FieldError`]. The `Display` implementation prints one line per field, similar to a
+/// Wireshark dissection:
+///
+/// ```text
+/// 0..1     32                           fixed header     Publish dup=false qos=1 retain=false
+/// 1..2     0a                           remaining length 10
+/// ```
+///
+/// [`Packet::explain()`]: enum.Packet.html#method.explain
+/// [`Annotation`]: struct.Annotation.html
+/// [`FieldError`]: struct.FieldError.html
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation<'a> {
+    /// The decoded packet, or the field that failed to decode.
+    pub packet: Result<Packet<'a>, FieldError>,
+    /// The encoded packet bytes.
+    pub bytes: &'a [u8],
+    /// One annotation per successfully decoded field, in order.
+    pub annotations: Vec<Annotation>,
+}
+
+impl<'a> Packet<'a> {
+    /// Decode one packet from `buf`, mapping each of its bytes to the field it encodes.
+    ///
+    /// This is meant for debugging interoperability problems. Like [`decode_slice()`], it returns
+    /// `None` if `buf` doesn't contain a full packet yet. A malformed packet is annotated up to
+    /// the field that failed to decode:
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let buf = [0b01000000, 2, 0, 10];
+    /// let explanation = Packet::explain(&buf).unwrap();
+    /// let names: Vec<_> = explanation.annotations.iter().map(|a| a.name).collect();
+    /// assert_eq!(names, ["fixed header", "remaining length", "pid"]);
+    /// println!("{}", explanation);
+    ///
+    /// // A pid of 0.
+    /// let failure = Packet::explain(&[0b01000000, 2, 0, 0]).unwrap().packet.unwrap_err();
+    /// assert_eq!((2..4, "pid", Error::InvalidPid), (failure.range, failure.name, failure.error));
+    /// ```
+    ///
+    /// [`decode_slice()`]: fn.decode_slice.html
+    pub fn explain(buf: &'a [u8]) -> Option<Explanation<'a>> {
+        let (decoded, bytes) = match decode_slice_partial(buf) {
+            Ok(Some((packet, header))) => (Ok(packet), &buf[..header.packet_len()]),
+            Ok(None) => return None,
+            Err(e) => {
+                let len = match (e.header, read_length(buf, 0)) {
+                    (Some(header), _) => header.packet_len(),
+                    (None, Ok(Some((header_len, len)))) => header_len + len,
+                    // The remaining length is malformed.
+                    (None, _) => buf.len().min(5),
+                };
+                (Err(e.error), &buf[..len])
+            }
+        };
+        let mut d = Dissector {
+            bytes,
+            offset: 0,
+            annotations: Vec::new(),
+        };
+        let dissected = d.packet();
+        let packet = match (decoded, dissected) {
+            (Ok(packet), _) => Ok(packet),
+            (Err(_), Err(failure)) => Err(failure),
+            // The dissector rejects everything the decoder rejects, this is only a fallback.
+            (Err(error), Ok(())) => Err(FieldError {
+                range: d.offset..bytes.len(),
+                name: "packet",
+                error,
+            }),
+        };
+        Some(Explanation {
+            packet,
+            bytes,
+            annotations: d.annotations,
+        })
+    }
+}
+
+/// Walks the bytes of a packet, checking each field the same way as the decoder.
+struct Dissector<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    annotations: Vec<Annotation>,
+}
+
+impl<'a> Dissector<'a> {
+    /// Decode a field of `len` bytes with `read`, annotating it with `describe(value)`.
+    fn field<T>(
+        &mut self,
+        len: usize,
+        name: &'static str,
+        read: impl FnOnce(&'a [u8], &mut usize) -> Result<T, Error>,
+        describe: impl FnOnce(&T) -> String,
+    ) -> Result<T, FieldError> {
+        let start = self.offset;
+        let end = (start + len).min(self.bytes.len());
+        let mut offset = start;
+        match read(&self.bytes[..end], &mut offset) {
+            Ok(value) => {
+                let description = describe(&value);
+                self.annotations.push(Annotation {
+                    range: start..offset,
+                    name,
+                    value: description,
+                });
+                self.offset = offset;
+                Ok(value)
+            }
+            Err(error) => Err(FieldError {
+                range: start..end,
+                name,
+                error,
+            }),
+        }
+    }
+
+    /// Length-prefixed data; `read` converts the bytes after the length.
+    fn data<T>(
+        &mut self,
+        name: &'static str,
+        read: impl FnOnce(&'a [u8]) -> Result<T, Error>,
+        describe: impl FnOnce(&T) -> String,
+    ) -> Result<T, FieldError> {
+        let len = self.field(2, "length", read_u16, |len| format!("{}", len))?;
+        let len = usize::from(len);
+        let read = |buf: &'a [u8], offset: &mut usize| {
+            let data = buf
+                .get(*offset..*offset + len)
+                .ok_or(Error::InvalidLength)?;
+            let value = read(data)?;
+            *offset += len;
+            Ok(value)
+        };
+        self.field(len, name, read, describe)
+    }
+
+    /// A string; `field` names it in `InvalidString` errors, like the decoder.
+    fn string(&mut self, name: &'static str, field: &'static str) -> Result<&'a str, FieldError> {
+        let read = |data| {
+            core::str::from_utf8(data).map_err(|error| Error::InvalidString { field, error })
+        };
+        self.data(name, read, |value| format!("{:?}", value))
+    }
+
+    fn binary(&mut self, name: &'static str) -> Result<&'a [u8], FieldError> {
+        self.data(name, Ok, |value| format!("{:?}", value))
+    }
+
+    fn pid(&mut self) -> Result<Pid, FieldError> {
+        self.field(2, "pid", Pid::from_buffer, |pid| format!("{}", pid.get()))
+    }
+
+    fn byte<T>(
+        &mut self,
+        name: &'static str,
+        parse: impl FnOnce(u8) -> Result<T, Error>,
+        describe: impl FnOnce(&T) -> String,
+    ) -> Result<T, FieldError> {
+        let read = |buf: &'a [u8], offset: &mut usize| parse(read_u8(buf, offset)?);
+        self.field(1, name, read, describe)
+    }
+
+    fn packet(&mut self) -> Result<(), FieldError> {
+        let header = self.byte("fixed header", Header::new, |h| {
+            format!(
+                "{:?} dup={} qos={} retain={}",
+                h.typ,
+                h.dup,
+                h.qos.to_u8(),
+                h.retain
+            )
+        })?;
+        let read = |buf: &[u8], offset: &mut usize| match remaining_length::decode(&buf[1..])? {
+            Some((len, size)) => {
+                *offset += size;
+                Ok(len)
+            }
+            None => Err(Error::InvalidLength),
+        };
+        self.field(4, "remaining length", read, |len| format!("{}", len))?;
+        match header.typ {
+            PacketType::Connect => self.connect(),
+            PacketType::Connack => {
+                self.byte("connack flags", Ok, |flags| {
+                    format!("session_present={}", flags & 1 == 1)
+                })?;
+                self.byte("return code", ConnectReturnCode::from_u8, |code| {
+                    format!("{:?}", code)
+                })?;
+                Ok(())
+            }
+            PacketType::Publish => {
+                self.string("topic", "topic name")?;
+                if header.qos != QoS::AtMostOnce {
+                    self.pid()?;
+                }
+                let len = self.bytes.len() - self.offset;
+                let read = |buf: &'a [u8], offset: &mut usize| {
+                    let payload = &buf[*offset..];
+                    *offset += payload.len();
+                    Ok(payload)
+                };
+                self.field(len, "payload", read, |payload| format!("{:?}", payload))?;
+                Ok(())
+            }
+            PacketType::Puback
+            | PacketType::Pubrec
+            | PacketType::Pubrel
+            | PacketType::Pubcomp
+            | PacketType::Unsuback => self.pid().map(drop),
+            PacketType::Subscribe => {
+                self.pid()?;
+                while self.offset < self.bytes.len() {
+                    let start = self.offset;
+                    let filter = self.string("topic filter", "topic filter")?;
+                    TopicFilter::new(filter).map_err(|error| FieldError {
+                        range: start..self.offset,
+                        name: "topic filter",
+                        error,
+                    })?;
+                    self.byte("requested qos", SubscriptionOptions::from_u8, |options| {
+                        format!("{:?}", options.qos)
+                    })?;
+                }
+                Ok(())
+            }
+            PacketType::Suback => {
+                self.pid()?;
+                while self.offset < self.bytes.len() {
+                    let read = SubscribeReturnCodes::from_buffer;
+                    self.field(1, "return code", read, |code| format!("{:?}", code))?;
+                }
+                Ok(())
+            }
+            PacketType::Unsubscribe => {
+                self.pid()?;
+                while self.offset < self.bytes.len() {
+                    self.string("topic filter", "topic filter")?;
+                }
+                Ok(())
+            }
+            PacketType::Pingreq | PacketType::Pingresp | PacketType::Disconnect => Ok(()),
+        }
+    }
+
+    fn connect(&mut self) -> Result<(), FieldError> {
+        let name = self.string("protocol name", "protocol name")?;
+        self.byte(
+            "protocol level",
+            |level| Protocol::new(name, level),
+            |protocol| format!("{}", protocol.level()),
+        )?;
+        let flags_range = self.offset..self.offset + 1;
+        let flags = self.byte("connect flags", Ok, |flags| {
+            format!(
+                "{:#010b} clean_session={} will={} will_qos={} will_retain={} password={} username={}",
+                flags,
+                flags & 0b10 != 0,
+                flags & 0b100 != 0,
+                (flags & 0b11000) >> 3,
+                flags & 0b100000 != 0,
+                flags & 0b1000000 != 0,
+                flags & 0b10000000 != 0
+            )
+        })?;
+        self.field(2, "keep alive", read_u16, |keep_alive| {
+            format!("{}", keep_alive)
+        })?;
+        self.string("client id", "client id")?;
+        if flags & 0b100 != 0 {
+            self.string("will topic", "will topic")?;
+            self.binary("will message")?;
+            QoS::from_u8((flags & 0b11000) >> 3).map_err(|error| FieldError {
+                range: flags_range,
+                name: "connect flags",
+                error,
+            })?;
+        }
+        if flags & 0b10000000 != 0 {
+            self.string("username", "username")?;
+        }
+        if flags & 0b01000000 != 0 {
+            self.binary("password")?;
+        }
+        Ok(())
+    }
+}
+
+/// Hex dump of at most a few bytes of `field`.
+fn hex(field: &[u8]) -> String {
+    // Show at most this many bytes per field.
+    const MAX_BYTES: usize = 8;
+    let mut hex = String::new();
+    for b in field.iter().take(MAX_BYTES) {
+        hex.push_str(&format!("{:02x} ", b));
+    }
+    if field.len() > MAX_BYTES {
+        hex.push('…');
+    }
+    hex
+}
+
+impl fmt::Display for Explanation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for a in &self.annotations {
+            let range = format!("{}..{}", a.range.start, a.range.end);
+            let hex = hex(&self.bytes[a.range.clone()]);
+            writeln!(f, "{:<8} {:<28} {:<16} {}", range, hex, a.name, a.value)?;
+        }
+        if let Err(failure) = &self.packet {
+            let range = format!("{}..{}", failure.range.start, failure.range.end);
+            let hex = hex(&self.bytes[failure.range.clone()]);
+            let error = format!("error: {}", failure.error);
+            writeln!(f, "{:<8} {:<28} {:<16} {}", range, hex, failure.name, error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use std::vec;
+
+    /// Every byte is covered by exactly one annotation, in order.
+    fn check_coverage(buf: &[u8]) {
+        let explanation = Packet::explain(buf).unwrap();
+        assert!(explanation.packet.is_ok(), "{:?}", explanation);
+        let mut end = 0;
+        for a in &explanation.annotations {
+            assert_eq!(end, a.range.start, "{:?}", explanation);
+            end = a.range.end;
+        }
+        assert_eq!(buf.len(), end, "{:?}", explanation);
+    }
+
+    #[test]
+    fn explain_publish() {
+        let buf = [
+            0b00110010, 10, 0, 3, b'a', b'/', b'b', 0, 10, b'h', b'e', b'y', 0xff,
+        ];
+        let explanation = Packet::explain(&buf).unwrap();
+        let annotations: Vec<_> = explanation
+            .annotations
+            .iter()
+            .map(|a| (a.range.clone(), a.name, a.value.as_str()))
+            .collect();
+        assert_eq!(
+            annotations,
+            [
+                (0..1, "fixed header", "Publish dup=false qos=1 retain=false"),
+                (1..2, "remaining length", "10"),
+                (2..4, "length", "3"),
+                (4..7, "topic", "\"a/b\""),
+                (7..9, "pid", "10"),
+                (9..12, "payload", "[104, 101, 121]"),
+            ]
+        );
+        assert_eq!(&buf[..12], explanation.bytes);
+        assert!(explanation
+            .to_string()
+            .starts_with("0..1     32                           fixed header     Publish"));
+        assert_eq!(None, Packet::explain(&buf[..11]));
+    }
+
+    #[test]
+    fn explain_coverage() {
+        let packets: Vec<Packet> = vec![
+            Connect {
+                protocol: Protocol::MQTT311,
+                keep_alive: 30,
+                client_id: "client",
+                clean_session: false,
+                last_will: Some(LastWill {
+                    topic: "will",
+                    message: b"bye",
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                }),
+                username: Some("user"),
+                password: Some(b"pass"),
+            }
+            .into(),
+            Connack {
+                session_present: true,
+                code: ConnectReturnCode::Accepted,
+            }
+            .into(),
+            Packet::Pubrel(Pid::new()),
+            Subscribe::new(
+                Pid::new(),
+                vec![SubscribeTopic::new("a/#", QoS::ExactlyOnce).unwrap()],
+            )
+            .into(),
+            Suback::new(Pid::new(), vec![SubscribeReturnCodes::Failure]).into(),
+            Unsubscribe::new(Pid::new(), vec!["a/+".into(), "b".into()]).into(),
+            Packet::Pingreq,
+        ];
+        for pkt in packets {
+            let mut buf = [0u8; 100];
+            let len = encode_slice(&pkt, &mut buf).unwrap();
+            check_coverage(&buf[..len]);
+        }
+    }
+
+    #[test]
+    fn explain_malformed() {
+        let failure = |buf: &[u8]| {
+            let explanation = Packet::explain(buf).unwrap();
+            let names: Vec<_> = explanation.annotations.iter().map(|a| a.name).collect();
+            let failure = explanation.packet.unwrap_err();
+            (names, failure.range, failure.name, failure.error)
+        };
+        assert_eq!(
+            (vec![], 0..1, "fixed header", Error::UnknownPacketType(0)),
+            failure(&[0, 0])
+        );
+        assert_eq!(
+            (
+                vec!["fixed header"],
+                1..5,
+                "remaining length",
+                Error::MalformedRemainingLength
+            ),
+            failure(&[0b00110000, 0xff, 0xff, 0xff, 0xff])
+        );
+        // Invalid UTF-8 in the topic.
+        let (names, range, name, error) = failure(&[0b00110000, 5, 0, 2, b'a', 0xc0, b'x']);
+        assert_eq!(
+            (
+                vec!["fixed header", "remaining length", "length"],
+                4..6,
+                "topic"
+            ),
+            (names, range, name)
+        );
+        assert!(matches!(
+            error,
+            Error::InvalidString {
+                field: "topic name",
+                ..
+            }
+        ));
+        // A topic longer than the packet.
+        assert_eq!(
+            (
+                vec!["fixed header", "remaining length", "length"],
+                4..5,
+                "topic",
+                Error::InvalidLength
+            ),
+            failure(&[0b00110000, 3, 0, 9, b'a'])
+        );
+        // An unknown protocol level.
+        let connect = [
+            0b00010000, 12, 0, 4, b'M', b'Q', b'T', b'T', 9, 2, 0, 0, 0, 0,
+        ];
+        assert_eq!(
+            (
+                vec![
+                    "fixed header",
+                    "remaining length",
+                    "length",
+                    "protocol name"
+                ],
+                8..9,
+                "protocol level",
+                Error::InvalidProtocol("MQTT".into(), 9)
+            ),
+            failure(&connect)
+        );
+        let explanation = Packet::explain(&connect).unwrap();
+        assert!(explanation
+            .to_string()
+            .ends_with("protocol level   error: unsupported protocol \"MQTT\" level 9\n"));
+    }
+
+    /// The dissector fails with the same error as the decoder.
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn explain_corrupt() {
+        for (name, packet) in test_util::corpus() {
+            for malformed in test_util::corrupt(&packet) {
+                let error = match decode_slice(&malformed.bytes) {
+                    Err(error) => error,
+                    // An overlong topic length can be absorbed by a large payload.
+                    Ok(_) => continue,
+                };
+                let explanation = Packet::explain(&malformed.bytes).unwrap();
+                let failure = explanation.packet.unwrap_err();
+                assert_eq!(error, failure.error, "{} {:?}", name, malformed);
+            }
+        }
+    }
+}
@@ -0,0 +1,489 @@
+//! C API, enabled by the `ffi` feature.
+//!
+//! This exposes [`mqttrs_decode()`] and [`mqttrs_encode()`], working with a flat
+//! [`MqttrsPacket`] struct that can be used from C. Everything is `#[repr(C)]` and free of
+//! generics, so a header can be generated with [cbindgen]:
+//!
+//! ```text
+//! cbindgen --config cbindgen.toml --output mqttrs.h
+//! ```
+//!
+//! To link the codec into C firmware, build a static library with
+//! `cargo rustc --release --features ffi --crate-type staticlib`.
+//!
+//! Decoded packets borrow from the input buffer: all [`MqttrsBytes`] fields point into it, so the
+//! buffer must outlive the `MqttrsPacket`. Nothing is ever allocated or freed on the C side.
+//!
+//! [`mqttrs_decode()`]: fn.mqttrs_decode.html
+//! [`mqttrs_encode()`]: fn.mqttrs_encode.html
+//! [`MqttrsPacket`]: struct.MqttrsPacket.html
+//! [`MqttrsBytes`]: struct.MqttrsBytes.html
+//! [cbindgen]: https://github.com/eqrion/cbindgen
+
+use crate::{decoder::*, subscribe::LimitedVec, *};
+use core::{convert::TryFrom, ptr, slice, str};
+
+/// Borrowed byte slice. `ptr` may be null when `len` is 0.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MqttrsBytes {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl MqttrsBytes {
+    const EMPTY: MqttrsBytes = MqttrsBytes {
+        ptr: ptr::null(),
+        len: 0,
+    };
+
+    fn new(bytes: &[u8]) -> Self {
+        MqttrsBytes {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` readable bytes that outlive `'a`.
+    unsafe fn as_slice<'a>(self) -> &'a [u8] {
+        if self.ptr.is_null() || self.len == 0 {
+            &[]
+        } else {
+            slice::from_raw_parts(self.ptr, self.len)
+        }
+    }
+
+    /// # Safety
+    ///
+    /// Same as `as_slice()`.
+    unsafe fn as_str<'a>(self, field: &'static str) -> Result<&'a str, Error> {
+        str::from_utf8(self.as_slice()).map_err(|error| Error::InvalidString { field, error })
+    }
+}
+
+/// C representation of a [`Packet`](../enum.Packet.html).
+///
+/// Only the fields relevant to `packet_type` are used, the others are zeroed when decoding and
+/// ignored when encoding. Boolean fields are `0` for false and `1` for true.
+///
+/// `Subscribe`, `Suback` and `Unsubscribe` packets carry a list of items. To avoid allocating,
+/// `payload` holds the list in wire format (after the pid), as described in [MQTT 3.8.3],
+/// [MQTT 3.9.3] and [MQTT 3.10.3].
+///
+/// [MQTT 3.8.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc384800442
+/// [MQTT 3.9.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc384800445
+/// [MQTT 3.10.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc384800448
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MqttrsPacket {
+    /// Control packet type, from `1` (Connect) to `14` (Disconnect) as in [MQTT 2.2.1].
+    ///
+    /// [MQTT 2.2.1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718021
+    pub packet_type: u8,
+    /// Packet identifier, `0` if the packet doesn't have one.
+    pub pid: u16,
+    /// Publish QoS.
+    pub qos: u8,
+    /// Publish dup flag.
+    pub dup: u8,
+    /// Publish retain flag.
+    pub retain: u8,
+    /// Publish topic name.
+    pub topic: MqttrsBytes,
+    /// Publish payload, or item list of `Subscribe`/`Suback`/`Unsubscribe`.
+    pub payload: MqttrsBytes,
+    /// Connect protocol level: `4` for MQTT 3.1.1, `3` for MQIsdp.
+    pub protocol_level: u8,
+    pub keep_alive: u16,
+    pub clean_session: u8,
+    pub client_id: MqttrsBytes,
+    pub has_will: u8,
+    pub will_topic: MqttrsBytes,
+    pub will_message: MqttrsBytes,
+    pub will_qos: u8,
+    pub will_retain: u8,
+    pub has_username: u8,
+    pub username: MqttrsBytes,
+    pub has_password: u8,
+    pub password: MqttrsBytes,
+    /// Connack session present flag.
+    pub session_present: u8,
+    /// Connack return code.
+    pub return_code: u8,
+}
+
+impl Default for MqttrsPacket {
+    fn default() -> Self {
+        MqttrsPacket {
+            packet_type: 0,
+            pid: 0,
+            qos: 0,
+            dup: 0,
+            retain: 0,
+            topic: MqttrsBytes::EMPTY,
+            payload: MqttrsBytes::EMPTY,
+            protocol_level: 0,
+            keep_alive: 0,
+            clean_session: 0,
+            client_id: MqttrsBytes::EMPTY,
+            has_will: 0,
+            will_topic: MqttrsBytes::EMPTY,
+            will_message: MqttrsBytes::EMPTY,
+            will_qos: 0,
+            will_retain: 0,
+            has_username: 0,
+            username: MqttrsBytes::EMPTY,
+            has_password: 0,
+            password: MqttrsBytes::EMPTY,
+            session_present: 0,
+            return_code: 0,
+        }
+    }
+}
+
+/// Returned when a pointer argument is null.
+pub const MQTTRS_ERR_NULL: isize = -1;
+/// Returned for any decoding or encoding error not covered by a more specific code.
+pub const MQTTRS_ERR_OTHER: isize = -2;
+/// See [`Error::WriteZero`](../enum.Error.html#variant.WriteZero).
+pub const MQTTRS_ERR_WRITE_ZERO: isize = -3;
+/// See [`Error::InvalidPid`](../enum.Error.html#variant.InvalidPid).
+pub const MQTTRS_ERR_INVALID_PID: isize = -4;
+/// See [`Error::InvalidQos`](../enum.Error.html#variant.InvalidQos).
+pub const MQTTRS_ERR_INVALID_QOS: isize = -5;
+/// See [`Error::InvalidConnectReturnCode`](../enum.Error.html#variant.InvalidConnectReturnCode).
+pub const MQTTRS_ERR_INVALID_CONNECT_RETURN_CODE: isize = -6;
+/// See [`Error::InvalidProtocol`](../enum.Error.html#variant.InvalidProtocol).
+pub const MQTTRS_ERR_INVALID_PROTOCOL: isize = -7;
+/// See [`Error::InvalidLength`](../enum.Error.html#variant.InvalidLength).
+pub const MQTTRS_ERR_INVALID_LENGTH: isize = -9;
+/// See [`Error::InvalidString`](../enum.Error.html#variant.InvalidString).
+pub const MQTTRS_ERR_INVALID_STRING: isize = -10;
+/// See [`Error::LimitExceeded`](../enum.Error.html#variant.LimitExceeded).
+pub const MQTTRS_ERR_LIMIT_EXCEEDED: isize = -11;
+/// See [`Error::UnknownPacketType`](../enum.Error.html#variant.UnknownPacketType). Also returned
+/// when encoding an unknown `packet_type`.
+pub const MQTTRS_ERR_UNKNOWN_PACKET_TYPE: isize = -13;
+/// See [`Error::InvalidFlags`](../enum.Error.html#variant.InvalidFlags).
+pub const MQTTRS_ERR_INVALID_FLAGS: isize = -14;
+/// See [`Error::MalformedRemainingLength`](../enum.Error.html#variant.MalformedRemainingLength).
+pub const MQTTRS_ERR_MALFORMED_REMAINING_LENGTH: isize = -15;
+/// See [`Error::UnexpectedPacketType`](../enum.Error.html#variant.UnexpectedPacketType).
+pub const MQTTRS_ERR_UNEXPECTED_PACKET_TYPE: isize = -16;
+/// See [`Error::InvalidTopicFilter`](../enum.Error.html#variant.InvalidTopicFilter).
+pub const MQTTRS_ERR_INVALID_TOPIC_FILTER: isize = -17;
+/// See [`Error::UnsupportedOption`](../enum.Error.html#variant.UnsupportedOption).
+pub const MQTTRS_ERR_UNSUPPORTED_OPTION: isize = -18;
+/// See [`Error::UnknownSessionFormat`](../enum.Error.html#variant.UnknownSessionFormat).
+pub const MQTTRS_ERR_UNKNOWN_SESSION_FORMAT: isize = -19;
+/// See [`Error::BudgetExceeded`](../enum.Error.html#variant.BudgetExceeded).
+pub const MQTTRS_ERR_BUDGET_EXCEEDED: isize = -20;
+
+/// The codes are the negated `Error::code()`.
+fn error_code(err: &Error) -> isize {
+    match err {
+        #[cfg(feature = "std")]
+        Error::IoError(..) => MQTTRS_ERR_OTHER,
+        _ => -(err.code() as isize),
+    }
+}
+
+/// Decode one packet from `buf`.
+///
+/// Returns the number of bytes consumed if a packet was decoded into `out`, `0` if `buf` doesn't
+/// contain a full packet yet, or a negative `MQTTRS_ERR_*` code.
+///
+/// # Safety
+///
+/// `buf` must point to `len` readable bytes, and `out` to a writable `MqttrsPacket`.
+#[no_mangle]
+pub unsafe extern "C" fn mqttrs_decode(
+    buf: *const u8,
+    len: usize,
+    out: *mut MqttrsPacket,
+) -> isize {
+    if buf.is_null() || out.is_null() {
+        return MQTTRS_ERR_NULL;
+    }
+    let buf = slice::from_raw_parts(buf, len);
+    match decode(buf) {
+        Ok(Some((pkt, consumed))) => {
+            *out = pkt;
+            consumed as isize
+        }
+        Ok(None) => 0,
+        Err(e) => error_code(&e),
+    }
+}
+
+fn decode(buf: &[u8]) -> Result<Option<(MqttrsPacket, usize)>, Error> {
+    let mut offset = 0;
+    let (header, remaining_len) = match read_header(buf, &mut offset)? {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    let body_start = offset;
+    let end = body_start + remaining_len;
+    let packet = read_packet(header, remaining_len, buf, &mut offset)?;
+
+    let mut out = MqttrsPacket {
+        packet_type: header.typ.control_type(),
+        ..MqttrsPacket::default()
+    };
+    match packet {
+        Packet::Connect(c) => {
+            out.protocol_level = c.protocol.level();
+            out.keep_alive = c.keep_alive;
+            out.clean_session = c.clean_session as u8;
+            out.client_id = MqttrsBytes::new(c.client_id.as_bytes());
+            if let Some(w) = c.last_will {
+                out.has_will = 1;
+                out.will_topic = MqttrsBytes::new(w.topic.as_bytes());
+                out.will_message = MqttrsBytes::new(w.message);
+                out.will_qos = w.qos.to_u8();
+                out.will_retain = w.retain as u8;
+            }
+            if let Some(u) = c.username {
+                out.has_username = 1;
+                out.username = MqttrsBytes::new(u.as_bytes());
+            }
+            if let Some(p) = c.password {
+                out.has_password = 1;
+                out.password = MqttrsBytes::new(p);
+            }
+        }
+        Packet::Connack(c) => {
+            out.session_present = c.session_present as u8;
+            out.return_code = c.code.to_u8();
+        }
+        Packet::Publish(p) => {
+            out.dup = p.dup as u8;
+            out.qos = p.qospid.qos().to_u8();
+            out.pid = p.qospid.pid().map_or(0, Pid::get);
+            out.retain = p.retain as u8;
+            out.topic = MqttrsBytes::new(p.topic_name.as_bytes());
+            out.payload = MqttrsBytes::new(p.payload);
+        }
+        Packet::Puback(pid)
+        | Packet::Pubrec(pid)
+        | Packet::Pubrel(pid)
+        | Packet::Pubcomp(pid)
+        | Packet::Unsuback(pid) => out.pid = pid.get(),
+        Packet::Subscribe(Subscribe { pid, .. })
+        | Packet::Suback(Suback { pid, .. })
+        | Packet::Unsubscribe(Unsubscribe { pid, .. }) => {
+            out.pid = pid.get();
+            out.payload = MqttrsBytes::new(&buf[body_start + 2..end]);
+        }
+        Packet::Pingreq | Packet::Pingresp | Packet::Disconnect => (),
+    }
+    Ok(Some((out, end)))
+}
+
+/// Encode `pkt` into `buf`.
+///
+/// Returns the number of bytes written, or a negative `MQTTRS_ERR_*` code.
+///
+/// # Safety
+///
+/// `pkt` must point to a valid `MqttrsPacket` (including the memory referenced by its
+/// `MqttrsBytes` fields), and `buf` to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn mqttrs_encode(
+    pkt: *const MqttrsPacket,
+    buf: *mut u8,
+    len: usize,
+) -> isize {
+    if pkt.is_null() || buf.is_null() {
+        return MQTTRS_ERR_NULL;
+    }
+    let buf = slice::from_raw_parts_mut(buf, len);
+    match encode(&*pkt, buf) {
+        Ok(written) => written as isize,
+        Err(e) => error_code(&e),
+    }
+}
+
+/// # Safety
+///
+/// See `mqttrs_encode()`.
+unsafe fn encode(pkt: &MqttrsPacket, buf: &mut [u8]) -> Result<usize, Error> {
+    let pid = || Pid::try_from(pkt.pid);
+    let packet = match pkt.packet_type {
+        1 => Packet::Connect(Connect {
+            protocol: match pkt.protocol_level {
+                4 => Protocol::MQTT311,
+                3 => Protocol::MQIsdp,
+                l => return Err(Error::InvalidProtocol("".into(), l)),
+            },
+            keep_alive: pkt.keep_alive,
+            client_id: pkt.client_id.as_str("client id")?,
+            clean_session: pkt.clean_session != 0,
+            last_will: match pkt.has_will {
+                0 => None,
+                _ => Some(LastWill {
+                    topic: pkt.will_topic.as_str("will topic")?,
+                    message: pkt.will_message.as_slice(),
+                    qos: QoS::from_u8(pkt.will_qos)?,
+                    retain: pkt.will_retain != 0,
+                }),
+            },
+            username: match pkt.has_username {
+                0 => None,
+                _ => Some(pkt.username.as_str("username")?),
+            },
+            password: match pkt.has_password {
+                0 => None,
+                _ => Some(pkt.password.as_slice()),
+            },
+        }),
+        2 => Packet::Connack(Connack {
+            session_present: pkt.session_present != 0,
+            code: ConnectReturnCode::from_u8(pkt.return_code)?,
+        }),
+        3 => Packet::Publish(Publish {
+            dup: pkt.dup != 0,
+            qospid: match QoS::from_u8(pkt.qos)? {
+                QoS::AtMostOnce => QosPid::AtMostOnce,
+                QoS::AtLeastOnce => QosPid::AtLeastOnce(pid()?),
+                QoS::ExactlyOnce => QosPid::ExactlyOnce(pid()?),
+            },
+            retain: pkt.retain != 0,
+            topic_name: pkt.topic.as_str("topic name")?,
+            payload: pkt.payload.as_slice(),
+        }),
+        4 => Packet::Puback(pid()?),
+        5 => Packet::Pubrec(pid()?),
+        6 => Packet::Pubrel(pid()?),
+        7 => Packet::Pubcomp(pid()?),
+        8 => Packet::Subscribe(Subscribe {
+            pid: pid()?,
+            topics: read_items(pkt.payload.as_slice(), |buf, offset| {
+                let filter = read_str(buf, offset, "topic filter")?;
+                let qos = QoS::from_u8(*buf.get(*offset).ok_or(Error::InvalidLength)?)?;
+                *offset += 1;
+                SubscribeTopic::new(filter, qos)
+            })?,
+        }),
+        9 => Packet::Suback(Suback {
+            pid: pid()?,
+            return_codes: read_items(pkt.payload.as_slice(), SubscribeReturnCodes::from_buffer)?,
+        }),
+        10 => Packet::Unsubscribe(Unsubscribe {
+            pid: pid()?,
+            topics: read_items(pkt.payload.as_slice(), |buf, offset| {
+                Ok(read_str(buf, offset, "topic filter")?.into())
+            })?,
+        }),
+        11 => Packet::Unsuback(pid()?),
+        12 => Packet::Pingreq,
+        13 => Packet::Pingresp,
+        14 => Packet::Disconnect,
+        t => return Err(Error::UnknownPacketType(t)),
+    };
+    encode_slice(&packet, buf)
+}
+
+/// Parse a wire-format item list, as found in the `payload` field.
+fn read_items<T>(
+    buf: &[u8],
+    read: impl Fn(&[u8], &mut usize) -> Result<T, Error>,
+) -> Result<LimitedVec<T>, Error> {
+    let mut items = LimitedVec::new();
+    let mut offset = 0;
+    while offset < buf.len() {
+        #[allow(clippy::let_unit_value)]
+        let _res = items.push(read(buf, &mut offset)?);
+
+        #[cfg(not(feature = "std"))]
+        _res.map_err(|_| crate::subscribe::too_many("items", items.capacity()))?;
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_encode_publish() {
+        let data = [
+            0b0011_0011,
+            9,
+            0,
+            3,
+            b'a',
+            b'/',
+            b'b',
+            0,
+            42,
+            b'h',
+            b'i',
+            0xff,
+        ];
+        let mut pkt = MqttrsPacket::default();
+        let res = unsafe { mqttrs_decode(data.as_ptr(), data.len(), &mut pkt) };
+        assert_eq!(11, res);
+        assert_eq!(
+            (3, 1, 0, 1, 42),
+            (pkt.packet_type, pkt.qos, pkt.dup, pkt.retain, pkt.pid)
+        );
+        assert_eq!(b"a/b", unsafe { pkt.topic.as_slice() });
+        assert_eq!(b"hi", unsafe { pkt.payload.as_slice() });
+
+        let mut out = [0u8; 32];
+        let res = unsafe { mqttrs_encode(&pkt, out.as_mut_ptr(), out.len()) };
+        assert_eq!(11, res);
+        assert_eq!(&data[..11], &out[..11]);
+    }
+
+    #[test]
+    fn decode_encode_subscribe() {
+        let data = [0b1000_0010, 8, 0, 10, 0, 3, b'a', b'/', b'b', 1];
+        let mut pkt = MqttrsPacket::default();
+        let res = unsafe { mqttrs_decode(data.as_ptr(), data.len(), &mut pkt) };
+        assert_eq!(10, res);
+        assert_eq!((8, 10), (pkt.packet_type, pkt.pid));
+        assert_eq!(&data[4..], unsafe { pkt.payload.as_slice() });
+
+        let mut out = [0u8; 32];
+        let res = unsafe { mqttrs_encode(&pkt, out.as_mut_ptr(), out.len()) };
+        assert_eq!(10, res);
+        assert_eq!(&data, &out[..10]);
+
+        // Truncated item list
+        pkt.payload.len -= 1;
+        let res = unsafe { mqttrs_encode(&pkt, out.as_mut_ptr(), out.len()) };
+        assert_eq!(MQTTRS_ERR_INVALID_LENGTH, res);
+    }
+
+    #[test]
+    fn errors() {
+        let mut pkt = MqttrsPacket::default();
+        let mut out = [0u8; 1];
+        unsafe {
+            assert_eq!(0, mqttrs_decode([0x30].as_ptr(), 1, &mut pkt));
+            assert_eq!(
+                MQTTRS_ERR_UNKNOWN_PACKET_TYPE,
+                mqttrs_decode([0, 0].as_ptr(), 2, &mut pkt)
+            );
+            assert_eq!(MQTTRS_ERR_NULL, mqttrs_decode(ptr::null(), 0, &mut pkt));
+            assert_eq!(
+                MQTTRS_ERR_UNKNOWN_PACKET_TYPE,
+                mqttrs_encode(&pkt, out.as_mut_ptr(), 1)
+            );
+            pkt.packet_type = 12;
+            assert_eq!(
+                MQTTRS_ERR_WRITE_ZERO,
+                mqttrs_encode(&pkt, out.as_mut_ptr(), 1)
+            );
+            pkt.packet_type = 4;
+            assert_eq!(
+                MQTTRS_ERR_INVALID_PID,
+                mqttrs_encode(&pkt, out.as_mut_ptr(), 1)
+            );
+        }
+    }
+}
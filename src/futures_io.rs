@@ -0,0 +1,233 @@
+//! Asynchronous packet I/O over any [`AsyncRead`]/[`AsyncWrite`], enabled by the `futures-io`
+//! feature.
+//!
+//! These are the traits of the [futures-io] crate, implemented by smol, async-std, glommio and
+//! many embedded executors, so [`read_packet()`] and [`write_packet()`] work on any runtime.
+//! Tokio streams can use them through the `tokio-util` compat layer.
+//!
+//! [`AsyncRead`]: https://docs.rs/futures-io/0.3/futures_io/trait.AsyncRead.html
+//! [`AsyncWrite`]: https://docs.rs/futures-io/0.3/futures_io/trait.AsyncWrite.html
+//! [futures-io]: https://docs.rs/futures-io
+//! [`read_packet()`]: fn.read_packet.html
+//! [`write_packet()`]: fn.write_packet.html
+
+use crate::{decode_slice, decoder::read_length, encode_slice, Error, Packet};
+use ::futures_io::{AsyncRead, AsyncWrite};
+use core::{future::poll_fn, mem, pin::Pin};
+use std::{
+    io::{self, ErrorKind},
+    vec,
+    vec::Vec,
+};
+
+/// Read one packet from `reader`, using `buf` to hold its bytes.
+///
+/// The fixed header is read first, then exactly the announced number of bytes, so no byte of
+/// the next packet is consumed and `reader` doesn't need to be buffered. Returns `Ok(None)` if
+/// `reader` is at end of file before the first byte of a packet; reaching the end of file in the
+/// middle of a packet is an `UnexpectedEof` [`Error::IoError`].
+///
+/// Packets larger than `max_packet_size` bytes (fixed header included) fail with
+/// [`Error::BudgetExceeded`] before `buf` grows, with the rest of the packet left unread.
+///
+/// [`Error::IoError`]: ../enum.Error.html#variant.IoError
+/// [`Error::BudgetExceeded`]: ../enum.Error.html#variant.BudgetExceeded
+pub async fn read_packet<'b, R>(
+    reader: &mut R,
+    buf: &'b mut Vec<u8>,
+    max_packet_size: usize,
+) -> Result<Option<Packet<'b>>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    buf.clear();
+    buf.push(0);
+    loop {
+        match read_some(reader, &mut buf[..1]).await {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    // Read the remaining length one byte at a time, until the continuation bit is cleared.
+    let (header_len, remaining_len) = loop {
+        buf.push(0);
+        let last = buf.len() - 1;
+        read_exact(reader, &mut buf[last..]).await?;
+        if let Some(lengths) = read_length(buf, 0)? {
+            break lengths;
+        }
+    };
+    let required = header_len + remaining_len;
+    if required > max_packet_size {
+        return Err(Error::BudgetExceeded {
+            budget: max_packet_size,
+            required,
+        });
+    }
+    buf.resize(required, 0);
+    read_exact(reader, &mut buf[header_len..]).await?;
+    decode_slice(buf)
+}
+
+/// Encode `packet` and write it to `writer`.
+///
+/// The writer isn't flushed.
+pub async fn write_packet<W>(writer: &mut W, packet: &Packet<'_>) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; packet.encoded_len()];
+    let len = encode_slice(packet, &mut buf)?;
+    let mut remaining = &buf[..len];
+    while !remaining.is_empty() {
+        match poll_fn(|cx| Pin::new(&mut *writer).poll_write(cx, remaining)).await {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero).into()),
+            Ok(n) => remaining = &remaining[n..],
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+async fn read_some<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, buf)).await
+}
+
+async fn read_exact<R: AsyncRead + Unpin>(reader: &mut R, mut buf: &mut [u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match read_some(reader, buf).await {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => buf = &mut mem::take(&mut buf)[n..],
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use core::{
+        convert::TryFrom,
+        future::Future,
+        task::{Context, Poll, Waker},
+    };
+    use std::{boxed::Box, sync::Arc, task::Wake};
+
+    /// Reads and writes at most 3 bytes at a time, like a slow network.
+    struct Trickle<'a> {
+        read: &'a [u8],
+        written: Vec<u8>,
+    }
+
+    impl AsyncRead for Trickle<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let len = buf.len().min(3);
+            Pin::new(&mut self.read).poll_read(cx, &mut buf[..len])
+        }
+    }
+
+    impl AsyncWrite for Trickle<'_> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let len = buf.len().min(3);
+            Pin::new(&mut self.written).poll_write(cx, &buf[..len])
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    struct Noop;
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Run a future whose I/O is always ready.
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Noop));
+        let mut fut = Box::pin(fut);
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut Context::from_waker(&waker)) {
+                return out;
+            }
+        }
+    }
+
+    #[test]
+    fn partial_io() {
+        let publish = Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::ExactlyOnce(Pid::new()),
+            retain: true,
+            topic_name: "a/b",
+            payload: &[7; 300],
+        });
+        let mut writer = Trickle {
+            read: &[],
+            written: Vec::new(),
+        };
+        block_on(write_packet(&mut writer, &publish)).unwrap();
+        block_on(write_packet(&mut writer, &Packet::Disconnect)).unwrap();
+
+        let mut stream = Trickle {
+            read: &writer.written,
+            written: Vec::new(),
+        };
+        let mut buf = Vec::new();
+        assert_eq!(
+            Ok(Some(publish)),
+            block_on(read_packet(&mut stream, &mut buf, 1024))
+        );
+        assert_eq!(
+            Ok(Some(Packet::Disconnect)),
+            block_on(read_packet(&mut stream, &mut buf, 1024))
+        );
+        assert_eq!(Ok(None), block_on(read_packet(&mut stream, &mut buf, 1024)));
+
+        let mut truncated: &[u8] = &[0b00110000, 10, 0];
+        assert!(matches!(
+            block_on(read_packet(&mut truncated, &mut buf, 1024)),
+            Err(Error::IoError(ErrorKind::UnexpectedEof, _))
+        ));
+    }
+
+    #[test]
+    fn max_packet_size() {
+        // A publish announcing 268MB, followed by nothing.
+        let mut huge: &[u8] = &[0b00110000, 0xff, 0xff, 0xff, 0x7f];
+        let mut buf = Vec::new();
+        assert_eq!(
+            Err(Error::BudgetExceeded {
+                budget: 1024,
+                required: 268_435_460,
+            }),
+            block_on(read_packet(&mut huge, &mut buf, 1024))
+        );
+        assert!(buf.capacity() < 1024);
+
+        let mut exact: &[u8] = &[0b01000000, 2, 0, 1];
+        let puback = Packet::Puback(Pid::try_from(1).unwrap());
+        assert_eq!(
+            Ok(Some(puback)),
+            block_on(read_packet(&mut exact, &mut buf, 4))
+        );
+        let mut over: &[u8] = &[0b01000000, 2, 0, 1];
+        assert!(block_on(read_packet(&mut over, &mut buf, 3)).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::*;
+use core::time::Duration;
+
+/// Keep alive interval in effect for a connection ([MQTT 3.1.2.10]).
+///
+/// This is the value requested by the client in its [`Connect`] packet, unless the server
+/// overrides it. MQTT 5 servers send their value as the Server Keep Alive CONNACK property; MQTT
+/// 3.1.1 servers can only impose it out of band, through [`ServerCapabilities::keep_alive`].
+///
+/// ```
+/// # use mqttrs::*;
+/// # use core::time::Duration;
+/// let mut keep_alive = KeepAlive::new(60);
+/// assert_eq!(Some(Duration::from_secs(60)), keep_alive.interval());
+/// keep_alive.set_server_keep_alive(20);
+/// assert_eq!(20, keep_alive.secs());
+/// assert_eq!(Some(Duration::from_secs(30)), keep_alive.timeout());
+/// ```
+///
+/// [MQTT 3.1.2.10]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028
+/// [`Connect`]: struct.Connect.html
+/// [`ServerCapabilities::keep_alive`]: struct.ServerCapabilities.html#structfield.keep_alive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAlive {
+    requested: u16,
+    server: Option<u16>,
+}
+
+impl KeepAlive {
+    /// Use the keep alive requested by the client, in seconds.
+    pub fn new(requested: u16) -> Self {
+        KeepAlive {
+            requested,
+            server: None,
+        }
+    }
+
+    /// Use the keep alive of `connect`, overridden by the server's if it has one.
+    pub fn negotiate(connect: &Connect, caps: &ServerCapabilities) -> Self {
+        KeepAlive {
+            requested: connect.keep_alive,
+            server: caps.keep_alive,
+        }
+    }
+
+    /// Adopt the keep alive imposed by the server, in seconds.
+    pub fn set_server_keep_alive(&mut self, secs: u16) {
+        self.server = Some(secs);
+    }
+
+    /// Keep alive requested by the client, in seconds.
+    pub fn requested(&self) -> u16 {
+        self.requested
+    }
+
+    /// Keep alive in effect, in seconds. Zero means that the keep alive mechanism is disabled.
+    pub fn secs(&self) -> u16 {
+        self.server.unwrap_or(self.requested)
+    }
+
+    /// Maximum time between two packets sent by the client, or `None` if disabled.
+    pub fn interval(&self) -> Option<Duration> {
+        match self.secs() {
+            0 => None,
+            n => Some(Duration::from_secs(n.into())),
+        }
+    }
+
+    /// Time after which the server disconnects a silent client, one and a half times the interval
+    /// ([MQTT-3.1.2-24]), or `None` if disabled.
+    ///
+    /// [MQTT-3.1.2-24]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028
+    pub fn timeout(&self) -> Option<Duration> {
+        self.interval().map(|i| i + i / 2)
+    }
+}
+
+/// Decides when a client must send a `Pingreq`, and detects a missing `Pingresp`.
+///
+/// Report each sent and received packet with [`sent()`] and [`received()`], and call [`poll()`]
+/// when [`next_wakeup()`] is reached. Times are durations since an arbitrary reference, like the
+/// start of the program.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use core::time::Duration;
+/// let secs = Duration::from_secs;
+/// let mut pings = PingScheduler::new(KeepAlive::new(30), secs(5), secs(0));
+/// pings.sent(&Packet::Pingreq, secs(0));
+/// assert_eq!(None, pings.poll(secs(4)));
+/// pings.received(&Packet::Pingresp);
+///
+/// assert_eq!(Some(secs(30)), pings.next_wakeup());
+/// assert_eq!(Some(PingAction::SendPingreq), pings.poll(secs(30)));
+/// pings.sent(&Packet::Pingreq, secs(30));
+/// assert_eq!(Some(secs(35)), pings.next_wakeup());
+/// assert_eq!(Some(PingAction::PingrespMissing), pings.poll(secs(35)));
+/// assert_eq!(Some(KeepAliveAction::DropConnection), pings.pingresp_timeout(secs(35)));
+/// ```
+///
+/// [`sent()`]: #method.sent
+/// [`received()`]: #method.received
+/// [`poll()`]: #method.poll
+/// [`next_wakeup()`]: #method.next_wakeup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingScheduler {
+    keep_alive: KeepAlive,
+    grace: Duration,
+    last_sent: Duration,
+    /// When the `Pingreq` waiting for a `Pingresp` was sent.
+    ping_sent: Option<Duration>,
+}
+
+/// Action required by a [`PingScheduler`].
+///
+/// [`PingScheduler`]: struct.PingScheduler.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingAction {
+    /// Nothing was sent for a whole keep alive interval: send a `Pingreq`.
+    SendPingreq,
+    /// No `Pingresp` arrived within the grace period: the connection should be closed
+    /// ([MQTT 3.1.2.10]).
+    ///
+    /// [MQTT 3.1.2.10]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028
+    PingrespMissing,
+}
+
+/// What to do with a connection whose peer is silent, returned by
+/// [`PingScheduler::pingresp_timeout()`] and [`KeepAliveMonitor::poll()`].
+///
+/// [`PingScheduler::pingresp_timeout()`]: struct.PingScheduler.html#method.pingresp_timeout
+/// [`KeepAliveMonitor::poll()`]: struct.KeepAliveMonitor.html#method.poll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepAliveAction {
+    /// Send a `Disconnect` with this MQTT 5 reason code, then close the network connection.
+    Disconnect { reason_code: u8 },
+    /// Close the network connection without sending anything.
+    DropConnection,
+}
+
+impl PingScheduler {
+    /// Start scheduling at `now`, waiting at most `grace` for each `Pingresp`.
+    pub fn new(keep_alive: KeepAlive, grace: Duration, now: Duration) -> Self {
+        PingScheduler {
+            keep_alive,
+            grace,
+            last_sent: now,
+            ping_sent: None,
+        }
+    }
+
+    /// Record a packet sent at `now`.
+    pub fn sent(&mut self, packet: &Packet, now: Duration) {
+        self.last_sent = now;
+        if *packet == Packet::Pingreq && self.ping_sent.is_none() {
+            self.ping_sent = Some(now);
+        }
+    }
+
+    /// Record a received packet.
+    pub fn received(&mut self, packet: &Packet) {
+        if *packet == Packet::Pingresp {
+            self.ping_sent = None;
+        }
+    }
+
+    /// Returns true if a `Pingreq` is waiting for its `Pingresp`.
+    pub fn is_waiting(&self) -> bool {
+        self.ping_sent.is_some()
+    }
+
+    /// Next time at which [`poll()`] may return an action, or `None` if keep alive is disabled.
+    ///
+    /// [`poll()`]: #method.poll
+    pub fn next_wakeup(&self) -> Option<Duration> {
+        let ping = self.last_sent + self.keep_alive.interval()?;
+        Some(match self.ping_sent {
+            Some(sent) => ping.min(sent + self.grace),
+            None => ping,
+        })
+    }
+
+    /// Returns the action required at `now`, if any.
+    ///
+    /// While a `Pingreq` is waiting for its `Pingresp`, no other `Pingreq` is requested.
+    pub fn poll(&self, now: Duration) -> Option<PingAction> {
+        let interval = self.keep_alive.interval()?;
+        match self.ping_sent {
+            Some(sent) if now >= sent + self.grace => Some(PingAction::PingrespMissing),
+            Some(_) => None,
+            None if now >= self.last_sent + interval => Some(PingAction::SendPingreq),
+            None => None,
+        }
+    }
+
+    /// Returns the action to take at `now` if the `Pingresp` wasn't received within the grace
+    /// period.
+    ///
+    /// That is always [`KeepAliveAction::DropConnection`]: a `Disconnect` would make the server
+    /// discard the client's will, which is meant for exactly this kind of failure
+    /// ([MQTT 3.1.2.5]).
+    ///
+    /// [`KeepAliveAction::DropConnection`]: enum.KeepAliveAction.html#variant.DropConnection
+    /// [MQTT 3.1.2.5]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028
+    pub fn pingresp_timeout(&self, now: Duration) -> Option<KeepAliveAction> {
+        match self.poll(now)? {
+            PingAction::PingrespMissing => Some(KeepAliveAction::DropConnection),
+            PingAction::SendPingreq => None,
+        }
+    }
+}
+
+/// Detects clients that send nothing for one and a half keep alive intervals, for servers
+/// ([MQTT-3.1.2-24]).
+///
+/// Report each packet received from the client with [`received()`], and call [`poll()`] when
+/// [`deadline()`] is reached. Times are durations since an arbitrary reference, like the start of
+/// the program.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use core::time::Duration;
+/// let secs = Duration::from_secs;
+/// let mut monitor = KeepAliveMonitor::new(KeepAlive::new(10), Protocol::MQTT311, secs(0));
+/// monitor.received(secs(5));
+/// assert_eq!(Some(secs(20)), monitor.deadline());
+/// assert_eq!(None, monitor.poll(secs(19)));
+/// assert_eq!(Some(KeepAliveAction::DropConnection), monitor.poll(secs(20)));
+///
+/// let mqtt5 = KeepAliveMonitor::new(KeepAlive::new(10), Protocol::custom("MQTT", 5)?, secs(0));
+/// assert_eq!(Some(KeepAliveAction::Disconnect { reason_code: 0x8D }), mqtt5.poll(secs(15)));
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [MQTT-3.1.2-24]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028
+/// [`received()`]: #method.received
+/// [`poll()`]: #method.poll
+/// [`deadline()`]: #method.deadline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAliveMonitor {
+    keep_alive: KeepAlive,
+    protocol: Protocol,
+    last_received: Duration,
+}
+
+impl KeepAliveMonitor {
+    /// MQTT 5 reason code of the `Disconnect` sent to a silent client ("Keep Alive timeout").
+    pub const KEEP_ALIVE_TIMEOUT: u8 = 0x8D;
+
+    /// Start monitoring a client connected with `protocol` at `now`.
+    pub fn new(keep_alive: KeepAlive, protocol: Protocol, now: Duration) -> Self {
+        KeepAliveMonitor {
+            keep_alive,
+            protocol,
+            last_received: now,
+        }
+    }
+
+    /// Record a packet received at `now`.
+    pub fn received(&mut self, now: Duration) {
+        self.last_received = now;
+    }
+
+    /// Time after which the client is considered gone, or `None` if keep alive is disabled.
+    pub fn deadline(&self) -> Option<Duration> {
+        Some(self.last_received + self.keep_alive.timeout()?)
+    }
+
+    /// Returns the action to take at `now` if the client has been silent for too long.
+    ///
+    /// MQTT 5 clients, connected with protocol name `MQTT` and level 5 (possibly with the bridge
+    /// bit set), are sent a `Disconnect` with the [`KEEP_ALIVE_TIMEOUT`] reason code. MQTT 3.1.1
+    /// servers can't send a `Disconnect`, so for any other protocol the connection is just
+    /// dropped.
+    ///
+    /// [`KEEP_ALIVE_TIMEOUT`]: #associatedconstant.KEEP_ALIVE_TIMEOUT
+    pub fn poll(&self, now: Duration) -> Option<KeepAliveAction> {
+        if now < self.deadline()? {
+            return None;
+        }
+        Some(match (self.protocol.name(), self.protocol.level() & 0x7f) {
+            ("MQTT", 5) => KeepAliveAction::Disconnect {
+                reason_code: Self::KEEP_ALIVE_TIMEOUT,
+            },
+            _ => KeepAliveAction::DropConnection,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn monitor_protocols() {
+        let secs = Duration::from_secs;
+        let poll = |name, level| {
+            let protocol = Protocol::custom(name, level).unwrap();
+            KeepAliveMonitor::new(KeepAlive::new(10), protocol, secs(0)).poll(secs(15))
+        };
+        let disconnect = Some(KeepAliveAction::Disconnect {
+            reason_code: KeepAliveMonitor::KEEP_ALIVE_TIMEOUT,
+        });
+        assert_eq!(disconnect, poll("MQTT", 5));
+        // MQTT 5 bridge.
+        assert_eq!(disconnect, poll("MQTT", 0x85));
+        // MQTT 3.1.1 bridge, unknown levels and other protocol names.
+        assert_eq!(Some(KeepAliveAction::DropConnection), poll("MQTT", 0x84));
+        assert_eq!(Some(KeepAliveAction::DropConnection), poll("MQTT", 6));
+        assert_eq!(Some(KeepAliveAction::DropConnection), poll("MQIsdp", 5));
+        assert_eq!(Some(KeepAliveAction::DropConnection), poll("MQTT", 4));
+    }
+}
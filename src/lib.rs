@@ -33,7 +33,7 @@
 //! let mut incomplete = encoded.split_at(10).0;
 //! assert_eq!(Ok(None), decode_slice(&mut incomplete));
 //! let mut garbage = BytesMut::from(&[0u8,0,0,0] as &[u8]);
-//! assert_eq!(Err(Error::InvalidHeader), decode_slice(&mut garbage));
+//! assert_eq!(Err(Error::UnknownPacketType(0)), decode_slice(&mut garbage));
 //! ```
 //!
 //! [MQTT 3.1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html
@@ -49,30 +49,119 @@
 #[cfg(feature = "std")]
 extern crate std;
 
+#[cfg(feature = "alloc-stats")]
+pub mod alloc_stats;
+#[cfg(any(test, feature = "quickcheck"))]
+mod arbitrary;
+#[cfg(feature = "async-std")]
+pub mod async_std;
+#[cfg(feature = "broker")]
+pub mod broker;
+mod capabilities;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "std")]
+pub mod conformance;
 mod connect;
 mod decoder;
+#[cfg(feature = "std")]
+mod diff;
 mod encoder;
+#[cfg(feature = "std")]
+mod explain;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "futures-io")]
+pub mod futures_io;
+mod keep_alive;
+pub mod metrics;
+#[cfg(feature = "std")]
+mod notify;
+#[cfg(feature = "std")]
+mod owned;
 mod packet;
+#[cfg(feature = "pcap")]
+pub mod pcap;
 mod publish;
+pub mod remaining_length;
+#[cfg(feature = "std")]
+pub mod replay;
+#[cfg(feature = "std")]
+mod sequencer;
+mod session;
+#[cfg(any(test, feature = "strategy"))]
+pub mod strategy;
+#[cfg(feature = "std")]
+mod stream;
 mod subscribe;
+#[cfg(feature = "std")]
+mod subscriptions;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+mod topic;
+#[cfg(feature = "tracing")]
+mod trace;
+#[cfg(feature = "std")]
+mod transport;
 mod utils;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "std")]
+mod websocket;
 
-// Proptest does not currently support borrowed data in strategies:
-// https://github.com/AltSysrq/proptest/issues/9
-//
-// #[cfg(test)]
-// mod codec_test;
 #[cfg(test)]
+mod codec_test;
+#[cfg(test)]
+#[allow(
+    clippy::bool_assert_comparison,
+    clippy::char_lit_as_u8,
+    clippy::get_first,
+    clippy::match_like_matches_macro,
+    clippy::needless_borrow,
+    clippy::unnecessary_cast,
+    clippy::unnecessary_mut_passed
+)]
 mod decoder_test;
 #[cfg(test)]
+#[allow(unused_imports, clippy::char_lit_as_u8, clippy::iter_cloned_collect)]
 mod encoder_test;
 
 pub use crate::{
-    connect::{Connack, Connect, ConnectReturnCode, LastWill, Protocol},
-    decoder::{clone_packet, decode_slice},
-    encoder::encode_slice,
-    packet::{Packet, PacketType},
-    publish::Publish,
-    subscribe::{Suback, Subscribe, SubscribeReturnCodes, SubscribeTopic, Unsubscribe},
-    utils::{Error, Pid, QoS, QosPid},
+    capabilities::{CapabilityError, ConnackBuilder, ConnectResponse, ServerCapabilities},
+    connect::{
+        Connack, Connect, ConnectError, ConnectReturnCode, Credentials, LastWill, Protocol,
+        Redacted, SessionConfig, SessionExpiry, SessionPresent,
+    },
+    decoder::{
+        clone_packet, decode_publish_header, decode_slice, decode_slice_partial,
+        decode_slice_with_limits, read_binary_data, read_mqtt_string, DecodeLimits, FixedHeader,
+        PartialDecodeError,
+    },
+    encoder::{
+        encode_publish_header, encode_slice, encode_subscribe, write_binary_data, write_mqtt_string,
+    },
+    keep_alive::{KeepAlive, KeepAliveAction, KeepAliveMonitor, PingAction, PingScheduler},
+    packet::{acks_for, Packet, PacketType},
+    publish::{rewrite_publish_pid, Publish, PublishHeader, RetainedMessage},
+    session::SessionState,
+    subscribe::{
+        RetainHandling, Suback, Subscribe, SubscribeReturnCodes, SubscribeTopic,
+        SubscriptionOptions, Unsubscribe, UnsubscribeBuilder,
+    },
+    topic::{FilterLevel, TopicError, TopicErrorKind, TopicFilter},
+    utils::{Error, ErrorClass, OverflowPolicy, Pid, PidCycle, QoS, QosPid, ERROR_CODES},
+};
+#[cfg(feature = "std")]
+pub use crate::{
+    diff::{DiffOptions, Difference},
+    encoder::{encode_all, encode_grow, encode_publish_from},
+    explain::{Annotation, Explanation, FieldError},
+    notify::{Notification, Notifier},
+    owned::{OwnedConnect, OwnedLastWill, OwnedPacket, OwnedPublish},
+    publish::{PublishTemplate, SharedPublish},
+    sequencer::{PidRange, PidSequencer},
+    stream::{DeadlineEvent, StreamDecoder},
+    subscriptions::{PendingUnsubscribes, SubscriptionIds, SubscriptionSet, UnsubscribeResult},
+    transport::{IoTransport, MemoryTransport, PacketSink, PacketStream, QuotaSink},
+    websocket::WebSocketDecoder,
 };
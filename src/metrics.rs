@@ -0,0 +1,163 @@
+//! Per-packet metrics hooks.
+//!
+//! [`encode_slice_with_metrics()`] and [`decode_slice_with_metrics()`] behave like
+//! `encode_slice()` and `decode_slice()`, and report each packet to a [`Metrics`]
+//! implementation. This lets brokers export per-packet-type counters without wrapping the codec:
+//!
+//! ```
+//! # use mqttrs::{*, metrics::*};
+//! #[derive(Default)]
+//! struct Counters {
+//!     decoded: [u64; 15],
+//!     errors: u64,
+//! }
+//! impl Metrics for Counters {
+//!     fn packet(&mut self, event: &PacketEvent) {
+//!         match (event.direction, event.packet_type, event.error) {
+//!             (_, _, Some(_)) => self.errors += 1,
+//!             (Direction::Decode, Some(typ), None) => self.decoded[typ as usize] += 1,
+//!             _ => (),
+//!         }
+//!     }
+//! }
+//!
+//! let mut counters = Counters::default();
+//! decode_slice_with_metrics(&[0b11000000, 0], &mut counters).unwrap();
+//! decode_slice_with_metrics(&[0b11110000, 0], &mut counters).unwrap_err();
+//! assert_eq!(1, counters.decoded[PacketType::Pingreq as usize]);
+//! assert_eq!(1, counters.errors);
+//! ```
+//!
+//! [`Metrics`]: trait.Metrics.html
+//! [`encode_slice_with_metrics()`]: fn.encode_slice_with_metrics.html
+//! [`decode_slice_with_metrics()`]: fn.decode_slice_with_metrics.html
+
+use crate::{
+    decoder::{decode_slice_with_len, Header},
+    *,
+};
+
+/// Whether a packet was encoded or decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Encode,
+    Decode,
+}
+
+/// A packet reported to [`Metrics`].
+///
+/// [`Metrics`]: trait.Metrics.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketEvent {
+    pub direction: Direction,
+    /// The packet type, if known. It is unknown when decoding fails on the fixed header.
+    pub packet_type: Option<PacketType>,
+    /// Encoded size of the packet, or size of the buffer on error.
+    pub size: usize,
+    /// Class of the error, if encoding or decoding failed.
+    pub error: Option<ErrorClass>,
+}
+
+/// Receives a [`PacketEvent`] for each packet encoded or decoded.
+///
+/// [`PacketEvent`]: struct.PacketEvent.html
+pub trait Metrics {
+    fn packet(&mut self, event: &PacketEvent);
+}
+
+impl<M: Metrics + ?Sized> Metrics for &mut M {
+    fn packet(&mut self, event: &PacketEvent) {
+        (**self).packet(event)
+    }
+}
+
+/// Same as `encode_slice()`, reporting the packet to `metrics`.
+pub fn encode_slice_with_metrics(
+    packet: &Packet,
+    buf: &mut [u8],
+    mut metrics: impl Metrics,
+) -> Result<usize, Error> {
+    let res = encode_slice(packet, buf);
+    metrics.packet(&PacketEvent {
+        direction: Direction::Encode,
+        packet_type: Some(packet.get_type()),
+        size: *res.as_ref().unwrap_or(&buf.len()),
+        error: res.as_ref().err().map(Error::class),
+    });
+    res
+}
+
+/// Same as `decode_slice()`, reporting the packet to `metrics`.
+///
+/// Incomplete packets (`Ok(None)`) aren't reported.
+pub fn decode_slice_with_metrics(
+    buf: &[u8],
+    mut metrics: impl Metrics,
+) -> Result<Option<Packet<'_>>, Error> {
+    match decode_slice_with_len(buf) {
+        Ok(None) => Ok(None),
+        Ok(Some((packet, len))) => {
+            metrics.packet(&PacketEvent {
+                direction: Direction::Decode,
+                packet_type: Some(packet.get_type()),
+                size: len,
+                error: None,
+            });
+            Ok(Some(packet))
+        }
+        Err(e) => {
+            metrics.packet(&PacketEvent {
+                direction: Direction::Decode,
+                packet_type: buf
+                    .first()
+                    .and_then(|b| Header::new(*b).ok())
+                    .map(|h| h.typ),
+                size: buf.len(),
+                error: Some(e.class()),
+            });
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    impl Metrics for Vec<PacketEvent> {
+        fn packet(&mut self, event: &PacketEvent) {
+            self.push(*event);
+        }
+    }
+
+    #[test]
+    fn events() {
+        let mut events = Vec::new();
+        let mut buf = [0u8; 4];
+        let pkt = Packet::Puback(Pid::new());
+        assert_eq!(
+            Ok(4),
+            encode_slice_with_metrics(&pkt, &mut buf, &mut events)
+        );
+        assert!(encode_slice_with_metrics(&pkt, &mut buf[..3], &mut events).is_err());
+        assert_eq!(Ok(Some(pkt)), decode_slice_with_metrics(&buf, &mut events));
+        assert_eq!(Ok(None), decode_slice_with_metrics(&buf[..3], &mut events));
+        assert!(decode_slice_with_metrics(&[0b01000000, 2, 0, 0], &mut events).is_err());
+        let event = |direction, size, error| PacketEvent {
+            direction,
+            packet_type: Some(PacketType::Puback),
+            size,
+            error,
+        };
+        assert_eq!(
+            events,
+            [
+                event(Direction::Encode, 4, None),
+                event(Direction::Encode, 3, Some(ErrorClass::LimitExceeded)),
+                event(Direction::Decode, 4, None),
+                event(Direction::Decode, 4, Some(ErrorClass::Malformed)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{Packet, Pid, QosPid, SharedPublish};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Something that happened on a connection, returned by [`Notifier::received()`].
+///
+/// [`Notifier::received()`]: struct.Notifier.html#method.received
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    /// A new message was received. `QoS 2` messages are only notified once, even if the peer
+    /// sends them again before their `Pubrel`.
+    Message(SharedPublish),
+    /// The peer received a `QoS 2` message sent with this pid: a `Pubrel` must be sent.
+    Received(Pid),
+    /// The peer acknowledged a `QoS 1` message or completed a `QoS 2` one sent with this pid.
+    Completed(Pid),
+}
+
+/// Acknowledgement an outgoing message is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Puback,
+    Pubrec,
+    Pubcomp,
+}
+
+/// Turns the packets of a connection into [`Notification`]s, for clients and brokers alike.
+///
+/// Pass each sent packet to [`sent()`] and each received packet to [`received()`]. The notifier
+/// filters out duplicate `QoS 2` messages and acknowledgements of unknown pids, so that
+/// applications only see each message and each completion once.
+///
+/// [`client::Client`] tracks its connection with a `Notifier`. The [`broker`] building blocks
+/// have no per-connection state, so servers keep one `Notifier` per connection themselves.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use core::convert::TryFrom;
+/// let pid = Pid::try_from(7).unwrap();
+/// let publish = Publish { dup: false,
+///                         qospid: QosPid::ExactlyOnce(pid),
+///                         retain: false,
+///                         topic_name: "a/b",
+///                         payload: b"hi" };
+/// let mut notifier = Notifier::new();
+/// let notification = notifier.received(&publish.clone().into());
+/// assert_eq!(Some(Notification::Message(SharedPublish::new(&publish))), notification);
+/// assert_eq!(None, notifier.received(&publish.clone().into()));
+///
+/// let forwarded = Publish { qospid: QosPid::AtLeastOnce(pid), ..publish };
+/// notifier.sent(&forwarded.into());
+/// assert_eq!(Some(Notification::Completed(pid)), notifier.received(&Packet::Puback(pid)));
+/// ```
+///
+/// [`Notification`]: enum.Notification.html
+/// [`client::Client`]: client/struct.Client.html
+/// [`broker`]: broker/index.html
+/// [`sent()`]: #method.sent
+/// [`received()`]: #method.received
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    /// Pids of the messages sent, with the acknowledgement they're waiting for.
+    outgoing: BTreeMap<Pid, Stage>,
+    /// Pids of the `QoS 2` messages received, until their `Pubrel`.
+    incoming: BTreeSet<Pid>,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a packet sent to the peer.
+    pub fn sent(&mut self, packet: &Packet) {
+        match packet {
+            Packet::Publish(publish) => match publish.qospid {
+                QosPid::AtMostOnce => {}
+                QosPid::AtLeastOnce(pid) => {
+                    self.outgoing.insert(pid, Stage::Puback);
+                }
+                QosPid::ExactlyOnce(pid) => {
+                    self.outgoing.insert(pid, Stage::Pubrec);
+                }
+            },
+            Packet::Pubrel(pid) if self.outgoing.contains_key(pid) => {
+                self.outgoing.insert(*pid, Stage::Pubcomp);
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle a packet received from the peer, returning what it means for the application.
+    pub fn received(&mut self, packet: &Packet) -> Option<Notification> {
+        match packet {
+            Packet::Publish(publish) => match publish.qospid {
+                QosPid::ExactlyOnce(pid) if !self.incoming.insert(pid) => None,
+                _ => Some(Notification::Message(SharedPublish::new(publish))),
+            },
+            Packet::Pubrel(pid) => {
+                self.incoming.remove(pid);
+                None
+            }
+            Packet::Puback(pid) => self.complete(*pid, Stage::Puback),
+            // A duplicate `Pubrec` needs the `Pubrel` to be sent again.
+            Packet::Pubrec(pid) => match self.outgoing.get(pid) {
+                Some(Stage::Pubrec) | Some(Stage::Pubcomp) => {
+                    self.outgoing.insert(*pid, Stage::Pubcomp);
+                    Some(Notification::Received(*pid))
+                }
+                _ => None,
+            },
+            Packet::Pubcomp(pid) => self.complete(*pid, Stage::Pubcomp),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the message sent with `pid` isn't acknowledged yet.
+    pub fn is_pending(&self, pid: Pid) -> bool {
+        self.outgoing.contains_key(&pid)
+    }
+
+    /// Number of sent messages that aren't acknowledged yet.
+    pub fn pending(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    /// Forget all messages, when the session ends.
+    pub fn clear(&mut self) {
+        self.outgoing.clear();
+        self.incoming.clear();
+    }
+
+    fn complete(&mut self, pid: Pid, stage: Stage) -> Option<Notification> {
+        if self.outgoing.get(&pid) == Some(&stage) {
+            self.outgoing.remove(&pid);
+            Some(Notification::Completed(pid))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Publish;
+    use core::convert::TryFrom;
+
+    #[test]
+    fn outgoing() {
+        let pid = |n| Pid::try_from(n).unwrap();
+        let publish = |qospid| {
+            Packet::Publish(Publish {
+                dup: false,
+                qospid,
+                retain: false,
+                topic_name: "a",
+                payload: b"x",
+            })
+        };
+        let mut notifier = Notifier::new();
+        notifier.sent(&publish(QosPid::AtMostOnce));
+        notifier.sent(&publish(QosPid::AtLeastOnce(pid(1))));
+        notifier.sent(&publish(QosPid::ExactlyOnce(pid(2))));
+        assert_eq!(2, notifier.pending());
+
+        // Acknowledgements of unknown pids or at the wrong stage are ignored.
+        assert_eq!(None, notifier.received(&Packet::Puback(pid(3))));
+        assert_eq!(None, notifier.received(&Packet::Pubcomp(pid(2))));
+        assert_eq!(None, notifier.received(&Packet::Pubrec(pid(1))));
+        let completed = notifier.received(&Packet::Puback(pid(1)));
+        assert_eq!(Some(Notification::Completed(pid(1))), completed);
+        assert_eq!(None, notifier.received(&Packet::Puback(pid(1))));
+
+        let received = Some(Notification::Received(pid(2)));
+        assert_eq!(received, notifier.received(&Packet::Pubrec(pid(2))));
+        assert_eq!(received, notifier.received(&Packet::Pubrec(pid(2))));
+        assert!(notifier.is_pending(pid(2)));
+        let completed = notifier.received(&Packet::Pubcomp(pid(2)));
+        assert_eq!(Some(Notification::Completed(pid(2))), completed);
+        assert_eq!(0, notifier.pending());
+    }
+}
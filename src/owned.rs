@@ -0,0 +1,368 @@
+use crate::*;
+use core::fmt;
+#[cfg(feature = "derive")]
+use serde::{Deserialize, Serialize};
+use std::{string::String, vec::Vec};
+
+/// Owned counterpart of [`Packet`], which doesn't borrow the buffer it was decoded from.
+///
+/// Decoding borrows topics and payloads from the network buffer. [`Packet::into_owned()`] copies
+/// them, so that a server can look at a packet for free and only pay for a copy when it decides
+/// to keep it. [`as_borrowed()`] gives back a `Packet` to encode it:
+///
+/// ```
+/// # use mqttrs::*;
+/// let buf = vec![0b0011_0000, 5, 0, 1, b'a', b'h', b'i'];
+/// let queued = match decode_slice(&buf)? {
+///     Some(packet @ Packet::Publish(_)) => packet.into_owned(),
+///     other => panic!("unexpected {:?}", other),
+/// };
+/// drop(buf);
+///
+/// let mut out = [0; 7];
+/// assert_eq!(7, encode_slice(&queued.as_borrowed(), &mut out)?);
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`Packet`]: enum.Packet.html
+/// [`Packet::into_owned()`]: enum.Packet.html#method.into_owned
+/// [`as_borrowed()`]: #method.as_borrowed
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+pub enum OwnedPacket {
+    Connect(OwnedConnect),
+    Connack(Connack),
+    Publish(OwnedPublish),
+    Puback(Pid),
+    Pubrec(Pid),
+    Pubrel(Pid),
+    Pubcomp(Pid),
+    Subscribe(Subscribe),
+    Suback(Suback),
+    Unsubscribe(Unsubscribe),
+    Unsuback(Pid),
+    Pingreq,
+    Pingresp,
+    Disconnect,
+}
+
+/// Owned counterpart of [`Connect`].
+///
+/// [`Connect`]: struct.Connect.html
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+pub struct OwnedConnect {
+    pub protocol: Protocol,
+    pub keep_alive: u16,
+    pub client_id: String,
+    pub clean_session: bool,
+    pub last_will: Option<OwnedLastWill>,
+    pub username: Option<String>,
+    #[cfg_attr(feature = "derive", serde(with = "serde_opt_byte_buf"))]
+    pub password: Option<Vec<u8>>,
+}
+
+/// Owned counterpart of [`LastWill`].
+///
+/// [`LastWill`]: struct.LastWill.html
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+pub struct OwnedLastWill {
+    pub topic: String,
+    #[cfg_attr(feature = "derive", serde(with = "serde_byte_buf"))]
+    pub message: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// Owned counterpart of [`Publish`].
+///
+/// Use [`SharedPublish`] instead to send the same message to many connections.
+///
+/// [`Publish`]: struct.Publish.html
+/// [`SharedPublish`]: struct.SharedPublish.html
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+pub struct OwnedPublish {
+    pub dup: bool,
+    pub qospid: QosPid,
+    pub retain: bool,
+    pub topic_name: String,
+    #[cfg_attr(feature = "derive", serde(with = "serde_byte_buf"))]
+    pub payload: Vec<u8>,
+}
+
+impl Packet<'_> {
+    /// Copy the borrowed parts of this packet, to keep it after its buffer is gone.
+    pub fn into_owned(self) -> OwnedPacket {
+        match self {
+            Packet::Connect(c) => OwnedPacket::Connect(c.into_owned()),
+            Packet::Connack(c) => OwnedPacket::Connack(c),
+            Packet::Publish(p) => OwnedPacket::Publish(p.into_owned()),
+            Packet::Puback(pid) => OwnedPacket::Puback(pid),
+            Packet::Pubrec(pid) => OwnedPacket::Pubrec(pid),
+            Packet::Pubrel(pid) => OwnedPacket::Pubrel(pid),
+            Packet::Pubcomp(pid) => OwnedPacket::Pubcomp(pid),
+            Packet::Subscribe(s) => OwnedPacket::Subscribe(s),
+            Packet::Suback(s) => OwnedPacket::Suback(s),
+            Packet::Unsubscribe(u) => OwnedPacket::Unsubscribe(u),
+            Packet::Unsuback(pid) => OwnedPacket::Unsuback(pid),
+            Packet::Pingreq => OwnedPacket::Pingreq,
+            Packet::Pingresp => OwnedPacket::Pingresp,
+            Packet::Disconnect => OwnedPacket::Disconnect,
+        }
+    }
+}
+
+impl OwnedPacket {
+    /// Borrow this as a `Packet`, to encode it.
+    pub fn as_borrowed(&self) -> Packet<'_> {
+        match self {
+            OwnedPacket::Connect(c) => Packet::Connect(c.as_borrowed()),
+            OwnedPacket::Connack(c) => Packet::Connack(*c),
+            OwnedPacket::Publish(p) => Packet::Publish(p.as_borrowed()),
+            OwnedPacket::Puback(pid) => Packet::Puback(*pid),
+            OwnedPacket::Pubrec(pid) => Packet::Pubrec(*pid),
+            OwnedPacket::Pubrel(pid) => Packet::Pubrel(*pid),
+            OwnedPacket::Pubcomp(pid) => Packet::Pubcomp(*pid),
+            OwnedPacket::Subscribe(s) => Packet::Subscribe(s.clone()),
+            OwnedPacket::Suback(s) => Packet::Suback(s.clone()),
+            OwnedPacket::Unsubscribe(u) => Packet::Unsubscribe(u.clone()),
+            OwnedPacket::Unsuback(pid) => Packet::Unsuback(*pid),
+            OwnedPacket::Pingreq => Packet::Pingreq,
+            OwnedPacket::Pingresp => Packet::Pingresp,
+            OwnedPacket::Disconnect => Packet::Disconnect,
+        }
+    }
+}
+
+impl From<Packet<'_>> for OwnedPacket {
+    fn from(packet: Packet) -> Self {
+        packet.into_owned()
+    }
+}
+
+impl Connect<'_> {
+    /// Copy the borrowed parts of this packet.
+    pub fn into_owned(self) -> OwnedConnect {
+        OwnedConnect {
+            protocol: self.protocol,
+            keep_alive: self.keep_alive,
+            client_id: self.client_id.into(),
+            clean_session: self.clean_session,
+            last_will: self.last_will.map(LastWill::into_owned),
+            username: self.username.map(String::from),
+            password: self.password.map(Vec::from),
+        }
+    }
+}
+
+impl OwnedConnect {
+    /// Borrow this as a `Connect`.
+    pub fn as_borrowed(&self) -> Connect<'_> {
+        Connect {
+            protocol: self.protocol,
+            keep_alive: self.keep_alive,
+            client_id: &self.client_id,
+            clean_session: self.clean_session,
+            last_will: self.last_will.as_ref().map(OwnedLastWill::as_borrowed),
+            username: self.username.as_deref(),
+            password: self.password.as_deref(),
+        }
+    }
+}
+
+/// Same as `Connect`, the password isn't shown.
+impl fmt::Debug for OwnedConnect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.as_borrowed(), f)
+    }
+}
+
+impl LastWill<'_> {
+    /// Copy the topic and message of this will.
+    pub fn into_owned(self) -> OwnedLastWill {
+        OwnedLastWill {
+            topic: self.topic.into(),
+            message: self.message.into(),
+            qos: self.qos,
+            retain: self.retain,
+        }
+    }
+}
+
+impl OwnedLastWill {
+    /// Borrow this as a `LastWill`.
+    pub fn as_borrowed(&self) -> LastWill<'_> {
+        LastWill {
+            topic: &self.topic,
+            message: &self.message,
+            qos: self.qos,
+            retain: self.retain,
+        }
+    }
+}
+
+impl Publish<'_> {
+    /// Copy the topic and payload of this packet.
+    pub fn into_owned(self) -> OwnedPublish {
+        OwnedPublish {
+            dup: self.dup,
+            qospid: self.qospid,
+            retain: self.retain,
+            topic_name: self.topic_name.into(),
+            payload: self.payload.into(),
+        }
+    }
+}
+
+impl OwnedPublish {
+    /// Borrow this as a `Publish`.
+    pub fn as_borrowed(&self) -> Publish<'_> {
+        Publish {
+            dup: self.dup,
+            qospid: self.qospid,
+            retain: self.retain,
+            topic_name: &self.topic_name,
+            payload: &self.payload,
+        }
+    }
+}
+
+/// Serialize `Vec<u8>` fields as bytes, like the `&[u8]` fields of `Packet`.
+#[cfg(feature = "derive")]
+mod serde_byte_buf {
+    use serde::{de, Deserializer, Serializer};
+    use std::{fmt, vec::Vec};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        crate::utils::serde_bytes::serialize(bytes, s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        d.deserialize_byte_buf(ByteBufVisitor)
+    }
+
+    /// Accepts bytes, and sequences of `u8` for formats without bytes.
+    struct ByteBufVisitor;
+
+    impl<'de> de::Visitor<'de> for ByteBufVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.into())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+/// Same as `serde_byte_buf`, for `Option<Vec<u8>>` fields.
+#[cfg(feature = "derive")]
+mod serde_opt_byte_buf {
+    use serde::{de, Deserializer, Serializer};
+    use std::{fmt, vec::Vec};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        crate::utils::serde_opt_bytes::serialize(&bytes.as_deref(), s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+        d.deserialize_option(OptionVisitor)
+    }
+
+    struct OptionVisitor;
+
+    impl<'de> de::Visitor<'de> for OptionVisitor {
+        type Value = Option<Vec<u8>>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("optional bytes")
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+            super::serde_byte_buf::deserialize(d).map(Some)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::format;
+
+    fn connect() -> Connect<'static> {
+        Connect {
+            protocol: Protocol::MQTT311,
+            keep_alive: 30,
+            client_id: "c",
+            clean_session: true,
+            last_will: Some(LastWill {
+                topic: "status",
+                message: b"gone",
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            username: Some("user"),
+            password: Some(b"secret"),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let publish = Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: true,
+            topic_name: "a/1",
+            payload: &[1, 2, 3],
+        };
+        let owned = {
+            let topic = format!("a/{}", 1);
+            let payload = vec![1, 2, 3];
+            let borrowed = Publish {
+                topic_name: &topic,
+                payload: &payload,
+                ..publish.clone()
+            };
+            Packet::Publish(borrowed).into_owned()
+        };
+        assert_eq!(Packet::Publish(publish), owned.as_borrowed());
+
+        let owned = OwnedPacket::from(Packet::Connect(connect()));
+        assert_eq!(Packet::Connect(connect()), owned.as_borrowed());
+        assert!(!format!("{:?}", owned).contains("secret"));
+        let pid = Pid::new();
+        for packet in [Packet::Pubrel(pid), Packet::Pingreq, Packet::Unsuback(pid)] {
+            assert_eq!(packet, packet.clone().into_owned().as_borrowed());
+        }
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn serde() {
+        // Same representation as `Packet`.
+        let bytes = postcard::to_allocvec(&Packet::Connect(connect())).unwrap();
+        let owned: OwnedPacket = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(Packet::Connect(connect()), owned.as_borrowed());
+        assert_eq!(bytes, postcard::to_allocvec(&owned).unwrap());
+    }
+}
@@ -1,4 +1,6 @@
 use crate::*;
+#[cfg(feature = "derive")]
+use serde::{Deserialize, Serialize};
 
 /// Base enum for all MQTT packet types.
 ///
@@ -25,13 +27,14 @@ use crate::*;
 /// [`encode()`]: fn.encode.html
 /// [`decode_slice()`]: fn.decode_slice.html
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub enum Packet<'a> {
     /// [MQTT 3.1](http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028)
-    Connect(Connect<'a>),
+    Connect(#[cfg_attr(feature = "derive", serde(borrow))] Connect<'a>),
     /// [MQTT 3.2](http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718033)
     Connack(Connack),
     /// [MQTT 3.3](http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718037)
-    Publish(Publish<'a>),
+    Publish(#[cfg_attr(feature = "derive", serde(borrow))] Publish<'a>),
     /// [MQTT 3.4](http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718043)
     Puback(Pid),
     /// [MQTT 3.5](http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718048)
@@ -80,6 +83,40 @@ impl<'a> Packet<'a> {
     }
 }
 
+/// Return the packets the protocol requires in response to receiving `packet`.
+///
+/// That is a `Puback` for a `QoS 1` publish, a `Pubrec` for a `QoS 2` publish, a `Pubrel` for a
+/// `Pubrec`, a `Pubcomp` for a `Pubrel` and a `Pingresp` for a `Pingreq`. Other packets need no
+/// acknowledgement, or one carrying application decisions like a `Connack` or `Suback`.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use core::convert::TryFrom;
+/// let pid = Pid::try_from(42).unwrap();
+/// let publish = Publish { dup: false,
+///                         qospid: QosPid::ExactlyOnce(pid),
+///                         retain: false,
+///                         topic_name: "to/pic",
+///                         payload: b"payload" };
+/// assert_eq!(vec![Packet::Pubrec(pid)], acks_for(&publish.into()).collect::<Vec<_>>());
+/// assert_eq!(Some(Packet::Pingresp), acks_for(&Packet::Pingreq).next());
+/// assert_eq!(None, acks_for(&Packet::Puback(pid)).next());
+/// ```
+pub fn acks_for(packet: &Packet) -> impl Iterator<Item = Packet<'static>> {
+    let ack = match packet {
+        Packet::Publish(publish) => match publish.qospid {
+            QosPid::AtMostOnce => None,
+            QosPid::AtLeastOnce(pid) => Some(Packet::Puback(pid)),
+            QosPid::ExactlyOnce(pid) => Some(Packet::Pubrec(pid)),
+        },
+        Packet::Pubrec(pid) => Some(Packet::Pubrel(*pid)),
+        Packet::Pubrel(pid) => Some(Packet::Pubcomp(*pid)),
+        Packet::Pingreq => Some(Packet::Pingresp),
+        _ => None,
+    };
+    ack.into_iter()
+}
+
 macro_rules! packet_from_borrowed {
     ($($t:ident),+) => {
         $(
@@ -108,6 +145,7 @@ packet_from!(Suback, Connack, Subscribe, Unsubscribe);
 
 /// Packet type variant, without the associated data.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub enum PacketType {
     Connect,
     Connack,
@@ -124,3 +162,55 @@ pub enum PacketType {
     Pingresp,
     Disconnect,
 }
+impl PacketType {
+    /// Return the type of a packet starting with `byte`, without checking its flags.
+    ///
+    /// This classifies traffic cheaply, for routing, metrics or logging: only the decoder checks
+    /// that the flags are valid for the packet type.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// assert_eq!(Ok(PacketType::Publish), PacketType::from_first_byte(0b0011_1101));
+    /// assert_eq!(Ok(PacketType::Pingreq), PacketType::from_first_byte(0xC0));
+    /// assert_eq!(Err(Error::UnknownPacketType(15)), PacketType::from_first_byte(0xF0));
+    /// ```
+    pub fn from_first_byte(byte: u8) -> Result<Self, Error> {
+        Ok(match byte >> 4 {
+            1 => PacketType::Connect,
+            2 => PacketType::Connack,
+            3 => PacketType::Publish,
+            4 => PacketType::Puback,
+            5 => PacketType::Pubrec,
+            6 => PacketType::Pubrel,
+            7 => PacketType::Pubcomp,
+            8 => PacketType::Subscribe,
+            9 => PacketType::Suback,
+            10 => PacketType::Unsubscribe,
+            11 => PacketType::Unsuback,
+            12 => PacketType::Pingreq,
+            13 => PacketType::Pingresp,
+            14 => PacketType::Disconnect,
+            typ => return Err(Error::UnknownPacketType(typ)),
+        })
+    }
+
+    /// Control type of the packet, the 4 high bits of its first byte.
+    pub fn control_type(self) -> u8 {
+        match self {
+            PacketType::Connect => 1,
+            PacketType::Connack => 2,
+            PacketType::Publish => 3,
+            PacketType::Puback => 4,
+            PacketType::Pubrec => 5,
+            PacketType::Pubrel => 6,
+            PacketType::Pubcomp => 7,
+            PacketType::Subscribe => 8,
+            PacketType::Suback => 9,
+            PacketType::Unsubscribe => 10,
+            PacketType::Unsuback => 11,
+            PacketType::Pingreq => 12,
+            PacketType::Pingresp => 13,
+            PacketType::Disconnect => 14,
+        }
+    }
+}
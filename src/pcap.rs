@@ -0,0 +1,563 @@
+//! Packet capture ingestion, enabled by the `pcap` feature.
+//!
+//! [`read_pcapng()`] extracts MQTT traffic from a [pcapng] capture (as written by Wireshark or
+//! `tcpdump -w`), reassembling TCP streams and splitting them into MQTT packets. This is useful to
+//! analyze captured broker traffic, or to replay it in tests.
+//!
+//! ```no_run
+//! # use mqttrs::{pcap::read_pcapng, Packet};
+//! let capture = std::fs::read("broker.pcapng").unwrap();
+//! for captured in read_pcapng(&capture, 1883).unwrap() {
+//!     match captured.packet() {
+//!         Ok(pkt) => println!("{:?} {} -> {}: {:?}", captured.timestamp, captured.src,
+//!                             captured.dst, pkt),
+//!         Err(e) => println!("undecodable packet: {:?}", e),
+//!     }
+//! }
+//! ```
+//!
+//! Supported link types are Ethernet (with optional 802.1Q tags), raw IP, BSD loopback and Linux
+//! cooked captures, over IPv4 or IPv6. Other interfaces and non-TCP traffic are ignored.
+//!
+//! [`read_pcapng()`]: fn.read_pcapng.html
+//! [pcapng]: https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-02.html
+
+use crate::{decoder::read_header, remaining_length, Error, Packet};
+use core::{convert::TryInto, fmt, time::Duration};
+use std::{
+    collections::HashMap,
+    error::Error as ErrorTrait,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    vec::Vec,
+};
+
+/// One MQTT packet extracted from a capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedPacket {
+    /// Capture time of the TCP segment that completed this packet, since the Unix epoch.
+    pub timestamp: Duration,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// Encoded packet.
+    pub bytes: Vec<u8>,
+}
+
+impl CapturedPacket {
+    /// Decode the packet.
+    ///
+    /// This fails if the captured stream contains invalid MQTT data. Once that happens, the
+    /// remainder of that TCP stream is returned as one last undecodable `CapturedPacket`.
+    pub fn packet(&self) -> Result<Packet<'_>, Error> {
+        crate::decode_slice(&self.bytes)?.ok_or(Error::InvalidLength)
+    }
+}
+
+/// Errors returned by [`read_pcapng()`](fn.read_pcapng.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcapError {
+    /// The data doesn't start with a pcapng Section Header Block.
+    NotPcapng,
+    /// The capture ends in the middle of a block.
+    Truncated,
+    /// A block is inconsistent (bad length, unknown interface id...).
+    InvalidBlock,
+    /// More data than the largest MQTT packet arrived ahead of a missing segment of the TCP
+    /// stream from `src` to `dst`. The stream is dropped.
+    PendingLimitExceeded { src: SocketAddr, dst: SocketAddr },
+}
+
+impl ErrorTrait for PcapError {}
+
+impl fmt::Display for PcapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Extract MQTT packets from a pcapng capture.
+///
+/// Only TCP streams from or to `port` (usually `1883`) are considered. Packets are returned in
+/// capture order.
+///
+/// Out of order segments are buffered until the missing data arrives. A stream with more than
+/// [`remaining_length::MAX`] bytes waiting fails with [`PcapError::PendingLimitExceeded`], rather
+/// than buffering the rest of a capture with a lost segment.
+///
+/// [`remaining_length::MAX`]: ../remaining_length/constant.MAX.html
+/// [`PcapError::PendingLimitExceeded`]: enum.PcapError.html#variant.PendingLimitExceeded
+pub fn read_pcapng(data: &[u8], port: u16) -> Result<Vec<CapturedPacket>, PcapError> {
+    let mut reader = BlockReader {
+        data,
+        pos: 0,
+        big_endian: false,
+        interfaces: Vec::new(),
+    };
+    let mut streams = Streams::new(remaining_length::MAX);
+    if data.len() < 12 || reader.u32_le(0) != BLOCK_SHB {
+        return Err(PcapError::NotPcapng);
+    }
+    while reader.pos < data.len() {
+        if let Some(frame) = reader.next_block()? {
+            if let Some(segment) = parse_frame(frame.link_type, frame.data) {
+                if segment.src.port() == port || segment.dst.port() == port {
+                    streams.push(frame.timestamp, segment)?;
+                }
+            }
+        }
+    }
+    Ok(streams.out)
+}
+
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_IDB: u32 = 1;
+const BLOCK_EPB: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+struct Interface {
+    link_type: u16,
+    /// Timestamp units per second.
+    ts_units: u64,
+}
+
+struct Frame<'a> {
+    link_type: u16,
+    timestamp: Duration,
+    data: &'a [u8],
+}
+
+struct BlockReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+    interfaces: Vec<Interface>,
+}
+
+impl<'a> BlockReader<'a> {
+    fn u32_le(&self, pos: usize) -> u32 {
+        u32::from_le_bytes(self.data[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn u16_at(&self, buf: &[u8], pos: usize) -> u16 {
+        let b = buf[pos..pos + 2].try_into().unwrap();
+        match self.big_endian {
+            true => u16::from_be_bytes(b),
+            false => u16::from_le_bytes(b),
+        }
+    }
+
+    fn u32_at(&self, buf: &[u8], pos: usize) -> u32 {
+        let b = buf[pos..pos + 4].try_into().unwrap();
+        match self.big_endian {
+            true => u32::from_be_bytes(b),
+            false => u32::from_le_bytes(b),
+        }
+    }
+
+    /// Read the next block, returning captured frames.
+    fn next_block(&mut self) -> Result<Option<Frame<'a>>, PcapError> {
+        let data = self.data;
+        if data.len() - self.pos < 12 {
+            return Err(PcapError::Truncated);
+        }
+        // The SHB type is a palindrome, so it can be identified before knowing the endianness.
+        if self.u32_le(self.pos) == BLOCK_SHB {
+            // Each section can have a different endianness and starts a new interface list.
+            self.big_endian = match self.u32_le(self.pos + 8) {
+                BYTE_ORDER_MAGIC => false,
+                m if m.swap_bytes() == BYTE_ORDER_MAGIC => true,
+                _ => return Err(PcapError::InvalidBlock),
+            };
+            self.interfaces.clear();
+        }
+        let typ = self.u32_at(data, self.pos);
+        let len = self.u32_at(data, self.pos + 4) as usize;
+        if len < 12 || len & 3 != 0 {
+            return Err(PcapError::InvalidBlock);
+        }
+        if data.len() - self.pos < len {
+            return Err(PcapError::Truncated);
+        }
+        let body = &data[self.pos + 8..self.pos + len - 4];
+        self.pos += len;
+        match typ {
+            BLOCK_IDB if body.len() >= 8 => {
+                let interface = Interface {
+                    link_type: self.u16_at(body, 0),
+                    ts_units: self.ts_units(&body[8..]),
+                };
+                self.interfaces.push(interface);
+                Ok(None)
+            }
+            BLOCK_EPB if body.len() >= 20 => {
+                let iface = self
+                    .interfaces
+                    .get(self.u32_at(body, 0) as usize)
+                    .ok_or(PcapError::InvalidBlock)?;
+                let ts = (u64::from(self.u32_at(body, 4)) << 32) | u64::from(self.u32_at(body, 8));
+                let nanos = u128::from(ts % iface.ts_units) * 1_000_000_000;
+                let timestamp = Duration::from_secs(ts / iface.ts_units)
+                    + Duration::from_nanos((nanos / u128::from(iface.ts_units)) as u64);
+                let cap_len = self.u32_at(body, 12) as usize;
+                Ok(Some(Frame {
+                    link_type: iface.link_type,
+                    timestamp,
+                    data: body.get(20..20 + cap_len).ok_or(PcapError::InvalidBlock)?,
+                }))
+            }
+            BLOCK_IDB | BLOCK_EPB => Err(PcapError::InvalidBlock),
+            _ => Ok(None),
+        }
+    }
+
+    /// Look for the `if_tsresol` option, defaulting to microseconds.
+    fn ts_units(&self, mut options: &[u8]) -> u64 {
+        while options.len() >= 4 {
+            let code = self.u16_at(options, 0);
+            let len = self.u16_at(options, 2) as usize;
+            let padded = (len + 3) & !3;
+            if code == 0 || options.len() < 4 + padded {
+                break;
+            }
+            if code == 9 && len == 1 {
+                let res = options[4];
+                let exp = u32::from(res & 0x7f);
+                return match res & 0x80 {
+                    0 => 10u64.checked_pow(exp),
+                    _ => 2u64.checked_pow(exp),
+                }
+                .unwrap_or(1_000_000);
+            }
+            options = &options[4 + padded..];
+        }
+        1_000_000
+    }
+}
+
+struct Segment<'a> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    syn: bool,
+    payload: &'a [u8],
+}
+
+fn parse_frame(link_type: u16, frame: &[u8]) -> Option<Segment<'_>> {
+    match link_type {
+        // BSD loopback: 4-byte host-endian address family.
+        0 => parse_ip(frame.get(4..)?),
+        // Ethernet
+        1 => {
+            let mut ethertype = u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?);
+            let mut start = 14;
+            while ethertype == 0x8100 || ethertype == 0x88a8 {
+                ethertype = u16::from_be_bytes(frame.get(start + 2..start + 4)?.try_into().ok()?);
+                start += 4;
+            }
+            match ethertype {
+                0x0800 | 0x86dd => parse_ip(frame.get(start..)?),
+                _ => None,
+            }
+        }
+        // Raw IP
+        101 => parse_ip(frame),
+        // Linux cooked capture
+        113 => parse_ip(frame.get(16..)?),
+        _ => None,
+    }
+}
+
+fn parse_ip(packet: &[u8]) -> Option<Segment<'_>> {
+    let (src, dst, tcp) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes(packet.get(2..4)?.try_into().ok()?));
+            let fragmented = u16::from_be_bytes(packet.get(6..8)?.try_into().ok()?) & 0x3fff != 0;
+            if packet.get(9)? != &6 || fragmented {
+                return None;
+            }
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::from(Ipv4Addr::from(src)),
+                IpAddr::from(Ipv4Addr::from(dst)),
+                packet.get(header_len..total_len)?,
+            )
+        }
+        6 => {
+            let payload_len = usize::from(u16::from_be_bytes(packet.get(4..6)?.try_into().ok()?));
+            if packet.get(6)? != &6 {
+                return None;
+            }
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::from(Ipv6Addr::from(src)),
+                IpAddr::from(Ipv6Addr::from(dst)),
+                packet.get(40..40 + payload_len)?,
+            )
+        }
+        _ => return None,
+    };
+    let header_len = usize::from(tcp.get(12)? >> 4) * 4;
+    Some(Segment {
+        src: SocketAddr::new(src, u16::from_be_bytes(tcp.get(0..2)?.try_into().ok()?)),
+        dst: SocketAddr::new(dst, u16::from_be_bytes(tcp.get(2..4)?.try_into().ok()?)),
+        seq: u32::from_be_bytes(tcp.get(4..8)?.try_into().ok()?),
+        syn: tcp.get(13)? & 0x02 != 0,
+        payload: tcp.get(header_len..)?,
+    })
+}
+
+/// Reassembly state for one direction of a TCP connection.
+#[derive(Default)]
+struct Stream {
+    /// Next expected sequence number, `None` until the first segment.
+    next_seq: Option<u32>,
+    /// In-order data not yet split into packets.
+    buf: Vec<u8>,
+    /// Segments received ahead of `next_seq`.
+    pending: Vec<(u32, Vec<u8>)>,
+    /// Total length of the `pending` segments.
+    pending_len: usize,
+    /// Set after invalid MQTT data, the rest of the stream is ignored.
+    broken: bool,
+}
+
+impl Stream {
+    /// Append the in-order part of `data` to the buffer, returning false if it is out of order.
+    fn append(&mut self, seq: u32, data: &[u8]) -> bool {
+        let next = *self.next_seq.get_or_insert(seq);
+        let ahead = seq.wrapping_sub(next) as i32;
+        if ahead > 0 {
+            return false;
+        }
+        // Skip retransmitted bytes.
+        let skip = ahead.unsigned_abs() as usize;
+        if skip < data.len() {
+            self.buf.extend_from_slice(&data[skip..]);
+            self.next_seq = Some(seq.wrapping_add(data.len() as u32));
+        }
+        true
+    }
+}
+
+struct Streams {
+    streams: HashMap<(SocketAddr, SocketAddr), Stream>,
+    out: Vec<CapturedPacket>,
+    /// Maximum `pending_len` of a stream.
+    max_pending: usize,
+}
+
+impl Streams {
+    fn new(max_pending: usize) -> Self {
+        Streams {
+            streams: HashMap::new(),
+            out: Vec::new(),
+            max_pending,
+        }
+    }
+
+    fn push(&mut self, timestamp: Duration, segment: Segment) -> Result<(), PcapError> {
+        let key = (segment.src, segment.dst);
+        let stream = self.streams.entry(key).or_default();
+        if segment.syn {
+            *stream = Stream::default();
+            stream.next_seq = Some(segment.seq.wrapping_add(1));
+            return Ok(());
+        }
+        if stream.broken || segment.payload.is_empty() {
+            return Ok(());
+        }
+        if !stream.append(segment.seq, segment.payload) {
+            stream.pending_len += segment.payload.len();
+            if stream.pending_len > self.max_pending {
+                self.streams.remove(&key);
+                return Err(PcapError::PendingLimitExceeded {
+                    src: segment.src,
+                    dst: segment.dst,
+                });
+            }
+            stream.pending.push((segment.seq, segment.payload.to_vec()));
+            return Ok(());
+        }
+        // Filling a gap may allow buffered segments to be appended.
+        while let Some(i) = (0..stream.pending.len()).find(|&i| {
+            let (seq, _) = stream.pending[i];
+            seq.wrapping_sub(stream.next_seq.unwrap()) as i32 <= 0
+        }) {
+            let (seq, data) = stream.pending.swap_remove(i);
+            stream.pending_len -= data.len();
+            stream.append(seq, &data);
+        }
+        // Split complete MQTT packets.
+        loop {
+            let mut offset = 0;
+            let len = match read_header(&stream.buf, &mut offset) {
+                Ok(Some((_, remaining_len))) => offset + remaining_len,
+                Ok(None) => break,
+                Err(_) => {
+                    stream.broken = true;
+                    stream.buf.len()
+                }
+            };
+            self.out.push(CapturedPacket {
+                timestamp,
+                src: segment.src,
+                dst: segment.dst,
+                bytes: stream.buf.drain(..len).collect(),
+            });
+            if stream.broken {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use std::vec;
+
+    fn block(typ: u32, body: &[u8]) -> Vec<u8> {
+        let mut body = body.to_vec();
+        body.resize((body.len() + 3) & !3, 0);
+        let len = (body.len() + 12) as u32;
+        let mut b = typ.to_le_bytes().to_vec();
+        b.extend_from_slice(&len.to_le_bytes());
+        b.extend_from_slice(&body);
+        b.extend_from_slice(&len.to_le_bytes());
+        b
+    }
+
+    /// Timestamp, source port, destination port, sequence number, TCP flags and payload.
+    type TestSegment<'a> = (u64, u16, u16, u32, u8, &'a [u8]);
+
+    fn capture(segments: &[TestSegment]) -> Vec<u8> {
+        let mut cap = block(
+            BLOCK_SHB,
+            &[
+                0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            ],
+        );
+        // Ethernet interface with millisecond timestamps
+        cap.extend(block(
+            BLOCK_IDB,
+            &[1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 3, 0, 0, 0, 0, 0, 0, 0],
+        ));
+        for &(ts, sport, dport, seq, flags, payload) in segments {
+            let mut frame = vec![0u8; 12];
+            frame.extend_from_slice(&[0x08, 0x00]);
+            let total = (20 + 20 + payload.len()) as u16;
+            frame.extend_from_slice(&[
+                0x45,
+                0,
+                (total >> 8) as u8,
+                total as u8,
+                0,
+                0,
+                0x40,
+                0,
+                64,
+                6,
+                0,
+                0,
+            ]);
+            frame.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+            frame.extend_from_slice(&sport.to_be_bytes());
+            frame.extend_from_slice(&dport.to_be_bytes());
+            frame.extend_from_slice(&seq.to_be_bytes());
+            frame.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+            frame.extend_from_slice(payload);
+            frame.extend_from_slice(&[0; 6]); // Ethernet padding
+            let mut body = 0u32.to_le_bytes().to_vec();
+            body.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(ts as u32).to_le_bytes());
+            body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            body.extend_from_slice(&frame);
+            cap.extend(block(BLOCK_EPB, &body));
+        }
+        cap
+    }
+
+    #[test]
+    fn reassembly() {
+        let publish = [0x30, 7, 0, 3, b'a', b'/', b'b', b'h', b'i'];
+        let cap = capture(&[
+            (1000, 5000, 1883, 99, 0x02, &[]),               // SYN
+            (1500, 5000, 1883, 100, 0x18, &[0xc0, 0, 0x30]), // Pingreq + start of publish
+            (2000, 5000, 1883, 107, 0x18, &publish[5..]),    // out of order
+            (2500, 5000, 1883, 103, 0x18, &publish[1..7]),   // fills the gap, with overlap
+            (3000, 5000, 1883, 103, 0x18, &publish[1..7]),   // retransmission
+            (3500, 1883, 5000, 1, 0x18, &[0xd0, 0]),         // Pingresp, no SYN seen
+            (4000, 5000, 80, 1, 0x18, &[0xc0, 0]),           // other port
+        ]);
+        let pkts = read_pcapng(&cap, 1883).unwrap();
+        assert_eq!(3, pkts.len());
+        assert_eq!(Ok(Packet::Pingreq), pkts[0].packet());
+        assert_eq!(Duration::from_millis(1500), pkts[0].timestamp);
+        assert_eq!("10.0.0.1:5000".parse(), Ok(pkts[0].src));
+        assert_eq!(&publish[..], &pkts[1].bytes[..]);
+        assert!(matches!(pkts[1].packet(), Ok(Packet::Publish(p)) if p.payload == b"hi"));
+        assert_eq!(Duration::from_millis(2500), pkts[1].timestamp);
+        assert_eq!(Ok(Packet::Pingresp), pkts[2].packet());
+    }
+
+    #[test]
+    fn invalid_stream() {
+        let cap = capture(&[
+            (1, 5000, 1883, 1, 0x18, &[0xc0, 0, 0xff, 0, 1, 2]),
+            (2, 5000, 1883, 7, 0x18, &[0xc0, 0]),
+        ]);
+        let pkts = read_pcapng(&cap, 1883).unwrap();
+        assert_eq!(2, pkts.len());
+        assert_eq!(Ok(Packet::Pingreq), pkts[0].packet());
+        assert_eq!(vec![0xff, 0, 1, 2], pkts[1].bytes);
+        assert_eq!(Err(Error::UnknownPacketType(15)), pkts[1].packet());
+    }
+
+    #[test]
+    fn pending_limit() {
+        let src: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:1883".parse().unwrap();
+        let segment = |seq, payload| Segment {
+            src,
+            dst,
+            seq,
+            syn: false,
+            payload,
+        };
+        let mut streams = Streams::new(4);
+        streams.push(Duration::ZERO, segment(1, &[0xc0])).unwrap();
+        // Two pending segments, one of them filling the limit.
+        streams
+            .push(Duration::ZERO, segment(5, &[0xd0, 0]))
+            .unwrap();
+        streams
+            .push(Duration::ZERO, segment(3, &[0xd0, 0]))
+            .unwrap();
+        assert_eq!(4, streams.streams[&(src, dst)].pending_len);
+        streams.push(Duration::ZERO, segment(2, &[0])).unwrap();
+        assert_eq!(0, streams.streams[&(src, dst)].pending_len);
+        assert_eq!(3, streams.out.len());
+
+        streams
+            .push(Duration::ZERO, segment(10, &[0xc0, 0, 0xc0]))
+            .unwrap();
+        assert_eq!(
+            Err(PcapError::PendingLimitExceeded { src, dst }),
+            streams.push(Duration::ZERO, segment(20, &[0xc0, 0]))
+        );
+        assert!(streams.streams.is_empty());
+    }
+
+    #[test]
+    fn invalid_capture() {
+        assert_eq!(Err(PcapError::NotPcapng), read_pcapng(&[0; 32], 1883));
+        let mut cap = capture(&[(1, 5000, 1883, 1, 0x18, &[0xc0, 0])]);
+        cap.pop();
+        assert_eq!(Err(PcapError::Truncated), read_pcapng(&cap, 1883));
+    }
+}
@@ -1,14 +1,19 @@
 use crate::{decoder::*, encoder::*, *};
+use core::time::Duration;
+#[cfg(feature = "derive")]
+use serde::{Deserialize, Serialize};
 
 /// Publish packet ([MQTT 3.3]).
 ///
 /// [MQTT 3.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718037
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub struct Publish<'a> {
     pub dup: bool,
     pub qospid: QosPid,
     pub retain: bool,
     pub topic_name: &'a str,
+    #[cfg_attr(feature = "derive", serde(borrow, with = "crate::utils::serde_bytes"))]
     pub payload: &'a [u8],
 }
 
@@ -20,7 +25,7 @@ impl<'a> Publish<'a> {
         offset: &mut usize,
     ) -> Result<Self, Error> {
         let payload_end = *offset + remaining_len;
-        let topic_name = read_str(buf, offset)?;
+        let topic_name = read_str(buf, offset, "topic name")?;
 
         let qospid = match header.qos {
             QoS::AtMostOnce => QosPid::AtMostOnce,
@@ -33,34 +38,153 @@ impl<'a> Publish<'a> {
             qospid,
             retain: header.retain,
             topic_name,
-            payload: &buf[*offset..payload_end],
+            payload: buf.get(*offset..payload_end).ok_or(Error::InvalidLength)?,
         })
     }
+    /// Return the [`PublishHeader`] of this packet, with `payload_len` set to the payload's length.
+    ///
+    /// [`PublishHeader`]: struct.PublishHeader.html
+    pub fn header(&self) -> PublishHeader<'a> {
+        PublishHeader {
+            dup: self.dup,
+            qospid: self.qospid,
+            retain: self.retain,
+            topic_name: self.topic_name,
+            payload_len: self.payload.len(),
+        }
+    }
+
+    /// Set the dup flag, which indicates a retransmission ([MQTT 3.3.1.1]).
+    ///
+    /// Fails with `Error::InvalidFlags` if setting it on a `QoS 0` publish ([MQTT-3.3.1-2]).
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let mut publish = Publish { dup: false,
+    ///                             qospid: QosPid::AtMostOnce,
+    ///                             retain: false,
+    ///                             topic_name: "a/b",
+    ///                             payload: b"" };
+    /// assert!(publish.set_dup(true).is_err());
+    /// assert!(!publish.dup);
+    /// ```
+    ///
+    /// [MQTT 3.3.1.1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718038
+    /// [MQTT-3.3.1-2]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718038
+    pub fn set_dup(&mut self, dup: bool) -> Result<(), Error> {
+        if dup && self.qospid == QosPid::AtMostOnce {
+            return Err(Error::InvalidFlags {
+                packet_type: PacketType::Publish,
+                flags: 0b1000 | self.retain as u8,
+            });
+        }
+        self.dup = dup;
+        Ok(())
+    }
+
+    /// Return this publish with the retain flag set to `retain`.
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Clear the retain flag before a broker forwards this publish to an existing subscription.
+    ///
+    /// The retain flag is only kept when sending a retained message because of a new
+    /// subscription ([MQTT-3.3.1-9]). The dup flag isn't propagated either ([MQTT-3.3.1-3]), and
+    /// is set by the broker's own retransmissions with [`set_dup()`].
+    ///
+    /// [MQTT-3.3.1-9]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718038
+    /// [MQTT-3.3.1-3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718038
+    /// [`set_dup()`]: #method.set_dup
+    pub fn clear_retain_for_forwarding(&mut self) {
+        self.retain = false;
+    }
+
+    /// Return the payload as a `&str`, or `Error::InvalidString` for the `"payload"` field.
+    ///
+    /// MQTT 3.1.1 has no payload format indicator, so the decoder never validates payloads. Call
+    /// this when your application expects UTF-8 payloads; it costs a pass over the payload, so
+    /// it's best skipped for large binary ones.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let publish = Publish { dup: false,
+    ///                         qospid: QosPid::AtMostOnce,
+    ///                         retain: false,
+    ///                         topic_name: "a/b",
+    ///                         payload: &[0xc0] };
+    /// assert!(matches!(publish.payload_str(),
+    ///                  Err(Error::InvalidString { field: "payload", .. })));
+    /// ```
+    pub fn payload_str(&self) -> Result<&'a str, Error> {
+        core::str::from_utf8(self.payload).map_err(|error| Error::InvalidString {
+            field: "payload",
+            error,
+        })
+    }
+
+    pub(crate) fn remaining_len(&self) -> usize {
+        self.header().remaining_len()
+    }
+
     pub(crate) fn to_buffer(&self, buf: &mut [u8], offset: &mut usize) -> Result<usize, Error> {
-        // Header
-        let mut header: u8 = match self.qospid {
-            QosPid::AtMostOnce => 0b00110000,
-            QosPid::AtLeastOnce(_) => 0b00110010,
-            QosPid::ExactlyOnce(_) => 0b00110100,
-        };
-        if self.dup {
-            header |= 0b00001000 as u8;
-        };
-        if self.retain {
-            header |= 0b00000001 as u8;
-        };
-        check_remaining(buf, offset, 1)?;
-        write_u8(buf, offset, header)?;
+        let write_len = self.header().to_buffer(buf, offset)?;
+
+        // Payload
+        check_remaining(buf, offset, self.payload.len())?;
+        for &byte in self.payload {
+            write_u8(buf, offset, byte)?;
+        }
+
+        Ok(write_len + self.payload.len())
+    }
+}
 
-        // Length: topic (2+len) + pid (0/2) + payload (len)
-        let length = self.topic_name.len()
+/// A [`Publish`] packet without its payload.
+///
+/// This is used to encode and decode large payloads (like firmware updates) separately from the
+/// rest of the packet, without buffering them. The encoded header must be followed by exactly
+/// `payload_len` bytes of payload. See [`encode_publish_header()`].
+///
+/// [`Publish`]: struct.Publish.html
+/// [`encode_publish_header()`]: fn.encode_publish_header.html
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishHeader<'a> {
+    pub dup: bool,
+    pub qospid: QosPid,
+    pub retain: bool,
+    pub topic_name: &'a str,
+    pub payload_len: usize,
+}
+
+impl PublishHeader<'_> {
+    /// Length: topic (2+len) + pid (0/2) + payload (len)
+    pub(crate) fn remaining_len(&self) -> usize {
+        self.topic_name.len()
             + match self.qospid {
                 QosPid::AtMostOnce => 2,
                 _ => 4,
             }
-            + self.payload.len();
+            + self.payload_len
+    }
 
-        let write_len = write_length(buf, offset, length)? + 1;
+    /// Number of bytes that [`encode_publish_header()`] will write, excluding the payload.
+    ///
+    /// [`encode_publish_header()`]: fn.encode_publish_header.html
+    pub fn encoded_len(&self) -> usize {
+        let len = self.remaining_len();
+        1 + length_len(len).unwrap_or(4) + len - self.payload_len
+    }
+
+    /// Write everything but the payload, returning the number of bytes written.
+    pub(crate) fn to_buffer(&self, buf: &mut [u8], offset: &mut usize) -> Result<usize, Error> {
+        // Header
+        let header = first_byte(self.dup, self.qospid, self.retain)?;
+        check_remaining(buf, offset, 1)?;
+        write_u8(buf, offset, header)?;
+        write_length_field(buf, offset, self.remaining_len())?;
+        check_remaining(buf, offset, self.remaining_len() - self.payload_len)?;
 
         // Topic
         write_string(buf, offset, self.topic_name)?;
@@ -72,11 +196,316 @@ impl<'a> Publish<'a> {
             QosPid::ExactlyOnce(pid) => pid.to_buffer(buf, offset)?,
         }
 
-        // Payload
-        for &byte in self.payload {
-            write_u8(buf, offset, byte)?;
+        Ok(self.encoded_len())
+    }
+}
+
+/// Rewrite the pid and dup flag of an encoded `QoS 1` or `QoS 2` publish, in place.
+///
+/// This avoids encoding the packet again for retransmissions, or to assign each subscriber its
+/// own pid. `buf` must start with the publish packet. Fails with `Error::InvalidFlags` for a
+/// `QoS 0` publish, which has no pid.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use std::convert::TryFrom;
+/// let mut buf = [0b00110010, 5, 0, 1, b'a', 0, 1];
+/// rewrite_publish_pid(&mut buf, Pid::try_from(258).unwrap(), true).unwrap();
+/// assert_eq!(buf, [0b00111010, 5, 0, 1, b'a', 1, 2]);
+/// ```
+pub fn rewrite_publish_pid(buf: &mut [u8], pid: Pid, dup: bool) -> Result<(), Error> {
+    let (header_len, remaining_len) = read_length(buf, 0)?.ok_or(Error::InvalidLength)?;
+    let header = Header::new(buf[0])?;
+    if header.typ != PacketType::Publish {
+        return Err(Error::UnexpectedPacketType(header.typ));
+    }
+    if header.qos == QoS::AtMostOnce {
+        return Err(Error::InvalidFlags {
+            packet_type: PacketType::Publish,
+            flags: buf[0] & 0x0f,
+        });
+    }
+    let mut offset = header_len;
+    let topic_len = read_u16(buf, &mut offset)? as usize;
+    offset += topic_len;
+    if offset + 2 > header_len + remaining_len || offset + 2 > buf.len() {
+        return Err(Error::InvalidLength);
+    }
+    pid.to_buffer(buf, &mut offset)?;
+    match dup {
+        true => buf[0] |= 0b1000,
+        false => buf[0] &= !0b1000,
+    }
+    Ok(())
+}
+
+/// First byte of the fixed header, rejecting the dup flag on `QoS 0`.
+fn first_byte(dup: bool, qospid: QosPid, retain: bool) -> Result<u8, Error> {
+    let mut header: u8 = match qospid {
+        QosPid::AtMostOnce => 0b00110000,
+        QosPid::AtLeastOnce(_) => 0b00110010,
+        QosPid::ExactlyOnce(_) => 0b00110100,
+    };
+    if dup {
+        header |= 0b00001000_u8;
+    };
+    if retain {
+        header |= 0b00000001_u8;
+    };
+    if dup && qospid == QosPid::AtMostOnce {
+        return Err(Error::InvalidFlags {
+            packet_type: PacketType::Publish,
+            flags: header & 0b1111,
+        });
+    }
+    Ok(header)
+}
+
+/// A `Publish` whose topic is encoded once, to be sent to many subscribers.
+///
+/// A broker forwarding an incoming message usually sends the same topic and payload to each
+/// subscriber, with a different pid and QoS. `PublishTemplate` encodes the topic when created,
+/// and [`write_header()`] only stamps the fixed header and pid before copying it. The payload
+/// isn't copied at all: send the header followed by [`payload()`], for example with a vectored
+/// write.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use std::convert::TryFrom;
+/// let incoming = Publish { dup: false,
+///                          qospid: QosPid::AtLeastOnce(Pid::try_from(7).unwrap()),
+///                          retain: false,
+///                          topic_name: "sensors/1",
+///                          payload: b"21.5" };
+/// let template = PublishTemplate::new(&incoming).unwrap();
+/// let mut buf = [0u8; 32];
+/// for qospid in [QosPid::AtMostOnce, QosPid::ExactlyOnce(Pid::new())] {
+///     let len = template.write_header(qospid, false, &mut buf).unwrap();
+///     let mut packet = buf[..len].to_vec();
+///     packet.extend_from_slice(template.payload());
+///     assert!(matches!(decode_slice(&packet), Ok(Some(Packet::Publish(p))) if p.qospid == qospid));
+/// }
+/// ```
+///
+/// [`write_header()`]: #method.write_header
+/// [`payload()`]: #method.payload
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishTemplate<'a> {
+    retain: bool,
+    /// Length-prefixed topic.
+    topic: std::vec::Vec<u8>,
+    payload: &'a [u8],
+}
+
+#[cfg(feature = "std")]
+impl<'a> PublishTemplate<'a> {
+    /// Encode the topic of `publish`, keeping its retain flag and borrowing its payload.
+    ///
+    /// Fails with `Error::LimitExceeded` if the topic is too long, or if the packet would be too
+    /// long once a pid is added.
+    pub fn new(publish: &Publish<'a>) -> Result<Self, Error> {
+        let mut topic = std::vec![0; 2 + publish.topic_name.len()];
+        write_string(&mut topic, &mut 0, publish.topic_name)?;
+        length_len(topic.len() + 2 + publish.payload.len())?;
+        Ok(PublishTemplate {
+            retain: publish.retain,
+            topic,
+            payload: publish.payload,
+        })
+    }
+
+    /// The payload, to send after each header.
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Number of bytes that [`write_header()`] will write for `qospid`.
+    ///
+    /// [`write_header()`]: #method.write_header
+    pub fn header_len(&self, qospid: QosPid) -> usize {
+        let remaining_len = self.remaining_len(qospid);
+        1 + length_len(remaining_len).unwrap_or(4) + remaining_len - self.payload.len()
+    }
+
+    fn remaining_len(&self, qospid: QosPid) -> usize {
+        let pid_len = match qospid {
+            QosPid::AtMostOnce => 0,
+            _ => 2,
+        };
+        self.topic.len() + pid_len + self.payload.len()
+    }
+
+    /// Write the fixed header, topic and pid of the packet for one subscriber, returning the
+    /// number of bytes written.
+    ///
+    /// Fails with `Error::InvalidFlags` if `dup` is set for `QoS 0`.
+    pub fn write_header(&self, qospid: QosPid, dup: bool, buf: &mut [u8]) -> Result<usize, Error> {
+        let header = first_byte(dup, qospid, self.retain)?;
+        let len = self.header_len(qospid);
+        if buf.len() < len {
+            return Err(Error::WriteZero {
+                required: len,
+                available: buf.len(),
+            });
+        }
+        let mut offset = 0;
+        write_u8(buf, &mut offset, header)?;
+        write_length_field(buf, &mut offset, self.remaining_len(qospid))?;
+        buf[offset..offset + self.topic.len()].copy_from_slice(&self.topic);
+        offset += self.topic.len();
+        if let Some(pid) = qospid.pid() {
+            pid.to_buffer(buf, &mut offset)?;
         }
+        Ok(offset)
+    }
+}
 
-        Ok(write_len)
+/// A `Publish` with reference-counted topic and payload, cheap to clone.
+///
+/// A broker fanning a message out to many subscribers can clone this for each of them for the
+/// cost of two reference count increments, and use [`as_publish()`] to encode it.
+///
+/// ```
+/// # use mqttrs::*;
+/// let publish = Publish { dup: false,
+///                         qospid: QosPid::AtMostOnce,
+///                         retain: false,
+///                         topic_name: "a/b",
+///                         payload: b"hello" };
+/// let shared = SharedPublish::new(&publish);
+/// let copies = vec![shared.clone(); 10_000];
+/// assert_eq!(publish, copies[9_999].as_publish());
+/// ```
+///
+/// [`as_publish()`]: #method.as_publish
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedPublish {
+    pub dup: bool,
+    pub qospid: QosPid,
+    pub retain: bool,
+    pub topic_name: std::sync::Arc<str>,
+    pub payload: bytes::Bytes,
+}
+
+#[cfg(feature = "std")]
+impl SharedPublish {
+    /// Copy the topic and payload of `publish` once.
+    pub fn new(publish: &Publish) -> Self {
+        SharedPublish {
+            dup: publish.dup,
+            qospid: publish.qospid,
+            retain: publish.retain,
+            topic_name: publish.topic_name.into(),
+            payload: bytes::Bytes::copy_from_slice(publish.payload),
+        }
+    }
+
+    /// Create a `SharedPublish` from a `publish` decoded from `buf`, sharing the payload with
+    /// `buf` instead of copying it.
+    ///
+    /// Panics if `publish.payload` doesn't point into `buf`.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// # use bytes::Bytes;
+    /// let buf = Bytes::from_static(&[0b00110000, 7, 0, 3, b'a', b'/', b'b', b'h', b'i']);
+    /// let publish = match decode_slice(&buf) {
+    ///     Ok(Some(Packet::Publish(p))) => p,
+    ///     other => panic!("{:?}", other),
+    /// };
+    /// let shared = SharedPublish::from_buffer(&buf, &publish);
+    /// assert_eq!(shared.payload.as_ptr(), buf[7..].as_ptr());
+    /// ```
+    pub fn from_buffer(buf: &bytes::Bytes, publish: &Publish) -> Self {
+        SharedPublish {
+            payload: buf.slice_ref(publish.payload),
+            ..SharedPublish::new(&Publish {
+                payload: &[],
+                ..publish.clone()
+            })
+        }
+    }
+
+    /// Borrow this as a `Publish`, to encode it.
+    pub fn as_publish(&self) -> Publish<'_> {
+        Publish {
+            dup: self.dup,
+            qospid: self.qospid,
+            retain: self.retain,
+            topic_name: &self.topic_name,
+            payload: &self.payload,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<&Publish<'_>> for SharedPublish {
+    fn from(publish: &Publish) -> Self {
+        SharedPublish::new(publish)
+    }
+}
+
+/// A retained message, as stored by a broker.
+///
+/// Times are `Duration`s since an arbitrary reference (like in [`replay`]), so this works
+/// without `std`.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use core::time::Duration;
+/// let publish = Publish { dup: false,
+///                         qospid: QosPid::AtMostOnce,
+///                         retain: true,
+///                         topic_name: "status",
+///                         payload: b"online" };
+/// let retained = RetainedMessage { publish,
+///                                  received_at: Duration::from_secs(100),
+///                                  expiry: Some(Duration::from_secs(60)) };
+/// assert!(!retained.is_expired(Duration::from_secs(150)));
+/// assert_eq!(Some(Duration::from_secs(10)), retained.remaining_expiry(Duration::from_secs(150)));
+/// assert!(retained.is_expired(Duration::from_secs(160)));
+/// ```
+///
+/// [`replay`]: replay/index.html
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetainedMessage<'a> {
+    pub publish: Publish<'a>,
+    /// When the broker received the message.
+    pub received_at: Duration,
+    /// How long the message stays retained, if it expires.
+    pub expiry: Option<Duration>,
+}
+
+impl RetainedMessage<'_> {
+    /// Returns true if the message expired at time `now`.
+    pub fn is_expired(&self, now: Duration) -> bool {
+        self.remaining_expiry(now) == Some(Duration::from_secs(0))
+    }
+
+    /// Time left before the message expires, or `None` if it doesn't expire.
+    pub fn remaining_expiry(&self, now: Duration) -> Option<Duration> {
+        let elapsed = now.checked_sub(self.received_at).unwrap_or_default();
+        self.expiry
+            .map(|e| e.checked_sub(elapsed).unwrap_or_default())
+    }
+
+    /// Return the publish to send to a new subscriber, with the retain flag set and the given
+    /// `qospid` ([MQTT-3.3.1-8]).
+    ///
+    /// MQTT 3.1.1 publishes have no message expiry interval, so the remaining expiry isn't sent.
+    /// Check [`is_expired()`] before delivering the message.
+    ///
+    /// [MQTT-3.3.1-8]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718038
+    /// [`is_expired()`]: #method.is_expired
+    pub fn to_publish(&self, qospid: QosPid) -> Publish<'_> {
+        Publish {
+            dup: false,
+            qospid,
+            retain: true,
+            topic_name: self.publish.topic_name,
+            payload: self.publish.payload,
+        }
     }
 }
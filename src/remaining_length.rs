@@ -0,0 +1,136 @@
+//! Remaining length field of the fixed header ([MQTT 2.2.3]).
+//!
+//! The remaining length is the number of bytes following the fixed header, encoded on 1 to 4
+//! bytes with 7 bits per byte, least significant first. This lets proxies and transports find
+//! packet boundaries without decoding packets:
+//!
+//! ```
+//! # use mqttrs::remaining_length;
+//! let mut buf = [0; 4];
+//! assert_eq!(Ok(2), remaining_length::encode(321, &mut buf));
+//! assert_eq!([0xC1, 0x02], buf[..2]);
+//! assert_eq!(Ok(Some((321, 2))), remaining_length::decode(&buf));
+//! // The second byte is missing.
+//! assert_eq!(Ok(None), remaining_length::decode(&buf[..1]));
+//! ```
+//!
+//! [MQTT 2.2.3]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718023
+
+use crate::Error;
+
+/// Largest value that the remaining length field can hold.
+pub const MAX: usize = 268_435_455;
+
+/// Number of bytes needed to encode `len`.
+///
+/// Fails with `LimitExceeded` if `len` is larger than [`MAX`].
+///
+/// [`MAX`]: constant.MAX.html
+pub fn encoded_len(len: usize) -> Result<usize, Error> {
+    match len {
+        0..=127 => Ok(1),
+        128..=16_383 => Ok(2),
+        16_384..=2_097_151 => Ok(3),
+        2_097_152..=MAX => Ok(4),
+        _ => Err(Error::LimitExceeded {
+            field: "remaining length",
+            limit: MAX,
+            actual: len,
+        }),
+    }
+}
+
+/// Encode `len` at the start of `buf`, returning the number of bytes written.
+///
+/// Fails with `LimitExceeded` if `len` is larger than [`MAX`], or `WriteZero` if `buf` is too
+/// small.
+///
+/// [`MAX`]: constant.MAX.html
+pub fn encode(len: usize, buf: &mut [u8]) -> Result<usize, Error> {
+    let size = encoded_len(len)?;
+    if buf.len() < size {
+        return Err(Error::WriteZero {
+            required: size,
+            available: buf.len(),
+        });
+    }
+    let mut x = len;
+    for byte in &mut buf[..size] {
+        *byte = (x % 128) as u8;
+        x /= 128;
+        if x > 0 {
+            *byte |= 128;
+        }
+    }
+    Ok(size)
+}
+
+/// Decode the remaining length at the start of `buf`, returning its value and the number of bytes
+/// it used.
+///
+/// Returns `Ok(None)` if `buf` ends before the field does, and fails with
+/// `MalformedRemainingLength` if the field is longer than 4 bytes.
+pub fn decode(buf: &[u8]) -> Result<Option<(usize, usize)>, Error> {
+    let mut len: usize = 0;
+    for pos in 0..=3 {
+        let byte = match buf.get(pos) {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+        len += (byte as usize & 0x7F) << (pos * 7);
+        if (byte & 0x80) == 0 {
+            return Ok(Some((len, pos + 1)));
+        }
+    }
+    // Continuation bit set four times, that's illegal.
+    Err(Error::MalformedRemainingLength)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn boundaries() {
+        let cases: &[(usize, &[u8])] = &[
+            (0, &[0x00]),
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xFF, 0x7F]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_151, &[0xFF, 0xFF, 0x7F]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+            (MAX, &[0xFF, 0xFF, 0xFF, 0x7F]),
+        ];
+        for (len, bytes) in cases {
+            let mut buf = [0; 5];
+            assert_eq!(Ok(bytes.len()), encoded_len(*len), "{}", len);
+            assert_eq!(Ok(bytes.len()), encode(*len, &mut buf), "{}", len);
+            assert_eq!(*bytes, &buf[..bytes.len()], "{}", len);
+            assert_eq!(Ok(Some((*len, bytes.len()))), decode(&buf), "{}", len);
+            assert_eq!(Ok(None), decode(&bytes[..bytes.len() - 1]), "{}", len);
+            assert_eq!(
+                Err(Error::WriteZero {
+                    required: bytes.len(),
+                    available: bytes.len() - 1
+                }),
+                encode(*len, &mut buf[..bytes.len() - 1])
+            );
+        }
+
+        let too_long = Err(Error::LimitExceeded {
+            field: "remaining length",
+            limit: MAX,
+            actual: MAX + 1,
+        });
+        assert_eq!(too_long, encoded_len(MAX + 1));
+        assert_eq!(too_long, encode(MAX + 1, &mut [0; 5]));
+        assert_eq!(
+            Err(Error::MalformedRemainingLength),
+            decode(&[0xFF, 0xFF, 0xFF, 0xFF, 0x01])
+        );
+        assert_eq!(Ok(None), decode(&[]));
+        // Only the field is read.
+        assert_eq!(Ok(Some((5, 1))), decode(&[0x05, 0xFF]));
+    }
+}
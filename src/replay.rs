@@ -0,0 +1,231 @@
+//! Replay recorded MQTT traffic through a client or broker implementation.
+//!
+//! A trace is a list of [`Record`]s (for example converted from a [pcap] capture). [`Replayer`]
+//! feeds them to a [`ReplayTarget`] in order, sleeping between records to reproduce the original
+//! timing, optionally accelerated. This is meant to regression-test session logic (keep-alive,
+//! retries, in-flight windows...) against real-world traces.
+//!
+//! ```
+//! # use mqttrs::{*, replay::*};
+//! # use std::time::Duration;
+//! let trace = vec![
+//!     Record { timestamp: Duration::from_secs(0), direction: Direction::ClientToServer,
+//!              packet: Packet::Pingreq },
+//!     Record { timestamp: Duration::from_secs(10), direction: Direction::ServerToClient,
+//!              packet: Packet::Pingresp },
+//! ];
+//! let mut seen = Vec::new();
+//! Replayer::new(Pace::Accelerated(1000.0))
+//!     .replay(&trace, |r: &Record| -> Result<(), ()> {
+//!         seen.push(r.packet.get_type());
+//!         Ok(())
+//!     })
+//!     .unwrap();
+//! assert_eq!(seen, [PacketType::Pingreq, PacketType::Pingresp]);
+//! ```
+//!
+//! [`Record`]: struct.Record.html
+//! [`Replayer`]: struct.Replayer.html
+//! [`ReplayTarget`]: trait.ReplayTarget.html
+//! [pcap]: ../pcap/index.html
+
+use crate::*;
+use core::time::Duration;
+use std::{boxed::Box, fmt};
+
+/// Direction of a recorded packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// A packet of a recorded trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record<'a> {
+    /// Time since an arbitrary reference, typically the start of the capture.
+    pub timestamp: Duration,
+    pub direction: Direction,
+    pub packet: Packet<'a>,
+}
+
+/// Client or broker engine receiving the replayed records.
+///
+/// Implemented for closures taking a `&Record` and returning a `Result`.
+pub trait ReplayTarget {
+    type Error;
+
+    /// Handle the next record. Returning an error stops the replay.
+    fn feed(&mut self, record: &Record) -> Result<(), Self::Error>;
+}
+
+impl<F, E> ReplayTarget for F
+where
+    F: FnMut(&Record) -> Result<(), E>,
+{
+    type Error = E;
+
+    fn feed(&mut self, record: &Record) -> Result<(), E> {
+        self(record)
+    }
+}
+
+/// Replay speed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pace {
+    /// Reproduce the recorded delays between records.
+    Original,
+    /// Divide the recorded delays by this factor. Delays that overflow a `Duration`, with a tiny
+    /// factor, are clamped to `Duration::MAX`.
+    Accelerated(f64),
+    /// Don't wait between records.
+    Immediate,
+}
+
+/// Error returned by [`Replayer::replay()`].
+///
+/// [`Replayer::replay()`]: struct.Replayer.html#method.replay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError<E> {
+    /// The record at this index has a timestamp earlier than the previous one.
+    OutOfOrder(usize),
+    /// The target failed to handle the record at this index.
+    Target(usize, E),
+}
+
+impl<E: fmt::Debug> fmt::Display for ReplayError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Feeds a trace to a [`ReplayTarget`] at the chosen [`Pace`].
+///
+/// [`ReplayTarget`]: trait.ReplayTarget.html
+/// [`Pace`]: enum.Pace.html
+pub struct Replayer {
+    pace: Pace,
+    sleep: Box<dyn FnMut(Duration)>,
+}
+
+impl fmt::Debug for Replayer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Replayer")
+            .field("pace", &self.pace)
+            .finish()
+    }
+}
+
+impl Replayer {
+    /// Create a replayer sleeping with `std::thread::sleep()`.
+    pub fn new(pace: Pace) -> Self {
+        Replayer {
+            pace,
+            sleep: Box::new(std::thread::sleep),
+        }
+    }
+
+    /// Use `sleep` instead of `std::thread::sleep()` to wait between records, for example to
+    /// advance a simulated clock.
+    pub fn sleep_with(mut self, sleep: impl FnMut(Duration) + 'static) -> Self {
+        self.sleep = Box::new(sleep);
+        self
+    }
+
+    fn delay(&self, recorded: Duration) -> Duration {
+        match self.pace {
+            Pace::Original => recorded,
+            Pace::Accelerated(factor) if factor > 0.0 => {
+                Duration::try_from_secs_f64(recorded.as_secs_f64() / factor)
+                    .unwrap_or(Duration::MAX)
+            }
+            Pace::Accelerated(_) | Pace::Immediate => Duration::from_secs(0),
+        }
+    }
+
+    /// Feed every record of `trace` to `target`, in order.
+    ///
+    /// The first record is fed immediately, later ones after the (scaled) delay since the
+    /// previous record.
+    pub fn replay<T: ReplayTarget>(
+        &mut self,
+        trace: &[Record],
+        mut target: T,
+    ) -> Result<(), ReplayError<T::Error>> {
+        let mut prev = None;
+        for (i, record) in trace.iter().enumerate() {
+            if let Some(prev) = prev {
+                let recorded = record
+                    .timestamp
+                    .checked_sub(prev)
+                    .ok_or(ReplayError::OutOfOrder(i))?;
+                let delay = self.delay(recorded);
+                if delay > Duration::from_secs(0) {
+                    (self.sleep)(delay);
+                }
+            }
+            prev = Some(record.timestamp);
+            target.feed(record).map_err(|e| ReplayError::Target(i, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc, vec, vec::Vec};
+
+    fn trace() -> Vec<Record<'static>> {
+        let record = |ms, direction, packet| Record {
+            timestamp: Duration::from_millis(ms),
+            direction,
+            packet,
+        };
+        vec![
+            record(1000, Direction::ClientToServer, Packet::Pingreq),
+            record(1500, Direction::ServerToClient, Packet::Pingresp),
+            record(3500, Direction::ClientToServer, Packet::Disconnect),
+        ]
+    }
+
+    fn sleeps(pace: Pace) -> Vec<Duration> {
+        let slept = Rc::new(RefCell::new(Vec::new()));
+        let s = slept.clone();
+        Replayer::new(pace)
+            .sleep_with(move |d| s.borrow_mut().push(d))
+            .replay(&trace(), |_: &Record| -> Result<(), ()> { Ok(()) })
+            .unwrap();
+        slept.take()
+    }
+
+    #[test]
+    fn pace() {
+        let ms = Duration::from_millis;
+        assert_eq!(sleeps(Pace::Original), [ms(500), ms(2000)]);
+        assert_eq!(sleeps(Pace::Accelerated(10.0)), [ms(50), ms(200)]);
+        let max = Duration::MAX;
+        assert_eq!(sleeps(Pace::Accelerated(1e-20)), [max, max]);
+        assert!(sleeps(Pace::Immediate).is_empty());
+    }
+
+    #[test]
+    fn errors() {
+        let mut replayer = Replayer::new(Pace::Immediate);
+        let mut fed = 0;
+        let res = replayer.replay(&trace(), |r: &Record| {
+            fed += 1;
+            match r.packet {
+                Packet::Pingresp => Err("unexpected"),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(Err(ReplayError::Target(1, "unexpected")), res);
+        assert_eq!(2, fed);
+
+        let mut reversed = trace();
+        reversed.reverse();
+        let res = replayer.replay(&reversed, |_: &Record| -> Result<(), ()> { Ok(()) });
+        assert_eq!(Err(ReplayError::OutOfOrder(1)), res);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{Error, Pid};
+use core::convert::TryFrom;
+use std::collections::BTreeSet;
+
+/// Assigns pids to outgoing messages, never reusing one until it is released.
+///
+/// At most `window` pids are in use at once, usually the peer's receive maximum. Besides single
+/// pids, [`reserve_contiguous()`] returns blocks of consecutive pids, for messages sent as a
+/// batch like the chunks of a file:
+///
+/// ```
+/// # use mqttrs::*;
+/// let mut pids = PidSequencer::new(10);
+/// let single = pids.next_pid()?;
+/// let chunks = pids.reserve_contiguous(8)?;
+/// assert_eq!(vec![2, 3, 4, 5, 6, 7, 8, 9], chunks.iter().map(Pid::get).collect::<Vec<_>>());
+/// // Only one pid is left in the window.
+/// assert!(pids.reserve_contiguous(2).is_err());
+/// pids.release(single);
+/// assert_eq!(10, pids.reserve_contiguous(2)?.first().get());
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`reserve_contiguous()`]: #method.reserve_contiguous
+#[derive(Debug, Clone)]
+pub struct PidSequencer {
+    window: u16,
+    /// Where the search for free pids starts.
+    next: Pid,
+    in_use: BTreeSet<Pid>,
+}
+
+/// Consecutive pids returned by [`PidSequencer::reserve_contiguous()`].
+///
+/// [`PidSequencer::reserve_contiguous()`]: struct.PidSequencer.html#method.reserve_contiguous
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PidRange {
+    first: Pid,
+    len: u16,
+}
+
+impl PidSequencer {
+    /// Allow at most `window` pids in use at once. Zero is treated as one.
+    pub fn new(window: u16) -> Self {
+        PidSequencer {
+            window: window.max(1),
+            next: Pid::new(),
+            in_use: BTreeSet::new(),
+        }
+    }
+
+    /// Reserve one pid.
+    ///
+    /// Fails with `LimitExceeded` if `window` pids are already in use.
+    pub fn next_pid(&mut self) -> Result<Pid, Error> {
+        Ok(self.reserve_contiguous(1)?.first())
+    }
+
+    /// Reserve `n` consecutive pids, searching from the last reserved pid.
+    ///
+    /// Blocks never wrap from 65535 to 1. Fails with `LimitExceeded` if the window can't
+    /// accommodate `n` more pids, or if no `n` consecutive pids are free. Nothing is reserved on
+    /// error.
+    pub fn reserve_contiguous(&mut self, n: u16) -> Result<PidRange, Error> {
+        let in_use = self.in_use.len();
+        if in_use + usize::from(n) > usize::from(self.window) {
+            return Err(Error::LimitExceeded {
+                field: "pid window",
+                limit: self.window.into(),
+                actual: in_use + usize::from(n),
+            });
+        }
+        let first = match n {
+            0 => self.next,
+            n => self.find_free(n).ok_or_else(|| Error::LimitExceeded {
+                field: "contiguous pids",
+                limit: self.longest_free_run(),
+                actual: n.into(),
+            })?,
+        };
+        let range = PidRange { first, len: n };
+        self.in_use.extend(range.iter());
+        if n > 0 {
+            self.next = range.last().wrapping_next();
+        }
+        Ok(range)
+    }
+
+    /// Release `pid`, once its message is acknowledged. Returns false if it wasn't in use.
+    pub fn release(&mut self, pid: Pid) -> bool {
+        self.in_use.remove(&pid)
+    }
+
+    /// Release all the pids of `range`.
+    pub fn release_range(&mut self, range: PidRange) {
+        for pid in range.iter() {
+            self.in_use.remove(&pid);
+        }
+    }
+
+    /// Returns true if `pid` is reserved.
+    pub fn is_in_use(&self, pid: Pid) -> bool {
+        self.in_use.contains(&pid)
+    }
+
+    /// Number of reserved pids.
+    pub fn in_use(&self) -> usize {
+        self.in_use.len()
+    }
+
+    /// First pid of the first run of `n` free pids, starting from `next` and wrapping once.
+    fn find_free(&self, n: u16) -> Option<Pid> {
+        let start = self.next.get();
+        let mut first = start;
+        let mut wrapped = false;
+        loop {
+            if wrapped && first >= start {
+                return None;
+            }
+            let last = match first.checked_add(n - 1) {
+                Some(last) => last,
+                None if wrapped => return None,
+                None => {
+                    wrapped = true;
+                    first = 1;
+                    continue;
+                }
+            };
+            let (from, to) = (pid(first), pid(last));
+            match self.in_use.range(from..=to).next_back() {
+                None => return Some(from),
+                Some(used) if used.get() == u16::MAX && !wrapped => {
+                    wrapped = true;
+                    first = 1;
+                }
+                Some(used) if used.get() == u16::MAX => return None,
+                Some(used) => first = used.get() + 1,
+            }
+        }
+    }
+
+    /// Length of the longest run of free pids.
+    fn longest_free_run(&self) -> usize {
+        let mut longest = 0;
+        let mut previous = 0;
+        for used in self.in_use.iter().map(|p| usize::from(p.get())) {
+            longest = longest.max(used - previous - 1);
+            previous = used;
+        }
+        longest.max(usize::from(u16::MAX) - previous)
+    }
+}
+
+impl PidRange {
+    /// First pid of the range. For an empty range, this is the pid that would have been used.
+    pub fn first(&self) -> Pid {
+        self.first
+    }
+
+    /// Last pid of the range, or `first()` if it is empty.
+    pub fn last(&self) -> Pid {
+        self.first + self.len.saturating_sub(1)
+    }
+
+    /// Number of pids.
+    pub fn len(&self) -> u16 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns true if `pid` is in the range.
+    pub fn contains(&self, pid: Pid) -> bool {
+        !self.is_empty() && self.first <= pid && pid <= self.last()
+    }
+
+    /// Iterate over the pids of the range, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = Pid> {
+        let first = self.first.get();
+        (0..self.len).map(move |i| pid(first + i))
+    }
+}
+
+fn pid(n: u16) -> Pid {
+    Pid::try_from(n).expect("pid is never 0")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    fn values(range: PidRange) -> Vec<u16> {
+        range.iter().map(Pid::get).collect()
+    }
+
+    #[test]
+    fn reserve() {
+        let mut pids = PidSequencer::new(u16::MAX);
+        let a = pids.reserve_contiguous(3).unwrap();
+        let b = pids.reserve_contiguous(3).unwrap();
+        assert_eq!((vec![1, 2, 3], vec![4, 5, 6]), (values(a), values(b)));
+        assert!(b.contains(pid(5)) && !b.contains(pid(7)));
+        pids.release_range(a);
+        assert!(!pids.release(pid(1)));
+        assert!(pids.release(pid(5)));
+        assert_eq!(2, pids.in_use());
+
+        // The search continues after the last reservation, skipping used pids.
+        let empty = pids.reserve_contiguous(0).unwrap();
+        assert!(empty.is_empty() && empty.iter().next().is_none());
+        assert_eq!(7, pids.next_pid().unwrap().get());
+        assert_eq!(vec![8, 9], values(pids.reserve_contiguous(2).unwrap()));
+
+        // Blocks don't wrap, single pids do.
+        pids.next = pid(u16::MAX - 1);
+        assert_eq!(vec![1, 2, 3], values(pids.reserve_contiguous(3).unwrap()));
+        assert_eq!(5, pids.next_pid().unwrap().get());
+        pids.next = pid(u16::MAX);
+        assert_eq!(u16::MAX, pids.next_pid().unwrap().get());
+        assert_eq!(10, pids.next_pid().unwrap().get());
+    }
+
+    #[test]
+    fn limits() {
+        let mut pids = PidSequencer::new(4);
+        pids.reserve_contiguous(3).unwrap();
+        assert_eq!(
+            Err(Error::LimitExceeded {
+                field: "pid window",
+                limit: 4,
+                actual: 5
+            }),
+            pids.reserve_contiguous(2)
+        );
+        assert_eq!(3, pids.in_use());
+
+        // Every other pid is in use: there is room, but no 2 consecutive free pids.
+        let mut pids = PidSequencer::new(u16::MAX);
+        for n in (2..=u16::MAX).step_by(2) {
+            pids.in_use.insert(pid(n));
+        }
+        assert_eq!(
+            Err(Error::LimitExceeded {
+                field: "contiguous pids",
+                limit: 1,
+                actual: 2
+            }),
+            pids.reserve_contiguous(2)
+        );
+        assert_eq!(1, pids.next_pid().unwrap().get());
+        assert!(pids.next_pid().is_ok());
+    }
+}
@@ -0,0 +1,494 @@
+use crate::{decoder::*, encoder::*, subscribe::LimitedVec, *};
+#[cfg(feature = "derive")]
+use core::fmt;
+#[cfg(feature = "derive")]
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// Client-side session state.
+///
+/// This is the state that a client needs to keep across network connections when connecting
+/// with `clean_session == false`, as described in [MQTT 4.1]. Keeping it up to date is the
+/// responsibility of the client, `mqttrs` only provides storage.
+///
+/// [`persist()`] and [`restore()`] store it using a documented binary format that future versions
+/// of `mqttrs` will keep reading. With the `derive` feature, it can also be serialized using a
+/// compact, versioned serde format. This is typically used with [postcard] to store pending
+/// messages in flash on embedded clients. Borrowed data (topics and payloads) is restored or
+/// deserialized without copying when the format allows it.
+///
+/// ```
+/// # use mqttrs::*;
+/// let mut session = SessionState::new();
+/// session.pending_publishes.push(Publish {
+///     dup: false,
+///     qospid: QosPid::AtLeastOnce(session.next_pid),
+///     retain: false,
+///     topic_name: "sensor/temp",
+///     payload: b"21.5",
+/// });
+/// session.next_pid = session.next_pid.wrapping_next();
+/// # #[cfg(feature = "derive")]
+/// # {
+/// let bytes = postcard::to_allocvec(&session).unwrap();
+/// assert_eq!(session, postcard::from_bytes(&bytes).unwrap());
+/// # }
+/// ```
+///
+/// [MQTT 4.1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718105
+/// [postcard]: https://docs.rs/postcard
+/// [`persist()`]: #method.persist
+/// [`restore()`]: #method.restore
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SessionState<'a> {
+    /// The next [`Pid`] to use for an outgoing packet.
+    ///
+    /// [`Pid`]: struct.Pid.html
+    pub next_pid: Pid,
+    /// `QoS 1` and `QoS 2` publishes that have been sent but not acknowledged yet (with a
+    /// `Puback` or `Pubrec`).
+    pub pending_publishes: LimitedVec<Publish<'a>>,
+    /// Pids of `QoS 2` publishes that have been acknowledged with a `Pubrec`, and for which we
+    /// are waiting for a `Pubcomp`.
+    pub pending_pubrels: LimitedVec<Pid>,
+    /// Pids of received `QoS 2` publishes that we acknowledged with a `Pubrec`, and for which we
+    /// are waiting for a `Pubrel`.
+    pub pending_incoming: LimitedVec<Pid>,
+}
+
+impl<'a> SessionState<'a> {
+    /// Version of the serialized format, written first so that future versions can still read
+    /// old data.
+    pub const FORMAT_VERSION: u8 = 1;
+
+    /// Returns an empty session.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if there are no messages in flight in either direction.
+    pub fn is_empty(&self) -> bool {
+        self.pending_publishes.is_empty()
+            && self.pending_pubrels.is_empty()
+            && self.pending_incoming.is_empty()
+    }
+
+    /// Update the session for the `session_present` flag of the server's `Connack`, and return
+    /// the packets to send again before any new one.
+    ///
+    /// If the server kept the session, that is a `Pubrel` for each of `pending_pubrels`, followed
+    /// by `pending_publishes` with their dup flag set, as required by [MQTT 4.4]. Pids of
+    /// `pending_incoming` stay reserved until the server sends their `Pubrel` again.
+    ///
+    /// If the server has no session, the messages in flight are discarded ([MQTT-3.2.2-4]) and
+    /// nothing needs to be sent again. Unacknowledged publishes may never have reached the
+    /// server: take `pending_publishes` beforehand to publish them again as new messages.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let mut session = SessionState::new();
+    /// session.pending_publishes.push(Publish {
+    ///     dup: false,
+    ///     qospid: QosPid::AtLeastOnce(session.next_pid),
+    ///     retain: false,
+    ///     topic_name: "sensor/temp",
+    ///     payload: b"21.5",
+    /// });
+    /// for packet in session.clone().resume(true) {
+    ///     assert!(matches!(packet, Packet::Publish(Publish { dup: true, .. })));
+    /// }
+    /// assert_eq!(None, session.resume(false).next());
+    /// assert!(session.is_empty());
+    /// ```
+    ///
+    /// [MQTT 4.4]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718103
+    /// [MQTT-3.2.2-4]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718035
+    pub fn resume(&mut self, session_present: bool) -> impl Iterator<Item = Packet<'a>> + '_ {
+        if !session_present {
+            self.pending_publishes.clear();
+            self.pending_pubrels.clear();
+            self.pending_incoming.clear();
+        }
+        // Publishes reach the `Pubrel` stage in the order they were sent, so those are older.
+        let pubrels = self.pending_pubrels.iter().map(|pid| Packet::Pubrel(*pid));
+        let publishes = self.pending_publishes.iter().map(|publish| {
+            Packet::Publish(Publish {
+                dup: true,
+                ..publish.clone()
+            })
+        });
+        pubrels.chain(publishes)
+    }
+}
+
+impl<'a> SessionState<'a> {
+    /// Magic bytes at the start of a persisted session.
+    pub const PERSIST_MAGIC: [u8; 4] = *b"MQSS";
+    /// Version of the format written by [`persist()`].
+    ///
+    /// [`persist()`]: #method.persist
+    pub const PERSIST_VERSION: u8 = 1;
+
+    /// Number of bytes written by [`persist()`].
+    ///
+    /// [`persist()`]: #method.persist
+    pub fn persisted_len(&self) -> usize {
+        let publishes: usize = self
+            .pending_publishes
+            .iter()
+            .map(|p| Packet::Publish(p.clone()).encoded_len())
+            .sum();
+        Self::PERSIST_MAGIC.len()
+            + 1
+            + 2
+            + (2 + publishes)
+            + (2 + 2 * self.pending_pubrels.len())
+            + (2 + 2 * self.pending_incoming.len())
+    }
+
+    /// Write the session to `buf` in the [`PERSIST_VERSION`] format, returning the number of
+    /// bytes written.
+    ///
+    /// All integers are big-endian. Version 1 is:
+    ///
+    /// | Field               | Size      | Content                                        |
+    /// |---------------------|-----------|------------------------------------------------|
+    /// | magic               | 4         | [`PERSIST_MAGIC`]                              |
+    /// | version             | 1         | `1`                                            |
+    /// | `next_pid`          | 2         |                                                |
+    /// | `pending_publishes` | 2 + ...   | count, then each `Publish` encoded as a packet |
+    /// | `pending_pubrels`   | 2 + n * 2 | count, then each pid                           |
+    /// | `pending_incoming`  | 2 + n * 2 | count, then each pid                           |
+    ///
+    /// New versions may change anything after the version byte.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let mut session = SessionState::new();
+    /// session.pending_pubrels.push(Pid::new());
+    /// let mut buf = [0u8; 64];
+    /// let len = session.persist(&mut buf)?;
+    /// assert_eq!(session, SessionState::restore(&buf[..len])?);
+    /// # Ok::<(), Error>(())
+    /// ```
+    ///
+    /// [`PERSIST_VERSION`]: #associatedconstant.PERSIST_VERSION
+    /// [`PERSIST_MAGIC`]: #associatedconstant.PERSIST_MAGIC
+    pub fn persist(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = self.persisted_len();
+        if buf.len() < len {
+            return Err(Error::WriteZero {
+                required: len,
+                available: buf.len(),
+            });
+        }
+        buf[..4].copy_from_slice(&Self::PERSIST_MAGIC);
+        let mut offset = 4;
+        write_u8(buf, &mut offset, Self::PERSIST_VERSION)?;
+        self.next_pid.to_buffer(buf, &mut offset)?;
+        write_count(buf, &mut offset, self.pending_publishes.len())?;
+        for publish in self.pending_publishes.iter() {
+            let packet = Packet::Publish(publish.clone());
+            offset += encode_slice(&packet, &mut buf[offset..])?;
+        }
+        for pids in &[&self.pending_pubrels, &self.pending_incoming] {
+            write_count(buf, &mut offset, pids.len())?;
+            for pid in pids.iter() {
+                pid.to_buffer(buf, &mut offset)?;
+            }
+        }
+        Ok(offset)
+    }
+
+    /// Read a session written by [`persist()`] with this or an older version of `mqttrs`.
+    ///
+    /// Fails with `UnknownSessionFormat` if `buf` isn't a persisted session, or was written by a
+    /// newer version. Use [`restore_with()`] to handle those.
+    ///
+    /// [`persist()`]: #method.persist
+    /// [`restore_with()`]: #method.restore_with
+    pub fn restore(buf: &'a [u8]) -> Result<Self, Error> {
+        Self::restore_with(buf, |version, _| {
+            Err(Error::UnknownSessionFormat {
+                version: Some(version),
+            })
+        })
+    }
+
+    /// Same as [`restore()`], calling `migrate` with the version and the data following it for
+    /// the versions this `mqttrs` doesn't know, for example after a downgrade.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let future = b"MQSS\x09...";
+    /// assert_eq!(Ok(9), SessionState::persisted_version(future));
+    /// let session = SessionState::restore_with(future, |version, _data| {
+    ///     // Can't read it: start over.
+    ///     Ok(SessionState::new())
+    /// });
+    /// assert_eq!(Ok(SessionState::new()), session);
+    /// ```
+    ///
+    /// [`restore()`]: #method.restore
+    pub fn restore_with<F>(buf: &'a [u8], migrate: F) -> Result<Self, Error>
+    where
+        F: FnOnce(u8, &'a [u8]) -> Result<Self, Error>,
+    {
+        let version = Self::persisted_version(buf)?;
+        let data = &buf[5..];
+        match version {
+            1 => Self::restore_v1(data),
+            other => migrate(other, data),
+        }
+    }
+
+    /// Return the version of the format of a persisted session, without reading it.
+    pub fn persisted_version(buf: &[u8]) -> Result<u8, Error> {
+        match buf {
+            [m0, m1, m2, m3, version, ..] if [*m0, *m1, *m2, *m3] == Self::PERSIST_MAGIC => {
+                Ok(*version)
+            }
+            _ => Err(Error::UnknownSessionFormat { version: None }),
+        }
+    }
+
+    fn restore_v1(buf: &'a [u8]) -> Result<Self, Error> {
+        let mut offset = 0;
+        let mut session = SessionState {
+            next_pid: Pid::from_buffer(buf, &mut offset)?,
+            ..Self::default()
+        };
+        for _ in 0..read_u16(buf, &mut offset)? {
+            let publish = match decode_slice_with_len(&buf[offset..])? {
+                Some((Packet::Publish(publish), len)) => {
+                    offset += len;
+                    publish
+                }
+                Some((other, _)) => return Err(Error::UnexpectedPacketType(other.get_type())),
+                None => return Err(Error::InvalidLength),
+            };
+            #[allow(clippy::let_unit_value)]
+            let _res = session.pending_publishes.push(publish);
+            #[cfg(not(feature = "std"))]
+            _res.map_err(|_| {
+                crate::subscribe::too_many(
+                    "pending publishes",
+                    session.pending_publishes.capacity(),
+                )
+            })?;
+        }
+        for pids in [&mut session.pending_pubrels, &mut session.pending_incoming] {
+            for _ in 0..read_u16(buf, &mut offset)? {
+                #[allow(clippy::let_unit_value)]
+                let _res = pids.push(Pid::from_buffer(buf, &mut offset)?);
+                #[cfg(not(feature = "std"))]
+                _res.map_err(|_| crate::subscribe::too_many("pending pids", pids.capacity()))?;
+            }
+        }
+        if offset != buf.len() {
+            return Err(Error::InvalidLength);
+        }
+        Ok(session)
+    }
+}
+
+/// Write the number of elements of a list.
+fn write_count(buf: &mut [u8], offset: &mut usize, count: usize) -> Result<(), Error> {
+    if count > u16::MAX as usize {
+        return Err(Error::LimitExceeded {
+            field: "persisted list",
+            limit: u16::MAX as usize,
+            actual: count,
+        });
+    }
+    write_u16(buf, offset, count as u16)
+}
+
+#[cfg(feature = "derive")]
+impl Serialize for SessionState<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut t = s.serialize_tuple(5)?;
+        t.serialize_element(&Self::FORMAT_VERSION)?;
+        t.serialize_element(&self.next_pid)?;
+        t.serialize_element(&self.pending_publishes)?;
+        t.serialize_element(&self.pending_pubrels)?;
+        t.serialize_element(&self.pending_incoming)?;
+        t.end()
+    }
+}
+
+#[cfg(feature = "derive")]
+impl<'de: 'a, 'a> Deserialize<'de> for SessionState<'a> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct SessionVisitor<'a>(core::marker::PhantomData<SessionState<'a>>);
+
+        impl<'de: 'a, 'a> Visitor<'de> for SessionVisitor<'a> {
+            type Value = SessionState<'a>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a version {} session", SessionState::FORMAT_VERSION)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let version: u8 = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                if version != SessionState::FORMAT_VERSION {
+                    return Err(de::Error::invalid_value(
+                        de::Unexpected::Unsigned(version.into()),
+                        &self,
+                    ));
+                }
+                Ok(SessionState {
+                    next_pid: seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(1, &self))?,
+                    pending_publishes: seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(2, &self))?,
+                    pending_pubrels: seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(3, &self))?,
+                    pending_incoming: seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(4, &self))?,
+                })
+            }
+        }
+
+        d.deserialize_tuple(5, SessionVisitor(core::marker::PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use core::convert::TryFrom;
+    use std::vec;
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn session_roundtrip() {
+        let pid = Pid::try_from(42).unwrap();
+        let session = SessionState {
+            next_pid: pid + 1,
+            pending_publishes: vec![Publish {
+                dup: true,
+                qospid: QosPid::ExactlyOnce(pid),
+                retain: false,
+                topic_name: "a/b",
+                payload: b"payload",
+            }],
+            pending_pubrels: vec![pid - 1],
+            pending_incoming: vec![pid - 2, pid - 3],
+        };
+        let bytes = postcard::to_allocvec(&session).unwrap();
+        assert_eq!(SessionState::FORMAT_VERSION, bytes[0]);
+        assert_eq!(Ok(session), postcard::from_bytes(&bytes));
+    }
+
+    #[test]
+    fn resume_order() {
+        let pid = Pid::try_from(42).unwrap();
+        let publish = Publish {
+            dup: false,
+            qospid: QosPid::ExactlyOnce(pid),
+            retain: false,
+            topic_name: "a/b",
+            payload: b"payload",
+        };
+        let mut session = SessionState {
+            next_pid: pid + 1,
+            pending_publishes: vec![publish.clone()],
+            pending_pubrels: vec![pid - 1],
+            pending_incoming: vec![pid - 2],
+        };
+        let resent: vec::Vec<_> = session.resume(true).collect();
+        let dup = Publish {
+            dup: true,
+            ..publish
+        };
+        assert_eq!(vec![Packet::Pubrel(pid - 1), dup.into()], resent);
+        assert_eq!(vec![pid - 2], session.pending_incoming);
+    }
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn session_bad_version() {
+        let mut bytes = postcard::to_allocvec(&SessionState::new()).unwrap();
+        bytes[0] = SessionState::FORMAT_VERSION + 1;
+        assert!(postcard::from_bytes::<SessionState>(&bytes).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn packet_roundtrip() {
+        let packets: vec::Vec<Packet> = vec![
+            Connect {
+                protocol: Protocol::MQTT311,
+                keep_alive: 30,
+                client_id: "client",
+                clean_session: false,
+                last_will: Some(LastWill {
+                    topic: "will",
+                    message: b"bye",
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                }),
+                username: Some("user"),
+                password: Some(b"pass"),
+            }
+            .into(),
+            Packet::Pubrel(Pid::new()),
+            Suback::new(Pid::new(), vec![SubscribeReturnCodes::Failure]).into(),
+            Packet::Pingreq,
+        ];
+        for pkt in packets {
+            let bytes = postcard::to_allocvec(&pkt).unwrap();
+            assert_eq!(Ok(pkt), postcard::from_bytes(&bytes));
+        }
+    }
+
+    #[test]
+    fn persist_roundtrip() {
+        let pid = Pid::try_from(42).unwrap();
+        let session = SessionState {
+            next_pid: pid + 1,
+            pending_publishes: vec![Publish {
+                dup: true,
+                qospid: QosPid::ExactlyOnce(pid),
+                retain: false,
+                topic_name: "a/b",
+                payload: b"payload",
+            }],
+            pending_pubrels: vec![pid - 1],
+            pending_incoming: vec![pid - 2, pid - 3],
+        };
+        let mut buf = [0; 64];
+        assert_eq!(
+            Err(Error::WriteZero {
+                required: session.persisted_len(),
+                available: 8
+            }),
+            session.persist(&mut buf[..8])
+        );
+        let len = session.persist(&mut buf).unwrap();
+        assert_eq!(session.persisted_len(), len);
+        assert_eq!(Ok(session), SessionState::restore(&buf[..len]));
+
+        assert_eq!(
+            Err(Error::InvalidLength),
+            SessionState::restore(&buf[..len - 1])
+        );
+        buf[4] = 2;
+        let unknown = Err(Error::UnknownSessionFormat { version: Some(2) });
+        assert_eq!(unknown, SessionState::restore(&buf[..len]));
+        buf[0] = b'X';
+        let foreign = Err(Error::UnknownSessionFormat { version: None });
+        assert_eq!(foreign, SessionState::restore(&buf[..len]));
+        assert_eq!(foreign, SessionState::restore(b"MQS"));
+    }
+}
@@ -0,0 +1,223 @@
+//! [Proptest] strategies generating valid packets and packet elements.
+//!
+//! Enable the `strategy` feature to use this module. All generated values respect the spec rules
+//! that `mqttrs` can express (non-zero pids, no `dup` flag on `QoS 0` publishes, no password
+//! without a username, at least one topic per subscribe...), so they can be fed directly into
+//! your session logic.
+//!
+//! ```
+//! use mqttrs::{decode_slice, encode_slice, strategy};
+//! use proptest::prelude::*;
+//!
+//! proptest!(|(pkt in strategy::packet())| {
+//!     let mut buf = [0u8; 4096];
+//!     let len = encode_slice(&pkt, &mut buf).unwrap();
+//!     prop_assert_eq!(Ok(Some(pkt)), decode_slice(&buf[..len]));
+//! });
+//! ```
+//!
+//! Proptest does not support borrowed data in strategies
+//! (<https://github.com/AltSysrq/proptest/issues/9>), so the borrowed fields of generated
+//! [`Connect`] and [`Publish`] packets point to leaked allocations. This is fine for the
+//! short-lived processes that test suites run in, but don't use these strategies in production
+//! code.
+//!
+//! [Proptest]: https://docs.rs/proptest
+//! [`Connect`]: ../struct.Connect.html
+//! [`Publish`]: ../struct.Publish.html
+
+use crate::*;
+use core::convert::TryFrom;
+use proptest::{collection::vec, option, prelude::*};
+use std::{boxed::Box, string::String, vec::Vec};
+
+fn leak_str(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+fn leak_bytes(b: Vec<u8>) -> &'static [u8] {
+    Box::leak(b.into_boxed_slice())
+}
+
+/// Any valid [`Pid`](../struct.Pid.html).
+pub fn pid() -> impl Strategy<Value = Pid> {
+    (1..=u16::MAX).prop_map(|p| Pid::try_from(p).unwrap())
+}
+
+/// Any [`QoS`](../enum.QoS.html).
+pub fn qos() -> impl Strategy<Value = QoS> {
+    prop_oneof![
+        Just(QoS::AtMostOnce),
+        Just(QoS::AtLeastOnce),
+        Just(QoS::ExactlyOnce)
+    ]
+}
+
+/// Any [`QosPid`](../enum.QosPid.html).
+pub fn qospid() -> impl Strategy<Value = QosPid> {
+    prop_oneof![
+        Just(QosPid::AtMostOnce),
+        pid().prop_map(QosPid::AtLeastOnce),
+        pid().prop_map(QosPid::ExactlyOnce)
+    ]
+}
+
+/// A topic name, as used in [`Publish`] packets: one to six `/`-separated levels, without
+/// wildcards.
+///
+/// [`Publish`]: ../struct.Publish.html
+pub fn topic_name() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9_-]{1,12}(/[a-zA-Z0-9_-]{0,12}){0,5}"
+}
+
+/// A topic filter, as used in [`Subscribe`] and [`Unsubscribe`] packets: a topic name where some
+/// levels may be replaced by the `+` and `#` wildcards.
+///
+/// [`Subscribe`]: ../struct.Subscribe.html
+/// [`Unsubscribe`]: ../struct.Unsubscribe.html
+pub fn topic_filter() -> impl Strategy<Value = String> {
+    "([a-zA-Z0-9_-]{1,12}|\\+)(/([a-zA-Z0-9_-]{0,12}|\\+)){0,5}(/#)?|#"
+}
+
+/// Any [`SubscribeTopic`](../struct.SubscribeTopic.html).
+pub fn subscribe_topic() -> impl Strategy<Value = SubscribeTopic> {
+    (topic_filter(), qos()).prop_map(|(filter, qos)| SubscribeTopic::new(&filter, qos).unwrap())
+}
+
+/// Any [`SubscribeReturnCodes`](../enum.SubscribeReturnCodes.html).
+pub fn subscribe_return_code() -> impl Strategy<Value = SubscribeReturnCodes> {
+    prop_oneof![
+        qos().prop_map(SubscribeReturnCodes::Success),
+        Just(SubscribeReturnCodes::Failure)
+    ]
+}
+
+/// Any [`ConnectReturnCode`](../enum.ConnectReturnCode.html).
+pub fn connect_return_code() -> impl Strategy<Value = ConnectReturnCode> {
+    (0u8..=5).prop_map(|c| ConnectReturnCode::from_u8(c).unwrap())
+}
+
+/// Any [`LastWill`](../struct.LastWill.html).
+pub fn last_will() -> impl Strategy<Value = LastWill<'static>> {
+    (topic_name(), vec(any::<u8>(), 0..256), qos(), any::<bool>()).prop_map(
+        |(topic, message, qos, retain)| LastWill {
+            topic: leak_str(topic),
+            message: leak_bytes(message),
+            qos,
+            retain,
+        },
+    )
+}
+
+/// A [`Connect`](../struct.Connect.html) packet.
+pub fn connect() -> impl Strategy<Value = Packet<'static>> {
+    (
+        any::<u16>(),
+        "[a-zA-Z0-9]{0,23}",
+        any::<bool>(),
+        option::of(last_will()),
+        option::of(("[a-zA-Z0-9_.@-]{1,32}", option::of(vec(any::<u8>(), 0..64)))),
+    )
+        .prop_map(|(keep_alive, client_id, clean_session, last_will, creds)| {
+            // A password can only be sent along with a username.
+            let (username, password) = match creds {
+                Some((u, p)) => (Some(leak_str(u)), p.map(leak_bytes)),
+                None => (None, None),
+            };
+            Packet::Connect(Connect {
+                protocol: Protocol::MQTT311,
+                keep_alive,
+                client_id: leak_str(client_id),
+                clean_session,
+                last_will,
+                username,
+                password,
+            })
+        })
+}
+
+/// A [`Connack`](../struct.Connack.html) packet.
+pub fn connack() -> impl Strategy<Value = Packet<'static>> {
+    (any::<bool>(), connect_return_code()).prop_map(|(session_present, code)| {
+        // The session can only be present if the connection was accepted.
+        let session_present = session_present && code == ConnectReturnCode::Accepted;
+        Packet::Connack(Connack {
+            session_present,
+            code,
+        })
+    })
+}
+
+/// A [`Publish`](../struct.Publish.html) packet.
+pub fn publish() -> impl Strategy<Value = Packet<'static>> {
+    (
+        any::<bool>(),
+        qospid(),
+        any::<bool>(),
+        topic_name(),
+        vec(any::<u8>(), 0..1024),
+    )
+        .prop_map(|(dup, qospid, retain, topic_name, payload)| {
+            Packet::Publish(Publish {
+                // The dup flag must not be set for `QoS 0` messages.
+                dup: dup && qospid != QosPid::AtMostOnce,
+                qospid,
+                retain,
+                topic_name: leak_str(topic_name),
+                payload: leak_bytes(payload),
+            })
+        })
+}
+
+/// A [`Subscribe`](../struct.Subscribe.html) packet.
+pub fn subscribe() -> impl Strategy<Value = Packet<'static>> {
+    (pid(), vec(subscribe_topic(), 1..10))
+        .prop_map(|(pid, topics)| Packet::Subscribe(Subscribe { pid, topics }))
+}
+
+/// A [`Suback`](../struct.Suback.html) packet.
+pub fn suback() -> impl Strategy<Value = Packet<'static>> {
+    (pid(), vec(subscribe_return_code(), 1..10))
+        .prop_map(|(pid, return_codes)| Packet::Suback(Suback { pid, return_codes }))
+}
+
+/// An [`Unsubscribe`](../struct.Unsubscribe.html) packet.
+pub fn unsubscribe() -> impl Strategy<Value = Packet<'static>> {
+    (pid(), vec(topic_filter(), 1..10))
+        .prop_map(|(pid, topics)| Packet::Unsubscribe(Unsubscribe { pid, topics }))
+}
+
+/// Any packet containing only a [`Pid`](../struct.Pid.html) (`Puback`, `Pubrec`, `Pubrel`,
+/// `Pubcomp` or `Unsuback`).
+pub fn pid_only() -> impl Strategy<Value = Packet<'static>> {
+    prop_oneof![
+        pid().prop_map(Packet::Puback),
+        pid().prop_map(Packet::Pubrec),
+        pid().prop_map(Packet::Pubrel),
+        pid().prop_map(Packet::Pubcomp),
+        pid().prop_map(Packet::Unsuback)
+    ]
+}
+
+/// Any packet without a body (`Pingreq`, `Pingresp` or `Disconnect`).
+pub fn empty() -> impl Strategy<Value = Packet<'static>> {
+    prop_oneof![
+        Just(Packet::Pingreq),
+        Just(Packet::Pingresp),
+        Just(Packet::Disconnect)
+    ]
+}
+
+/// Any valid packet.
+pub fn packet() -> impl Strategy<Value = Packet<'static>> {
+    prop_oneof![
+        connect(),
+        connack(),
+        publish(),
+        pid_only(),
+        subscribe(),
+        suback(),
+        unsubscribe(),
+        empty()
+    ]
+}
@@ -0,0 +1,399 @@
+use crate::{decoder::decode_slice_with_len, remaining_length, Error, KeepAlive, Packet};
+use core::{
+    mem::MaybeUninit,
+    sync::atomic::{compiler_fence, Ordering},
+    time::Duration,
+};
+use std::vec::Vec;
+
+/// Incremental decoder for a non-blocking byte stream, tracking keep alive deadlines.
+///
+/// Feed the bytes read from the connection to [`push()`] and call [`poll_packet()`] until it
+/// returns `Ok(None)`. Once a [`KeepAlive`] is set, [`next_deadline()`] tells when the event loop
+/// needs to wake up even if nothing is received, and [`expired()`] what to do then. Times are
+/// durations since an arbitrary reference, like the start of the program.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use core::time::Duration;
+/// let secs = Duration::from_secs;
+/// let mut stream = StreamDecoder::new();
+/// stream.set_keep_alive(KeepAlive::new(10), secs(0));
+/// assert_eq!(Some(secs(10)), stream.next_deadline());
+///
+/// stream.push(&[0b11010000, 0]);
+/// assert_eq!(Some(Packet::Pingresp), stream.poll_packet(secs(4))?);
+/// assert_eq!(Some(DeadlineEvent::SendPingreq), stream.expired(secs(10)));
+/// stream.sent(secs(10));
+/// assert_eq!(Some(secs(19)), stream.next_deadline());
+/// assert_eq!(Some(DeadlineEvent::PeerTimeout), stream.expired(secs(19)));
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// To avoid the copy made by [`push()`], read directly into the decoder's buffer with
+/// [`unfilled()`] and [`advance()`]. The spare capacity isn't zeroed first, which suits
+/// `tokio::io::ReadBuf::uninit()` and other APIs filling `MaybeUninit<u8>` slices.
+///
+/// Deployments that must not leave payloads in memory can [`set_zeroize()`], so that bytes are
+/// overwritten with zeros once released, when the buffer grows and when the decoder is dropped.
+///
+/// A peer can announce a huge packet and then send it slowly, to make the decoder buffer it for a
+/// long time. [`set_budget()`] caps the size of the packets a connection may send, and thus the
+/// memory it holds.
+///
+/// [`push()`]: #method.push
+/// [`poll_packet()`]: #method.poll_packet
+/// [`set_budget()`]: #method.set_budget
+/// [`unfilled()`]: #method.unfilled
+/// [`advance()`]: #method.advance
+/// [`set_zeroize()`]: #method.set_zeroize
+/// [`KeepAlive`]: struct.KeepAlive.html
+/// [`next_deadline()`]: #method.next_deadline
+/// [`expired()`]: #method.expired
+#[derive(Debug, Clone, Default)]
+pub struct StreamDecoder {
+    buf: Vec<u8>,
+    /// Length of the packet returned by the last `poll_packet()`, to drop at the next call.
+    consumed: usize,
+    keep_alive: Option<KeepAlive>,
+    last_sent: Duration,
+    last_received: Duration,
+    /// Zero released bytes. The whole capacity of `buf` is then initialized.
+    zeroize: bool,
+    budget: Option<usize>,
+}
+
+/// Keep alive deadline reached, returned by [`StreamDecoder::expired()`].
+///
+/// [`StreamDecoder::expired()`]: struct.StreamDecoder.html#method.expired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlineEvent {
+    /// Nothing was sent for a whole keep alive interval: a client must send a `Pingreq`.
+    SendPingreq,
+    /// Nothing was received for one and a half keep alive intervals: the connection should be
+    /// closed ([MQTT-3.1.2-24]).
+    ///
+    /// [MQTT-3.1.2-24]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028
+    PeerTimeout,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking keep alive deadlines, counting from `now`.
+    pub fn set_keep_alive(&mut self, keep_alive: KeepAlive, now: Duration) {
+        self.keep_alive = Some(keep_alive);
+        self.last_sent = now;
+        self.last_received = now;
+    }
+
+    /// Overwrite bytes with zeros once they are released: packets at the next call to `push()`,
+    /// `unfilled()` or `poll_packet()`, the old buffer when it grows, and everything on drop.
+    ///
+    /// This costs an extra pass over each byte. It is disabled by default.
+    pub fn set_zeroize(&mut self, zeroize: bool) {
+        if zeroize && !self.zeroize {
+            zero(self.buf.spare_capacity_mut());
+        }
+        self.zeroize = zeroize;
+    }
+
+    /// Limit the bytes buffered for a single packet, or remove the limit with `None`.
+    ///
+    /// Once set, `poll_packet()` fails with `BudgetExceeded` as soon as the fixed header of a
+    /// larger packet is received, without waiting for the rest of it:
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// # use core::time::Duration;
+    /// let mut stream = StreamDecoder::new();
+    /// stream.set_budget(Some(1024));
+    /// // A publish with a remaining length of 2 MiB.
+    /// stream.push(&[0b00110000, 0x80, 0x80, 0x80, 0x01]);
+    /// assert_eq!(
+    ///     Err(Error::BudgetExceeded { budget: 1024, required: 2_097_157 }),
+    ///     stream.poll_packet(Duration::ZERO)
+    /// );
+    /// ```
+    pub fn set_budget(&mut self, budget: Option<usize>) {
+        self.budget = budget;
+    }
+
+    /// Append bytes read from the connection.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.compact();
+        self.reserve(bytes.len());
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Return at least `additional` bytes of uninitialized buffer space, to read data into.
+    ///
+    /// Once `n` bytes have been written at the start of the returned slice, make them part of
+    /// the stream with [`advance()`]:
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// # use core::time::Duration;
+    /// let mut stream = StreamDecoder::new();
+    /// let unfilled = stream.unfilled(1024);
+    /// // Typically done by `tokio::io::ReadBuf` or a vectored read.
+    /// unfilled[0].write(0b11000000);
+    /// unfilled[1].write(0);
+    /// // Safety: the first two bytes were just written.
+    /// unsafe { stream.advance(2) };
+    /// assert_eq!(Some(Packet::Pingreq), stream.poll_packet(Duration::ZERO)?);
+    /// # Ok::<(), Error>(())
+    /// ```
+    ///
+    /// [`advance()`]: #method.advance
+    pub fn unfilled(&mut self, additional: usize) -> &mut [MaybeUninit<u8>] {
+        self.compact();
+        self.reserve(additional);
+        self.buf.spare_capacity_mut()
+    }
+
+    /// Append the `n` bytes written at the start of the slice returned by [`unfilled()`].
+    ///
+    /// # Safety
+    ///
+    /// The first `n` bytes of the last slice returned by `unfilled()` must have been
+    /// initialized, and no other method may have been called since.
+    ///
+    /// [`unfilled()`]: #method.unfilled
+    pub unsafe fn advance(&mut self, n: usize) {
+        debug_assert!(n <= self.buf.capacity() - self.buf.len());
+        self.buf.set_len(self.buf.len() + n);
+    }
+
+    /// Decode the next complete packet, or return `Ok(None)` if more bytes are needed.
+    ///
+    /// A decoded packet counts as activity from the peer at `now`. The returned packet borrows
+    /// the decoder's buffer; its bytes are released by the next call to `push()` or
+    /// `poll_packet()`. After an error the stream can't be resynchronized.
+    ///
+    /// Fails with `BudgetExceeded` if the next packet is larger than the budget set by
+    /// [`set_budget()`].
+    ///
+    /// [`set_budget()`]: #method.set_budget
+    pub fn poll_packet(&mut self, now: Duration) -> Result<Option<Packet<'_>>, Error> {
+        self.compact();
+        if let Some(budget) = self.budget {
+            match self.next_packet_len()? {
+                Some(required) if required > budget => {
+                    return Err(Error::BudgetExceeded { budget, required })
+                }
+                _ => (),
+            }
+        }
+        match decode_slice_with_len(&self.buf)? {
+            Some((packet, len)) => {
+                self.consumed = len;
+                self.last_received = now;
+                Ok(Some(packet))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Record that a packet was sent at `now`.
+    pub fn sent(&mut self, now: Duration) {
+        self.last_sent = now;
+    }
+
+    /// Earliest time at which [`expired()`] returns an event, or `None` if keep alive is
+    /// disabled.
+    ///
+    /// [`expired()`]: #method.expired
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let (ping, timeout) = self.deadlines()?;
+        Some(ping.min(timeout))
+    }
+
+    /// Returns the keep alive deadline reached at `now`, if any. A peer timeout takes precedence.
+    pub fn expired(&self, now: Duration) -> Option<DeadlineEvent> {
+        let (ping, timeout) = self.deadlines()?;
+        if now >= timeout {
+            Some(DeadlineEvent::PeerTimeout)
+        } else if now >= ping {
+            Some(DeadlineEvent::SendPingreq)
+        } else {
+            None
+        }
+    }
+
+    /// Number of buffered bytes that aren't part of a returned packet yet.
+    pub fn pending_len(&self) -> usize {
+        self.buf.len() - self.consumed
+    }
+
+    /// Returns true if no partial packet is buffered.
+    pub fn is_empty(&self) -> bool {
+        self.pending_len() == 0
+    }
+
+    fn deadlines(&self) -> Option<(Duration, Duration)> {
+        let keep_alive = self.keep_alive?;
+        Some((
+            self.last_sent + keep_alive.interval()?,
+            self.last_received + keep_alive.timeout()?,
+        ))
+    }
+
+    /// Total size of the next packet, once its fixed header is buffered.
+    fn next_packet_len(&self) -> Result<Option<usize>, Error> {
+        let buf = self.buf.get(1..).unwrap_or_default();
+        Ok(remaining_length::decode(buf)?.map(|(len, size)| 1 + size + len))
+    }
+
+    fn compact(&mut self) {
+        self.buf.drain(..self.consumed);
+        if self.zeroize {
+            // The end of the buffer still holds the bytes that were moved to the front.
+            zero(&mut self.buf.spare_capacity_mut()[..self.consumed]);
+        }
+        self.consumed = 0;
+    }
+
+    /// Make room for `additional` bytes. With zeroize, the old buffer is zeroed before being
+    /// freed, and the new one is zeroed to keep it initialized.
+    fn reserve(&mut self, additional: usize) {
+        let len = self.buf.len();
+        if !self.zeroize || self.buf.capacity() - len >= additional {
+            self.buf.reserve(additional);
+            return;
+        }
+        let mut buf = Vec::with_capacity((len + additional).max(2 * self.buf.capacity()));
+        buf.extend_from_slice(&self.buf);
+        zero(buf.spare_capacity_mut());
+        self.buf.clear();
+        zero(self.buf.spare_capacity_mut());
+        self.buf = buf;
+    }
+}
+
+impl Drop for StreamDecoder {
+    fn drop(&mut self) {
+        if self.zeroize {
+            self.buf.clear();
+            zero(self.buf.spare_capacity_mut());
+        }
+    }
+}
+
+/// Overwrite `bytes` with zeros, in a way the compiler can't optimize out.
+fn zero(bytes: &mut [MaybeUninit<u8>]) {
+    for byte in bytes {
+        // Safety: `byte` is a valid, aligned pointer to a single byte.
+        unsafe { core::ptr::write_volatile(byte.as_mut_ptr(), 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn deadlines() {
+        let secs = Duration::from_secs;
+        let mut stream = StreamDecoder::new();
+        assert_eq!(None, stream.next_deadline());
+        stream.set_keep_alive(KeepAlive::new(0), secs(0));
+        assert_eq!(None, stream.next_deadline());
+        assert_eq!(None, stream.expired(secs(1000)));
+
+        stream.set_keep_alive(KeepAlive::new(60), secs(100));
+        assert_eq!(Some(secs(160)), stream.next_deadline());
+        assert_eq!(None, stream.expired(secs(159)));
+        stream.sent(secs(150));
+        // Receiving a partial packet isn't activity.
+        stream.push(&[0b11000000]);
+        assert_eq!(Ok(None), stream.poll_packet(secs(150)));
+        assert_eq!(Some(secs(190)), stream.next_deadline());
+        assert_eq!(Some(DeadlineEvent::PeerTimeout), stream.expired(secs(190)));
+        stream.push(&[0]);
+        assert_eq!(Ok(Some(Packet::Pingreq)), stream.poll_packet(secs(180)));
+        assert_eq!(Some(secs(210)), stream.next_deadline());
+        assert_eq!(Some(DeadlineEvent::SendPingreq), stream.expired(secs(210)));
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn uninit_reads() {
+        let mut bytes = [0u8; 64];
+        let len = encode_slice(&Packet::Puback(Pid::new() + 4), &mut bytes).unwrap();
+        let len = len + encode_slice(&Packet::Pingresp, &mut bytes[len..]).unwrap();
+
+        let mut stream = StreamDecoder::new();
+        // Fill in chunks of 3 bytes, like short network reads.
+        let mut packets = 0;
+        for chunk in bytes[..len].chunks(3) {
+            let unfilled = stream.unfilled(3);
+            assert!(unfilled.len() >= 3);
+            for (dst, src) in unfilled.iter_mut().zip(chunk) {
+                dst.write(*src);
+            }
+            unsafe { stream.advance(chunk.len()) };
+            while stream.poll_packet(Duration::ZERO).unwrap().is_some() {
+                packets += 1;
+            }
+        }
+        assert_eq!(2, packets);
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn budget() {
+        let mut stream = StreamDecoder::new();
+        stream.set_budget(Some(2));
+        stream.push(&[0b11000000, 0, 0b01000000]);
+        let pingreq = Packet::Pingreq;
+        assert_eq!(Ok(Some(pingreq)), stream.poll_packet(Duration::ZERO));
+        assert_eq!(Ok(None), stream.poll_packet(Duration::ZERO));
+        // Checked before the whole packet is received.
+        stream.push(&[2]);
+        assert_eq!(
+            Err(Error::BudgetExceeded {
+                budget: 2,
+                required: 4
+            }),
+            stream.poll_packet(Duration::ZERO)
+        );
+        stream.set_budget(None);
+        stream.push(&[0, 1]);
+        let puback = Packet::Puback(Pid::new());
+        assert_eq!(Ok(Some(puback)), stream.poll_packet(Duration::ZERO));
+    }
+
+    #[test]
+    fn zeroize() {
+        let publish = Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "a",
+            payload: b"secret",
+        });
+        let mut bytes = [0u8; 64];
+        let len = encode_slice(&publish, &mut bytes).unwrap();
+
+        let mut stream = StreamDecoder::new();
+        stream.push(&bytes[..2]);
+        stream.set_zeroize(true);
+        // Grow the buffer, then receive the rest and a partial packet.
+        stream.push(&bytes[2..len]);
+        stream.push(&[0b11000000]);
+        assert_eq!(Ok(Some(publish)), stream.poll_packet(Duration::ZERO));
+        assert_eq!(Ok(None), stream.poll_packet(Duration::ZERO));
+        assert_eq!(1, stream.pending_len());
+
+        // Safety: with zeroize, the whole capacity is initialized.
+        let all =
+            unsafe { core::slice::from_raw_parts(stream.buf.as_ptr(), stream.buf.capacity()) };
+        assert_eq!(0b11000000, all[0]);
+        assert!(all[1..].iter().all(|b| *b == 0));
+    }
+}
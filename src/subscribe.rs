@@ -12,6 +12,135 @@ pub(crate) type LimitedString = std::string::String;
 #[cfg(not(feature = "std"))]
 pub(crate) type LimitedString = heapless::String<256>;
 
+/// Error for a `LimitedVec` that is full.
+#[cfg(not(feature = "std"))]
+pub(crate) fn too_many(field: &'static str, limit: usize) -> Error {
+    Error::LimitExceeded {
+        field,
+        limit,
+        actual: limit + 1,
+    }
+}
+
+/// When the server sends retained messages for a new subscription ([MQTT 5 3.8.3.1]).
+///
+/// MQTT 3.1.1 only supports `SendAtSubscribe`.
+///
+/// [MQTT 5 3.8.3.1]: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901169
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+pub enum RetainHandling {
+    /// Send retained messages each time the client subscribes.
+    SendAtSubscribe,
+    /// Send retained messages only if the subscription didn't already exist.
+    SendAtSubscribeIfNew,
+    /// Never send retained messages for this subscription.
+    DoNotSend,
+}
+
+/// Options of a subscription, sent along with each [`TopicFilter`] of a [Subscribe] packet.
+///
+/// Only `qos` exists in MQTT 3.1.1, the other options come from [MQTT 5] and encoding a
+/// `Subscribe` packet where they differ from [`v3()`] fails with
+/// [`Error::UnsupportedOption`]. Use one of the presets to build options:
+///
+/// ```
+/// # use mqttrs::*;
+/// let topic = SubscribeTopic::new("a", SubscriptionOptions::v3(QoS::AtLeastOnce))?;
+/// assert!(topic.options.is_v3_compatible());
+///
+/// let bridge = SubscribeTopic::new("a", SubscriptionOptions::bridge(QoS::AtLeastOnce))?;
+/// let packet = Subscribe::new(Pid::new(), vec![bridge]).into();
+/// assert_eq!(Err(Error::UnsupportedOption { field: "no local" }),
+///            encode_slice(&packet, &mut [0; 16]));
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`TopicFilter`]: struct.TopicFilter.html
+/// [Subscribe]: struct.Subscribe.html
+/// [MQTT 5]: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901169
+/// [`v3()`]: #method.v3
+/// [`Error::UnsupportedOption`]: enum.Error.html#variant.UnsupportedOption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+pub struct SubscriptionOptions {
+    /// Maximum `QoS` at which the server can send messages to the client.
+    pub qos: QoS,
+    /// Don't receive the messages published by this client.
+    pub no_local: bool,
+    /// Keep the retain flag of forwarded messages instead of clearing it.
+    pub retain_as_published: bool,
+    /// When to send the retained messages matching the filter.
+    pub retain_handling: RetainHandling,
+}
+
+impl SubscriptionOptions {
+    /// Options supported by MQTT 3.1.1: only the `QoS` is set.
+    pub fn v3(qos: QoS) -> Self {
+        SubscriptionOptions {
+            qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::SendAtSubscribe,
+        }
+    }
+
+    /// MQTT 5 options for a client that doesn't want its own messages back nor the retained
+    /// messages again when resubscribing.
+    pub fn v5_strict(qos: QoS) -> Self {
+        SubscriptionOptions {
+            qos,
+            no_local: true,
+            retain_as_published: false,
+            retain_handling: RetainHandling::SendAtSubscribeIfNew,
+        }
+    }
+
+    /// MQTT 5 options for a bridge between servers: messages aren't looped back to the server
+    /// they came from, and keep their retain flag.
+    pub fn bridge(qos: QoS) -> Self {
+        SubscriptionOptions {
+            qos,
+            no_local: true,
+            retain_as_published: true,
+            retain_handling: RetainHandling::SendAtSubscribe,
+        }
+    }
+
+    /// Returns true if the options can be encoded in an MQTT 3.1.1 `Subscribe`.
+    pub fn is_v3_compatible(self) -> bool {
+        self.check_v3().is_ok()
+    }
+
+    fn check_v3(self) -> Result<(), Error> {
+        let field = if self.no_local {
+            "no local"
+        } else if self.retain_as_published {
+            "retain as published"
+        } else if self.retain_handling != RetainHandling::SendAtSubscribe {
+            "retain handling"
+        } else {
+            return Ok(());
+        };
+        Err(Error::UnsupportedOption { field })
+    }
+
+    pub(crate) fn from_u8(byte: u8) -> Result<Self, Error> {
+        Ok(SubscriptionOptions::v3(QoS::from_u8(byte)?))
+    }
+
+    pub(crate) fn to_u8(self) -> Result<u8, Error> {
+        self.check_v3()?;
+        Ok(self.qos.to_u8())
+    }
+}
+
+impl From<QoS> for SubscriptionOptions {
+    fn from(qos: QoS) -> Self {
+        SubscriptionOptions::v3(qos)
+    }
+}
+
 /// Subscribe topic.
 ///
 /// [Subscribe] packets contain a `Vec` of those.
@@ -20,34 +149,70 @@ pub(crate) type LimitedString = heapless::String<256>;
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub struct SubscribeTopic {
-    pub topic_path: LimitedString,
-    pub qos: QoS,
+    pub topic_filter: TopicFilter,
+    pub options: SubscriptionOptions,
 }
 
 impl SubscribeTopic {
+    /// Validate `filter` and build a subscription to it.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let topic = SubscribeTopic::new("a/+", QoS::AtLeastOnce).unwrap();
+    /// assert_eq!("a/+", topic.topic_filter.as_str());
+    /// assert_eq!(QoS::AtLeastOnce, topic.options.qos);
+    /// assert!(SubscribeTopic::new("", QoS::AtMostOnce).is_err());
+    /// ```
+    pub fn new(filter: &str, options: impl Into<SubscriptionOptions>) -> Result<Self, Error> {
+        Ok(SubscribeTopic {
+            topic_filter: TopicFilter::new(filter)?,
+            options: options.into(),
+        })
+    }
+
     pub(crate) fn from_buffer(buf: &[u8], offset: &mut usize) -> Result<Self, Error> {
-        let topic_path = LimitedString::from(read_str(buf, offset)?);
-        let qos = QoS::from_u8(buf[*offset])?;
-        *offset += 1;
-        Ok(SubscribeTopic { topic_path, qos })
+        let topic_filter = TopicFilter::new(read_str(buf, offset, "topic filter")?)?;
+        let options = SubscriptionOptions::from_u8(read_u8(buf, offset)?)?;
+        Ok(SubscribeTopic {
+            topic_filter,
+            options,
+        })
     }
 }
 
 /// Subscribe return value.
 ///
-/// [Suback] packets contain a `Vec` of those.
+/// [Suback] packets contain a `Vec` of those, one per topic of the acknowledged [Subscribe], in
+/// the same order.
 ///
-/// [Suback]: struct.Subscribe.html
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// [Suback]: struct.Suback.html
+/// [Subscribe]: struct.Subscribe.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub enum SubscribeReturnCodes {
+    /// The subscription was accepted, with this maximum `QoS` (which may be lower than the
+    /// requested one).
     Success(QoS),
+    /// The subscription was rejected (return code `0x80`).
     Failure,
 }
 
 impl SubscribeReturnCodes {
-    pub(crate) fn from_buffer<'a>(buf: &'a [u8], offset: &mut usize) -> Result<Self, Error> {
-        let code = buf[*offset];
-        *offset += 1;
+    /// Returns true if the subscription was accepted.
+    pub fn is_success(self) -> bool {
+        matches!(self, SubscribeReturnCodes::Success(_))
+    }
+
+    /// Returns the granted `QoS`, or `None` if the subscription was rejected.
+    pub fn granted_qos(self) -> Option<QoS> {
+        match self {
+            SubscribeReturnCodes::Success(qos) => Some(qos),
+            SubscribeReturnCodes::Failure => None,
+        }
+    }
+
+    pub(crate) fn from_buffer(buf: &[u8], offset: &mut usize) -> Result<Self, Error> {
+        let code = read_u8(buf, offset)?;
 
         if code == 0x80 {
             Ok(SubscribeReturnCodes::Failure)
@@ -56,8 +221,8 @@ impl SubscribeReturnCodes {
         }
     }
 
-    pub(crate) fn to_u8(&self) -> u8 {
-        match *self {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
             SubscribeReturnCodes::Failure => 0x80,
             SubscribeReturnCodes::Success(qos) => qos.to_u8(),
         }
@@ -68,6 +233,7 @@ impl SubscribeReturnCodes {
 ///
 /// [MQTT 3.8]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718063
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub struct Subscribe {
     pub pid: Pid,
     pub topics: LimitedVec<SubscribeTopic>,
@@ -77,6 +243,7 @@ pub struct Subscribe {
 ///
 /// [MQTT 3.9]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718068
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub struct Suback {
     pub pid: Pid,
     pub return_codes: LimitedVec<SubscribeReturnCodes>,
@@ -86,6 +253,7 @@ pub struct Suback {
 ///
 /// [MQTT 3.10]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718072
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub struct Unsubscribe {
     pub pid: Pid,
     pub topics: LimitedVec<LimitedString>,
@@ -106,45 +274,140 @@ impl Subscribe {
 
         let mut topics = LimitedVec::new();
         while *offset < payload_end {
+            #[allow(clippy::let_unit_value)]
             let _res = topics.push(SubscribeTopic::from_buffer(buf, offset)?);
 
             #[cfg(not(feature = "std"))]
-            _res.map_err(|_| Error::InvalidLength)?;
+            _res.map_err(|_| too_many("topics", topics.capacity()))?;
         }
 
         Ok(Subscribe { pid, topics })
     }
 
+    /// Length: pid(2) + topic.for_each(2+len + qos(1))
+    pub(crate) fn remaining_len(&self) -> usize {
+        let mut length = 2;
+        for topic in &self.topics {
+            length += topic.topic_filter.len() + 2 + 1;
+        }
+        length
+    }
+
     pub(crate) fn to_buffer(&self, buf: &mut [u8], offset: &mut usize) -> Result<usize, Error> {
         let header: u8 = 0b10000010;
         check_remaining(buf, offset, 1)?;
         write_u8(buf, offset, header)?;
 
-        // Length: pid(2) + topic.for_each(2+len + qos(1))
-        let mut length = 2;
-        for topic in &self.topics {
-            length += topic.topic_path.len() + 2 + 1;
-        }
-        let write_len = write_length(buf, offset, length)? + 1;
+        let write_len = write_length(buf, offset, self.remaining_len())? + 1;
 
         // Pid
         self.pid.to_buffer(buf, offset)?;
 
         // Topics
         for topic in &self.topics {
-            write_string(buf, offset, topic.topic_path.as_str())?;
-            write_u8(buf, offset, topic.qos.to_u8())?;
+            write_string(buf, offset, topic.topic_filter.as_str())?;
+            write_u8(buf, offset, topic.options.to_u8()?)?;
         }
 
         Ok(write_len)
     }
 }
 
+/// Builds an [Unsubscribe] packet, validating its topic filters.
+///
+/// Filters are validated like [`TopicFilter::new()`] does, duplicates are skipped, and adding
+/// more than [`max_topics()`] filters fails with `LimitExceeded`.
+///
+/// ```
+/// # use mqttrs::*;
+/// let unsubscribe = UnsubscribeBuilder::new(Pid::new())
+///     .max_topics(2)
+///     .topic("a/+")?
+///     .topic("a/+")?
+///     .topic("b")?
+///     .build();
+/// assert_eq!(2, unsubscribe.topics.len());
+/// let builder = UnsubscribeBuilder::new(Pid::new()).max_topics(1).topic("a")?;
+/// assert!(matches!(builder.topic("b"), Err(Error::LimitExceeded { .. })));
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [Unsubscribe]: struct.Unsubscribe.html
+/// [`TopicFilter::new()`]: struct.TopicFilter.html#method.new
+/// [`max_topics()`]: #method.max_topics
+#[derive(Debug, Clone)]
+pub struct UnsubscribeBuilder {
+    unsubscribe: Unsubscribe,
+    max_topics: usize,
+}
+
+impl UnsubscribeBuilder {
+    pub fn new(pid: Pid) -> Self {
+        let topics = LimitedVec::new();
+        #[cfg(feature = "std")]
+        let max_topics = usize::MAX;
+        #[cfg(not(feature = "std"))]
+        let max_topics = topics.capacity();
+        UnsubscribeBuilder {
+            unsubscribe: Unsubscribe { pid, topics },
+            max_topics,
+        }
+    }
+
+    /// Limit the number of topic filters, typically to the maximum accepted by the server.
+    ///
+    /// Without `std`, the limit can't exceed the capacity of the topics `Vec`.
+    pub fn max_topics(mut self, max_topics: usize) -> Self {
+        #[cfg(not(feature = "std"))]
+        let max_topics = max_topics.min(self.unsubscribe.topics.capacity());
+        self.max_topics = max_topics;
+        self
+    }
+
+    /// Add a topic filter, unless it was already added.
+    pub fn topic(mut self, filter: &str) -> Result<Self, Error> {
+        TopicFilter::validate(filter)?;
+        let topics = &mut self.unsubscribe.topics;
+        if topics.iter().any(|t| t == filter) {
+            return Ok(self);
+        }
+        if topics.len() >= self.max_topics {
+            return Err(Error::LimitExceeded {
+                field: "topics",
+                limit: self.max_topics,
+                actual: topics.len() + 1,
+            });
+        }
+        #[allow(clippy::let_unit_value)]
+        let _res = topics.push(LimitedString::from(filter));
+        #[cfg(not(feature = "std"))]
+        _res.map_err(|_| too_many("topics", self.max_topics))?;
+        Ok(self)
+    }
+
+    pub fn build(self) -> Unsubscribe {
+        self.unsubscribe
+    }
+}
+
 impl Unsubscribe {
     pub fn new(pid: Pid, topics: LimitedVec<LimitedString>) -> Self {
         Unsubscribe { pid, topics }
     }
 
+    /// Return the topic filters acknowledged by an `Unsuback` with `pid`, or `None` if the pid
+    /// doesn't match.
+    ///
+    /// An MQTT 3.1.1 `Unsuback` has no per-topic result: all the filters of the `Unsubscribe`
+    /// are acknowledged together.
+    pub fn acknowledged_by(&self, pid: Pid) -> Option<impl Iterator<Item = &str>> {
+        if pid == self.pid {
+            Some(self.topics.iter().map(|t| t.as_str()))
+        } else {
+            None
+        }
+    }
+
     pub(crate) fn from_buffer(
         remaining_len: usize,
         buf: &[u8],
@@ -155,21 +418,27 @@ impl Unsubscribe {
 
         let mut topics = LimitedVec::new();
         while *offset < payload_end {
-            let _res = topics.push(LimitedString::from(read_str(buf, offset)?));
+            #[allow(clippy::let_unit_value)]
+            let _res = topics.push(LimitedString::from(read_str(buf, offset, "topic filter")?));
 
             #[cfg(not(feature = "std"))]
-            _res.map_err(|_| Error::InvalidLength)?;
+            _res.map_err(|_| too_many("topics", topics.capacity()))?;
         }
 
         Ok(Unsubscribe { pid, topics })
     }
 
-    pub(crate) fn to_buffer(&self, buf: &mut [u8], offset: &mut usize) -> Result<usize, Error> {
-        let header: u8 = 0b10100010;
+    pub(crate) fn remaining_len(&self) -> usize {
         let mut length = 2;
         for topic in &self.topics {
             length += 2 + topic.len();
         }
+        length
+    }
+
+    pub(crate) fn to_buffer(&self, buf: &mut [u8], offset: &mut usize) -> Result<usize, Error> {
+        let header: u8 = 0b10100010;
+        let length = self.remaining_len();
         check_remaining(buf, offset, 1)?;
         write_u8(buf, offset, header)?;
 
@@ -187,6 +456,99 @@ impl Suback {
         Suback { pid, return_codes }
     }
 
+    /// Build the `Suback` acknowledging `subscribe`, with one return code per topic, in order.
+    ///
+    /// `policy` is called with each topic and decides its return code. A granted `QoS` higher
+    /// than the requested one is lowered to the requested one ([MQTT-3.9.3-2] allows the
+    /// server to grant less, not more).
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let subscribe = Subscribe::new(Pid::new(), vec![
+    ///     SubscribeTopic::new("a", QoS::AtLeastOnce)?,
+    ///     SubscribeTopic::new("$SYS/#", QoS::AtMostOnce)?,
+    /// ]);
+    /// let suback = Suback::respond_to(&subscribe, |topic| {
+    ///     if topic.topic_filter.starts_with('$') {
+    ///         SubscribeReturnCodes::Failure
+    ///     } else {
+    ///         SubscribeReturnCodes::Success(QoS::ExactlyOnce)
+    ///     }
+    /// });
+    /// assert_eq!(subscribe.pid, suback.pid);
+    /// assert_eq!(&[SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+    ///              SubscribeReturnCodes::Failure][..], &suback.return_codes[..]);
+    /// # Ok::<(), Error>(())
+    /// ```
+    ///
+    /// [MQTT-3.9.3-2]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718071
+    pub fn respond_to<F>(subscribe: &Subscribe, mut policy: F) -> Self
+    where
+        F: FnMut(&SubscribeTopic) -> SubscribeReturnCodes,
+    {
+        let return_codes = subscribe
+            .topics
+            .iter()
+            .map(|topic| match policy(topic) {
+                SubscribeReturnCodes::Success(qos) => {
+                    SubscribeReturnCodes::Success(qos.min(topic.options.qos))
+                }
+                SubscribeReturnCodes::Failure => SubscribeReturnCodes::Failure,
+            })
+            .collect();
+        Suback::new(subscribe.pid, return_codes)
+    }
+
+    /// Pair each return code with the topic of the `subscribe` it acknowledges.
+    ///
+    /// Returns `None` if the pids differ, or if the number of return codes doesn't match the
+    /// number of topics ([MQTT-3.8.4-5]).
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let subscribe = Subscribe::new(Pid::new(), vec![
+    ///     SubscribeTopic::new("a", QoS::AtLeastOnce).unwrap(),
+    ///     SubscribeTopic::new("$SYS/#", QoS::AtMostOnce).unwrap(),
+    /// ]);
+    /// let suback = Suback::new(Pid::new(), vec![SubscribeReturnCodes::Success(QoS::AtMostOnce),
+    ///                                           SubscribeReturnCodes::Failure]);
+    /// let rejected: Vec<_> = suback.rejected(&subscribe).unwrap().collect();
+    /// assert_eq!("$SYS/#", rejected[0].topic_filter.as_str());
+    /// ```
+    ///
+    /// [MQTT-3.8.4-5]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718068
+    pub fn pair_with<'s>(
+        &'s self,
+        subscribe: &'s Subscribe,
+    ) -> Option<impl Iterator<Item = (&'s SubscribeTopic, SubscribeReturnCodes)> + 's> {
+        if self.pid != subscribe.pid || self.return_codes.len() != subscribe.topics.len() {
+            return None;
+        }
+        Some(
+            subscribe
+                .topics
+                .iter()
+                .zip(self.return_codes.iter().copied()),
+        )
+    }
+
+    /// Return the topics of `subscribe` that were rejected.
+    ///
+    /// Returns `None` in the same cases as [`pair_with()`].
+    ///
+    /// [`pair_with()`]: #method.pair_with
+    pub fn rejected<'s>(
+        &'s self,
+        subscribe: &'s Subscribe,
+    ) -> Option<impl Iterator<Item = &'s SubscribeTopic> + 's> {
+        let pairs = self.pair_with(subscribe)?;
+        Some(
+            pairs
+                .filter(|(_, code)| !code.is_success())
+                .map(|(topic, _)| topic),
+        )
+    }
+
     pub(crate) fn from_buffer(
         remaining_len: usize,
         buf: &[u8],
@@ -197,10 +559,11 @@ impl Suback {
 
         let mut return_codes = LimitedVec::new();
         while *offset < payload_end {
+            #[allow(clippy::let_unit_value)]
             let _res = return_codes.push(SubscribeReturnCodes::from_buffer(buf, offset)?);
 
             #[cfg(not(feature = "std"))]
-            _res.map_err(|_| Error::InvalidLength)?;
+            _res.map_err(|_| too_many("return codes", return_codes.capacity()))?;
         }
 
         Ok(Suback { pid, return_codes })
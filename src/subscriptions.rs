@@ -0,0 +1,457 @@
+use crate::*;
+use core::iter::FromIterator;
+use std::{collections::HashMap, string::String, vec::Vec};
+
+/// A set of subscriptions, with at most one entry per topic filter.
+///
+/// Adding a filter that is already present keeps the highest `QoS`. Clients can use this to
+/// track their subscriptions and to send only the [`Subscribe`] packets that change something:
+///
+/// ```
+/// # use mqttrs::*;
+/// let mut subs = SubscriptionSet::new();
+/// let requested = vec![SubscribeTopic::new("a/#", QoS::AtMostOnce)?,
+///                      SubscribeTopic::new("b", QoS::AtLeastOnce)?,
+///                      SubscribeTopic::new("a/#", QoS::AtLeastOnce)?];
+/// let subscribe = subs.merge(Pid::new(), requested).unwrap();
+/// assert_eq!(2, subscribe.topics.len());
+/// assert_eq!(QoS::AtLeastOnce, subs.get("a/#").unwrap().options.qos);
+///
+/// // Nothing new: no packet to send.
+/// let requested = vec![SubscribeTopic::new("b", QoS::AtMostOnce)?];
+/// assert_eq!(None, subs.merge(Pid::new(), requested));
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`Subscribe`]: struct.Subscribe.html
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubscriptionSet {
+    topics: Vec<SubscribeTopic>,
+}
+
+impl SubscriptionSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a subscription, or raise the `QoS` of an existing one.
+    ///
+    /// Returns true if the set changed, meaning that the subscription needs to be sent to the
+    /// server.
+    pub fn insert(&mut self, topic: SubscribeTopic) -> bool {
+        match self
+            .topics
+            .iter_mut()
+            .find(|t| t.topic_filter == topic.topic_filter)
+        {
+            Some(existing) if existing.options.qos >= topic.options.qos => false,
+            Some(existing) => {
+                existing.options.qos = topic.options.qos;
+                true
+            }
+            None => {
+                self.topics.push(topic);
+                true
+            }
+        }
+    }
+
+    /// Add `requested` subscriptions, returning a `Subscribe` packet with only those that changed
+    /// the set, or `None` if there is nothing to send.
+    ///
+    /// Repeated filters are collapsed into one topic with the highest `QoS`.
+    pub fn merge(
+        &mut self,
+        pid: Pid,
+        requested: impl IntoIterator<Item = SubscribeTopic>,
+    ) -> Option<Subscribe> {
+        let mut changed = SubscriptionSet::new();
+        for topic in requested {
+            if self.insert(topic.clone()) {
+                changed.insert(topic);
+            }
+        }
+        if changed.is_empty() {
+            None
+        } else {
+            Some(changed.to_subscribe(pid))
+        }
+    }
+
+    /// Remove the subscription to `filter`, returning it if it was present.
+    pub fn remove(&mut self, filter: &str) -> Option<SubscribeTopic> {
+        let pos = self
+            .topics
+            .iter()
+            .position(|t| t.topic_filter.as_str() == filter)?;
+        Some(self.topics.remove(pos))
+    }
+
+    pub fn get(&self, filter: &str) -> Option<&SubscribeTopic> {
+        self.topics
+            .iter()
+            .find(|t| t.topic_filter.as_str() == filter)
+    }
+
+    /// Iterate over the subscriptions, in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = &SubscribeTopic> {
+        self.topics.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.topics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.topics.is_empty()
+    }
+
+    /// Build a `Subscribe` packet with all the subscriptions.
+    pub fn to_subscribe(&self, pid: Pid) -> Subscribe {
+        Subscribe::new(pid, self.topics.clone())
+    }
+
+    /// Build the `Subscribe` packets restoring all the subscriptions, typically after connecting
+    /// to a server that didn't keep the session.
+    ///
+    /// Each packet is at most `max_packet_size` bytes long once encoded. Pids are taken from
+    /// `next_pid`, which is advanced past the last one used. Fails with `LimitExceeded` if a
+    /// single subscription doesn't fit in `max_packet_size`.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let subs: SubscriptionSet = (0..100)
+    ///     .map(|i| SubscribeTopic::new(&format!("sensor/{}", i), QoS::AtLeastOnce).unwrap())
+    ///     .collect();
+    /// let mut next_pid = Pid::new();
+    /// let packets = subs.resubscribe(&mut next_pid, 256).unwrap();
+    /// assert_eq!(5, packets.len());
+    /// assert!(packets.iter().all(|s| Packet::from(s.clone()).encoded_len() <= 256));
+    /// assert_eq!(100, packets.iter().map(|s| s.topics.len()).sum::<usize>());
+    /// assert_eq!(Pid::new() + 5, next_pid);
+    /// ```
+    pub fn resubscribe(
+        &self,
+        next_pid: &mut Pid,
+        max_packet_size: usize,
+    ) -> Result<Vec<Subscribe>, Error> {
+        let mut packets = Vec::new();
+        let mut topics = Vec::new();
+        // Pid length.
+        let mut length = 2;
+        for topic in &self.topics {
+            let topic_len = 2 + topic.topic_filter.len() + 1;
+            if !topics.is_empty() && packet_len(length + topic_len)? > max_packet_size {
+                packets.push(Subscribe::new(*next_pid, core::mem::take(&mut topics)));
+                *next_pid = next_pid.wrapping_next();
+                length = 2;
+            }
+            length += topic_len;
+            if packet_len(length)? > max_packet_size {
+                return Err(Error::LimitExceeded {
+                    field: "packet size",
+                    limit: max_packet_size,
+                    actual: packet_len(length)?,
+                });
+            }
+            topics.push(topic.clone());
+        }
+        if !topics.is_empty() {
+            packets.push(Subscribe::new(*next_pid, topics));
+            *next_pid = next_pid.wrapping_next();
+        }
+        Ok(packets)
+    }
+}
+
+/// Encoded length of a packet with this remaining length.
+fn packet_len(remaining_len: usize) -> Result<usize, Error> {
+    Ok(1 + crate::encoder::length_len(remaining_len)? + remaining_len)
+}
+
+impl Extend<SubscribeTopic> for SubscriptionSet {
+    fn extend<I: IntoIterator<Item = SubscribeTopic>>(&mut self, iter: I) {
+        for topic in iter {
+            self.insert(topic);
+        }
+    }
+}
+
+impl FromIterator<SubscribeTopic> for SubscriptionSet {
+    fn from_iter<I: IntoIterator<Item = SubscribeTopic>>(iter: I) -> Self {
+        let mut set = SubscriptionSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+/// Subscription identifiers, mapping each topic filter to an id and a user value (typically a
+/// callback).
+///
+/// MQTT 5 servers send the ids of the matching subscriptions along with each publish. MQTT 3.1.1
+/// publishes carry no id, so [`resolve()`] finds them by matching the topic name against the
+/// filters, giving the same result.
+///
+/// ```
+/// # use mqttrs::*;
+/// let mut ids = SubscriptionIds::new();
+/// let temp = ids.assign(TopicFilter::new("sensors/+/temp")?, "on_temp")?;
+/// let all = ids.assign(TopicFilter::new("sensors/#")?, "on_any")?;
+/// let matched: Vec<_> = ids.resolve("sensors/kitchen/temp").collect();
+/// assert_eq!(vec![(temp, &"on_temp"), (all, &"on_any")], matched);
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`resolve()`]: #method.resolve
+#[derive(Debug, Clone)]
+pub struct SubscriptionIds<T> {
+    next_id: u32,
+    entries: Vec<(u32, TopicFilter, T)>,
+}
+
+impl<T> Default for SubscriptionIds<T> {
+    fn default() -> Self {
+        SubscriptionIds {
+            next_id: 1,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T> SubscriptionIds<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Assign an id to `filter`, replacing the previous id and value of the same filter.
+    ///
+    /// Ids start at 1 and are never reused. Once the MQTT 5 maximum (268,435,455) is reached,
+    /// fails with `LimitExceeded` and leaves the subscriptions unchanged.
+    pub fn assign(&mut self, filter: TopicFilter, value: T) -> Result<u32, Error> {
+        let id = self.next_id;
+        if id > remaining_length::MAX as u32 {
+            return Err(Error::LimitExceeded {
+                field: "subscription identifier",
+                limit: remaining_length::MAX,
+                actual: id as usize,
+            });
+        }
+        self.next_id += 1;
+        self.entries.retain(|(_, f, _)| *f != filter);
+        self.entries.push((id, filter, value));
+        Ok(id)
+    }
+
+    /// Remove the subscription to `filter`, returning its id and value.
+    pub fn remove(&mut self, filter: &str) -> Option<(u32, T)> {
+        let pos = self
+            .entries
+            .iter()
+            .position(|(_, f, _)| f.as_str() == filter)?;
+        let (id, _, value) = self.entries.remove(pos);
+        Some((id, value))
+    }
+
+    /// Return the id of `filter`.
+    pub fn id(&self, filter: &str) -> Option<u32> {
+        self.entries
+            .iter()
+            .find(|(_, f, _)| f.as_str() == filter)
+            .map(|(id, _, _)| *id)
+    }
+
+    /// Return the filter and value of `id`.
+    pub fn get(&self, id: u32) -> Option<(&TopicFilter, &T)> {
+        self.entries
+            .iter()
+            .find(|(i, _, _)| *i == id)
+            .map(|(_, f, v)| (f, v))
+    }
+
+    /// Return the ids and values of the subscriptions matching `topic_name`, in assignment order.
+    pub fn resolve<'s>(&'s self, topic_name: &'s str) -> impl Iterator<Item = (u32, &'s T)> + 's {
+        self.entries
+            .iter()
+            .filter(move |(_, f, _)| f.matches(topic_name))
+            .map(|(id, _, v)| (*id, v))
+    }
+}
+
+/// Result of unsubscribing from one topic filter.
+///
+/// An MQTT 3.1.1 `Unsuback` acknowledges all the filters of an `Unsubscribe` together, so this is
+/// always `Success`. MQTT 5 adds per-filter failure reasons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum UnsubscribeResult {
+    Success,
+}
+
+/// Tracks the `Unsubscribe` packets waiting for an `Unsuback`.
+///
+/// ```
+/// # use mqttrs::*;
+/// let mut pending = PendingUnsubscribes::new();
+/// let unsubscribe = UnsubscribeBuilder::new(Pid::new()).topic("a")?.topic("b/#")?.build();
+/// pending.sent(&unsubscribe);
+/// let results = pending.acknowledge(Pid::new()).unwrap();
+/// assert_eq!(("b/#".to_string(), UnsubscribeResult::Success), results[1]);
+/// assert!(pending.is_empty());
+/// # Ok::<(), Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PendingUnsubscribes {
+    pending: HashMap<Pid, Vec<String>>,
+}
+
+impl PendingUnsubscribes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember the topics of a sent `Unsubscribe`, replacing any pending one with the same pid.
+    pub fn sent(&mut self, unsubscribe: &Unsubscribe) {
+        self.pending
+            .insert(unsubscribe.pid, unsubscribe.topics.to_vec());
+    }
+
+    /// Handle the `Unsuback` with `pid`, returning the result for each topic of the matching
+    /// `Unsubscribe` in order, or `None` if no `Unsubscribe` with this pid is pending.
+    pub fn acknowledge(&mut self, pid: Pid) -> Option<Vec<(String, UnsubscribeResult)>> {
+        let topics = self.pending.remove(&pid)?;
+        Some(
+            topics
+                .into_iter()
+                .map(|t| (t, UnsubscribeResult::Success))
+                .collect(),
+        )
+    }
+
+    /// Returns true if an `Unsubscribe` with `pid` is waiting for its `Unsuback`.
+    pub fn contains(&self, pid: Pid) -> bool {
+        self.pending.contains_key(&pid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec;
+
+    #[test]
+    fn merge() {
+        let topic = |f, qos| SubscribeTopic::new(f, qos).unwrap();
+        let mut set: SubscriptionSet = vec![
+            topic("a", QoS::AtMostOnce),
+            topic("a", QoS::ExactlyOnce),
+            topic("b", QoS::AtLeastOnce),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(2, set.len());
+        assert_eq!(QoS::ExactlyOnce, set.get("a").unwrap().options.qos);
+
+        let subscribe = set
+            .merge(
+                Pid::new(),
+                vec![
+                    topic("a", QoS::AtLeastOnce),
+                    topic("b", QoS::ExactlyOnce),
+                    topic("c", QoS::AtMostOnce),
+                ],
+            )
+            .unwrap();
+        let sent: Vec<_> = subscribe
+            .topics
+            .iter()
+            .map(|t| (t.topic_filter.as_str(), t.options.qos))
+            .collect();
+        assert_eq!(vec![("b", QoS::ExactlyOnce), ("c", QoS::AtMostOnce)], sent);
+
+        assert_eq!(Some(topic("b", QoS::ExactlyOnce)), set.remove("b"));
+        assert_eq!(None, set.remove("b"));
+        assert_eq!(2, set.to_subscribe(Pid::new()).topics.len());
+    }
+
+    #[test]
+    fn resubscribe() {
+        let set: SubscriptionSet = ["a", "bb", "ccc"]
+            .iter()
+            .map(|f| SubscribeTopic::new(f, QoS::AtMostOnce).unwrap())
+            .collect();
+        let mut pid = Pid::new();
+        // Header(2) + pid(2) + "a"(4) + "bb"(5).
+        let packets = set.resubscribe(&mut pid, 13).unwrap();
+        let sizes: Vec<_> = packets.iter().map(|s| s.topics.len()).collect();
+        assert_eq!(vec![2, 1], sizes);
+        assert_eq!(Pid::new() + 1, packets[1].pid);
+        assert_eq!(Pid::new() + 2, pid);
+
+        assert_eq!(
+            Err(Error::LimitExceeded {
+                field: "packet size",
+                limit: 8,
+                actual: 9
+            }),
+            set.resubscribe(&mut pid, 8)
+        );
+        assert!(SubscriptionSet::new()
+            .resubscribe(&mut pid, 8)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn subscription_ids() {
+        let filter = |f| TopicFilter::new(f).unwrap();
+        let mut ids = SubscriptionIds::new();
+        assert_eq!(Ok(1), ids.assign(filter("a/+"), 'a'));
+        assert_eq!(Ok(2), ids.assign(filter("#"), 'b'));
+        assert_eq!(Ok(3), ids.assign(filter("a/+"), 'c'));
+        assert_eq!(Some(3), ids.id("a/+"));
+        assert_eq!(Some((&filter("#"), &'b')), ids.get(2));
+        assert_eq!(None, ids.get(1));
+
+        let matched: Vec<_> = ids.resolve("a/x").collect();
+        assert_eq!(vec![(2, &'b'), (3, &'c')], matched);
+        assert_eq!(0, ids.resolve("$SYS/x").count());
+
+        assert_eq!(Some((2, 'b')), ids.remove("#"));
+        assert_eq!(0, ids.resolve("b").count());
+
+        ids.next_id = 268_435_455;
+        assert_eq!(Ok(268_435_455), ids.assign(filter("b"), 'd'));
+        assert_eq!(
+            Err(Error::LimitExceeded {
+                field: "subscription identifier",
+                limit: 268_435_455,
+                actual: 268_435_456
+            }),
+            ids.assign(filter("a/+"), 'e')
+        );
+        assert_eq!(Some(3), ids.id("a/+"));
+    }
+
+    #[test]
+    fn pending_unsubscribes() {
+        let pid = Pid::new();
+        let mut pending = PendingUnsubscribes::new();
+        pending.sent(&Unsubscribe::new(pid, vec!["a".into()]));
+        pending.sent(&Unsubscribe::new(pid + 1, vec!["b".into(), "c".into()]));
+        assert!(pending.contains(pid + 1));
+        assert_eq!(None, pending.acknowledge(pid + 2));
+        let results = pending.acknowledge(pid + 1).unwrap();
+        let topics: Vec<_> = results.iter().map(|(t, _)| t.as_str()).collect();
+        assert_eq!(vec!["b", "c"], topics);
+        assert_eq!(None, pending.acknowledge(pid + 1));
+        assert_eq!(1, pending.len());
+    }
+}
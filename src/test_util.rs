@@ -0,0 +1,659 @@
+//! Helpers for testing MQTT implementations, enabled by the `test-util` feature.
+//!
+//! [`corrupt()`] turns a valid packet into systematically malformed variants, one per
+//! [`Corruption`] class, so that broker and client authors can check that their error handling
+//! covers each of them:
+//!
+//! ```
+//! # use mqttrs::{*, test_util::*};
+//! let pkt = Packet::Puback(Pid::new());
+//! for malformed in corrupt(&pkt) {
+//!     assert!(decode_slice(&malformed.bytes).is_err(), "{:?}", malformed);
+//! }
+//! ```
+//!
+//! [`LoadGenerator`] produces a seeded stream of realistic valid `Publish` packets, to benchmark
+//! the throughput of brokers built on `mqttrs`:
+//!
+//! ```
+//! # use mqttrs::{*, test_util::*};
+//! let profile = LoadProfile { topic_depth: 2..=4, ..LoadProfile::default() };
+//! let mut gen = LoadGenerator::new(profile, 42);
+//! let mut buf = [0u8; 2048];
+//! for _ in 0..100 {
+//!     let len = gen.encode_next(&mut buf).unwrap();
+//!     assert!(matches!(decode_slice(&buf[..len]), Ok(Some(Packet::Publish(_)))));
+//! }
+//! ```
+//!
+//! [`assert_roundtrip()`] checks that a packet survives encoding and decoding, and [`corpus()`]
+//! lists edge-case packets, so that codecs and wrappers built on `mqttrs` can check that they
+//! don't break round-tripping:
+//!
+//! ```
+//! # use mqttrs::test_util::*;
+//! for (_name, packet) in corpus() {
+//!     assert_roundtrip(&packet);
+//! }
+//! ```
+//!
+//! [`corrupt()`]: fn.corrupt.html
+//! [`Corruption`]: enum.Corruption.html
+//! [`LoadGenerator`]: struct.LoadGenerator.html
+//! [`assert_roundtrip()`]: fn.assert_roundtrip.html
+//! [`corpus()`]: fn.corpus.html
+
+use crate::*;
+use bytes::BytesMut;
+use core::{convert::TryFrom, ops::RangeInclusive};
+use std::{string::String, vec, vec::Vec};
+
+/// Class of corruption applied by [`corrupt()`].
+///
+/// [`corrupt()`]: fn.corrupt.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Corruption {
+    /// Fixed header flags that are invalid for the packet type (reserved flags set, or
+    /// `QoS 3` for `Publish`).
+    BadFlags,
+    /// Reserved packet type 15 in the fixed header.
+    ReservedType,
+    /// Remaining length encoded using more than the allowed 4 bytes.
+    OversizedRemainingLength,
+    /// Packet cut in the middle of a two-byte field (string length, pid...) or of the `Connack`
+    /// body, with the remaining length adjusted to match.
+    TruncatedField,
+    /// String or binary length prefix pointing past the end of the packet.
+    OverlongStringLength,
+    /// String that isn't valid UTF-8.
+    InvalidUtf8,
+    /// Invalid `QoS` in a `Subscribe`, or invalid return code in a `Connack` or `Suback`.
+    InvalidCode,
+}
+
+/// Malformed variant of a packet, returned by [`corrupt()`].
+///
+/// [`corrupt()`]: fn.corrupt.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Malformed {
+    pub corruption: Corruption,
+    pub bytes: Vec<u8>,
+}
+
+/// Encode `packet` into a `Vec`.
+pub(crate) fn encode_vec(packet: &Packet) -> Result<Vec<u8>, Error> {
+    let mut buf = BytesMut::new();
+    encode_grow(packet, &mut buf)?;
+    Ok(buf.into())
+}
+
+fn remaining_length(len: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut x = len;
+    loop {
+        let byte = (x % 128) as u8;
+        x /= 128;
+        if x == 0 {
+            out.push(byte);
+            return out;
+        }
+        out.push(byte | 128);
+    }
+}
+
+/// Rebuild a packet from its first byte and body, recomputing the remaining length.
+fn reframe(first: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![first];
+    out.extend(remaining_length(body.len()));
+    out.extend_from_slice(body);
+    out
+}
+
+/// Produce every applicable corruption of `packet`.
+///
+/// Classes that don't apply to a packet (like `InvalidUtf8` for a `Pingreq`) are skipped, so the
+/// result contains at most one variant per [`Corruption`] class. Each variant is a single packet
+/// that `decode_slice()` rejects with an error.
+///
+/// Panics if `packet` can't be encoded.
+///
+/// [`Corruption`]: enum.Corruption.html
+pub fn corrupt(packet: &Packet) -> Vec<Malformed> {
+    let bytes = encode_vec(packet).expect("encodable packet");
+    let explanation = Packet::explain(&bytes).expect("full packet");
+    let first = bytes[0];
+    let body_start = explanation.annotations[1].range.end;
+    let body = &bytes[body_start..];
+    // Body fields, with ranges relative to the body.
+    let fields: Vec<_> = explanation.annotations[2..]
+        .iter()
+        .map(|a| (a.name, a.range.start - body_start..a.range.end - body_start))
+        .collect();
+
+    let mut out = Vec::new();
+    let mut push = |corruption, bytes| out.push(Malformed { corruption, bytes });
+
+    let bad_flags = match packet.get_type() {
+        PacketType::Publish => first | 0b0110,
+        _ => first ^ 0b0001,
+    };
+    push(Corruption::BadFlags, reframe(bad_flags, body));
+
+    push(
+        Corruption::ReservedType,
+        reframe(0xf0 | (first & 0x0f), body),
+    );
+
+    let mut oversized = vec![first, 0xff, 0xff, 0xff, 0xff, 0x7f];
+    oversized.extend_from_slice(body);
+    push(Corruption::OversizedRemainingLength, oversized);
+
+    let two_bytes = fields
+        .iter()
+        .find(|(name, r)| r.len() == 2 && matches!(*name, "length" | "pid" | "keep alive"));
+    if let Some((_, r)) = two_bytes {
+        push(
+            Corruption::TruncatedField,
+            reframe(first, &body[..r.start + 1]),
+        );
+    } else if packet.get_type() == PacketType::Connack {
+        push(Corruption::TruncatedField, reframe(first, &body[..1]));
+    }
+
+    if let Some((_, r)) = fields.iter().find(|(name, _)| *name == "length") {
+        let len = (body.len() - r.end + 1) as u16;
+        let mut body = body.to_vec();
+        body[r.clone()].copy_from_slice(&len.to_be_bytes());
+        push(Corruption::OverlongStringLength, reframe(first, &body));
+    }
+
+    let string = fields.iter().find(|(name, r)| {
+        !r.is_empty()
+            && matches!(
+                *name,
+                "topic" | "topic filter" | "client id" | "will topic" | "username"
+            )
+    });
+    if let Some((_, r)) = string {
+        let mut body = body.to_vec();
+        body[r.start] = 0xff;
+        push(Corruption::InvalidUtf8, reframe(first, &body));
+    }
+
+    let code = fields
+        .iter()
+        .find(|(name, _)| matches!(*name, "requested qos" | "return code"));
+    if let Some((_, r)) = code {
+        let mut body = body.to_vec();
+        body[r.start] = match packet.get_type() {
+            PacketType::Connack => 6,
+            _ => 3,
+        };
+        push(Corruption::InvalidCode, reframe(first, &body));
+    }
+
+    out
+}
+
+/// Distribution of payload sizes generated by [`LoadGenerator`].
+///
+/// [`LoadGenerator`]: struct.LoadGenerator.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayloadSize {
+    /// Always the same size.
+    Fixed(usize),
+    /// Uniformly distributed in the range.
+    Uniform(RangeInclusive<usize>),
+    /// Exponentially distributed with the given mean (mostly small payloads with a long tail),
+    /// capped at `max`.
+    Exponential { mean: usize, max: usize },
+}
+
+/// Shape of the traffic produced by [`LoadGenerator`].
+///
+/// [`LoadGenerator`]: struct.LoadGenerator.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadProfile {
+    /// Number of levels in generated topic names.
+    pub topic_depth: RangeInclusive<usize>,
+    /// Number of distinct names at each topic level.
+    pub topic_fanout: u32,
+    pub payload_size: PayloadSize,
+    /// Relative weights of `QoS 0`, `QoS 1` and `QoS 2` publishes.
+    pub qos_weights: [u32; 3],
+    /// Percentage of retained publishes.
+    pub retain_percent: u8,
+}
+
+impl Default for LoadProfile {
+    /// Telemetry-like traffic: 3 to 5 topic levels, ~64 bytes payloads, mostly `QoS 0`.
+    fn default() -> Self {
+        LoadProfile {
+            topic_depth: 3..=5,
+            topic_fanout: 10,
+            payload_size: PayloadSize::Exponential {
+                mean: 64,
+                max: 1024,
+            },
+            qos_weights: [80, 15, 5],
+            retain_percent: 1,
+        }
+    }
+}
+
+/// Seeded generator of valid `Publish` packets following a [`LoadProfile`].
+///
+/// The same profile and seed always produce the same packets. Pids are allocated sequentially for
+/// `QoS 1` and `QoS 2` publishes. Packets borrow the generator's buffers, so it isn't an
+/// `Iterator`; use `next_packet()` or `encode_next()` in a loop.
+///
+/// [`LoadProfile`]: struct.LoadProfile.html
+#[derive(Debug, Clone)]
+pub struct LoadGenerator {
+    profile: LoadProfile,
+    state: u64,
+    pid: Pid,
+    topic: String,
+    payload: Vec<u8>,
+}
+
+impl LoadGenerator {
+    /// Create a generator for `profile`, seeded with `seed`.
+    pub fn new(profile: LoadProfile, seed: u64) -> Self {
+        LoadGenerator {
+            profile,
+            state: seed,
+            pid: Pid::new(),
+            topic: String::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    /// SplitMix64, good enough for load generation and free of extra dependencies.
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `0..n` (`n > 0`).
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    fn in_range(&mut self, r: &RangeInclusive<usize>) -> usize {
+        let span = r.end().saturating_sub(*r.start()) as u64 + 1;
+        r.start() + self.below(span) as usize
+    }
+
+    fn payload_len(&mut self) -> usize {
+        match self.profile.payload_size.clone() {
+            PayloadSize::Fixed(n) => n,
+            PayloadSize::Uniform(r) => self.in_range(&r),
+            PayloadSize::Exponential { mean, max } => {
+                // Inverse transform sampling, with u in (0, 1].
+                let u = (self.below(1 << 53) + 1) as f64 / (1u64 << 53) as f64;
+                ((-u.ln() * mean as f64) as usize).min(max)
+            }
+        }
+    }
+
+    fn qos(&mut self) -> QoS {
+        let [w0, w1, w2] = self.profile.qos_weights;
+        let total = u64::from(w0) + u64::from(w1) + u64::from(w2);
+        let n = if total == 0 { 0 } else { self.below(total) };
+        if n < u64::from(w0) || total == 0 {
+            QoS::AtMostOnce
+        } else if n < u64::from(w0) + u64::from(w1) {
+            QoS::AtLeastOnce
+        } else {
+            QoS::ExactlyOnce
+        }
+    }
+
+    /// Generate the next packet.
+    pub fn next_packet(&mut self) -> Packet<'_> {
+        let depth = self.in_range(&self.profile.topic_depth.clone()).max(1);
+        let fanout = u64::from(self.profile.topic_fanout.max(1));
+        let mut topic = std::mem::take(&mut self.topic);
+        topic.clear();
+        for level in 0..depth {
+            if level > 0 {
+                topic.push('/');
+            }
+            let name = self.below(fanout);
+            topic.push_str(&std::format!("l{}n{}", level, name));
+        }
+        self.topic = topic;
+
+        let len = self.payload_len();
+        self.payload.clear();
+        for _ in 0..len {
+            let byte = self.next_u64() as u8;
+            self.payload.push(byte);
+        }
+
+        let qospid = match self.qos() {
+            QoS::AtMostOnce => QosPid::AtMostOnce,
+            QoS::AtLeastOnce => {
+                self.pid = self.pid.wrapping_next();
+                QosPid::AtLeastOnce(self.pid)
+            }
+            QoS::ExactlyOnce => {
+                self.pid = self.pid.wrapping_next();
+                QosPid::ExactlyOnce(self.pid)
+            }
+        };
+        let retain = self.below(100) < u64::from(self.profile.retain_percent);
+        Packet::Publish(Publish {
+            dup: false,
+            qospid,
+            retain,
+            topic_name: &self.topic,
+            payload: &self.payload,
+        })
+    }
+
+    /// Generate the next packet and encode it into `buf`, see `encode_slice()`.
+    pub fn encode_next(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let packet = self.next_packet();
+        encode_slice(&packet, buf)
+    }
+}
+
+/// Check that `packet` survives an encode/decode round-trip.
+///
+/// This also checks that decoding a partial packet returns `Ok(None)` and that encoding into a
+/// buffer that is too small returns `Err(WriteZero)` with the right sizes. Panics with a description of the failure.
+pub fn assert_roundtrip(packet: &Packet) {
+    let encoded = match encode_vec(packet) {
+        Ok(encoded) => encoded,
+        Err(e) => panic!("failed to encode {:?}: {:?}", packet.get_type(), e),
+    };
+    let len = encoded.len();
+    match decode_slice(&encoded) {
+        Ok(Some(ref decoded)) if decoded == packet => (),
+        other => panic!(
+            "{:?} round-trip mismatch: {:?} != {:?}",
+            packet.get_type(),
+            other,
+            packet
+        ),
+    }
+    // Checking every length would be quadratic for large packets.
+    let lens = (0..len.min(16)).chain(vec![len / 2, len - 1]);
+    for l in lens {
+        assert_eq!(
+            Ok(None),
+            decode_slice(&encoded[..l]),
+            "{:?} partial decode {}/{}",
+            packet.get_type(),
+            l,
+            len
+        );
+        let mut small = vec![0; l];
+        assert_eq!(
+            Err(Error::WriteZero {
+                required: len,
+                available: l
+            }),
+            encode_slice(packet, &mut small),
+            "{:?} small buffer {}/{}",
+            packet.get_type(),
+            l,
+            len
+        );
+    }
+}
+
+static ZEROES: [u8; 2_097_152] = [0; 2_097_152];
+static LONG_STR: [u8; 65535] = [b'a'; 65535];
+
+/// Named edge-case packets: empty and maximum-length strings, and remaining lengths on each side
+/// of the variable-length encoding boundaries.
+///
+/// All of them are valid: none violates a rule of [`conformance::check_packet()`].
+///
+/// [`conformance::check_packet()`]: ../conformance/fn.check_packet.html
+pub fn corpus() -> Vec<(&'static str, Packet<'static>)> {
+    let long = core::str::from_utf8(&LONG_STR).expect("ascii");
+    let pid_max = Pid::try_from(u16::MAX).expect("non-zero");
+    // Publish with a one-byte topic and a remaining length of `len`.
+    let publish = |len: usize| {
+        Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "a",
+            payload: &ZEROES[..len - 3],
+        })
+    };
+    let connect = Connect {
+        protocol: Protocol::MQTT311,
+        keep_alive: 0,
+        client_id: "",
+        clean_session: true,
+        last_will: None,
+        username: None,
+        password: None,
+    };
+    vec![
+        ("connect empty client id", connect.clone().into()),
+        (
+            "connect empty strings",
+            Connect {
+                keep_alive: u16::MAX,
+                last_will: Some(LastWill {
+                    // Topic names can't be empty (MQTT-4.7.3-1).
+                    topic: "w",
+                    message: b"",
+                    qos: QoS::ExactlyOnce,
+                    retain: true,
+                }),
+                username: Some(""),
+                password: Some(b""),
+                ..connect.clone()
+            }
+            .into(),
+        ),
+        (
+            "connect max length strings",
+            Connect {
+                client_id: long,
+                last_will: Some(LastWill {
+                    topic: long,
+                    message: &ZEROES[..65535],
+                    qos: QoS::AtLeastOnce,
+                    retain: false,
+                }),
+                username: Some(long),
+                password: Some(&ZEROES[..65535]),
+                ..connect
+            }
+            .into(),
+        ),
+        (
+            "connack session present",
+            Connack {
+                session_present: true,
+                code: ConnectReturnCode::Accepted,
+            }
+            .into(),
+        ),
+        (
+            "connack refused",
+            Connack {
+                session_present: false,
+                code: ConnectReturnCode::NotAuthorized,
+            }
+            .into(),
+        ),
+        (
+            "publish empty payload",
+            Publish {
+                dup: true,
+                qospid: QosPid::ExactlyOnce(pid_max),
+                retain: true,
+                topic_name: "a",
+                payload: b"",
+            }
+            .into(),
+        ),
+        (
+            "publish max length topic",
+            Publish {
+                dup: false,
+                qospid: QosPid::AtLeastOnce(Pid::new()),
+                retain: false,
+                topic_name: long,
+                payload: b"x",
+            }
+            .into(),
+        ),
+        ("publish remaining length 3", publish(3)),
+        ("publish remaining length 127", publish(127)),
+        ("publish remaining length 128", publish(128)),
+        ("publish remaining length 16383", publish(16383)),
+        ("publish remaining length 16384", publish(16384)),
+        ("publish remaining length 2097151", publish(2_097_151)),
+        ("publish remaining length 2097152", publish(2_097_152)),
+        ("puback min pid", Packet::Puback(Pid::new())),
+        ("pubrec max pid", Packet::Pubrec(pid_max)),
+        ("pubrel", Packet::Pubrel(Pid::new())),
+        ("pubcomp", Packet::Pubcomp(pid_max)),
+        (
+            "subscribe shortest filter",
+            Subscribe::new(
+                pid_max,
+                vec![SubscribeTopic::new("#", QoS::ExactlyOnce).unwrap()],
+            )
+            .into(),
+        ),
+        (
+            "subscribe max length filter",
+            Subscribe::new(
+                Pid::new(),
+                vec![SubscribeTopic::new(long, QoS::AtMostOnce).unwrap()],
+            )
+            .into(),
+        ),
+        (
+            "suback all codes",
+            Suback::new(
+                Pid::new(),
+                vec![
+                    SubscribeReturnCodes::Success(QoS::AtMostOnce),
+                    SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+                    SubscribeReturnCodes::Success(QoS::ExactlyOnce),
+                    SubscribeReturnCodes::Failure,
+                ],
+            )
+            .into(),
+        ),
+        ("unsuback", Packet::Unsuback(pid_max)),
+        ("pingreq", Packet::Pingreq),
+        ("pingresp", Packet::Pingresp),
+        ("disconnect", Packet::Disconnect),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::strategy;
+    use proptest::prelude::*;
+
+    #[test]
+    fn corrupt_publish() {
+        let pkt = Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtLeastOnce(Pid::new()),
+            retain: false,
+            topic_name: "a",
+            payload: b"x",
+        });
+        let malformed: Vec<_> = corrupt(&pkt)
+            .into_iter()
+            .map(|m| (m.corruption, m.bytes))
+            .collect();
+        assert_eq!(
+            malformed,
+            [
+                (Corruption::BadFlags, vec![0x36, 6, 0, 1, b'a', 0, 1, b'x']),
+                (
+                    Corruption::ReservedType,
+                    vec![0xf2, 6, 0, 1, b'a', 0, 1, b'x']
+                ),
+                (
+                    Corruption::OversizedRemainingLength,
+                    vec![0x32, 0xff, 0xff, 0xff, 0xff, 0x7f, 0, 1, b'a', 0, 1, b'x']
+                ),
+                (Corruption::TruncatedField, vec![0x32, 1, 0]),
+                (
+                    Corruption::OverlongStringLength,
+                    vec![0x32, 6, 0, 5, b'a', 0, 1, b'x']
+                ),
+                (
+                    Corruption::InvalidUtf8,
+                    vec![0x32, 6, 0, 1, 0xff, 0, 1, b'x']
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn load_generator() {
+        let profile = LoadProfile {
+            topic_depth: 2..=3,
+            topic_fanout: 4,
+            payload_size: PayloadSize::Uniform(10..=20),
+            qos_weights: [0, 1, 1],
+            retain_percent: 0,
+        };
+        let mut a = LoadGenerator::new(profile.clone(), 1);
+        let mut b = LoadGenerator::new(profile, 1);
+        let mut buf = [0u8; 128];
+        for _ in 0..100 {
+            let len = a.encode_next(&mut buf).unwrap();
+            let pkt = decode_slice(&buf[..len]).unwrap().unwrap();
+            assert_eq!(b.next_packet(), pkt);
+            match pkt {
+                Packet::Publish(p) => {
+                    assert!((2..=3).contains(&p.topic_name.split('/').count()));
+                    assert!((10..=20).contains(&p.payload.len()));
+                    assert!(p.qospid.pid().is_some());
+                    assert!(!p.retain);
+                }
+                _ => panic!("{:?}", pkt),
+            }
+        }
+        let mut c = LoadGenerator::new(LoadProfile::default(), 2);
+        assert_ne!(a.next_packet(), c.next_packet());
+    }
+
+    #[test]
+    fn corpus_roundtrip() {
+        for (name, packet) in corpus() {
+            assert_roundtrip(&packet);
+            let violations = crate::conformance::check_packet(&packet);
+            assert!(violations.is_empty(), "{}: {:?}", name, violations);
+        }
+        // The largest publish uses a 4-byte remaining length.
+        let (_, largest) = corpus()
+            .into_iter()
+            .find(|(name, _)| *name == "publish remaining length 2097152")
+            .unwrap();
+        assert_eq!(1 + 4 + 2_097_152, encode_vec(&largest).unwrap().len());
+    }
+
+    proptest! {
+        #[test]
+        fn corrupt_is_rejected(pkt in strategy::packet()) {
+            for m in corrupt(&pkt) {
+                prop_assert!(decode_slice(&m.bytes).is_err(), "{:?}", m);
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{subscribe::LimitedString, *};
+use core::{convert::TryFrom, fmt, ops::Deref};
+#[cfg(feature = "derive")]
+use serde::{Deserialize, Serialize};
+
+/// A valid topic filter, as used in [`Subscribe`] packets ([MQTT 4.7]).
+///
+/// Topic filters are validated when constructed: they must not be empty, must not contain the
+/// null character, and the `+` and `#` wildcards must occupy a whole level, `#` being the last
+/// one. Use [`levels()`] to iterate over the parsed levels.
+///
+/// ```
+/// # use mqttrs::*;
+/// let filter = TopicFilter::new("sensors/+/temp/#").unwrap();
+/// let levels: Vec<_> = filter.levels().collect();
+/// assert_eq!(levels, [FilterLevel::Name("sensors"),
+///                     FilterLevel::SingleWildcard,
+///                     FilterLevel::Name("temp"),
+///                     FilterLevel::MultiWildcard]);
+/// let misplaced = TopicError { kind: TopicErrorKind::MisplacedWildcard('#'), level: 2 };
+/// assert_eq!(Err(Error::InvalidTopicFilter(misplaced)), TopicFilter::new("a/b#"));
+/// ```
+///
+/// [`Subscribe`]: struct.Subscribe.html
+/// [MQTT 4.7]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106
+/// [`levels()`]: #method.levels
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "derive",
+    derive(Serialize, Deserialize),
+    serde(try_from = "LimitedString", into = "LimitedString")
+)]
+pub struct TopicFilter(LimitedString);
+
+/// One level of a [`TopicFilter`].
+///
+/// [`TopicFilter`]: struct.TopicFilter.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FilterLevel<'a> {
+    /// A level matching only this exact (possibly empty) name.
+    Name(&'a str),
+    /// `+`, matching any single level.
+    SingleWildcard,
+    /// `#`, matching the parent level and any number of child levels.
+    MultiWildcard,
+}
+
+/// Why a topic filter is invalid, carried by [`Error::InvalidTopicFilter`].
+///
+/// [`Error::InvalidTopicFilter`]: enum.Error.html#variant.InvalidTopicFilter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopicError {
+    pub kind: TopicErrorKind,
+    /// The offending level, counting from 1.
+    pub level: usize,
+}
+
+/// Rule of [MQTT 4.7] broken by a topic filter.
+///
+/// [MQTT 4.7]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TopicErrorKind {
+    /// The filter is empty.
+    Empty,
+    /// The level contains a null character.
+    NullCharacter,
+    /// This wildcard doesn't occupy the whole level, or `#` isn't the last level.
+    MisplacedWildcard(char),
+    /// A `$share/` filter without a share name or topic filter.
+    IncompleteShare,
+    /// The share name of a `$share/` filter contains this wildcard.
+    ShareNameWildcard(char),
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            TopicErrorKind::Empty => write!(f, "topic filter is empty"),
+            TopicErrorKind::NullCharacter => write!(
+                f,
+                "topic filter contains a null character at level {}",
+                self.level
+            ),
+            TopicErrorKind::MisplacedWildcard(c) => write!(
+                f,
+                "topic filter contains misplaced wildcard '{}' at level {}",
+                c, self.level
+            ),
+            TopicErrorKind::IncompleteShare => {
+                write!(
+                    f,
+                    "shared subscription needs a share name and a topic filter"
+                )
+            }
+            TopicErrorKind::ShareNameWildcard(c) => {
+                write!(f, "shared subscription name contains wildcard '{}'", c)
+            }
+        }
+    }
+}
+
+impl TopicFilter {
+    /// Validate a topic filter.
+    ///
+    /// Returns [`Error::InvalidTopicFilter`] if `filter` breaks the rules of [MQTT 4.7], with the
+    /// offending level.
+    ///
+    /// [`Error::InvalidTopicFilter`]: enum.Error.html#variant.InvalidTopicFilter
+    /// [MQTT 4.7]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106
+    pub fn new(filter: &str) -> Result<Self, Error> {
+        Self::validate(filter)?;
+        Ok(TopicFilter(LimitedString::from(filter)))
+    }
+
+    pub(crate) fn validate(filter: &str) -> Result<(), Error> {
+        let invalid = |kind, level| Err(Error::InvalidTopicFilter(TopicError { kind, level }));
+        if filter.is_empty() {
+            return invalid(TopicErrorKind::Empty, 1);
+        }
+        let mut levels = filter.split('/').peekable();
+        let mut number = 0;
+        while let Some(level) = levels.next() {
+            number += 1;
+            let last = levels.peek().is_none();
+            if level.contains('\0') {
+                return invalid(TopicErrorKind::NullCharacter, number);
+            }
+            if level.contains('#') && (level != "#" || !last) {
+                return invalid(TopicErrorKind::MisplacedWildcard('#'), number);
+            }
+            if level.contains('+') && level != "+" {
+                return invalid(TopicErrorKind::MisplacedWildcard('+'), number);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Iterate over the `/`-separated levels of the filter.
+    pub fn levels(&self) -> impl Iterator<Item = FilterLevel<'_>> {
+        self.0.split('/').map(|level| match level {
+            "+" => FilterLevel::SingleWildcard,
+            "#" => FilterLevel::MultiWildcard,
+            name => FilterLevel::Name(name),
+        })
+    }
+
+    /// Returns true if `topic_name` matches this filter.
+    ///
+    /// Topic names starting with `$` aren't matched by a filter starting with a wildcard
+    /// ([MQTT-4.7.2-1]).
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// let filter = TopicFilter::new("sport/+/player1/#")?;
+    /// assert!(filter.matches("sport/tennis/player1"));
+    /// assert!(filter.matches("sport/tennis/player1/ranking"));
+    /// assert!(!filter.matches("sport/player1"));
+    /// assert!(!TopicFilter::new("#")?.matches("$SYS/uptime"));
+    /// # Ok::<(), Error>(())
+    /// ```
+    ///
+    /// [MQTT-4.7.2-1]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106
+    pub fn matches(&self, topic_name: &str) -> bool {
+        let mut levels = self.levels().peekable();
+        if topic_name.starts_with('$') && !matches!(levels.peek(), Some(FilterLevel::Name(_))) {
+            return false;
+        }
+        let mut names = topic_name.split('/');
+        for level in levels {
+            match level {
+                FilterLevel::MultiWildcard => return true,
+                FilterLevel::SingleWildcard => {
+                    if names.next().is_none() {
+                        return false;
+                    }
+                }
+                FilterLevel::Name(name) => {
+                    if names.next() != Some(name) {
+                        return false;
+                    }
+                }
+            }
+        }
+        names.next().is_none()
+    }
+
+    /// Returns true if the filter contains a wildcard.
+    pub fn has_wildcards(&self) -> bool {
+        self.0.contains(&['+', '#'][..])
+    }
+}
+
+impl Deref for TopicFilter {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<&str> for TopicFilter {
+    type Error = Error;
+    fn try_from(filter: &str) -> Result<Self, Error> {
+        TopicFilter::new(filter)
+    }
+}
+
+impl TryFrom<LimitedString> for TopicFilter {
+    type Error = Error;
+    fn try_from(filter: LimitedString) -> Result<Self, Error> {
+        Self::validate(&filter)?;
+        Ok(TopicFilter(filter))
+    }
+}
+
+impl From<TopicFilter> for LimitedString {
+    fn from(filter: TopicFilter) -> Self {
+        filter.0
+    }
+}
@@ -0,0 +1,153 @@
+//! Events emitted with the `tracing` feature.
+//!
+//! Each `encode_slice()`/`decode_slice()` call runs in an `encode`/`decode` span, and emits a
+//! `TRACE` event on success or a `DEBUG` event on failure. Fields are `packet_type`, `pid`,
+//! `remaining_len`, `len` (whole packet) and `error`. Decoding failures have the `packet_type` and
+//! `remaining_len` fields when the fixed header is valid, and the `first_byte` field otherwise.
+
+use crate::{decoder::read_header, Error, Packet, Pid};
+use tracing::{debug, trace};
+
+fn pid(packet: &Packet) -> Option<u16> {
+    match packet {
+        Packet::Publish(p) => p.qospid.pid(),
+        Packet::Puback(pid)
+        | Packet::Pubrec(pid)
+        | Packet::Pubrel(pid)
+        | Packet::Pubcomp(pid)
+        | Packet::Unsuback(pid) => Some(*pid),
+        Packet::Subscribe(s) => Some(s.pid),
+        Packet::Suback(s) => Some(s.pid),
+        Packet::Unsubscribe(u) => Some(u.pid),
+        Packet::Connect(_)
+        | Packet::Connack(_)
+        | Packet::Pingreq
+        | Packet::Pingresp
+        | Packet::Disconnect => None,
+    }
+    .map(Pid::get)
+}
+
+/// Remaining length of a valid encoded packet.
+fn remaining_len(encoded: &[u8]) -> Option<usize> {
+    match read_header(encoded, &mut 0) {
+        Ok(Some((_, len))) => Some(len),
+        _ => None,
+    }
+}
+
+pub(crate) fn encoded(packet: &Packet, buf: &[u8], res: &Result<usize, Error>) {
+    match res {
+        Ok(len) => trace!(
+            packet_type = ?packet.get_type(),
+            pid = ?pid(packet),
+            remaining_len = ?remaining_len(&buf[..*len]),
+            len,
+            "encoded packet"
+        ),
+        Err(error) => debug!(
+            packet_type = ?packet.get_type(),
+            pid = ?pid(packet),
+            buf_len = buf.len(),
+            ?error,
+            "failed to encode packet"
+        ),
+    }
+}
+
+pub(crate) fn decoded(buf: &[u8], res: &Result<Option<(Packet, usize)>, Error>) {
+    match res {
+        Ok(Some((packet, len))) => trace!(
+            packet_type = ?packet.get_type(),
+            pid = ?pid(packet),
+            remaining_len = ?remaining_len(&buf[..*len]),
+            len,
+            "decoded packet"
+        ),
+        Ok(None) => trace!(buf_len = buf.len(), "incomplete packet"),
+        Err(error) => match read_header(buf, &mut 0) {
+            Ok(Some((header, remaining_len))) => debug!(
+                packet_type = ?header.typ,
+                remaining_len,
+                ?error,
+                "failed to decode packet"
+            ),
+            _ => debug!(
+                first_byte = ?buf.first(),
+                ?error,
+                "failed to decode packet"
+            ),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::{format, string::String, sync::Mutex, vec::Vec};
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    /// Records the fields of every event.
+    #[derive(Default)]
+    struct Recorder {
+        spans: AtomicUsize,
+        events: Mutex<Vec<String>>,
+    }
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn core::fmt::Debug) {
+            self.0.push_str(&format!("{}={:?} ", field.name(), value));
+        }
+    }
+
+    impl Subscriber for &'static Recorder {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+        fn new_span(&self, _: &span::Attributes) -> span::Id {
+            span::Id::from_u64(self.spans.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, event: &Event) {
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            self.events.lock().unwrap().push(fields.0);
+        }
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn events() {
+        let recorder: &'static Recorder = Box::leak(Box::default());
+        tracing::subscriber::with_default(recorder, || {
+            let mut buf = [0u8; 4];
+            let len = encode_slice(&Packet::Puback(Pid::new() + 9), &mut buf).unwrap();
+            assert!(decode_slice(&buf[..len]).is_ok());
+            assert!(decode_slice(&buf[..2]).is_ok());
+            assert!(encode_slice(&Packet::Puback(Pid::new()), &mut buf[..2]).is_err());
+            assert!(decode_slice(&[0x20, 2, 0, 9]).is_err());
+            assert!(decode_slice(&[0xf0, 0]).is_err());
+        });
+        let events = recorder.events.lock().unwrap();
+        assert_eq!(
+            *events,
+            [
+                "message=encoded packet packet_type=Puback pid=Some(10) remaining_len=Some(2) len=4 ",
+                "message=decoded packet packet_type=Puback pid=Some(10) remaining_len=Some(2) len=4 ",
+                "message=incomplete packet buf_len=2 ",
+                "message=failed to encode packet packet_type=Puback pid=Some(1) buf_len=2 error=WriteZero { required: 4, available: 2 } ",
+                "message=failed to decode packet packet_type=Connack remaining_len=2 error=InvalidConnectReturnCode(9) ",
+                "message=failed to decode packet first_byte=Some(240) error=UnknownPacketType(15) ",
+            ]
+        );
+        assert_eq!(6, recorder.spans.load(Ordering::SeqCst));
+    }
+}
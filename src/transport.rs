@@ -0,0 +1,558 @@
+use crate::{decode_slice, decoder::read_length, encode_slice, Error, Packet, Pid, QosPid};
+use core::task::{Context, Poll, Waker};
+use std::{
+    collections::{HashSet, VecDeque},
+    io::{ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+    vec,
+    vec::Vec,
+};
+
+/// Something packets can be sent to, like a network connection.
+///
+/// Client engines and brokers can be written against `PacketSink` and [`PacketStream`], and be
+/// tested with a [`MemoryTransport`] instead of a socket.
+///
+/// [`PacketStream`]: trait.PacketStream.html
+/// [`MemoryTransport`]: struct.MemoryTransport.html
+pub trait PacketSink {
+    /// Encode and send `packet`.
+    fn send(&mut self, packet: &Packet<'_>) -> Result<(), Error>;
+}
+
+/// Something packets can be received from, like a network connection.
+///
+/// See [`PacketSink`].
+///
+/// [`PacketSink`]: trait.PacketSink.html
+pub trait PacketStream {
+    /// Receive the next packet.
+    ///
+    /// Returns `Ok(None)` if no packet is available: the peer closed the connection, or for a
+    /// non-blocking transport, no complete packet arrived yet. The packet borrows the stream's
+    /// buffer until the next call.
+    fn recv(&mut self) -> Result<Option<Packet<'_>>, Error>;
+}
+
+/// A [`PacketSink`] and [`PacketStream`] over a blocking `std::io` connection, like a
+/// `TcpStream`.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use std::io::Cursor;
+/// let mut client = IoTransport::new(Cursor::new(Vec::new()));
+/// client.send(&Packet::Pingreq)?;
+/// client.get_mut().set_position(0);
+/// assert_eq!(Some(Packet::Pingreq), client.recv()?);
+/// assert_eq!(None, client.recv()?);
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// Packets are read exactly, without reading ahead: the fixed header first, then the announced
+/// remaining length. Writes aren't buffered, so `T` may be wrapped in a `BufWriter`.
+///
+/// A peer can announce a packet of up to 256 MiB in a 5 byte header. Servers should
+/// [`set_max_packet_size()`] to bound the memory each connection can make them allocate.
+///
+/// To receive and send from different threads or tasks, [`split()`] the transport.
+///
+/// [`PacketSink`]: trait.PacketSink.html
+/// [`PacketStream`]: trait.PacketStream.html
+/// [`set_max_packet_size()`]: #method.set_max_packet_size
+/// [`split()`]: #method.split
+#[derive(Debug)]
+pub struct IoTransport<T> {
+    io: T,
+    buf: Vec<u8>,
+    max_packet_size: usize,
+}
+
+impl<T> IoTransport<T> {
+    pub fn new(io: T) -> Self {
+        IoTransport {
+            io,
+            buf: Vec::new(),
+            max_packet_size: usize::MAX,
+        }
+    }
+
+    /// Limit the size of received packets, fixed header included.
+    ///
+    /// `recv()` then fails with `BudgetExceeded` as soon as the fixed header of a larger packet
+    /// is read, before the buffer grows and with the rest of the packet left unread:
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// # use std::io::Cursor;
+    /// // A publish with a remaining length of 2 MiB.
+    /// let mut server = IoTransport::new(Cursor::new(vec![0b00110000, 0x80, 0x80, 0x80, 0x01]));
+    /// server.set_max_packet_size(1024);
+    /// assert_eq!(
+    ///     Err(Error::BudgetExceeded { budget: 1024, required: 2_097_157 }),
+    ///     server.recv()
+    /// );
+    /// ```
+    ///
+    /// There is no limit by default.
+    pub fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.max_packet_size = max_packet_size;
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    /// Split into a receiving and a sending half, each with its own buffer, that can be moved
+    /// to different threads or tasks.
+    ///
+    /// `split` separates the connection itself, like `TcpStream::try_clone()` or
+    /// `tokio::io::split()`. Bytes of a partially received packet and the maximum packet size
+    /// are kept by the receiving half.
+    ///
+    /// ```no_run
+    /// # use mqttrs::*;
+    /// # use std::{net::TcpStream, thread};
+    /// let stream = TcpStream::connect("localhost:1883")?;
+    /// let (mut rx, mut tx) = IoTransport::new(stream).split(|s| (s.try_clone().unwrap(), s));
+    /// thread::spawn(move || tx.send(&Packet::Pingreq));
+    /// while let Some(packet) = rx.recv()? {
+    ///     println!("{:?}", packet);
+    /// }
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn split<R, W>(self, split: impl FnOnce(T) -> (R, W)) -> (IoTransport<R>, IoTransport<W>) {
+        let (read, write) = split(self.io);
+        let rx = IoTransport {
+            io: read,
+            buf: self.buf,
+            max_packet_size: self.max_packet_size,
+        };
+        (rx, IoTransport::new(write))
+    }
+}
+
+impl<T: Write> PacketSink for IoTransport<T> {
+    fn send(&mut self, packet: &Packet<'_>) -> Result<(), Error> {
+        self.buf.clear();
+        self.buf.resize(packet.encoded_len(), 0);
+        let len = encode_slice(packet, &mut self.buf)?;
+        self.io.write_all(&self.buf[..len])?;
+        Ok(())
+    }
+}
+
+impl<T: Read> PacketStream for IoTransport<T> {
+    fn recv(&mut self) -> Result<Option<Packet<'_>>, Error> {
+        let buf = &mut self.buf;
+        buf.clear();
+        buf.push(0);
+        loop {
+            match self.io.read(&mut buf[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // Read the remaining length one byte at a time, until the continuation bit is cleared.
+        let (header_len, remaining_len) = loop {
+            buf.push(0);
+            let last = buf.len() - 1;
+            self.io.read_exact(&mut buf[last..])?;
+            if let Some(lengths) = read_length(buf, 0)? {
+                break lengths;
+            }
+        };
+        let required = header_len + remaining_len;
+        if required > self.max_packet_size {
+            return Err(Error::BudgetExceeded {
+                budget: self.max_packet_size,
+                required,
+            });
+        }
+        buf.resize(required, 0);
+        self.io.read_exact(&mut buf[header_len..])?;
+        decode_slice(buf)
+    }
+}
+
+/// One end of an in-memory, non-blocking connection, for tests.
+///
+/// ```
+/// # use mqttrs::*;
+/// let (mut client, mut server) = MemoryTransport::pair();
+/// client.send(&Packet::Pingreq)?;
+/// assert_eq!(Some(Packet::Pingreq), server.recv()?);
+/// assert_eq!(None, server.recv()?);
+/// server.send(&Packet::Pingresp)?;
+/// assert_eq!(Some(Packet::Pingresp), client.recv()?);
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// Both ends can be moved to different threads.
+#[derive(Debug, Clone)]
+pub struct MemoryTransport {
+    incoming: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    outgoing: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    buf: Vec<u8>,
+}
+
+impl MemoryTransport {
+    /// Create the two connected ends.
+    pub fn pair() -> (Self, Self) {
+        let a = Arc::new(Mutex::new(VecDeque::new()));
+        let b = Arc::new(Mutex::new(VecDeque::new()));
+        (
+            MemoryTransport {
+                incoming: a.clone(),
+                outgoing: b.clone(),
+                buf: Vec::new(),
+            },
+            MemoryTransport {
+                incoming: b,
+                outgoing: a,
+                buf: Vec::new(),
+            },
+        )
+    }
+
+    /// Number of packets sent by the other end and not received yet.
+    pub fn pending(&self) -> usize {
+        self.incoming.lock().unwrap().len()
+    }
+}
+
+impl PacketSink for MemoryTransport {
+    fn send(&mut self, packet: &Packet<'_>) -> Result<(), Error> {
+        let mut buf = vec![0; packet.encoded_len()];
+        let len = encode_slice(packet, &mut buf)?;
+        buf.truncate(len);
+        self.outgoing.lock().unwrap().push_back(buf);
+        Ok(())
+    }
+}
+
+impl PacketStream for MemoryTransport {
+    fn recv(&mut self) -> Result<Option<Packet<'_>>, Error> {
+        match self.incoming.lock().unwrap().pop_front() {
+            Some(bytes) => self.buf = bytes,
+            None => return Ok(None),
+        }
+        decode_slice(&self.buf)
+    }
+}
+
+/// A [`PacketSink`] that limits the number of unacknowledged `QoS 1` and `QoS 2` publishes.
+///
+/// MQTT 5 servers announce how many such publishes they accept at once, as their Receive
+/// Maximum. MQTT 3.1.1 has no such negotiation, but servers still drop or throttle clients that
+/// overrun them, so the limit can come from configuration. Publishes that would go over the
+/// limit fail with [`Error::LimitExceeded`] without being sent; other packets are always sent.
+/// Feed the received packets to [`received()`] to release the quota on `Puback` and `Pubcomp`.
+///
+/// ```
+/// # use mqttrs::*;
+/// let (client, _server) = MemoryTransport::pair();
+/// let mut client = QuotaSink::new(client, 1);
+/// let publish = |pid| Packet::Publish(Publish { dup: false,
+///                                               qospid: QosPid::AtLeastOnce(pid),
+///                                               retain: false,
+///                                               topic_name: "a",
+///                                               payload: b"" });
+/// client.send(&publish(Pid::new()))?;
+/// assert!(!client.is_ready());
+/// assert!(client.send(&publish(Pid::new() + 1)).is_err());
+///
+/// client.received(&Packet::Puback(Pid::new()));
+/// assert!(client.is_ready());
+/// client.send(&publish(Pid::new() + 1))?;
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// Async callers can wait for quota with [`poll_ready()`].
+///
+/// [`PacketSink`]: trait.PacketSink.html
+/// [`Error::LimitExceeded`]: enum.Error.html#variant.LimitExceeded
+/// [`received()`]: #method.received
+/// [`poll_ready()`]: #method.poll_ready
+#[derive(Debug)]
+pub struct QuotaSink<S> {
+    inner: S,
+    receive_maximum: u16,
+    in_flight: HashSet<Pid>,
+    waker: Option<Waker>,
+}
+
+impl<S: PacketSink> QuotaSink<S> {
+    /// Allow at most `receive_maximum` unacknowledged publishes. Zero is treated as one.
+    pub fn new(inner: S, receive_maximum: u16) -> Self {
+        QuotaSink {
+            inner,
+            receive_maximum: receive_maximum.max(1),
+            in_flight: HashSet::new(),
+            waker: None,
+        }
+    }
+
+    /// Number of `QoS 1` and `QoS 2` publishes that can be sent now.
+    pub fn available(&self) -> usize {
+        usize::from(self.receive_maximum).saturating_sub(self.in_flight.len())
+    }
+
+    /// Returns true if a `QoS 1` or `QoS 2` publish can be sent now.
+    pub fn is_ready(&self) -> bool {
+        self.available() > 0
+    }
+
+    /// Returns `Poll::Ready` if a `QoS 1` or `QoS 2` publish can be sent now. Otherwise the task
+    /// is woken when [`received()`] releases some quota.
+    ///
+    /// [`received()`]: #method.received
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.is_ready() {
+            Poll::Ready(())
+        } else {
+            self.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    /// Update the quota with a packet received from the peer.
+    ///
+    /// A `Puback` releases a `QoS 1` publish and a `Pubcomp` a `QoS 2` publish. Returns true if
+    /// some quota was released.
+    pub fn received(&mut self, packet: &Packet<'_>) -> bool {
+        let released = match packet {
+            Packet::Puback(pid) | Packet::Pubcomp(pid) => self.in_flight.remove(pid),
+            _ => false,
+        };
+        if released {
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+        }
+        released
+    }
+
+    /// Forget all in-flight publishes, after reconnecting with a clean session.
+    pub fn reset(&mut self) {
+        self.in_flight.clear();
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: PacketSink> PacketSink for QuotaSink<S> {
+    fn send(&mut self, packet: &Packet<'_>) -> Result<(), Error> {
+        let pid = match packet {
+            Packet::Publish(publish) => match publish.qospid {
+                QosPid::AtMostOnce => None,
+                QosPid::AtLeastOnce(pid) | QosPid::ExactlyOnce(pid) => Some(pid),
+            },
+            _ => None,
+        };
+        match pid {
+            // Retransmissions of an in-flight publish don't use more quota.
+            Some(pid) if !self.in_flight.contains(&pid) => {
+                if !self.is_ready() {
+                    return Err(Error::LimitExceeded {
+                        field: "receive maximum",
+                        limit: self.receive_maximum.into(),
+                        actual: self.in_flight.len() + 1,
+                    });
+                }
+                self.inner.send(packet)?;
+                self.in_flight.insert(pid);
+                Ok(())
+            }
+            _ => self.inner.send(packet),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use std::{io::Cursor, thread};
+
+    /// Reads one byte at a time, like a slow network.
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    /// Answers each `Pingreq` with a `Pingresp`, written against the traits only.
+    fn ping_server(transport: &mut (impl PacketSink + PacketStream)) -> Result<usize, Error> {
+        let mut pings = 0;
+        while let Some(packet) = transport.recv()? {
+            if packet == Packet::Pingreq {
+                pings += 1;
+                transport.send(&Packet::Pingresp)?;
+            }
+        }
+        Ok(pings)
+    }
+
+    #[test]
+    fn io_transport() {
+        let publish = Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "a",
+            payload: &[3; 200],
+        });
+        let mut writer = IoTransport::new(Vec::new());
+        writer.send(&publish).unwrap();
+        writer.send(&Packet::Disconnect).unwrap();
+
+        let mut reader = IoTransport::new(Trickle(Cursor::new(writer.into_inner())));
+        assert_eq!(Ok(Some(publish)), reader.recv());
+        assert_eq!(Ok(Some(Packet::Disconnect)), reader.recv());
+        assert_eq!(Ok(None), reader.recv());
+
+        let mut truncated = IoTransport::new(Cursor::new(vec![0b00110000, 10, 0]));
+        assert!(matches!(
+            truncated.recv(),
+            Err(Error::IoError(ErrorKind::UnexpectedEof, _))
+        ));
+    }
+
+    #[test]
+    fn max_packet_size() {
+        // A publish announcing 300 bytes, followed by a ping.
+        let mut bytes = vec![0b00110000, 0xAC, 0x02, 0, 1, b'a'];
+        bytes.extend_from_slice(&[0; 297]);
+        bytes.extend_from_slice(&[0b11000000, 0]);
+        let mut transport = IoTransport::new(Cursor::new(bytes));
+        transport.set_max_packet_size(302);
+        assert_eq!(
+            Err(Error::BudgetExceeded {
+                budget: 302,
+                required: 303
+            }),
+            transport.recv()
+        );
+        assert_eq!(3, transport.get_ref().position());
+
+        let mut huge = IoTransport::new(Cursor::new(vec![0b00110000, 0xFF, 0xFF, 0xFF, 0x7F]));
+        huge.set_max_packet_size(1024);
+        assert_eq!(
+            Err(Error::BudgetExceeded {
+                budget: 1024,
+                required: 268_435_460
+            }),
+            huge.recv()
+        );
+        assert!(huge.buf.capacity() < 1024);
+    }
+
+    #[test]
+    fn quota_sink() {
+        let (client, mut server) = MemoryTransport::pair();
+        let mut client = QuotaSink::new(client, 2);
+        let publish = |qospid| {
+            Packet::Publish(Publish {
+                dup: false,
+                qospid,
+                retain: false,
+                topic_name: "a",
+                payload: b"",
+            })
+        };
+        let (one, two, three) = (Pid::new(), Pid::new() + 1, Pid::new() + 2);
+        client.send(&publish(QosPid::AtLeastOnce(one))).unwrap();
+        client.send(&publish(QosPid::ExactlyOnce(two))).unwrap();
+        // QoS 0, retransmissions and other packets are still sent.
+        client.send(&publish(QosPid::AtMostOnce)).unwrap();
+        client.send(&publish(QosPid::AtLeastOnce(one))).unwrap();
+        client.send(&Packet::Pubrel(two)).unwrap();
+        assert_eq!(
+            Err(Error::LimitExceeded {
+                field: "receive maximum",
+                limit: 2,
+                actual: 3
+            }),
+            client.send(&publish(QosPid::AtLeastOnce(three)))
+        );
+        assert_eq!(5, server.pending());
+
+        let woken = Arc::new(Mutex::new(false));
+        struct Flag(Arc<Mutex<bool>>);
+        impl std::task::Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                *self.0.lock().unwrap() = true;
+            }
+        }
+        let waker = Waker::from(Arc::new(Flag(woken.clone())));
+        let mut cx = Context::from_waker(&waker);
+        assert_eq!(Poll::Pending, client.poll_ready(&mut cx));
+        // A Pubrec doesn't complete a QoS 2 flow.
+        assert!(!client.received(&Packet::Pubrec(two)));
+        assert!(client.received(&Packet::Pubcomp(two)));
+        assert!(*woken.lock().unwrap());
+        assert_eq!(Poll::Ready(()), client.poll_ready(&mut cx));
+        client.send(&publish(QosPid::AtLeastOnce(three))).unwrap();
+        assert_eq!(0, client.available());
+        while server.recv().unwrap().is_some() {}
+        client.reset();
+        assert_eq!(2, client.available());
+    }
+
+    #[test]
+    fn split_io_transport() {
+        let incoming = vec![0b11010000, 0, 0b11010000, 0];
+        let transport = IoTransport::new((Cursor::new(incoming), Vec::new()));
+        let (mut rx, mut tx) = transport.split(|io| io);
+        let sender = thread::spawn(move || {
+            tx.send(&Packet::Pingreq).unwrap();
+            tx.send(&Packet::Disconnect).unwrap();
+            tx.into_inner()
+        });
+        assert_eq!(Ok(Some(Packet::Pingresp)), rx.recv());
+        assert_eq!(Ok(Some(Packet::Pingresp)), rx.recv());
+        assert_eq!(Ok(None), rx.recv());
+        assert_eq!(vec![0b11000000, 0, 0b11100000, 0], sender.join().unwrap());
+    }
+
+    #[test]
+    fn memory_transport() {
+        let (mut client, mut server) = MemoryTransport::pair();
+        client.send(&Packet::Pingreq).unwrap();
+        client.send(&Packet::Pingreq).unwrap();
+        assert_eq!(2, server.pending());
+        let pings = thread::spawn(move || ping_server(&mut server).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(2, pings);
+        assert_eq!(Ok(Some(Packet::Pingresp)), client.recv());
+        assert_eq!(Ok(Some(Packet::Pingresp)), client.recv());
+        assert_eq!(Ok(None), client.recv());
+    }
+}
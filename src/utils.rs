@@ -1,4 +1,4 @@
-use crate::encoder::write_u16;
+use crate::{decoder::read_u16, encoder::write_u16, PacketType, TopicError};
 use core::{convert::TryFrom, fmt, num::NonZeroU16};
 
 #[cfg(feature = "derive")]
@@ -13,17 +13,24 @@ use std::{
 
 /// Errors returned by [`encode()`] and [`decode()`].
 ///
+/// New variants may be added in future versions. Use [`class()`] to handle errors by category
+/// rather than by variant.
+///
 /// [`encode()`]: fn.encode.html
 /// [`decode()`]: fn.decode.html
+/// [`class()`]: #method.class
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Error {
     /// Not enough space in the write buffer.
     ///
-    /// It is the caller's responsiblity to pass a big enough buffer to `encode()`.
-    WriteZero,
+    /// It is the caller's responsiblity to pass a big enough buffer to `encode()`. The error
+    /// tells how many bytes the packet `required` and how many were `available`, so that the
+    /// buffer can be resized once. Both are 0 when converted from an `io::Error`.
+    WriteZero { required: usize, available: usize },
     /// Tried to encode or decode a ProcessIdentifier==0.
     InvalidPid,
-    /// Tried to decode a QoS > 2.
+    /// Tried to decode a QoS > 2, or to set the pid of a `QoS 0` publish.
     InvalidQos(u8),
     /// Tried to decode a ConnectReturnCode > 5.
     InvalidConnectReturnCode(u8),
@@ -32,15 +39,63 @@ pub enum Error {
     InvalidProtocol(std::string::String, u8),
     #[cfg(not(feature = "std"))]
     InvalidProtocol(heapless::String<10>, u8),
-    /// Tried to decode an invalid fixed header (packet type, flags, or remaining_length).
-    InvalidHeader,
+    /// Tried to decode a fixed header with a reserved packet type (0, or 15 which is `AUTH` in
+    /// MQTT 5).
+    UnknownPacketType(u8),
+    /// Tried to decode a fixed header with flags that are invalid for this packet type, to
+    /// encode a `QoS 0` publish with the dup flag, or to rewrite the pid of a `QoS 0` publish.
+    InvalidFlags { packet_type: PacketType, flags: u8 },
+    /// Tried to decode a remaining length longer than 4 bytes.
+    MalformedRemainingLength,
+    /// The packet is valid, but of a type that this function doesn't handle.
+    UnexpectedPacketType(PacketType),
     /// Trying to encode/decode an invalid length.
     ///
     /// The difference with `WriteZero`/`UnexpectedEof` is that it refers to an invalid/corrupt
     /// length rather than a buffer size issue.
     InvalidLength,
+    /// Tried to decode or build a topic filter that breaks the rules of [MQTT 4.7] (empty,
+    /// containing a null character, or with misplaced wildcards).
+    ///
+    /// [MQTT 4.7]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106
+    InvalidTopicFilter(TopicError),
+    /// Tried to encode an option that MQTT 3.1.1 can't express, like the MQTT 5 subscription
+    /// options.
+    UnsupportedOption {
+        /// Name of the option, like `"no local"`.
+        field: &'static str,
+    },
     /// Trying to decode a non-utf8 string.
-    InvalidString(core::str::Utf8Error),
+    InvalidString {
+        /// Name of the field, like `"client id"` or `"topic name"`.
+        field: &'static str,
+        error: core::str::Utf8Error,
+    },
+    /// A value is larger than what MQTT or `mqttrs` can handle.
+    ///
+    /// For example a string longer than 65535 bytes, or (without the `std` feature) more topics
+    /// than fit in a `Subscribe`.
+    LimitExceeded {
+        /// What exceeded the limit, like `"string length"` or `"topics"`.
+        field: &'static str,
+        limit: usize,
+        /// The actual value, or the value that first went over the limit.
+        actual: usize,
+    },
+    /// Tried to restore a persisted [`SessionState`] that isn't in a known format. `version` is
+    /// `None` if the data doesn't start with the expected magic bytes.
+    ///
+    /// [`SessionState`]: struct.SessionState.html
+    UnknownSessionFormat { version: Option<u8> },
+    /// A peer announced a packet larger than the connection's buffer budget, see
+    /// [`StreamDecoder::set_budget()`] and the `max_packet_size` of the async `read_packet()`.
+    ///
+    /// [`StreamDecoder::set_budget()`]: struct.StreamDecoder.html#method.set_budget
+    BudgetExceeded {
+        budget: usize,
+        /// Total size of the announced packet, in bytes.
+        required: usize,
+    },
     /// Catch-all error when converting from `std::io::Error`.
     ///
     /// Note: Only available when std is available.
@@ -52,9 +107,212 @@ pub enum Error {
 #[cfg(feature = "std")]
 impl ErrorTrait for Error {}
 
+/// Category of an [`Error`].
+///
+/// Matching on the category rather than on individual `Error` variants keeps working when new
+/// variants are added.
+///
+/// [`Error`]: enum.Error.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorClass {
+    /// The data doesn't follow the MQTT spec.
+    Malformed,
+    /// Valid MQTT that `mqttrs` doesn't support, like an unknown protocol version.
+    Unsupported,
+    /// A buffer or value is too big (including `WriteZero`).
+    LimitExceeded,
+    /// Error from the underlying IO.
+    Io,
+}
+
+/// Numeric code and name of each `Error` variant, see [`Error::code()`].
+///
+/// Codes are stable: they won't be reused or renumbered in future versions. Code 8 was
+/// `InvalidHeader`, which has been split into codes 13 to 15.
+///
+/// [`Error::code()`]: enum.Error.html#method.code
+pub const ERROR_CODES: &[(u16, &str)] = &[
+    (3, "WriteZero"),
+    (4, "InvalidPid"),
+    (5, "InvalidQos"),
+    (6, "InvalidConnectReturnCode"),
+    (7, "InvalidProtocol"),
+    (9, "InvalidLength"),
+    (10, "InvalidString"),
+    (11, "LimitExceeded"),
+    (12, "IoError"),
+    (13, "UnknownPacketType"),
+    (14, "InvalidFlags"),
+    (15, "MalformedRemainingLength"),
+    (16, "UnexpectedPacketType"),
+    (17, "InvalidTopicFilter"),
+    (18, "UnsupportedOption"),
+    (19, "UnknownSessionFormat"),
+    (20, "BudgetExceeded"),
+];
+
+impl Error {
+    /// Return the stable numeric code of this error's variant.
+    ///
+    /// This is meant for FFI, logs and dashboards. Use [`code_name()`] for the reverse lookup.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// assert_eq!(9, Error::InvalidLength.code());
+    /// assert_eq!(Some("InvalidLength"), Error::code_name(9));
+    /// ```
+    ///
+    /// [`code_name()`]: #method.code_name
+    pub fn code(&self) -> u16 {
+        match self {
+            Error::WriteZero { .. } => 3,
+            Error::InvalidPid => 4,
+            Error::InvalidQos(_) => 5,
+            Error::InvalidConnectReturnCode(_) => 6,
+            Error::InvalidProtocol(..) => 7,
+            Error::UnknownPacketType(_) => 13,
+            Error::InvalidFlags { .. } => 14,
+            Error::MalformedRemainingLength => 15,
+            Error::UnexpectedPacketType(_) => 16,
+            Error::InvalidTopicFilter(_) => 17,
+            Error::UnsupportedOption { .. } => 18,
+            Error::UnknownSessionFormat { .. } => 19,
+            Error::BudgetExceeded { .. } => 20,
+            Error::InvalidLength => 9,
+            Error::InvalidString { .. } => 10,
+            Error::LimitExceeded { .. } => 11,
+            #[cfg(feature = "std")]
+            Error::IoError(..) => 12,
+        }
+    }
+
+    /// Return the name of the `Error` variant with this numeric code, if any.
+    pub fn code_name(code: u16) -> Option<&'static str> {
+        ERROR_CODES
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, name)| *name)
+    }
+
+    /// Return the class of this error.
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Error::WriteZero { .. }
+            | Error::LimitExceeded { .. }
+            | Error::BudgetExceeded { .. } => ErrorClass::LimitExceeded,
+            Error::InvalidProtocol(..)
+            | Error::UnknownPacketType(_)
+            | Error::UnexpectedPacketType(_)
+            | Error::UnsupportedOption { .. }
+            | Error::UnknownSessionFormat { .. } => ErrorClass::Unsupported,
+            #[cfg(feature = "std")]
+            Error::IoError(..) => ErrorClass::Io,
+            Error::InvalidPid
+            | Error::InvalidQos(_)
+            | Error::InvalidConnectReturnCode(_)
+            | Error::InvalidFlags { .. }
+            | Error::MalformedRemainingLength
+            | Error::InvalidLength
+            | Error::InvalidTopicFilter(_)
+            | Error::InvalidString { .. } => ErrorClass::Malformed,
+        }
+    }
+
+    /// Returns true if the network connection must be closed after this error.
+    ///
+    /// The spec requires closing the connection on any protocol violation ([MQTT 4.8]), which
+    /// includes every malformed packet, and after refusing an unsupported protocol ([MQTT-3.1.2-2]).
+    /// IO errors are fatal too, except for transient ones like `Interrupted` or `WouldBlock`, and
+    /// so is `BudgetExceeded` since the rest of the packet can't be skipped.
+    /// The other errors (like a too small buffer, a too long string when encoding, an
+    /// `UnexpectedPacketType` or an `UnsupportedOption`) only affect the current operation.
+    ///
+    /// ```
+    /// # use mqttrs::*;
+    /// assert!(decode_slice(&[0xf0, 0]).unwrap_err().is_connection_fatal());
+    /// assert!(!encode_slice(&Packet::Pingreq, &mut []).unwrap_err().is_connection_fatal());
+    /// ```
+    ///
+    /// [MQTT 4.8]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html
+    /// [MQTT-3.1.2-2]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718028
+    pub fn is_connection_fatal(&self) -> bool {
+        match self {
+            #[cfg(feature = "std")]
+            Error::IoError(kind, _) => !matches!(
+                kind,
+                ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+            ),
+            Error::UnexpectedPacketType(_)
+            | Error::UnsupportedOption { .. }
+            | Error::UnknownSessionFormat { .. } => false,
+            Error::BudgetExceeded { .. } => true,
+            _ => matches!(
+                self.class(),
+                ErrorClass::Malformed | ErrorClass::Unsupported
+            ),
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::WriteZero {
+                required,
+                available,
+            } => write!(
+                f,
+                "buffer too small: {} bytes required, {} available",
+                required, available
+            ),
+            Error::InvalidPid => write!(f, "packet identifier must not be 0"),
+            Error::InvalidQos(qos) => write!(f, "invalid QoS {}, must be 0, 1 or 2", qos),
+            Error::InvalidConnectReturnCode(code) => {
+                write!(f, "invalid connect return code {}, must be 0 to 5", code)
+            }
+            Error::InvalidProtocol(name, level) => {
+                write!(f, "unsupported protocol {:?} level {}", name, level)
+            }
+            Error::UnknownPacketType(typ) => write!(f, "unknown packet type {}", typ),
+            Error::InvalidFlags { packet_type, flags } => write!(
+                f,
+                "invalid fixed header flags {:#06b} for {:?} packet",
+                flags, packet_type
+            ),
+            Error::MalformedRemainingLength => {
+                write!(f, "remaining length is longer than 4 bytes")
+            }
+            Error::UnexpectedPacketType(typ) => write!(f, "unexpected {:?} packet", typ),
+            Error::InvalidLength => write!(f, "length is inconsistent with the packet size"),
+            Error::InvalidTopicFilter(e) => write!(f, "{}", e),
+            Error::UnsupportedOption { field } => {
+                write!(f, "{} is not supported by MQTT 3.1.1", field)
+            }
+            Error::UnknownSessionFormat {
+                version: Some(version),
+            } => {
+                write!(f, "unknown persisted session version {}", version)
+            }
+            Error::UnknownSessionFormat { version: None } => {
+                write!(f, "not a persisted session")
+            }
+            Error::BudgetExceeded { budget, required } => write!(
+                f,
+                "packet of {} bytes exceeds the connection budget of {} bytes",
+                required, budget
+            ),
+            Error::InvalidString { field, error } => {
+                write!(f, "{} is not valid UTF-8: {}", field, error)
+            }
+            Error::LimitExceeded {
+                field,
+                limit,
+                actual,
+            } => write!(f, "{}: {} exceeds the limit of {}", field, actual, limit),
+            #[cfg(feature = "std")]
+            Error::IoError(_, msg) => write!(f, "I/O error: {}", msg),
+        }
     }
 }
 
@@ -62,7 +320,7 @@ impl fmt::Display for Error {
 impl From<Error> for IoError {
     fn from(err: Error) -> IoError {
         match err {
-            Error::WriteZero => IoError::new(ErrorKind::WriteZero, err),
+            Error::WriteZero { .. } => IoError::new(ErrorKind::WriteZero, err),
             _ => IoError::new(ErrorKind::InvalidData, err),
         }
     }
@@ -72,33 +330,78 @@ impl From<Error> for IoError {
 impl From<IoError> for Error {
     fn from(err: IoError) -> Error {
         match err.kind() {
-            ErrorKind::WriteZero => Error::WriteZero,
+            ErrorKind::WriteZero => Error::WriteZero {
+                required: 0,
+                available: 0,
+            },
             k => Error::IoError(k, format!("{}", err)),
         }
     }
 }
 
+/// Serialize `&[u8]` fields as bytes instead of a sequence of `u8`, which is more compact in most
+/// formats.
+#[cfg(feature = "derive")]
+pub(crate) mod serde_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de: 'a, 'a, D: Deserializer<'de>>(d: D) -> Result<&'a [u8], D::Error> {
+        <&[u8]>::deserialize(d)
+    }
+}
+
+/// Same as `serde_bytes`, for `Option<&[u8]>` fields.
+#[cfg(feature = "derive")]
+pub(crate) mod serde_opt_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<&[u8]>, s: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(b) => s.serialize_some(&super::SerdeBytes(b)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de: 'a, 'a, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<&'a [u8]>, D::Error> {
+        Option::<&[u8]>::deserialize(d)
+    }
+}
+
+#[cfg(feature = "derive")]
+struct SerdeBytes<'a>(&'a [u8]);
+
+#[cfg(feature = "derive")]
+impl Serialize for SerdeBytes<'_> {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_bytes(self.0)
+    }
+}
+
 /// Packet Identifier.
 ///
 /// For packets with [`QoS::AtLeastOne` or `QoS::ExactlyOnce`] delivery.
 ///
 /// ```rust
-/// # use mqttrs::{Packet, Pid, QosPid};
+/// # use mqttrs::{Packet, Pid, PidCycle, QosPid};
 /// # use std::convert::TryFrom;
-/// #[derive(Default)]
 /// struct Session {
-///    pid: Pid,
+///    pids: PidCycle,
 /// }
 /// impl Session {
 ///    pub fn next_pid(&mut self) -> Pid {
-///        self.pid = self.pid + 1;
-///        self.pid
+///        self.pids.next().unwrap()
 ///    }
 /// }
 ///
-/// let mut sess = Session::default();
-/// assert_eq!(2, sess.next_pid().get());
-/// assert_eq!(Pid::try_from(3).unwrap(), sess.next_pid());
+/// let mut sess = Session { pids: Pid::cycle() };
+/// assert_eq!(1, sess.next_pid().get());
+/// assert_eq!(Pid::try_from(2).unwrap(), sess.next_pid());
 /// ```
 ///
 /// The spec ([MQTT-2.3.1-1], [MQTT-2.2.1-3]) disallows a pid of 0.
@@ -120,10 +423,54 @@ impl Pid {
         self.0.get()
     }
 
-    pub(crate) fn from_buffer<'a>(buf: &'a [u8], offset: &mut usize) -> Result<Self, Error> {
-        let pid = ((buf[*offset] as u16) << 8) | buf[*offset + 1] as u16;
-        *offset += 2;
-        Self::try_from(pid)
+    /// Returns an endless iterator over all pids, from `1` to `65535` then wrapping to `1`.
+    ///
+    /// Use [`PidCycle::starting_at()`] to start from another pid, like the last one used by a
+    /// restored session.
+    ///
+    /// [`PidCycle::starting_at()`]: struct.PidCycle.html#method.starting_at
+    pub fn cycle() -> PidCycle {
+        PidCycle::starting_at(Pid::new())
+    }
+
+    /// Returns the next `Pid`, wrapping from `65535` to `1`.
+    ///
+    /// ```
+    /// # use mqttrs::Pid;
+    /// # use std::convert::TryFrom;
+    /// assert_eq!(2, Pid::new().wrapping_next().get());
+    /// assert_eq!(Pid::new(), Pid::try_from(u16::MAX).unwrap().wrapping_next());
+    /// ```
+    pub fn wrapping_next(self) -> Pid {
+        match NonZeroU16::new(self.get().wrapping_add(1)) {
+            Some(nz) => Pid(nz),
+            None => Pid::new(),
+        }
+    }
+
+    /// Adds `u` to the `Pid`, returning `None` if the result is above `65535`.
+    ///
+    /// ```
+    /// # use mqttrs::Pid;
+    /// assert_eq!(11, Pid::new().checked_add(10).unwrap().get());
+    /// assert_eq!(None, Pid::new().checked_add(u16::MAX));
+    /// ```
+    pub fn checked_add(self, u: u16) -> Option<Pid> {
+        self.0.checked_add(u).map(Pid)
+    }
+
+    /// Subtracts `u` from the `Pid`, returning `None` if the result is below `1`.
+    ///
+    /// ```
+    /// # use mqttrs::Pid;
+    /// assert_eq!(None, Pid::new().checked_sub(1));
+    /// ```
+    pub fn checked_sub(self, u: u16) -> Option<Pid> {
+        NonZeroU16::new(self.get().checked_sub(u)?).map(Pid)
+    }
+
+    pub(crate) fn from_buffer(buf: &[u8], offset: &mut usize) -> Result<Self, Error> {
+        Self::try_from(read_u16(buf, offset)?)
     }
 
     pub(crate) fn to_buffer(self, buf: &mut [u8], offset: &mut usize) -> Result<(), Error> {
@@ -137,10 +484,56 @@ impl Default for Pid {
     }
 }
 
+/// Endless iterator over pids, returned by [`Pid::cycle()`].
+///
+/// Pids are yielded in increasing order, wrapping from `65535` to `1`, so `next()` never returns
+/// `None`.
+///
+/// ```
+/// # use mqttrs::*;
+/// # use std::convert::TryFrom;
+/// let mut pids = PidCycle::starting_at(Pid::try_from(u16::MAX)?);
+/// assert_eq!(Some(u16::MAX), pids.next().map(Pid::get));
+/// assert_eq!(Some(Pid::new()), pids.next());
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`Pid::cycle()`]: struct.Pid.html#method.cycle
+#[derive(Debug, Clone)]
+pub struct PidCycle {
+    next: Pid,
+}
+
+impl PidCycle {
+    /// Start the iteration at `pid`.
+    pub fn starting_at(pid: Pid) -> Self {
+        PidCycle { next: pid }
+    }
+}
+
+impl Iterator for PidCycle {
+    type Item = Pid;
+
+    fn next(&mut self) -> Option<Pid> {
+        let pid = self.next;
+        self.next = pid.wrapping_next();
+        Some(pid)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (usize::MAX, None)
+    }
+}
+
+impl core::iter::FusedIterator for PidCycle {}
+
 impl core::ops::Add<u16> for Pid {
     type Output = Pid;
 
     /// Adding a `u16` to a `Pid` will wrap around and avoid 0.
+    ///
+    /// Prefer [`Pid::wrapping_next()`] or [`Pid::checked_add()`], which make the overflow
+    /// behaviour explicit.
     fn add(self, u: u16) -> Pid {
         let n = match self.get().overflowing_add(u) {
             (n, false) => n,
@@ -153,10 +546,12 @@ impl core::ops::Add<u16> for Pid {
 impl core::ops::Sub<u16> for Pid {
     type Output = Pid;
 
-    /// Adding a `u16` to a `Pid` will wrap around and avoid 0.
+    /// Subtracting a `u16` from a `Pid` will wrap around and avoid 0.
+    ///
+    /// Prefer [`Pid::checked_sub()`], which makes the underflow behaviour explicit.
     fn sub(self, u: u16) -> Pid {
         let n = match self.get().overflowing_sub(u) {
-            (0, _) => core::u16::MAX,
+            (0, _) => u16::MAX,
             (n, false) => n,
             (n, true) => n - 1,
         };
@@ -185,8 +580,10 @@ impl TryFrom<u16> for Pid {
 
 /// Packet delivery [Quality of Service] level.
 ///
+/// Levels are ordered from `AtMostOnce` to `ExactlyOnce`.
+///
 /// [Quality of Service]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718099
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 pub enum QoS {
     /// `QoS 0`. No ack needed.
@@ -198,8 +595,8 @@ pub enum QoS {
 }
 
 impl QoS {
-    pub(crate) fn to_u8(&self) -> u8 {
-        match *self {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
             QoS::AtMostOnce => 0,
             QoS::AtLeastOnce => 1,
             QoS::ExactlyOnce => 2,
@@ -265,26 +662,134 @@ impl QosPid {
     }
 }
 
+/// What to do with a message when a queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message to make room, favoring fresh data.
+    DropOldest,
+    /// Drop the new message, favoring the messages already queued.
+    DropNewest,
+}
+
 #[cfg(test)]
 mod test {
-    use crate::Pid;
+    use crate::{Error, PacketType, Pid, PidCycle};
     use core::convert::TryFrom;
-    use std::vec;
+    use std::{io::ErrorKind, string::String, vec};
+
+    #[test]
+    fn error_codes() {
+        let errors = [
+            Error::WriteZero {
+                required: 2,
+                available: 0,
+            },
+            Error::InvalidPid,
+            Error::InvalidQos(3),
+            Error::InvalidConnectReturnCode(6),
+            Error::InvalidProtocol("MQTT".into(), 5),
+            Error::UnknownPacketType(0),
+            Error::InvalidFlags {
+                packet_type: PacketType::Connect,
+                flags: 1,
+            },
+            Error::MalformedRemainingLength,
+            Error::UnexpectedPacketType(PacketType::Connect),
+            Error::InvalidTopicFilter(crate::TopicError {
+                kind: crate::TopicErrorKind::Empty,
+                level: 1,
+            }),
+            Error::UnsupportedOption { field: "no local" },
+            Error::UnknownSessionFormat { version: Some(2) },
+            Error::BudgetExceeded {
+                budget: 4096,
+                required: 8192,
+            },
+            Error::InvalidLength,
+            crate::decoder::read_str(&[0, 1, 0xff], &mut 0, "topic name").unwrap_err(),
+            Error::LimitExceeded {
+                field: "topics",
+                limit: 5,
+                actual: 6,
+            },
+            Error::IoError(ErrorKind::Other, String::new()),
+        ];
+        assert_eq!(crate::ERROR_CODES.len(), errors.len());
+        for err in &errors {
+            let name = Error::code_name(err.code()).unwrap();
+            assert!(std::format!("{:?}", err).starts_with(name), "{:?}", err);
+        }
+        assert_eq!(None, Error::code_name(0));
+    }
+
+    #[test]
+    fn display() {
+        let msg = |e: Error| std::format!("{}", e);
+        assert_eq!(
+            "buffer too small: 20 bytes required, 8 available",
+            msg(Error::WriteZero {
+                required: 20,
+                available: 8
+            })
+        );
+        assert_eq!(
+            "invalid fixed header flags 0b0001 for Subscribe packet",
+            msg(Error::InvalidFlags {
+                packet_type: PacketType::Subscribe,
+                flags: 1
+            })
+        );
+        assert_eq!(
+            "topics: 6 exceeds the limit of 5",
+            msg(Error::LimitExceeded {
+                field: "topics",
+                limit: 5,
+                actual: 6
+            })
+        );
+        assert_eq!(
+            "topic filter contains misplaced wildcard '+' at level 3",
+            msg(crate::TopicFilter::new("a/b/c+").unwrap_err())
+        );
+        assert_eq!(
+            "topic name is not valid UTF-8: invalid utf-8 sequence of 1 bytes from index 0",
+            msg(crate::decoder::read_str(&[0, 1, 0xff], &mut 0, "topic name").unwrap_err())
+        );
+    }
+
+    #[test]
+    fn connection_fatal() {
+        assert!(Error::MalformedRemainingLength.is_connection_fatal());
+        assert!(Error::InvalidProtocol("MQTT".into(), 5).is_connection_fatal());
+        assert!(Error::IoError(ErrorKind::ConnectionReset, String::new()).is_connection_fatal());
+        assert!(!Error::IoError(ErrorKind::WouldBlock, String::new()).is_connection_fatal());
+        assert!(Error::BudgetExceeded {
+            budget: 4096,
+            required: 8192
+        }
+        .is_connection_fatal());
+        assert!(!Error::LimitExceeded {
+            field: "topics",
+            limit: 5,
+            actual: 6
+        }
+        .is_connection_fatal());
+    }
 
     #[test]
     fn pid_add_sub() {
         let t: Vec<(u16, u16, u16, u16)> = vec![
             (2, 1, 1, 3),
             (100, 1, 99, 101),
-            (1, 1, core::u16::MAX, 2),
-            (1, 2, core::u16::MAX - 1, 3),
-            (1, 3, core::u16::MAX - 2, 4),
-            (core::u16::MAX, 1, core::u16::MAX - 1, 1),
-            (core::u16::MAX, 2, core::u16::MAX - 2, 2),
-            (10, core::u16::MAX, 10, 10),
+            (1, 1, u16::MAX, 2),
+            (1, 2, u16::MAX - 1, 3),
+            (1, 3, u16::MAX - 2, 4),
+            (u16::MAX, 1, u16::MAX - 1, 1),
+            (u16::MAX, 2, u16::MAX - 2, 2),
+            (10, u16::MAX, 10, 10),
             (10, 0, 10, 10),
             (1, 0, 1, 1),
-            (core::u16::MAX, 0, core::u16::MAX, core::u16::MAX),
+            (u16::MAX, 0, u16::MAX, u16::MAX),
         ];
         for (cur, d, prev, next) in t {
             let sub = Pid::try_from(cur).unwrap() - d;
@@ -293,4 +798,25 @@ mod test {
             assert_eq!(next, add.get(), "{} + {} should be {}", cur, d, next);
         }
     }
+
+    #[test]
+    fn pid_checked() {
+        let pid = |u| Pid::try_from(u).unwrap();
+        assert_eq!(Some(pid(u16::MAX)), pid(1).checked_add(u16::MAX - 1));
+        assert_eq!(None, pid(2).checked_add(u16::MAX - 1));
+        assert_eq!(Some(pid(1)), pid(u16::MAX).checked_sub(u16::MAX - 1));
+        assert_eq!(None, pid(10).checked_sub(10));
+        assert_eq!(Some(pid(10)), pid(10).checked_add(0));
+        assert_eq!(pid(1), pid(u16::MAX).wrapping_next());
+        assert_eq!(pid(101), pid(100).wrapping_next());
+    }
+
+    #[test]
+    fn pid_cycle() {
+        let all: Vec<u16> = Pid::cycle().take(65536).map(Pid::get).collect();
+        assert_eq!((1..=u16::MAX).chain(Some(1)).collect::<Vec<_>>(), all);
+        let mut pids = PidCycle::starting_at(Pid::try_from(u16::MAX - 1).unwrap());
+        assert_eq!(Some(Pid::try_from(u16::MAX).unwrap()), pids.nth(1));
+        assert_eq!(Some(Pid::new()), pids.next());
+    }
 }
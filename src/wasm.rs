@@ -0,0 +1,103 @@
+//! WebAssembly bindings, enabled by the `wasm` feature.
+//!
+//! This exposes `decode()` and `encode()` to JavaScript using [wasm-bindgen], typically for
+//! MQTT-over-WebSocket clients running in the browser. Build the module with:
+//!
+//! ```text
+//! cargo rustc --release --target wasm32-unknown-unknown --features wasm --crate-type cdylib
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/mqttrs.wasm
+//! ```
+//!
+//! Packets use the same representation as with the `derive` feature, with binary data as
+//! `Uint8Array`:
+//!
+//! ```text
+//! import init, { decode, encode } from "./pkg/mqttrs.js";
+//!
+//! const bytes = encode({ Publish: { dup: false, qospid: "AtMostOnce", retain: false,
+//!                                   topic_name: "a/b", payload: new Uint8Array([1, 2]) } });
+//! const { packet, length } = decode(bytes); // Returns undefined for incomplete data.
+//! ```
+//!
+//! [wasm-bindgen]: https://rustwasm.github.io/docs/wasm-bindgen/
+
+use crate::{decoder::decode_slice_with_len, *};
+use bytes::BytesMut;
+use serde::Serialize;
+use std::vec::Vec;
+use wasm_bindgen::prelude::*;
+
+#[derive(Serialize)]
+struct Decoded<'a> {
+    packet: Packet<'a>,
+    length: usize,
+}
+
+/// Decode one packet from `buf`.
+///
+/// Returns `{ packet, length }`, or `undefined` if `buf` doesn't contain a full packet yet.
+#[wasm_bindgen]
+pub fn decode(buf: &[u8]) -> Result<JsValue, JsError> {
+    match decode_slice_with_len(buf)? {
+        Some((packet, length)) => Ok(serde_wasm_bindgen::to_value(&Decoded { packet, length })?),
+        None => Ok(JsValue::UNDEFINED),
+    }
+}
+
+/// Encode a packet, returning a `Uint8Array`.
+#[wasm_bindgen]
+pub fn encode(packet: JsValue) -> Result<Vec<u8>, JsError> {
+    // JavaScript values can't be deserialized into borrowed data.
+    let owned: OwnedPacket = serde_wasm_bindgen::from_value(packet)?;
+    Ok(encode_owned(&owned)?)
+}
+
+fn encode_owned(owned: &OwnedPacket) -> Result<Vec<u8>, Error> {
+    let mut buf = BytesMut::new();
+    encode_grow(&owned.as_borrowed(), &mut buf)?;
+    Ok(buf.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::convert::TryFrom;
+
+    #[test]
+    fn roundtrip() {
+        let packets = [
+            Packet::Connect(Connect {
+                protocol: Protocol::MQTT311,
+                keep_alive: 60,
+                client_id: "client",
+                clean_session: true,
+                last_will: Some(LastWill {
+                    topic: "will",
+                    message: b"gone",
+                    qos: QoS::AtLeastOnce,
+                    retain: false,
+                }),
+                username: Some("user"),
+                password: None,
+            }),
+            Packet::Publish(Publish {
+                dup: false,
+                qospid: QosPid::AtLeastOnce(Pid::try_from(10).unwrap()),
+                retain: true,
+                topic_name: "a/b",
+                payload: &[0; 2000],
+            }),
+            Packet::Pingreq,
+        ];
+        for packet in packets.iter() {
+            let mut buf = [0; 4096];
+            let len = encode_slice(packet, &mut buf).unwrap();
+            let (decoded, length) = decode_slice_with_len(&buf[..len]).unwrap().unwrap();
+            assert_eq!((packet, len), (&decoded, length));
+            // JavaScript hands `decode()`'s output back to `encode()` through serde.
+            let value = postcard::to_allocvec(&decoded).unwrap();
+            let owned: OwnedPacket = postcard::from_bytes(&value).unwrap();
+            assert_eq!(&buf[..len], &encode_owned(&owned).unwrap()[..]);
+        }
+    }
+}
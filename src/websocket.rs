@@ -0,0 +1,108 @@
+use crate::{Error, Packet, StreamDecoder};
+use core::time::Duration;
+
+/// Reassemble MQTT packets from the binary messages of a WebSocket connection ([MQTT 6.0]).
+///
+/// MQTT packets aren't aligned on WebSocket messages: a packet may span several messages, and a
+/// message may contain several packets. Feed each binary message to [`push()`] as it arrives,
+/// then call [`next_packet()`] until it returns `Ok(None)`:
+///
+/// ```
+/// # use mqttrs::*;
+/// let mut ws = WebSocketDecoder::new();
+/// // A Pingresp and the first half of a Puback, then the rest of the Puback.
+/// ws.push(&[0b11010000, 0, 0b01000000]);
+/// assert_eq!(Some(Packet::Pingresp), ws.next_packet()?);
+/// assert_eq!(None, ws.next_packet()?);
+/// ws.push(&[2, 0, 1]);
+/// assert_eq!(Some(Packet::Puback(Pid::new())), ws.next_packet()?);
+/// assert!(ws.is_empty());
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// Text messages must not be passed here: receiving one is a protocol error, and the connection
+/// should be closed. After [`next_packet()`] returns an error the stream can't be resynchronized
+/// either.
+///
+/// [MQTT 6.0]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718127
+/// [`push()`]: #method.push
+/// [`next_packet()`]: #method.next_packet
+#[derive(Debug, Clone, Default)]
+pub struct WebSocketDecoder {
+    stream: StreamDecoder,
+}
+
+impl WebSocketDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append the payload of a binary WebSocket message.
+    pub fn push(&mut self, message: &[u8]) {
+        self.stream.push(message);
+    }
+
+    /// Decode the next complete packet, or return `Ok(None)` if more messages are needed.
+    ///
+    /// The returned packet borrows the decoder's buffer; its bytes are released by the next call
+    /// to `push()` or `next_packet()`.
+    pub fn next_packet(&mut self) -> Result<Option<Packet<'_>>, Error> {
+        self.stream.poll_packet(Duration::ZERO)
+    }
+
+    /// Number of buffered bytes that aren't part of a returned packet yet.
+    pub fn pending_len(&self) -> usize {
+        self.stream.pending_len()
+    }
+
+    /// Returns true if no partial packet is buffered.
+    pub fn is_empty(&self) -> bool {
+        self.stream.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use std::{vec, vec::Vec};
+
+    #[test]
+    fn split_and_coalesced_messages() {
+        let publish = Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtLeastOnce(Pid::new() + 7),
+            retain: false,
+            topic_name: "a/b",
+            payload: &[1; 150],
+        });
+        let mut bytes = vec![0; publish.encoded_len()];
+        encode_slice(&publish, &mut bytes).unwrap();
+        bytes.extend_from_slice(&[0b11000000, 0, 0b11100000, 0]);
+
+        // Every way of splitting the bytes into three messages yields the same packets.
+        for (a, b) in [(0, 1), (1, 2), (2, 100), (150, 157), (158, 160), (160, 160)] {
+            let mut ws = WebSocketDecoder::new();
+            let mut packets = Vec::new();
+            for message in [&bytes[..a], &bytes[a..b], &bytes[b..]] {
+                ws.push(message);
+                while let Some(packet) = ws.next_packet().unwrap() {
+                    packets.push(format!("{:?}", packet));
+                }
+            }
+            assert_eq!(
+                vec![
+                    format!("{:?}", publish),
+                    "Pingreq".to_string(),
+                    "Disconnect".to_string()
+                ],
+                packets
+            );
+            assert!(ws.is_empty());
+        }
+
+        let mut ws = WebSocketDecoder::new();
+        ws.push(&[0b01000000, 2, 0, 0]);
+        assert_eq!(Err(Error::InvalidPid), ws.next_packet());
+    }
+}